@@ -5,7 +5,9 @@ MEMORY
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
 
   /* 3.2 FLASH main features: page size = 1K */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* last 3K - settings and serial number storage, see config::SETTINGS_FLASH_ADDR */
+  /* 9K before it - stored jobs, see config::JOB_FLASH_ADDR */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 116K
 
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
@@ -24,6 +26,18 @@ _stack_start = ORIGIN(RAM) + LENGTH(RAM);
    after the vector table */
 /* _stext = ORIGIN(FLASH) + 0x400; */
 
+/* firmware must not overlap stored jobs: 116K + 9K + 3K = 128K chip.
+   The link fails when the code does not fit in FLASH */
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) == 0x0801D000, "FLASH must end at config::JOB_FLASH_ADDR");
+
+/* crash report survives reset: not zeroed by the runtime, see support::crash */
+SECTIONS {
+  .noinit (NOLOAD) : ALIGN(4) {
+    *(.noinit .noinit.*);
+    . = ALIGN(4);
+  } > RAM
+} INSERT AFTER .bss;
+
 /* Example of putting non-initialized variables into custom RAM locations. */
 /* This assumes you have defined a region RAM2 above, and in the Rust
    sources added the attribute `#[link_section = ".ram2bss"]` to the data
@@ -2,6 +2,9 @@
 
 pub const XTAL_FREQ: u32 = 16_000_000;
 
+/// кварц или PLL не запустились за это время - работа от HSI, мс
+pub const CLOCK_STARTUP_TIMEOUT_MS: u32 = 100;
+
 //-----------------------------------------------------------------------------
 
 pub const GALVO_CLOCK_RATE: u32 = 2_000_000 * 2; // clock needs 2 ticks
@@ -45,6 +48,164 @@ pub const LASER_SYNC_CLOCK_KHZ: u32 = 45;
 /// red mark laser pwm frequency
 pub const LASER_RED_FREQ_KHZ: u32 = 1;
 
+/// максимальная длительность тестового выстрела M120, мс
+pub const LASER_FIRE_MAX_MS: f32 = 10_000.0;
+
+/// включение лазера: Sync работает до включения EE не меньше, мс
+pub const LASER_ARM_DELAY_MS: f32 = 1.0;
+
+/// включение лазера: EE включен до открытия EM не меньше, мс
+pub const LASER_READY_DELAY_MS: f32 = 5.0;
+
+//-----------------------------------------------------------------------------
+
+/// сторожевой таймер IWDG, мс
+pub const WATCHDOG_TIMEOUT_MS: u32 = 250;
+
+/// хост молчит при включенном лазере или движении дольше - авария, мс, 0 - выкл.
+pub const HOST_TIMEOUT_MS: f32 = 5_000.0;
+
+/// максимальное время излучения за одну команду, с, 0 - выкл.
+pub const EMISSION_MAX_S: f32 = 60.0;
+
+/// максимальная скважность излучения в окне, %, 100 - выкл.
+pub const EMISSION_DUTY_MAX: f32 = 100.0;
+
+/// окно скважности излучения, с
+pub const EMISSION_DUTY_WINDOW_S: f32 = 60.0;
+
+/// входы станка (дверь, E-stop, старт, ключ): подавление дребезга, мс
+pub const INPUT_DEBOUNCE_MS: f32 = 10.0;
+
+/// входы станка: маска инверсии, по умолчанию активны при 1. Дверь, старт, ключ - с подтяжкой к 0,
+/// E-stop - с подтяжкой к 1 и нормально замкнутым контактом на землю
+pub const INPUT_INVERT: u32 = 0;
+
+/// куда уводятся зеркала при аварии, мм
+pub const GALVO_PARK_X: f32 = 0.0;
+pub const GALVO_PARK_Y: f32 = 0.0;
+
+//-----------------------------------------------------------------------------
+
+/// skywriting: включен по умолчанию
+pub const SKYWRITING_ENABLED: bool = false;
+
+/// skywriting: длина разгона перед отрезком, мм
+pub const SKYWRITING_RUN_IN_MM: f32 = 0.5;
+
+/// skywriting: длина торможения после отрезка, мм
+pub const SKYWRITING_RUN_OUT_MM: f32 = 0.5;
+
+/// skywriting: угол поворота (градусы), начиная с которого отрезки не склеиваются
+pub const SKYWRITING_CORNER_DEG: f32 = 15.0;
+
+//-----------------------------------------------------------------------------
+
+/// точечная маркировка: шаг точек, мкм
+pub const DOT_PITCH_UM: f32 = 100.0;
+
+/// точечная маркировка: минимальная остановка в точке, мкс
+pub const DOT_DWELL_US: f32 = 0.0;
+
+/// точечная маркировка: импульсов в точке
+pub const DOT_PULSES: u32 = 1;
+
+//-----------------------------------------------------------------------------
+
+/// количество перьев (наборов параметров лазера)
+pub const PENS_COUNT: usize = 16;
+
+/// точек калибровки мощности лазера
+pub const POWER_CURVE_POINTS: usize = 16;
+
+/// область flash под настройки, не входит в FLASH из memory.x
+pub const SETTINGS_FLASH_ADDR: u32 = 0x0801_F400;
+pub const SETTINGS_FLASH_SIZE: usize = 3 * 1024;
+
+/// область flash под сохраненные задания (перед настройками), не входит в FLASH из memory.x
+pub const JOB_FLASH_ADDR: u32 = 0x0801_D000;
+pub const JOB_FLASH_SIZE: usize = 9 * 1024;
+
+/// число сохраненных заданий, область делится поровну (первая страница - счетчики запусков)
+pub const JOB_SLOTS: usize = 4;
+
+/// строк загрузки задания ждут записи во flash не больше
+pub const JOB_UPLOAD_QUEUE_LINES: usize = 2;
+
+// задания вплотную к настройкам, FLASH в memory.x кончается на JOB_FLASH_ADDR
+const _: () = assert!(JOB_FLASH_ADDR + JOB_FLASH_SIZE as u32 == SETTINGS_FLASH_ADDR);
+// настройки - последние страницы STM32F103CB (128 КБ)
+const _: () = assert!(SETTINGS_FLASH_ADDR + SETTINGS_FLASH_SIZE as u32 == 0x0802_0000);
+
+/// step-and-repeat: явных смещений копий (`$FO`) не больше
+pub const JOB_ARRAY_MAX_OFFSETS: usize = 32;
+
+/// вход запуска сохраненного задания: -1 - без ожидания, 0-3 - вход станка (2 - старт)
+pub const JOB_TRIGGER_INPUT: f32 = 2.0;
+
+//-----------------------------------------------------------------------------
+
+/// журнал серийного номера M150 - вторая и третья страницы области настроек
+pub const SERIAL_COUNTER_OFFSET: usize = 1024;
+
+/// M150: длина шаблона текста в команде
+pub const MARK_TEXT_MAX_LEN: usize = 48;
+
+/// начала смен A, B, C для `{SHIFT}`, часы, -1 - смены нет
+pub const SHIFT_START_H: [f32; 3] = [6.0, 14.0, 22.0];
+
+/// M152/M153: шаг штриховки модулей 2D кода, мкм
+pub const CODE_HATCH_PITCH_UM: f32 = 50.0;
+
 //-----------------------------------------------------------------------------
 
 pub type HlString = heapless::String<STR_MAX_LEN>;
+
+//-----------------------------------------------------------------------------
+
+/// часовой кварц (feature `rtc-lse`) не запустился за это время - часы не идут, мс
+pub const RTC_LSE_STARTUP_TIMEOUT_MS: u32 = 2000;
+
+/// журнал событий `$E`: последних записей
+pub const EVENT_LOG_SIZE: usize = 16;
+
+//-----------------------------------------------------------------------------
+
+/// M160: буфер принятых пикселей растра, байт
+pub const RASTER_BUFFER_SIZE: usize = 512;
+
+/// M160: пикселей в строке растра не больше
+pub const RASTER_LINE_MAX: usize = 1024;
+
+/// M160: разгон до и торможение после строки растра, мм
+pub const RASTER_OVERSCAN_MM: f32 = 0.5;
+
+/// M160: сдвиг пикселей по ходу прямых и обратных строк, мкм
+pub const RASTER_OFFSET_UM: [f32; 2] = [0.0, 0.0];
+
+//-----------------------------------------------------------------------------
+
+/// HPGL: единиц плоттера в мм (0.025 мм)
+pub const HPGL_UNITS_PER_MM: f32 = 40.0;
+
+//-----------------------------------------------------------------------------
+
+/// M170: точек в кадре ILDA, лишние отбрасываются
+pub const ILDA_FRAME_POINTS: usize = 256;
+
+/// M170: точек в секунду, не больше `SYSTICK_RATE_HZ`
+pub const ILDA_POINT_RATE: f32 = 10000.0;
+
+//-----------------------------------------------------------------------------
+
+/// Modbus RTU: адрес устройства по умолчанию ($341)
+pub const MODBUS_ADDRESS: u32 = 1;
+
+/// Modbus RTU: скорость USART3 по умолчанию ($342), бод
+pub const MODBUS_BAUD: u32 = 19200;
+
+/// Modbus RTU: очередь принятых байт от прерывания до idle
+pub const MODBUS_RX_QUEUE_SIZE: usize = 300;
+
+/// Modbus RTU: текст поля `{PLC}`, символов
+pub const PLC_TEXT_MAX_LEN: usize = 32;
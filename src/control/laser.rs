@@ -1,11 +1,17 @@
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
 use core::convert::Infallible;
 
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
 use embedded_hal::digital::v2::OutputPin;
 
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
 use crate::support::{
     parallel_input_bus::ParallelInputBus, parallel_output_bus::ParallelOutputBus,
 };
 
+use sequencer::Sequencer;
+pub use sequencer::LaserState;
+
 /// Table 5 Definition of alarm status.
 #[derive(Debug, Clone, Copy)]
 pub enum LaserStatus {
@@ -15,46 +21,142 @@ pub enum LaserStatus {
     SupplyVoltageAlarm = 4,
 }
 
+/// Допустимые параметры лазера конкретного типа
+pub struct LaserLimits {
+    pub name: &'static str,
+    /// B, Гц
+    pub freq_min: u32,
+    pub freq_max: u32,
+    pub freq_default: u32,
+    /// максимальный код S
+    pub power_max: u8,
+    /// допустимые длительности импульса Q, нс. Пусто - не настраивается
+    pub pulse_widths: &'static [u32],
+}
+
+/// IPG YLP type D: D[0..7] + защелка, Sync/EM/EE
+#[cfg(feature = "laser-ipg")]
+pub const IPG_TYPE_D: LaserLimits = LaserLimits {
+    name: "IPG type D",
+    freq_min: 20_000,
+    freq_max: 80_000,
+    freq_default: crate::config::LASER_SYNC_CLOCK_KHZ * 1000,
+    power_max: u8::MAX,
+    pulse_widths: &[],
+};
+
+/// JPT MOPA: как type D, номер длительности импульса в таблице - по шине D[0..7] со своей защелкой
+#[cfg(feature = "laser-mopa")]
+pub const JPT_MOPA: LaserLimits = LaserLimits {
+    name: "JPT MOPA",
+    freq_min: 1_000,
+    freq_max: 4_000_000,
+    freq_default: crate::config::LASER_SYNC_CLOCK_KHZ * 1000,
+    power_max: u8::MAX,
+    pulse_widths: &[
+        2, 4, 6, 9, 13, 20, 30, 45, 60, 80, 100, 150, 200, 250, 350, 500,
+    ],
+};
+
+/// CO2 трубка: ШИМ на EM (мощность - заполнение), EE - разрешение блока питания
+#[cfg(feature = "laser-co2")]
+pub const CO2_PWM: LaserLimits = LaserLimits {
+    name: "CO2 PWM",
+    freq_min: 1_000,
+    freq_max: 25_000,
+    freq_default: 5_000,
+    power_max: u8::MAX,
+    pulse_widths: &[],
+};
+
+/// Диодный модуль: ШИМ/TTL на EM, EE - разрешение
+#[cfg(feature = "laser-diode")]
+pub const DIODE_TTL: LaserLimits = LaserLimits {
+    name: "Diode TTL",
+    freq_min: 100,
+    freq_max: 40_000,
+    freq_default: 1_000,
+    power_max: u8::MAX,
+    pulse_widths: &[],
+};
+
 pub trait LaserInterface {
     /// устанавливает Power Setting
+    /// Запускает последовательность включения (см. tic):
     /// Включает меандр на Sync
-    /// Включает laser_emission_enable
     /// laser_emission_modulation duty = 0
+    /// Выключает красный лазер
     fn enable(&mut self);
 
     /// laser_emission_modulation duty = 0
     /// Выключает laser_emission_enable
     /// Выключает меандр на Sync
     /// устанавливает Power Setting = 0
+    /// Возвращает красный лазер
     fn disable(&mut self);
 
+    /// Продвигает последовательность включения:
+    /// Armed -> (задержка arm) включает laser_emission_enable -> (задержка ready) Ready,
+    /// открывает laser_emission_modulation, если излучение запрошено
+    fn tic(&mut self, now_nanos: u64);
+
+    /// Состояние последовательности включения
+    fn state(&self) -> LaserState;
+
+    /// Минимальные задержки Sync -> EE и EE -> EM, нс
+    fn set_sequence_delays(&mut self, arm_nanos: u64, ready_nanos: u64);
+
     /// Устанавливает laser_emission_modulation 0 - 100
+    /// Если лазер включен - сразу
     fn set_power_pwm(&mut self, power: f32);
 
+    /// Быстро включает/выключает излучение через laser_emission_modulation,
+    /// не трогая Sync, laser_emission_enable и Power Setting.
+    /// Имеет смысл только для включенного лазера, до Ready запоминается.
+    fn set_emission(&mut self, emit: bool);
+
+    /// Выдает пачку из count импульсов EM синхронно с Sync, после чего излучение закрывается.
+    /// Имеет смысл только в Ready.
+    fn fire_pulses(&mut self, count: u32);
+
     /// Устанваливает Power Setting
+    /// Если лазер включен - сразу
     fn set_pump_power(&mut self, power_code: u8);
 
     /// прочитать статус лазера
     fn get_status(&self) -> LaserStatus;
 
-    /// установить мощность красного лазера
+    /// установить мощность красного лазера, пока лазер включен - запоминается
     fn set_red_laser_power(&mut self, power: f32);
 
     // отладка включить/выключить сигнал EE
     fn debug_set_ee(&mut self, enable: bool);
 
     /// установить частоту (Гц по мануалу к лазеру)
+    /// Если лазер включен - с начала следующего периода Sync, без разрыва меандра
     fn set_frequency(&mut self, frequency: u32);
+
+    /// установить длительность импульса, нс из limits().pulse_widths
+    /// Если лазер включен - сразу
+    fn set_pulse_width(&mut self, width_ns: u32);
+
+    /// допустимые параметры этого лазера
+    fn limits(&self) -> &'static LaserLimits;
 }
 
+/// Лазер с шиной D[0..7] (IPG, MOPA)
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
 pub struct Laser<PBUS, ABUS, OUTPIN, EM, EE, ES, RL>
 where
     PBUS: ParallelOutputBus<Output = u8>,
     ABUS: ParallelInputBus<Input = u8>,
     OUTPIN: OutputPin<Error = Infallible>,
 {
+    limits: &'static LaserLimits,
+
     power_set_bus: PBUS,
     power_latch_pin: Option<OUTPIN>,
+    pulse_width_latch_pin: Option<OUTPIN>,
 
     alarm_bus: ABUS,
 
@@ -66,11 +168,52 @@ where
 
     current_power_seting: u8,
     power: f32,
+    pulse_width: u32,
 
     frequency: u32,
     laser_tim_freq: systick_monotonic::fugit::Hertz<u32>,
 
-    enabled: bool,
+    sequencer: Sequencer,
+    emission: bool,
+    red_power: f32,
 }
 
+/// Лазер с одним ШИМ/TTL входом (CO2, диодный модуль)
+#[cfg(any(feature = "laser-co2", feature = "laser-diode"))]
+pub struct PwmLaser<EM, EE, RL> {
+    limits: &'static LaserLimits,
+
+    laser_emission_modulation: EM,
+    laser_emission_enable: EE,
+
+    laser_red_beam: RL,
+
+    power_code: u8,
+    power: f32,
+
+    frequency: u32,
+    laser_tim_freq: systick_monotonic::fugit::Hertz<u32>,
+
+    sequencer: Sequencer,
+    emission: bool,
+    red_power: f32,
+}
+
+#[cfg(not(any(
+    feature = "laser-ipg",
+    feature = "laser-mopa",
+    feature = "laser-co2",
+    feature = "laser-diode"
+)))]
+compile_error!("Select laser backend: laser-ipg, laser-mopa, laser-co2 or laser-diode");
+
+mod emergency;
+mod sequencer;
+mod tim4;
+
+pub use emergency::emergency_off;
+
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
 pub mod laser_pa0_7_pa13_15_tom4_tim1;
+#[cfg(any(feature = "laser-co2", feature = "laser-diode"))]
+pub mod pwm_pb8_pb9_tim4_tim1;
@@ -0,0 +1,49 @@
+//! Аварийное выключение лазера прямой записью в регистры, без драйверов:
+//! для panic и HardFault, когда состояние программы не известно
+
+use core::arch::asm;
+
+use stm32f1xx_hal::pac::{GPIOA, GPIOB, TIM4};
+
+const SYNC_PB7: u32 = 7;
+const EM_PB8: u32 = 8;
+const EE_PB9: u32 = 9;
+const LATCH_PA9: u32 = 9;
+
+/// EM, EE, Sync -> 0 в порядке, обратном включению, Power Setting D[0..7] = 0 с защелкой.
+/// Пины таймера переводятся в обычные выходы с 0, чтобы не зависеть от состояния TIM4
+pub unsafe fn emergency_off() {
+    let tim4 = &*TIM4::ptr();
+    let gpioa = &*GPIOA::ptr();
+    let gpiob = &*GPIOB::ptr();
+
+    tim4.dier.write(|w| w.bits(0));
+    tim4.ccr3.write(|w| w.bits(0));
+    tim4.ccr4.write(|w| w.bits(0));
+    tim4.ccr2.write(|w| w.bits(0));
+
+    // output push-pull 2 МГц
+    const OUT_LOW: u32 = 0b0010;
+    for pin in [EM_PB8, EE_PB9] {
+        gpiob.brr.write(|w| w.bits(1 << pin));
+        let shift = (pin - 8) * 4;
+        gpiob
+            .crh
+            .modify(|r, w| w.bits(r.bits() & !(0xf << shift) | (OUT_LOW << shift)));
+    }
+    gpiob.brr.write(|w| w.bits(1 << SYNC_PB7));
+    let shift = SYNC_PB7 * 4;
+    gpiob
+        .crl
+        .modify(|r, w| w.bits(r.bits() & !(0xf << shift) | (OUT_LOW << shift)));
+
+    tim4.ccer.write(|w| w.bits(0));
+
+    // D[0..7] = 0 и строб защелки
+    gpioa.brr.write(|w| w.bits(0xff));
+    gpioa.bsrr.write(|w| w.bits(1 << LATCH_PA9));
+    for _ in 0..100 {
+        asm!("nop");
+    }
+    gpioa.brr.write(|w| w.bits(1 << LATCH_PA9));
+}
@@ -8,6 +8,9 @@ use stm32f1xx_hal::{
 
 use crate::support::{parallel_input_bus, parallel_output_bus::ParallelOutputBus};
 
+use super::sequencer::Sequencer;
+use super::tim4;
+
 impl<PBUS, ABUS, OUTPIN, EM, EE, ES, RL> super::Laser<PBUS, ABUS, OUTPIN, EM, EE, ES, RL>
 where
     PBUS: ParallelOutputBus<Output = u8>,
@@ -15,8 +18,10 @@ where
     OUTPIN: OutputPin<Error = Infallible>,
 {
     pub fn new(
+        limits: &'static super::LaserLimits,
         power_set_bus: PBUS,
         power_latch_pin: Option<OUTPIN>,
+        pulse_width_latch_pin: Option<OUTPIN>,
         alarm_bus: ABUS,
 
         laser_emission_modulation: EM,
@@ -28,8 +33,10 @@ where
         laser_tim_freq: systick_monotonic::fugit::Hertz<u32>,
     ) -> Self {
         Self {
+            limits,
             power_set_bus,
             power_latch_pin,
+            pulse_width_latch_pin,
             alarm_bus,
             laser_emission_modulation,
             laser_emission_enable,
@@ -40,10 +47,13 @@ where
 
             current_power_seting: 0,
             power: 0.0,
+            pulse_width: 0,
 
-            frequency: crate::config::LASER_SYNC_CLOCK_KHZ,
+            frequency: limits.freq_default,
 
-            enabled: false,
+            sequencer: Sequencer::default(),
+            emission: false,
+            red_power: 0.0,
         }
     }
 
@@ -51,32 +61,92 @@ where
         (max_duty as f32 / 100.0 * power) as u16
     }
 
+    fn strobe(latch: &mut OUTPIN) {
+        let _ = latch.set_high();
+        for _ in 0..100 {
+            unsafe { asm!("nop") };
+        }
+        let _ = latch.set_low();
+    }
+
     fn impl_set_pump_power(&mut self, power_code: u8) {
         self.power_set_bus.set(power_code);
         if let Some(latch) = &mut self.power_latch_pin {
-            let _ = latch.set_high();
-            for _ in 0..100 {
-                unsafe { asm!("nop") };
+            Self::strobe(latch);
+        }
+    }
+
+    /// MOPA: номер длительности импульса в таблице по той же шине, своя защелка
+    fn impl_set_pulse_width(&mut self) {
+        if let Some(index) = self
+            .limits
+            .pulse_widths
+            .iter()
+            .position(|w| *w == self.pulse_width)
+        {
+            if let Some(latch) = &mut self.pulse_width_latch_pin {
+                self.power_set_bus.set(index as u8);
+                Self::strobe(latch);
+                if self.power_latch_pin.is_none() {
+                    self.power_set_bus.set(self.current_power_seting);
+                }
             }
-            let _ = latch.set_low();
         }
     }
 
+    /// Прерывание TIM4 update: считает периоды Sync в пачке импульсов
+    pub unsafe fn pulse_event() {
+        tim4::pulse_event();
+    }
+
     fn impl_set_frequency(&mut self) {
-        use stm32f1xx_hal::pac;
+        tim4::set_frequency(self.frequency, self.laser_tim_freq.raw());
+    }
+}
 
-        const fn compute_arr_presc(freq: u32, clock: u32) -> (u32, u32) {
-            let ticks = clock / freq;
-            let psc = (ticks - 1) / (1 << 16);
-            let arr = ticks / (psc + 1) - 1;
-            (psc, arr)
-        }
+impl<PBUS, ABUS, OUTPIN>
+    super::Laser<
+        PBUS,
+        ABUS,
+        OUTPIN,
+        PwmChannel<TIM4, 2>,
+        PwmChannel<TIM4, 3>,
+        PwmChannel<TIM4, 1>,
+        PwmChannel<TIM1, 2>,
+    >
+where
+    PBUS: ParallelOutputBus<Output = u8>,
+    ABUS: parallel_input_bus::ParallelInputBus<Input = u8>,
+    OUTPIN: OutputPin<Error = Infallible>,
+{
+    /// Пересчитать заполнения Sync, EE и EM под текущий ARR и состояние последовательности
+    fn impl_update_duties(&mut self) {
+        self.laser_sync.set_duty(self.laser_sync.get_max_duty() / 2);
+        self.laser_emission_enable.set_duty(if self.sequencer.ee_on() {
+            self.laser_emission_enable.get_max_duty()
+        } else {
+            0
+        });
 
-        let (psc, arr) = compute_arr_presc(self.frequency, self.laser_tim_freq.raw());
+        let emit = self.emission && self.sequencer.is_ready();
+        let current_em_mod_seting = if emit {
+            Self::power2_pwm(self.power, self.laser_emission_modulation.get_max_duty())
+        } else {
+            0
+        };
+        self.laser_emission_modulation
+            .set_duty(current_em_mod_seting);
+        self.sequencer.set_emitting(emit);
+    }
 
-        unsafe {
-            (*pac::TIM4::ptr()).psc.write(|w| w.bits(psc));
-            (*pac::TIM4::ptr()).arr.write(|w| w.bits(arr));
+    fn impl_set_red_laser_power(&mut self, power: f32) {
+        if power > 0.0 {
+            self.laser_red_beam
+                .set_duty(Self::power2_pwm(power, self.laser_red_beam.get_max_duty()));
+            self.laser_red_beam.enable();
+        } else {
+            self.laser_red_beam.set_duty(0);
+            self.laser_red_beam.disable();
         }
     }
 }
@@ -97,29 +167,28 @@ where
     OUTPIN: OutputPin<Error = Infallible>,
 {
     fn enable(&mut self) {
-        if !self.enabled {
+        if !self.sequencer.is_on() {
+            // красный указатель и основной лазер не работают одновременно
+            self.impl_set_red_laser_power(0.0);
+
+            self.impl_set_pulse_width();
             self.impl_set_pump_power(self.current_power_seting);
 
             self.impl_set_frequency();
 
-            self.laser_sync.set_duty(self.laser_sync.get_max_duty() / 2);
-            self.laser_sync.enable();
+            self.emission = true;
+            self.sequencer.start();
+            self.impl_update_duties();
 
-            self.laser_emission_enable
-                .set_duty(self.laser_emission_enable.get_max_duty());
+            self.laser_sync.enable();
             self.laser_emission_enable.enable();
-
-            let current_em_mod_seting =
-                Self::power2_pwm(self.power, self.laser_emission_modulation.get_max_duty());
-            self.laser_emission_modulation
-                .set_duty(current_em_mod_seting);
-
             self.laser_emission_modulation.enable();
-            self.enabled = true;
         }
     }
 
     fn disable(&mut self) {
+        tim4::stop_pulses();
+
         self.laser_emission_modulation.set_duty(0);
         self.laser_emission_modulation.disable();
 
@@ -131,7 +200,22 @@ where
 
         self.impl_set_pump_power(0);
 
-        self.enabled = false;
+        self.sequencer.stop();
+        self.impl_set_red_laser_power(self.red_power);
+    }
+
+    fn tic(&mut self, now_nanos: u64) {
+        if self.sequencer.tic(now_nanos) {
+            self.impl_update_duties();
+        }
+    }
+
+    fn state(&self) -> super::LaserState {
+        self.sequencer.state()
+    }
+
+    fn set_sequence_delays(&mut self, arm_nanos: u64, ready_nanos: u64) {
+        self.sequencer.set_delays(arm_nanos, ready_nanos);
     }
 
     fn set_power_pwm(&mut self, mut power: f32) {
@@ -144,14 +228,71 @@ where
         }
 
         self.power = power;
+
+        if self.sequencer.state() == super::LaserState::Emitting {
+            self.laser_emission_modulation.set_duty(Self::power2_pwm(
+                self.power,
+                self.laser_emission_modulation.get_max_duty(),
+            ));
+        }
+    }
+
+    fn set_emission(&mut self, emit: bool) {
+        if self.sequencer.is_on() {
+            tim4::stop_pulses();
+
+            self.emission = emit;
+            let emit = emit && self.sequencer.is_ready();
+            let duty = if emit {
+                Self::power2_pwm(self.power, self.laser_emission_modulation.get_max_duty())
+            } else {
+                0
+            };
+            self.laser_emission_modulation.set_duty(duty);
+            self.sequencer.set_emitting(emit);
+        }
+    }
+
+    fn fire_pulses(&mut self, count: u32) {
+        if !self.sequencer.is_ready() || count == 0 {
+            return;
+        }
+
+        self.laser_emission_modulation.set_duty(Self::power2_pwm(
+            self.power,
+            self.laser_emission_modulation.get_max_duty(),
+        ));
+        tim4::start_pulses(count);
     }
 
     fn set_pump_power(&mut self, power_code: u8) {
+        if self.sequencer.is_on() && self.current_power_seting != power_code {
+            self.impl_set_pump_power(power_code);
+        }
         self.current_power_seting = power_code;
     }
 
     fn set_frequency(&mut self, frequency: u32) {
+        let changed = self.frequency != frequency;
         self.frequency = frequency;
+
+        if self.sequencer.is_on() && changed {
+            let clock = self.laser_tim_freq.raw();
+            tim4::change_frequency(frequency, clock, || self.impl_update_duties());
+        }
+    }
+
+    fn set_pulse_width(&mut self, width_ns: u32) {
+        let changed = self.pulse_width != width_ns;
+        self.pulse_width = width_ns;
+
+        if self.sequencer.is_on() && changed {
+            self.impl_set_pulse_width();
+        }
+    }
+
+    fn limits(&self) -> &'static super::LaserLimits {
+        self.limits
     }
 
     fn get_status(&self) -> super::LaserStatus {
@@ -165,13 +306,9 @@ where
     }
 
     fn set_red_laser_power(&mut self, power: f32) {
-        if power > 0.0 {
-            self.laser_red_beam
-                .set_duty(Self::power2_pwm(power, self.laser_red_beam.get_max_duty()));
-            self.laser_red_beam.enable();
-        } else {
-            self.laser_red_beam.set_duty(0);
-            self.laser_red_beam.disable();
+        self.red_power = power;
+        if !self.sequencer.is_on() {
+            self.impl_set_red_laser_power(power);
         }
     }
 
@@ -0,0 +1,197 @@
+use stm32f1xx_hal::{
+    device::{TIM1, TIM4},
+    timer::PwmChannel,
+};
+
+use super::sequencer::Sequencer;
+use super::tim4;
+
+impl<EM, EE, RL> super::PwmLaser<EM, EE, RL> {
+    pub fn new(
+        limits: &'static super::LaserLimits,
+
+        laser_emission_modulation: EM,
+        laser_emission_enable: EE,
+
+        laser_red_beam: RL,
+
+        laser_tim_freq: systick_monotonic::fugit::Hertz<u32>,
+    ) -> Self {
+        Self {
+            limits,
+            laser_emission_modulation,
+            laser_emission_enable,
+            laser_red_beam,
+
+            power_code: 0,
+            power: 0.0,
+
+            frequency: limits.freq_default,
+            laser_tim_freq,
+
+            sequencer: Sequencer::default(),
+            emission: false,
+            red_power: 0.0,
+        }
+    }
+
+    /// Прерывание TIM4 update: считает периоды ШИМ в пачке импульсов
+    pub unsafe fn pulse_event() {
+        tim4::pulse_event();
+    }
+}
+
+impl super::PwmLaser<PwmChannel<TIM4, 2>, PwmChannel<TIM4, 3>, PwmChannel<TIM1, 2>> {
+    /// Заполнение EM: S (код) масштабирует A
+    fn em_duty(&self) -> u16 {
+        let max_duty = self.laser_emission_modulation.get_max_duty() as f32;
+        (max_duty * self.power_code as f32 / self.limits.power_max as f32 * self.power / 100.0)
+            as u16
+    }
+
+    fn impl_update_duties(&mut self) {
+        self.laser_emission_enable.set_duty(if self.sequencer.ee_on() {
+            self.laser_emission_enable.get_max_duty()
+        } else {
+            0
+        });
+
+        let emit = self.emission && self.sequencer.is_ready();
+        let duty = if emit { self.em_duty() } else { 0 };
+        self.laser_emission_modulation.set_duty(duty);
+        self.sequencer.set_emitting(emit);
+    }
+
+    fn impl_set_red_laser_power(&mut self, power: f32) {
+        if power > 0.0 {
+            let max_duty = self.laser_red_beam.get_max_duty();
+            self.laser_red_beam
+                .set_duty((max_duty as f32 / 100.0 * power) as u16);
+            self.laser_red_beam.enable();
+        } else {
+            self.laser_red_beam.set_duty(0);
+            self.laser_red_beam.disable();
+        }
+    }
+}
+
+impl super::LaserInterface
+    for super::PwmLaser<PwmChannel<TIM4, 2>, PwmChannel<TIM4, 3>, PwmChannel<TIM1, 2>>
+{
+    fn enable(&mut self) {
+        if !self.sequencer.is_on() {
+            self.impl_set_red_laser_power(0.0);
+
+            tim4::set_frequency(self.frequency, self.laser_tim_freq.raw());
+
+            self.emission = true;
+            self.sequencer.start();
+            self.impl_update_duties();
+
+            self.laser_emission_enable.enable();
+            self.laser_emission_modulation.enable();
+        }
+    }
+
+    fn disable(&mut self) {
+        tim4::stop_pulses();
+
+        self.laser_emission_modulation.set_duty(0);
+        self.laser_emission_modulation.disable();
+
+        self.laser_emission_enable.set_duty(0);
+        self.laser_emission_enable.disable();
+
+        self.sequencer.stop();
+        self.impl_set_red_laser_power(self.red_power);
+    }
+
+    fn tic(&mut self, now_nanos: u64) {
+        if self.sequencer.tic(now_nanos) {
+            self.impl_update_duties();
+        }
+    }
+
+    fn state(&self) -> super::LaserState {
+        self.sequencer.state()
+    }
+
+    fn set_sequence_delays(&mut self, arm_nanos: u64, ready_nanos: u64) {
+        self.sequencer.set_delays(arm_nanos, ready_nanos);
+    }
+
+    fn set_power_pwm(&mut self, power: f32) {
+        self.power = power.max(0.0).min(100.0);
+
+        if self.sequencer.state() == super::LaserState::Emitting {
+            self.laser_emission_modulation.set_duty(self.em_duty());
+        }
+    }
+
+    fn set_emission(&mut self, emit: bool) {
+        if self.sequencer.is_on() {
+            tim4::stop_pulses();
+
+            self.emission = emit;
+            let emit = emit && self.sequencer.is_ready();
+            let duty = if emit { self.em_duty() } else { 0 };
+            self.laser_emission_modulation.set_duty(duty);
+            self.sequencer.set_emitting(emit);
+        }
+    }
+
+    fn fire_pulses(&mut self, count: u32) {
+        if !self.sequencer.is_ready() || count == 0 {
+            return;
+        }
+
+        self.laser_emission_modulation.set_duty(self.em_duty());
+        tim4::start_pulses(count);
+    }
+
+    fn set_pump_power(&mut self, power_code: u8) {
+        self.power_code = power_code;
+
+        if self.sequencer.state() == super::LaserState::Emitting {
+            self.laser_emission_modulation.set_duty(self.em_duty());
+        }
+    }
+
+    fn get_status(&self) -> super::LaserStatus {
+        // нет сигналов состояния
+        super::LaserStatus::Normal
+    }
+
+    fn set_red_laser_power(&mut self, power: f32) {
+        self.red_power = power;
+        if !self.sequencer.is_on() {
+            self.impl_set_red_laser_power(power);
+        }
+    }
+
+    fn debug_set_ee(&mut self, enable: bool) {
+        let _ = self.laser_emission_enable.set_duty(if enable {
+            self.laser_emission_enable.get_max_duty()
+        } else {
+            0
+        });
+    }
+
+    fn set_frequency(&mut self, frequency: u32) {
+        let changed = self.frequency != frequency;
+        self.frequency = frequency;
+
+        if self.sequencer.is_on() && changed {
+            let clock = self.laser_tim_freq.raw();
+            tim4::change_frequency(frequency, clock, || self.impl_update_duties());
+        }
+    }
+
+    fn set_pulse_width(&mut self, _width_ns: u32) {
+        // не настраивается
+    }
+
+    fn limits(&self) -> &'static super::LaserLimits {
+        self.limits
+    }
+}
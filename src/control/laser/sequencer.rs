@@ -0,0 +1,124 @@
+//! Последовательность включения лазера по паспорту:
+//! Sync (и Power Setting) -> задержка -> EE -> задержка -> можно открывать EM
+
+/// Состояние лазера
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LaserState {
+    /// все выключено
+    Off,
+    /// Sync идет, EE включается или прогревается, EM закрыт
+    Armed,
+    /// можно излучать, EM закрыт
+    Ready,
+    /// EM открыт
+    Emitting,
+}
+
+pub struct Sequencer {
+    state: LaserState,
+    ee_on: bool,
+    deadline: Option<u64>,
+
+    /// Sync -> EE, нс
+    arm_nanos: u64,
+    /// EE -> EM, нс
+    ready_nanos: u64,
+}
+
+impl Sequencer {
+    pub const fn new(arm_nanos: u64, ready_nanos: u64) -> Self {
+        Self {
+            state: LaserState::Off,
+            ee_on: false,
+            deadline: None,
+            arm_nanos,
+            ready_nanos,
+        }
+    }
+
+    pub fn state(&self) -> LaserState {
+        self.state
+    }
+
+    /// лазер не выключен
+    pub fn is_on(&self) -> bool {
+        self.state != LaserState::Off
+    }
+
+    /// EE должен быть включен
+    pub fn ee_on(&self) -> bool {
+        self.ee_on
+    }
+
+    /// EM можно открывать
+    pub fn is_ready(&self) -> bool {
+        matches!(self.state, LaserState::Ready | LaserState::Emitting)
+    }
+
+    pub fn set_delays(&mut self, arm_nanos: u64, ready_nanos: u64) {
+        self.arm_nanos = arm_nanos;
+        self.ready_nanos = ready_nanos;
+    }
+
+    /// Sync запущен, отсчет задержек начнется со следующего tic
+    pub fn start(&mut self) {
+        if self.state == LaserState::Off {
+            self.state = LaserState::Armed;
+            self.ee_on = false;
+            self.deadline = None;
+        }
+    }
+
+    /// выключение сразу, без задержек
+    pub fn stop(&mut self) {
+        self.state = LaserState::Off;
+        self.ee_on = false;
+        self.deadline = None;
+    }
+
+    /// EM открыт/закрыт, имеет смысл только в Ready/Emitting
+    pub fn set_emitting(&mut self, emitting: bool) {
+        if self.is_ready() {
+            self.state = if emitting {
+                LaserState::Emitting
+            } else {
+                LaserState::Ready
+            };
+        }
+    }
+
+    /// Продвинуть последовательность, true - EE или EM должны быть переключены
+    pub fn tic(&mut self, now_nanos: u64) -> bool {
+        if self.state != LaserState::Armed {
+            return false;
+        }
+
+        let delay = if self.ee_on {
+            self.ready_nanos
+        } else {
+            self.arm_nanos
+        };
+        let deadline = *self.deadline.get_or_insert(now_nanos.wrapping_add(delay));
+        if now_nanos < deadline {
+            return false;
+        }
+
+        if !self.ee_on {
+            self.ee_on = true;
+            self.deadline = Some(now_nanos.wrapping_add(self.ready_nanos));
+        } else {
+            self.deadline = None;
+            self.state = LaserState::Ready;
+        }
+        true
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new(
+            (crate::config::LASER_ARM_DELAY_MS * 1_000_000.0) as u64,
+            (crate::config::LASER_READY_DELAY_MS * 1_000_000.0) as u64,
+        )
+    }
+}
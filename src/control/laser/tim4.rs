@@ -0,0 +1,72 @@
+//! Общее для лазеров, у которых EM - TIM4_CH3: частота и счет импульсов в пачке
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use stm32f1xx_hal::pac::TIM4;
+
+// сколько импульсов EM осталось выдать в текущей пачке
+static PULSES_LEFT: AtomicU32 = AtomicU32::new(0);
+
+/// Прерывание TIM4 update: считает периоды в пачке импульсов
+pub unsafe fn pulse_event() {
+    let tim4 = &*TIM4::ptr();
+
+    tim4.sr.modify(|_, w| w.uif().clear_bit());
+
+    let left = PULSES_LEFT.load(Ordering::SeqCst);
+    if left > 1 {
+        PULSES_LEFT.store(left - 1, Ordering::SeqCst);
+    } else {
+        // последний импульс уже идет, CCR3 загрузится на следующем update
+        PULSES_LEFT.store(0, Ordering::SeqCst);
+        tim4.ccr3.write(|w| w.bits(0));
+        tim4.dier.modify(|_, w| w.uie().clear_bit());
+    }
+}
+
+/// Запустить счет: CCR3 уже должен быть записан, он с предзагрузкой,
+/// поэтому импульсы начнутся со следующего update
+pub(super) fn start_pulses(count: u32) {
+    let tim4 = unsafe { &*TIM4::ptr() };
+
+    PULSES_LEFT.store(count, Ordering::SeqCst);
+    tim4.sr.modify(|_, w| w.uif().clear_bit());
+    tim4.dier.modify(|_, w| w.uie().set_bit());
+}
+
+pub(super) fn stop_pulses() {
+    let tim4 = unsafe { &*TIM4::ptr() };
+
+    tim4.dier.modify(|_, w| w.uie().clear_bit());
+    PULSES_LEFT.store(0, Ordering::SeqCst);
+}
+
+/// Записать PSC и ARR под частоту frequency, Гц
+pub(super) fn set_frequency(frequency: u32, clock: u32) {
+    const fn compute_arr_presc(freq: u32, clock: u32) -> (u32, u32) {
+        let ticks = clock / freq;
+        let psc = (ticks - 1) / (1 << 16);
+        let arr = ticks / (psc + 1) - 1;
+        (psc, arr)
+    }
+
+    let (psc, arr) = compute_arr_presc(frequency, clock);
+
+    unsafe {
+        // ARR с предзагрузкой: новый период начнется только после update
+        (*TIM4::ptr()).cr1.modify(|_, w| w.arpe().set_bit());
+        (*TIM4::ptr()).psc.write(|w| w.bits(psc));
+        (*TIM4::ptr()).arr.write(|w| w.bits(arr));
+    }
+}
+
+/// Изменить частоту на ходу: PSC, ARR и CCR перезагрузятся одним update,
+/// без укороченного периода. update_duties должна переписать CCR под новый ARR
+pub(super) fn change_frequency<F: FnOnce()>(frequency: u32, clock: u32, update_duties: F) {
+    let tim4 = unsafe { &*TIM4::ptr() };
+
+    tim4.cr1.modify(|_, w| w.udis().set_bit());
+    set_frequency(frequency, clock);
+    update_duties();
+    tim4.cr1.modify(|_, w| w.udis().clear_bit());
+}
@@ -2,19 +2,20 @@ use core::{ptr::addr_of_mut, sync::atomic::AtomicBool};
 
 //use stm32f1xx_hal::pac::interrupt;
 
-// 1. Таймер триггерит DMA которая копирует u16 из памяти в GPIO (32)
-// 2. В буфере 40 u16 -> это 20 CLOCKов
+// 1. Таймер триггерит DMA которая копирует u32 из памяти в GPIO BSRR: меняются только
+//    линии GALVO, остальные выходы порта (M62-M65) и подтяжки входов не трогаются
+// 2. В буфере 40 u32 -> это 20 CLOCKов
 // 3. Прерывание DMA считает переданное и как только накопится 20 останавливает процесс.
 // 4. Если второй буфер готов к передаче буферы свапются и сразу начинается отправка
 // 5. Загрузка новой команды всегда в теневой буфер.
 
 const TX_POCKET_SIZE: usize = 20;
 
-static mut OUTPUT_BUF_A: [u16; TX_POCKET_SIZE * 2] = [0; TX_POCKET_SIZE * 2];
-static mut OUTPUT_BUF_B: [u16; TX_POCKET_SIZE * 2] = [0; TX_POCKET_SIZE * 2];
+static mut OUTPUT_BUF_A: [u32; TX_POCKET_SIZE * 2] = [0; TX_POCKET_SIZE * 2];
+static mut OUTPUT_BUF_B: [u32; TX_POCKET_SIZE * 2] = [0; TX_POCKET_SIZE * 2];
 
-static mut TX_BUF: *mut [u16] = unsafe { addr_of_mut!(OUTPUT_BUF_A) };
-static mut BACK_BUF: *mut [u16] = unsafe { addr_of_mut!(OUTPUT_BUF_B) };
+static mut TX_BUF: *mut [u32] = unsafe { addr_of_mut!(OUTPUT_BUF_A) };
+static mut BACK_BUF: *mut [u32] = unsafe { addr_of_mut!(OUTPUT_BUF_B) };
 
 static mut BACK_BUF_READY: AtomicBool = AtomicBool::new(false);
 
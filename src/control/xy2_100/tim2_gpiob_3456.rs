@@ -25,14 +25,14 @@ impl super::XY2_100Interface
             self.dma.stop();
             self.dma.set_memory_address(0u32, true); // not ready
             self.dma.set_peripheral_address(self.port_addr, false);
-            self.dma.set_transfer_length(TX_POCKET_SIZE * 2); // TX_POCKET_SIZE * 2 транзакций по таймера 16 -> 32
+            self.dma.set_transfer_length(TX_POCKET_SIZE * 2); // TX_POCKET_SIZE * 2 транзакций по таймера 32 -> 32
 
             unsafe {
                 (*stm32f1xx_hal::device::DMA1::ptr()).ch2.cr.modify(|_, w| {
                     w.pl()
                         .very_high() // prio
                         .msize()
-                        .bits16() // 16 bit
+                        .bits32() // 32 bit: set | reset << 16
                         .psize()
                         .bits32() // 32 bit
                         .circ()
@@ -106,14 +106,15 @@ impl super::XY2_100Interface
 
         unsafe {
             if let Some(back_buf) = BACK_BUF.as_mut() {
-                back_buf.iter_mut().enumerate().for_each(|(i, r)| {
-                    let (clk_mask, sync_mask, pin_data_x_mask, pin_data_y_mask) = (
-                        1 << self.outputs.0.pin_id(),
-                        1 << self.outputs.1.pin_id(),
-                        1 << self.outputs.2.pin_id(),
-                        1 << self.outputs.3.pin_id(),
-                    );
+                let (clk_mask, sync_mask, pin_data_x_mask, pin_data_y_mask) = (
+                    1u32 << self.outputs.0.pin_id(),
+                    1u32 << self.outputs.1.pin_id(),
+                    1u32 << self.outputs.2.pin_id(),
+                    1u32 << self.outputs.3.pin_id(),
+                );
+                let galvo_mask = clk_mask | sync_mask | pin_data_x_mask | pin_data_y_mask;
 
+                back_buf.iter_mut().enumerate().for_each(|(i, r)| {
                     let bit_n = i / 2;
 
                     *r = sync_mask; // sync == 1 by default
@@ -135,6 +136,9 @@ impl super::XY2_100Interface
                     if data_y & chk_mask != 0 {
                         *r |= pin_data_y_mask
                     }
+
+                    // BSRR: остальные линии GALVO сбрасываются
+                    *r |= (galvo_mask & !*r) << 16;
                 });
             }
 
@@ -173,7 +177,7 @@ impl
         Self {
             timer,
             dma,
-            port_addr: unsafe { &(*port_ptr).odr as *const _ as u32 },
+            port_addr: unsafe { &(*port_ptr).bsrr as *const _ as u32 },
             outputs,
         }
     }
@@ -218,7 +222,7 @@ impl
         // set font buffer address
         dma.ch2
             .mar
-            .write(|w| w.ma().bits(TX_BUF as *const u16 as u32));
+            .write(|w| w.ma().bits(TX_BUF as *const u32 as u32));
 
         // transfer length
         dma.ch2
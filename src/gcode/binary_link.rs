@@ -0,0 +1,313 @@
+//! Бинарный протокол `$B`: плотный поток векторов кадрами вместо текста.
+//!
+//! Кадр: `0xA5 | длина | номер | тип | данные[длина] | CRC16`, CRC-16/MODBUS (младшим
+//! байтом вперед) считается от длины до конца данных. На каждый кадр ответ
+//! `0x06 номер` (ACK) или `0x15 ожидаемый` (NAK: испорчен или не по порядку -
+//! передать заново начиная с ожидаемого). ACK уходит, когда все команды кадра встали
+//! в очередь G-кодов, пока очередь полна - прием по USB стоит.
+//!
+//! Записи кадра векторов - тег и поля little-endian, координаты в единицах
+//! 0..65535 на все поле. Мощность, скорость и частота действуют на следующий отрезок.
+
+use crate::config;
+use crate::support::crc16;
+
+use super::gcode::{Code, GCode, Request, MAX_LEN};
+use super::gcode_server::{dispatch_line, SerialErrResult};
+use super::raster::{PixelData, PixelResult};
+
+/// Команда входа в протокол
+const ENTER_CMD: &str = "$B";
+
+const SYNC: u8 = 0xa5;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+/// Синхробайт, длина, номер, тип
+const HEADER_SIZE: usize = 4;
+const CRC_SIZE: usize = 2;
+const FRAME_MAX: usize = HEADER_SIZE + u8::MAX as usize + CRC_SIZE;
+
+/// Типы кадров
+const FRAME_VECTORS: u8 = 1;
+const FRAME_PIXELS: u8 = 2;
+const FRAME_TEXT: u8 = 3;
+const FRAME_EXIT: u8 = 4;
+
+/// Записи кадра векторов
+const REC_JUMP: u8 = 1; // x: u16, y: u16 - G0
+const REC_MARK: u8 = 2; // x: u16, y: u16 - G1
+const REC_POWER: u8 = 3; // код мощности D[0..7]: u8
+const REC_SPEED: u8 = 4; // единиц/с: u32
+const REC_FREQUENCY: u8 = 5; // Гц: u32
+const REC_LASER: u8 = 6; // 1 - M3, 0 - M5
+
+pub struct BinaryLink {
+    active: bool,
+
+    frame: [u8; FRAME_MAX],
+    /// принято байт кадра
+    len: usize,
+    /// номер следующего кадра
+    seq: u8,
+    /// кадр принят, но не весь в очереди: смещение в данных
+    pending: Option<usize>,
+
+    /// параметры следующего отрезка
+    power: Option<u8>,
+    speed: Option<f32>,
+    frequency: Option<f32>,
+}
+
+impl BinaryLink {
+    pub const fn new() -> Self {
+        Self {
+            active: false,
+            frame: [0; FRAME_MAX],
+            len: 0,
+            seq: 0,
+            pending: None,
+            power: None,
+            speed: None,
+            frequency: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Строка `$B` - включить протокол, первый кадр с номером 0
+    pub fn enter(&mut self, line: &str) -> bool {
+        if !line.trim().eq_ignore_ascii_case(ENTER_CMD) {
+            return false;
+        }
+        self.reset();
+        self.active = true;
+        true
+    }
+
+    /// Назад в текстовый режим (потеря связи)
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Прием и исполнение кадров. `Incomplead` - очередь полна, кадр доисполнится позже
+    pub fn poll<B, GP, RP, PS>(
+        &mut self,
+        serial: &mut usbd_serial::SerialPort<'static, B>,
+        gcode_pusher: &mut GP,
+        request_pusher: &mut RP,
+        pixel_sink: &mut PS,
+    ) -> Result<usize, SerialErrResult>
+    where
+        GP: FnMut(GCode) -> Result<(), GCode>,
+        RP: FnMut(Request) -> Result<(), Request>,
+        PS: FnMut(PixelData) -> PixelResult,
+        B: usb_device::bus::UsbBus,
+    {
+        if self.pending.is_some() {
+            self.execute(serial, gcode_pusher, request_pusher, pixel_sink)?;
+        }
+
+        let mut consumed = 0;
+        while self.active {
+            let need = if self.len < 2 {
+                1
+            } else {
+                self.frame_size() - self.len
+            };
+
+            let n = match serial.read(&mut self.frame[self.len..self.len + need]) {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            consumed += n;
+
+            // мусор до синхробайта
+            if self.len == 0 && self.frame[0] != SYNC {
+                continue;
+            }
+            self.len += n;
+
+            if self.len > 1 && self.len == self.frame_size() {
+                self.len = 0;
+                if self.accept(serial) {
+                    self.pending = Some(0);
+                    self.execute(serial, gcode_pusher, request_pusher, pixel_sink)?;
+                }
+            }
+        }
+
+        Ok(consumed)
+    }
+
+    fn frame_size(&self) -> usize {
+        HEADER_SIZE + self.frame[1] as usize + CRC_SIZE
+    }
+
+    /// Проверка CRC и номера, false - кадр отброшен
+    fn accept<B: usb_device::bus::UsbBus>(
+        &mut self,
+        serial: &mut usbd_serial::SerialPort<'static, B>,
+    ) -> bool {
+        let end = HEADER_SIZE + self.frame[1] as usize;
+        let crc = u16::from_le_bytes([self.frame[end], self.frame[end + 1]]);
+        let seq = self.frame[2];
+
+        if crc16(&self.frame[1..end]) != crc {
+            let _ = serial.write(&[NAK, self.seq]);
+            return false;
+        }
+        if seq != self.seq {
+            // повтор принятого кадра - потерялся ACK
+            let reply = if seq == self.seq.wrapping_sub(1) {
+                [ACK, seq]
+            } else {
+                [NAK, self.seq]
+            };
+            let _ = serial.write(&reply);
+            return false;
+        }
+        true
+    }
+
+    /// Кадр в очередь начиная с pending, ACK когда весь
+    fn execute<B, GP, RP, PS>(
+        &mut self,
+        serial: &mut usbd_serial::SerialPort<'static, B>,
+        gcode_pusher: &mut GP,
+        request_pusher: &mut RP,
+        pixel_sink: &mut PS,
+    ) -> Result<(), SerialErrResult>
+    where
+        GP: FnMut(GCode) -> Result<(), GCode>,
+        RP: FnMut(Request) -> Result<(), Request>,
+        PS: FnMut(PixelData) -> PixelResult,
+        B: usb_device::bus::UsbBus,
+    {
+        let mut pos = self.pending.unwrap_or_default();
+        let payload = HEADER_SIZE..HEADER_SIZE + self.frame[1] as usize;
+
+        match self.frame[3] {
+            FRAME_VECTORS => {
+                while pos < payload.len() {
+                    let (gcode, size) = match self.record(payload.start + pos, payload.end) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            // остаток кадра пропускается, кадр подтверждается
+                            let _ = serial.write(e.as_bytes());
+                            break;
+                        }
+                    };
+                    if let Some(gcode) = gcode {
+                        if gcode_pusher(gcode).is_err() {
+                            self.pending = Some(pos);
+                            return Err(SerialErrResult::Incomplead(0));
+                        }
+                        if gcode.code() == Code::G(1) {
+                            self.power = None;
+                            self.speed = None;
+                            self.frequency = None;
+                        }
+                    }
+                    pos += size;
+                }
+            }
+            FRAME_PIXELS => {
+                if let PixelResult::Full = pixel_sink(PixelData::Frame(&self.frame[payload])) {
+                    return Err(SerialErrResult::Incomplead(0));
+                }
+            }
+            FRAME_TEXT => match core::str::from_utf8(&self.frame[payload]) {
+                Ok(text) => {
+                    match dispatch_line::<B, GP, RP, MAX_LEN>(
+                        serial,
+                        &text[pos..],
+                        false,
+                        gcode_pusher,
+                        request_pusher,
+                    ) {
+                        Err(SerialErrResult::Incomplead(n)) => {
+                            self.pending = Some(pos + n);
+                            return Err(SerialErrResult::Incomplead(0));
+                        }
+                        Err(e) => return Err(e),
+                        Ok(_) => {}
+                    }
+                }
+                Err(_) => {
+                    let _ = serial.write(b"Error: Invalid text frame\r\n");
+                }
+            },
+            FRAME_EXIT => self.active = false,
+            _ => {
+                let _ = serial.write(b"Error: Unknown frame type\r\n");
+            }
+        }
+
+        self.pending = None;
+        let _ = serial.write(&[ACK, self.seq]);
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Запись с from: G-код (если есть) и ее размер
+    fn record(&mut self, from: usize, end: usize) -> Result<(Option<GCode>, usize), &'static str> {
+        let data = &self.frame[from..end];
+        let field = |n: usize| data.get(1..1 + n).ok_or("Error: Truncated record\r\n");
+
+        match data[0] {
+            REC_JUMP | REC_MARK => {
+                let f = field(4)?;
+                let x = to_mm(u16::from_le_bytes([f[0], f[1]]), config::MOTION_X_RANGE);
+                let y = to_mm(u16::from_le_bytes([f[2], f[3]]), config::MOTION_Y_RANGE);
+
+                let mut gcode = if data[0] == REC_JUMP {
+                    GCode::new(Code::G(0))
+                } else {
+                    let mut gcode = GCode::new(Code::G(1));
+                    if let Some(power) = self.power {
+                        gcode.set_s_code(power);
+                    }
+                    if let Some(speed) = self.speed {
+                        gcode.set_f(speed);
+                    }
+                    if let Some(frequency) = self.frequency {
+                        gcode.set_b(frequency);
+                    }
+                    gcode
+                };
+                gcode.set_xy(x, y);
+                Ok((Some(gcode), 5))
+            }
+            REC_POWER => {
+                self.power = Some(field(1)?[0]);
+                Ok((None, 2))
+            }
+            REC_SPEED => {
+                let f = field(4)?;
+                let units = u32::from_le_bytes([f[0], f[1], f[2], f[3]]);
+                // единиц/с -> мм/мин по X
+                self.speed = Some(units as f32 * config::MOTION_X_RANGE / u16::MAX as f32 * 60.0);
+                Ok((None, 5))
+            }
+            REC_FREQUENCY => {
+                let f = field(4)?;
+                self.frequency = Some(u32::from_le_bytes([f[0], f[1], f[2], f[3]]) as f32);
+                Ok((None, 5))
+            }
+            REC_LASER => {
+                let code = if field(1)?[0] != 0 { 3 } else { 5 };
+                Ok((Some(GCode::new(Code::M(code))), 2))
+            }
+            _ => Err("Error: Unknown record\r\n"),
+        }
+    }
+}
+
+/// 0..65535 на поле -> мм от центра
+fn to_mm(units: u16, range: f32) -> f32 {
+    units as f32 / u16::MAX as f32 * range - range / 2.0
+}
@@ -0,0 +1,73 @@
+/// Число интервалов скользящего окна скважности
+const BUCKETS: usize = 10;
+
+/// Учет времени излучения: за текущую команду и в скользящем окне
+pub struct EmissionLimiter {
+    last_nanos: u64,
+    emitting: bool,
+
+    command_on: u64,
+
+    buckets: [u64; BUCKETS],
+    bucket: usize,
+    bucket_start: u64,
+}
+
+impl EmissionLimiter {
+    pub const fn new() -> Self {
+        Self {
+            last_nanos: 0,
+            emitting: false,
+            command_on: 0,
+            buckets: [0; BUCKETS],
+            bucket: 0,
+            bucket_start: 0,
+        }
+    }
+
+    /// Новая команда - счет времени излучения за команду сначала
+    pub fn new_command(&mut self) {
+        self.command_on = 0;
+    }
+
+    /// Время излучения в текущей команде, нс
+    pub fn command_on_nanos(&self) -> u64 {
+        self.command_on
+    }
+
+    /// Доля времени излучения в окне, 0.0 - 1.0
+    pub fn duty(&self, window_nanos: u64) -> f32 {
+        if window_nanos == 0 {
+            return 0.0;
+        }
+        let on: u64 = self.buckets.iter().sum();
+        (on as f32 / window_nanos as f32).min(1.0)
+    }
+
+    /// Вызывается каждый tic, emitting - излучение сейчас
+    pub fn update(&mut self, now_nanos: u64, emitting: bool, window_nanos: u64) {
+        let bucket_len = (window_nanos / BUCKETS as u64).max(1);
+
+        let passed = now_nanos.wrapping_sub(self.bucket_start);
+        if passed >= bucket_len * BUCKETS as u64 {
+            // простой дольше окна
+            self.buckets = [0; BUCKETS];
+            self.bucket_start = now_nanos;
+        } else {
+            for _ in 0..passed / bucket_len {
+                self.bucket = (self.bucket + 1) % BUCKETS;
+                self.buckets[self.bucket] = 0;
+                self.bucket_start = self.bucket_start.wrapping_add(bucket_len);
+            }
+        }
+
+        if self.emitting {
+            let dt = now_nanos.wrapping_sub(self.last_nanos);
+            self.buckets[self.bucket] += dt;
+            self.command_on += dt;
+        }
+
+        self.last_nanos = now_nanos;
+        self.emitting = emitting;
+    }
+}
@@ -0,0 +1,71 @@
+//! Журнал событий в RAM: последние `EVENT_LOG_SIZE` записей с временем часов
+//! (или секундами от запуска, если часы не установлены), вывод `$E`
+
+use core::fmt::Write;
+
+use crate::config;
+use crate::support::datetime::DateTime;
+
+use super::motion_mgr::{Alarm, LongString};
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    Boot,
+    Alarm(Alarm),
+    Unlock,
+    ClockSet,
+    JobStart(u8),
+    JobEnd(u8),
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// секунды с 1970, None - часы не установлены
+    time: Option<u32>,
+    /// секунды от запуска
+    uptime: u32,
+    event: Event,
+}
+
+pub struct EventLog {
+    entries: heapless::Deque<Entry, { config::EVENT_LOG_SIZE }>,
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Deque::new(),
+        }
+    }
+
+    /// Самая старая запись вытесняется
+    pub fn push(&mut self, time: Option<u32>, uptime: u32, event: Event) {
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        let _ = self.entries.push_back(Entry {
+            time,
+            uptime,
+            event,
+        });
+    }
+
+    /// `[EVT:<YYYY-MM-DD HH:MM:SS>|<событие>]` или `[EVT:+<с от запуска>|<событие>]`
+    pub fn print(&self, out: &mut LongString) {
+        for e in self.entries.iter() {
+            let _ = match e.time {
+                Some(t) => write!(out, "[EVT:{}|", DateTime::from_unix(t)),
+                None => write!(out, "[EVT:+{}|", e.uptime),
+            };
+            let _ = match e.event {
+                Event::Boot => write!(out, "Boot"),
+                Event::Alarm(alarm) => write!(out, "ALARM:{} {}", alarm as u32, alarm.message()),
+                Event::Unlock => write!(out, "Unlocked"),
+                Event::ClockSet => write!(out, "Clock set"),
+                Event::JobStart(slot) => write!(out, "Job {} start", slot),
+                Event::JobEnd(slot) => write!(out, "Job {} end", slot),
+            };
+            let _ = out.push_str("]\r\n");
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Фигуры, которые MotionMGR раскладывает на перемещения сам: текст M150
+//! и 2D коды M152/M153. Перемещения подаются по одному как G0/G1
+
+use super::matrix_mark::MatrixMark;
+use super::text_mark::TextMark;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StrokeKind {
+    /// G0, луч закрыт
+    Jump,
+    /// G1
+    Mark,
+    /// остановка с излучением в текущей точке ($211, $212)
+    Dot,
+}
+
+/// Следующее перемещение фигуры
+#[derive(Clone, Copy)]
+pub struct Stroke {
+    pub kind: StrokeKind,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Clone)]
+pub enum Figure {
+    Text(TextMark),
+    Matrix(MatrixMark),
+}
+
+impl Figure {
+    /// Следующее перемещение без продвижения, None - фигура закончена
+    pub fn peek(&self) -> Option<Stroke> {
+        match self {
+            Figure::Text(text) => text.peek(),
+            Figure::Matrix(matrix) => matrix.peek(),
+        }
+    }
+
+    pub fn advance(&mut self) {
+        match self {
+            Figure::Text(text) => {
+                text.next();
+            }
+            Figure::Matrix(matrix) => {
+                matrix.next();
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Однолинейный шрифт для маркировки текста (M150) в духе Hershey Simplex:
+//! каждый символ - ломаные по сетке 8 x 12, начало - левый нижний угол на базовой линии.
+//! Знаки, которых нет в шрифте, маркируются `?`, строчные - как прописные.
+
+/// Перо поднимается: следующая точка - начало новой ломаной
+const UP: (i8, i8) = (i8::MIN, i8::MIN);
+
+/// Ширина знакоместа
+pub const WIDTH: f32 = 8.0;
+/// Высота прописных и цифр
+pub const HEIGHT: f32 = 12.0;
+/// Габарит по вертикали со скобками, запятой и `_`
+pub const ASCENT: f32 = 13.0;
+pub const DESCENT: f32 = -2.0;
+/// Межбуквенный интервал по умолчанию
+pub const GAP: f32 = 4.0;
+
+/// Точки символа, `point()` разделителя ломаных - `None` (перо поднято)
+pub fn glyph(c: u8) -> &'static [(i8, i8)] {
+    points(c.to_ascii_uppercase())
+}
+
+pub fn point(p: (i8, i8)) -> Option<(f32, f32)> {
+    if p == UP {
+        None
+    } else {
+        Some((p.0 as f32, p.1 as f32))
+    }
+}
+
+#[rustfmt::skip]
+fn points(c: u8) -> &'static [(i8, i8)] {
+    match c {
+        b' ' => &[],
+        b'0' => &[(0, 2), (0, 10), (2, 12), (6, 12), (8, 10), (8, 2), (6, 0), (2, 0), (0, 2), UP, (1, 1), (7, 11)],
+        b'1' => &[(2, 10), (4, 12), (4, 0), UP, (1, 0), (7, 0)],
+        b'2' => &[(0, 10), (2, 12), (6, 12), (8, 10), (8, 8), (0, 0), (8, 0)],
+        b'3' => &[(0, 10), (2, 12), (6, 12), (8, 10), (8, 8), (6, 6), (3, 6), UP, (6, 6), (8, 4), (8, 2), (6, 0), (2, 0), (0, 2)],
+        b'4' => &[(6, 0), (6, 12), (0, 4), (8, 4)],
+        b'5' => &[(8, 12), (0, 12), (0, 7), (6, 7), (8, 5), (8, 2), (6, 0), (2, 0), (0, 2)],
+        b'6' => &[(7, 12), (3, 12), (0, 9), (0, 2), (2, 0), (6, 0), (8, 2), (8, 5), (6, 7), (0, 7)],
+        b'7' => &[(0, 12), (8, 12), (3, 0)],
+        b'8' => &[(2, 6), (0, 8), (0, 10), (2, 12), (6, 12), (8, 10), (8, 8), (6, 6), (2, 6), (0, 4), (0, 2), (2, 0), (6, 0), (8, 2), (8, 4), (6, 6)],
+        b'9' => &[(8, 7), (2, 7), (0, 9), (0, 10), (2, 12), (6, 12), (8, 10), (8, 3), (5, 0), (1, 0)],
+
+        b'A' => &[(0, 0), (4, 12), (8, 0), UP, (1, 3), (7, 3)],
+        b'B' => &[(0, 0), (0, 12), (6, 12), (8, 10), (8, 8), (6, 6), (0, 6), UP, (6, 6), (8, 4), (8, 2), (6, 0), (0, 0)],
+        b'C' => &[(8, 10), (6, 12), (2, 12), (0, 10), (0, 2), (2, 0), (6, 0), (8, 2)],
+        b'D' => &[(0, 0), (0, 12), (5, 12), (8, 9), (8, 3), (5, 0), (0, 0)],
+        b'E' => &[(8, 12), (0, 12), (0, 0), (8, 0), UP, (0, 6), (6, 6)],
+        b'F' => &[(8, 12), (0, 12), (0, 0), UP, (0, 6), (6, 6)],
+        b'G' => &[(8, 10), (6, 12), (2, 12), (0, 10), (0, 2), (2, 0), (6, 0), (8, 2), (8, 5), (4, 5)],
+        b'H' => &[(0, 0), (0, 12), UP, (8, 0), (8, 12), UP, (0, 6), (8, 6)],
+        b'I' => &[(2, 12), (6, 12), UP, (4, 12), (4, 0), UP, (2, 0), (6, 0)],
+        b'J' => &[(8, 12), (8, 2), (6, 0), (2, 0), (0, 2), (0, 4)],
+        b'K' => &[(0, 0), (0, 12), UP, (8, 12), (0, 4), UP, (3, 7), (8, 0)],
+        b'L' => &[(0, 12), (0, 0), (8, 0)],
+        b'M' => &[(0, 0), (0, 12), (4, 6), (8, 12), (8, 0)],
+        b'N' => &[(0, 0), (0, 12), (8, 0), (8, 12)],
+        b'O' => &[(2, 0), (0, 2), (0, 10), (2, 12), (6, 12), (8, 10), (8, 2), (6, 0), (2, 0)],
+        b'P' => &[(0, 0), (0, 12), (6, 12), (8, 10), (8, 8), (6, 6), (0, 6)],
+        b'Q' => &[(2, 0), (0, 2), (0, 10), (2, 12), (6, 12), (8, 10), (8, 2), (6, 0), (2, 0), UP, (5, 3), (8, 0)],
+        b'R' => &[(0, 0), (0, 12), (6, 12), (8, 10), (8, 8), (6, 6), (0, 6), UP, (4, 6), (8, 0)],
+        b'S' => &[(8, 10), (6, 12), (2, 12), (0, 10), (0, 8), (2, 6), (6, 6), (8, 4), (8, 2), (6, 0), (2, 0), (0, 2)],
+        b'T' => &[(0, 12), (8, 12), UP, (4, 12), (4, 0)],
+        b'U' => &[(0, 12), (0, 2), (2, 0), (6, 0), (8, 2), (8, 12)],
+        b'V' => &[(0, 12), (4, 0), (8, 12)],
+        b'W' => &[(0, 12), (2, 0), (4, 8), (6, 0), (8, 12)],
+        b'X' => &[(0, 0), (8, 12), UP, (0, 12), (8, 0)],
+        b'Y' => &[(0, 12), (4, 6), (8, 12), UP, (4, 6), (4, 0)],
+        b'Z' => &[(0, 12), (8, 12), (0, 0), (8, 0)],
+
+        b'-' => &[(1, 6), (7, 6)],
+        b'_' => &[(0, -2), (8, -2)],
+        b'.' => &[(3, 0), (4, 0), (4, 1), (3, 1), (3, 0)],
+        b',' => &[(4, 1), (4, 0), (2, -2)],
+        b':' => &[(3, 2), (4, 2), (4, 3), (3, 3), (3, 2), UP, (3, 8), (4, 8), (4, 9), (3, 9), (3, 8)],
+        b'/' => &[(0, 0), (8, 12)],
+        b'\'' => &[(4, 12), (4, 9)],
+        b'#' => &[(3, 1), (3, 11), UP, (6, 1), (6, 11), UP, (1, 4), (8, 4), UP, (0, 8), (7, 8)],
+        b'+' => &[(4, 2), (4, 10), UP, (0, 6), (8, 6)],
+        b'=' => &[(1, 4), (7, 4), UP, (1, 8), (7, 8)],
+        b'*' => &[(4, 2), (4, 10), UP, (1, 8), (7, 4), UP, (1, 4), (7, 8)],
+        b'(' => &[(6, 13), (4, 11), (3, 8), (3, 4), (4, 1), (6, -1)],
+        b')' => &[(2, 13), (4, 11), (5, 8), (5, 4), (4, 1), (2, -1)],
+        _ => &[(0, 10), (2, 12), (6, 12), (8, 10), (8, 8), (4, 5), (4, 3), UP, (4, 1), (4, 0)],
+    }
+}
@@ -1,7 +1,9 @@
 use core::fmt::Write;
 use core::str::FromStr;
 
+use crate::config;
 use crate::config::HlString;
+use crate::support::datetime::DateTime;
 
 pub const MAX_LEN: usize = 150;
 
@@ -15,7 +17,36 @@ pub enum Code {
 #[derive(Clone, Copy, Debug)]
 pub enum Request {
     Dollar(char),
+    Setting(u16, Option<f32>),
     Status,
+    FeedHold,
+    CycleStart,
+    Job(JobRequest),
+    /// `$T` - read clock, `$T=YYYY-MM-DD HH:MM:SS` - set, seconds since 1970
+    Time(Option<u32>),
+    /// `$P` - selected pen, `$P<n>` - pen n, one pen per reply
+    Pen(Option<u8>),
+    /// `M105`, `M114`, `M115` in the Marlin personality ($330), answered out of the G-code queue
+    Marlin(u32),
+}
+
+/// `$F..` - сохраненные задания, загрузка `$FU<n>` разбирается до G-кода (JobStore)
+#[derive(Clone, Copy, Debug)]
+pub enum JobRequest {
+    /// `$FL`
+    List,
+    /// `$FD<n>`
+    Delete(u8),
+    /// `$FR<n>[=<повторов>]`, 0 - пока не остановят
+    Run(u8, u32),
+    /// `$FS`
+    Stop,
+    /// `$FG=<колонок>,<рядов>,<шаг X>,<шаг Y>` - копии сеткой
+    Grid(u16, u16, f32, f32),
+    /// `$FO=<X>,<Y>[,<угол>]` - добавить копию
+    Offset(f32, f32, f32),
+    /// `$FG` или `$FO` без значений - одна копия без смещения
+    ClearArray,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -24,11 +55,35 @@ pub struct GCode {
 
     x: Option<f32>,
     y: Option<f32>,
+    i: Option<f32>, // Arc center X offset
+    j: Option<f32>, // Arc center Y offset
     a: Option<f32>, // Laser pump Power
     b: Option<f32>, // Laser frequency
+    q: Option<f32>, // Laser pulse width
 
     s: Option<f32>, // Laser pwm Power
     f: Option<f32>, // FeedRate
+    p: Option<f32>, // Dwell time
+    t: Option<f32>, // Pen
+    l: Option<f32>, // M66 wait mode, M150 letter spacing, M152/M153 fill, M160 pixels
+    r: Option<f32>, // M150 text angle, M152/M153 code angle, M160 line angle
+
+    text: Option<MarkText>, // M150/M152/M153 "<template>"
+
+    s_code: bool, // S is a D[0..7] code, not $220 units (binary protocol)
+}
+
+/// Текст в кавычках в конце строки (M150, M152, M153), хранится в GCode без кучи
+#[derive(Clone, Copy, Debug)]
+pub struct MarkText {
+    buf: [u8; config::MARK_TEXT_MAX_LEN],
+    len: u8,
+}
+
+impl MarkText {
+    pub fn as_str(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len as usize]) }
+    }
 }
 
 pub enum ParceResult {
@@ -42,15 +97,43 @@ pub enum ParceError {
     Error(HlString),
 }
 
+/// Числа в тексте команды, f32 - через `support::parse_float`
+trait Value: Sized {
+    fn parse_value(text: &str) -> Option<Self>;
+}
+
+impl Value for f32 {
+    fn parse_value(text: &str) -> Option<Self> {
+        crate::support::parse_float(text)
+    }
+}
+
+macro_rules! integer_value {
+    ($($t:ty),*) => {$(
+        impl Value for $t {
+            fn parse_value(text: &str) -> Option<Self> {
+                text.parse().ok()
+            }
+        }
+    )*};
+}
+integer_value!(u8, u16, u32);
+
 impl GCode {
     pub fn from_string<const N: usize>(text: &str) -> Result<ParceResult, ParceError> {
         if text.is_empty() {
             return Err(ParceError::Empty);
         }
+        // текст в кавычках (M150, M152, M153) сохраняет регистр
+        let mut quoted = false;
         let upper_text = text
             .chars()
             .map(|mut c| {
-                c.make_ascii_uppercase();
+                if c == '"' {
+                    quoted = !quoted;
+                } else if !quoted {
+                    c.make_ascii_uppercase();
+                }
                 c
             })
             .collect::<heapless::String<N>>();
@@ -59,15 +142,28 @@ impl GCode {
 
     fn from_string_private<const N: usize>(text: &str) -> Result<ParceResult, ParceError> {
         let first_char = text.chars().nth(0).unwrap_or_default();
-        if ['/', '(', ':'].contains(&first_char) {
+        // `;` - комментарий, строки пикселей M160 (`;H:`, `;B:`) разбираются до G-кода
+        if ['/', '(', ':', ';'].contains(&first_char) {
             Err(ParceError::Empty)
         } else if first_char == '%' {
             Err(ParceError::Error(unsafe {
                 HlString::from_str("error: 1").unwrap_unchecked()
             }))
+        } else if first_char == '!' {
+            Ok(ParceResult::Request(Request::FeedHold))
+        } else if first_char == '~' {
+            Ok(ParceResult::Request(Request::CycleStart))
         } else if ['?', '$'].contains(&first_char) {
             if Self::has_command('$', text) {
-                if Self::has_command('J', text) {
+                if text.starts_with("$F") {
+                    Self::parse_job_request(text)
+                } else if text.starts_with("$T") {
+                    Self::parse_time(text)
+                } else if text.starts_with("$P") {
+                    Ok(ParceResult::Request(Request::Pen(
+                        Self::search_value::<u8>('P', text).ok(),
+                    )))
+                } else if Self::has_command('J', text) {
                     // Jog
                     let mut new_code = Self::default();
                     if Self::has_command('G', text) {
@@ -80,6 +176,11 @@ impl GCode {
                     } else {
                         Err(ParceError::Error(HlString::from_str("jog error").unwrap()))
                     }
+                } else if let Ok(id) = Self::search_value::<u16>('$', text) {
+                    // $<n> - read setting, $<n>=<value> - write setting
+                    let value = Self::get_val::<f32>('=', text)
+                        .map_err(|_| ParceError::Error("Invalid setting value".into()))?;
+                    Ok(ParceResult::Request(Request::Setting(id, value)))
                 } else {
                     Ok(ParceResult::Request(Request::Dollar(
                         match { text.chars().skip_while(|c| *c != '$').skip(1).next() } {
@@ -93,18 +194,24 @@ impl GCode {
             }
         } else {
             let mut new_code = Self::default();
+            let text = match text.split_once('"') {
+                Some((code, quoted)) => {
+                    new_code.text = Some(Self::parse_text(quoted)?);
+                    code
+                }
+                None => text,
+            };
 
             if Self::has_command('M', text) {
                 new_code.code = Code::M(
                     Self::search_value('M', text)
-                        .or_else(|_| Err(ParceError::Error("Failed to parse M command".into())))?,
+                        .map_err(|_| ParceError::Error("Failed to parse M command".into()))?,
                 );
 
-                new_code.s = Self::get_val('S', text)
-                    .or_else(|_| Err(ParceError::Error("Invalid S value".into())))?;
+                new_code.fill_letters(text)?;
             } else if Self::has_command('G', text) {
                 let command_number = Self::search_value::<f32>('G', text)
-                    .or_else(|_| Err(ParceError::Error("Failed to parse Gcode number".into())))?;
+                    .map_err(|_| ParceError::Error("Failed to parse Gcode number".into()))?;
                 if command_number > 43.0 && command_number < 43.9 {
                     new_code.code = Code::G(43); // дробная часть не интересна
                 } else {
@@ -123,21 +230,102 @@ impl GCode {
         }
     }
 
+    fn parse_job_request(text: &str) -> Result<ParceResult, ParceError> {
+        let slot = || {
+            Self::search_value::<u8>(text.chars().nth(2).unwrap_or_default(), text)
+                .map_err(|_| ParceError::Error("Job slot required".into()))
+        };
+        let req = match text.chars().nth(2) {
+            Some('L') => JobRequest::List,
+            Some('D') => JobRequest::Delete(slot()?),
+            Some('R') => JobRequest::Run(
+                slot()?,
+                Self::get_val::<u32>('=', text)
+                    .map_err(|_| ParceError::Error("Invalid repeat count".into()))?
+                    .unwrap_or(1),
+            ),
+            Some('S') => JobRequest::Stop,
+            Some(c @ ('G' | 'O')) => {
+                let v = Self::parse_list::<4>(text)?;
+                match (c, v.len()) {
+                    (_, 0) => JobRequest::ClearArray,
+                    ('G', 4) if v[0] >= 1.0 && v[1] >= 1.0 => {
+                        JobRequest::Grid(v[0] as u16, v[1] as u16, v[2], v[3])
+                    }
+                    ('O', 2 | 3) => JobRequest::Offset(v[0], v[1], *v.get(2).unwrap_or(&0.0)),
+                    _ => return Err(ParceError::Error("Invalid array parameters".into())),
+                }
+            }
+            _ => return Err(ParceError::Error("Unknown job command".into())),
+        };
+        Ok(ParceResult::Request(Request::Job(req)))
+    }
+
+    /// `$T` или `$T=YYYY-MM-DD HH:MM:SS`
+    fn parse_time(text: &str) -> Result<ParceResult, ParceError> {
+        let time = match text.split_once('=') {
+            Some((_, value)) => Some(
+                DateTime::parse(value)
+                    .ok_or_else(|| ParceError::Error("Invalid time, YYYY-MM-DD HH:MM:SS".into()))?
+                    .to_unix(),
+            ),
+            None => None,
+        };
+        Ok(ParceResult::Request(Request::Time(time)))
+    }
+
+    /// `<текст>"` в конце строки
+    fn parse_text(quoted: &str) -> Result<MarkText, ParceError> {
+        let (text, rest) = quoted
+            .split_once('"')
+            .ok_or_else(|| ParceError::Error("Unterminated text".into()))?;
+        if !rest.trim().is_empty() {
+            return Err(ParceError::Error("Text must end the line".into()));
+        }
+        if text.len() > config::MARK_TEXT_MAX_LEN {
+            return Err(ParceError::Error("Text too long".into()));
+        }
+
+        let mut res = MarkText {
+            buf: [0; config::MARK_TEXT_MAX_LEN],
+            len: text.len() as u8,
+        };
+        res.buf[..text.len()].copy_from_slice(text.as_bytes());
+        Ok(res)
+    }
+
+    /// `=<v>,<v>,...`, пусто - если нет `=`
+    fn parse_list<const K: usize>(text: &str) -> Result<heapless::Vec<f32, K>, ParceError> {
+        let mut res = heapless::Vec::new();
+        let values = match text.split_once('=') {
+            Some((_, values)) => values.trim(),
+            None => return Ok(res),
+        };
+        for v in values.split(',') {
+            let v = crate::support::parse_float(v.trim())
+                .ok_or_else(|| ParceError::Error("Invalid value list".into()))?;
+            res.push(v)
+                .map_err(|_| ParceError::Error("Too many values".into()))?;
+        }
+        Ok(res)
+    }
+
     #[inline]
     fn has_command(key: char, text: &str) -> bool {
         text.contains(key)
     }
 
-    fn search_value<T: FromStr>(key: char, text: &str) -> Result<T, T::Err> {
-        text.chars()
+    fn search_value<T: Value>(key: char, text: &str) -> Result<T, ()> {
+        let value = text
+            .chars()
             .skip_while(|c| *c != key)
             .skip(1)
-            .take_while(|c| ['.', '+', '-'].contains(c) || c.is_numeric())
-            .collect::<HlString>()
-            .parse()
+            .take_while(|c| ['.', '+', '-'].contains(c) || c.is_ascii_digit())
+            .collect::<HlString>();
+        T::parse_value(&value).ok_or(())
     }
 
-    fn get_val<T: FromStr>(key: char, text: &str) -> Result<Option<T>, T::Err> {
+    fn get_val<T: Value>(key: char, text: &str) -> Result<Option<T>, ()> {
         if Self::has_command(key, text) {
             Ok(Some(Self::search_value(key, text)?))
         } else {
@@ -149,13 +337,20 @@ impl GCode {
         for (field, letter) in [
             &mut self.x,
             &mut self.y,
+            &mut self.i,
+            &mut self.j,
             &mut self.a,
             &mut self.b,
+            &mut self.q,
             &mut self.f,
             &mut self.s,
+            &mut self.p,
+            &mut self.t,
+            &mut self.l,
+            &mut self.r,
         ]
         .iter_mut()
-        .zip(['X', 'Y', 'A', 'B', 'F', 'S'])
+        .zip(['X', 'Y', 'I', 'J', 'A', 'B', 'Q', 'F', 'S', 'P', 'T', 'L', 'R'])
         {
             **field = Self::get_val(letter, text).or_else(|_| {
                 let mut str = HlString::new();
@@ -166,6 +361,49 @@ impl GCode {
         Ok(())
     }
 
+    /// Команда из записи бинарного протокола или инструкции HPGL
+    pub fn new(code: Code) -> Self {
+        Self {
+            code,
+            ..Self::default()
+        }
+    }
+
+    /// X Y в мм
+    pub fn set_xy(&mut self, x: f32, y: f32) {
+        self.x = Some(x);
+        self.y = Some(y);
+    }
+
+    /// S - код D[0..7] без пересчета по $220
+    pub fn set_s_code(&mut self, code: u8) {
+        self.s = Some(code as f32);
+        self.s_code = true;
+    }
+
+    pub fn set_f(&mut self, f: f32) {
+        self.f = Some(f);
+    }
+
+    pub fn set_b(&mut self, b: f32) {
+        self.b = Some(b);
+    }
+
+    /// Центр дуги относительно начала
+    pub fn set_ij(&mut self, i: f32, j: f32) {
+        self.i = Some(i);
+        self.j = Some(j);
+    }
+
+    pub fn set_t(&mut self, t: f32) {
+        self.t = Some(t);
+    }
+
+    #[inline]
+    pub fn is_s_code(&self) -> bool {
+        self.s_code
+    }
+
     #[inline]
     pub fn code(&self) -> Code {
         self.code
@@ -186,6 +424,16 @@ impl GCode {
         self.y
     }
 
+    #[inline]
+    pub fn get_i(&self) -> Option<f32> {
+        self.i
+    }
+
+    #[inline]
+    pub fn get_j(&self) -> Option<f32> {
+        self.j
+    }
+
     #[inline]
     pub fn get_s(&self) -> Option<f32> {
         self.s
@@ -201,10 +449,40 @@ impl GCode {
         self.b
     }
 
+    #[inline]
+    pub fn get_q(&self) -> Option<f32> {
+        self.q
+    }
+
     #[inline]
     pub fn get_f(&self) -> Option<f32> {
         self.f
     }
+
+    #[inline]
+    pub fn get_p(&self) -> Option<f32> {
+        self.p
+    }
+
+    #[inline]
+    pub fn get_t(&self) -> Option<f32> {
+        self.t
+    }
+
+    #[inline]
+    pub fn get_l(&self) -> Option<f32> {
+        self.l
+    }
+
+    #[inline]
+    pub fn get_r(&self) -> Option<f32> {
+        self.r
+    }
+
+    #[inline]
+    pub fn get_text(&self) -> Option<&str> {
+        self.text.as_ref().map(|t| t.as_str())
+    }
 }
 
 impl Default for GCode {
@@ -213,11 +491,22 @@ impl Default for GCode {
             code: Code::Empty,
             x: None,
             y: None,
+            i: None,
+            j: None,
             a: None,
             b: None,
+            q: None,
 
             s: None,
             f: None,
+            p: None,
+            t: None,
+            l: None,
+            r: None,
+
+            text: None,
+
+            s_code: false,
         }
     }
 }
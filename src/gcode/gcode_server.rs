@@ -2,7 +2,12 @@ use core::fmt::Write;
 
 use usb_device::UsbError;
 
+use super::binary_link::BinaryLink;
 use super::gcode;
+use super::hpgl::Hpgl;
+use super::job_store::UploadResult;
+use super::marlin::{marlin_error, MarlinLine, MarlinLink};
+use super::raster::{PixelData, PixelResult};
 
 pub enum SerialErrResult {
     OutOfMemory,
@@ -10,56 +15,165 @@ pub enum SerialErrResult {
     Incomplead(usize),
 }
 
-pub fn serial_process<'a, B, GP, RP, const N: usize>(
+/// Состояние разбора потока с USB между вызовами
+pub struct LinkState {
+    /// бинарный протокол `$B`
+    pub binary: BinaryLink,
+    /// язык строк ($310) и разбор HPGL
+    pub hpgl: Hpgl,
+    /// номера строк Marlin ($330)
+    pub marlin: MarlinLink,
+    /// `$340=0` в потоке Modbus RTU по USB
+    pub modbus_escape: ModbusEscape,
+}
+
+impl LinkState {
+    pub const fn new() -> Self {
+        Self {
+            binary: BinaryLink::new(),
+            hpgl: Hpgl::new(),
+            marlin: MarlinLink::new(),
+            modbus_escape: ModbusEscape::new(),
+        }
+    }
+
+    /// Потеря связи: текстовый режим, язык определяется заново, номера строк - с начала
+    pub fn reset(&mut self) {
+        let dialect = self.hpgl.dialect();
+        let marlin = self.marlin.is_enabled();
+        *self = Self::new();
+        self.hpgl.set_dialect(dialect);
+        self.marlin.set_enabled(marlin);
+    }
+}
+
+/// Строка `$340=0` из терминала, когда USB занят Modbus RTU ($340=2): без нее консоль
+/// была бы недоступна до команды ПЛК. В кадрах Modbus такой текст с концом строки не встречается
+pub struct ModbusEscape {
+    matched: usize,
+}
+
+impl ModbusEscape {
+    const TEXT: &'static [u8] = b"$340=0";
+
+    pub const fn new() -> Self {
+        Self { matched: 0 }
+    }
+
+    /// Принятые байты, true - строка `$340=0` закончена `\r` или `\n`
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        let mut found = false;
+        for &b in data {
+            self.matched = match Self::TEXT.get(self.matched) {
+                Some(&c) if c.eq_ignore_ascii_case(&b) => self.matched + 1,
+                None if b == b'\r' || b == b'\n' => {
+                    found = true;
+                    0
+                }
+                _ if b == Self::TEXT[0] => 1,
+                _ => 0,
+            };
+        }
+        found
+    }
+}
+
+pub fn serial_process<'a, B, GP, RP, US, PS, const N: usize>(
     serial: &mut usbd_serial::SerialPort<'static, B>,
     buf: &'a mut heapless::String<N>,
+    link: &mut LinkState,
     mut gcode_pusher: GP,
     mut request_pusher: RP,
+    mut upload_sink: US,
+    mut pixel_sink: PS,
 ) -> Result<usize, SerialErrResult>
 where
     GP: FnMut(gcode::GCode) -> Result<(), gcode::GCode>,
     RP: FnMut(gcode::Request) -> Result<(), gcode::Request>,
+    US: FnMut(&str) -> UploadResult,
+    PS: FnMut(PixelData) -> PixelResult,
     B: usb_device::bus::UsbBus,
 {
-    use gcode::{ParceError, ParceResult};
+    // бинарный протокол `$B`: кадры вместо строк
+    if link.binary.is_active() {
+        return link.binary.poll(
+            serial,
+            &mut gcode_pusher,
+            &mut request_pusher,
+            &mut pixel_sink,
+        );
+    }
 
     let mut consumed_data_len = 0;
 
-    match readline(serial, buf) {
-        Ok(mut s) => 'partial: loop {
-            match super::GCode::from_string::<N>(s) {
-                Ok(ParceResult::GCode(gcode)) => {
-                    gcode_pusher(gcode)
-                        .map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
-                    consumed_data_len += s.len();
-                    break 'partial;
-                }
-                Ok(ParceResult::Request(req)) => {
-                    request_pusher(req)
-                        .map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
-                    consumed_data_len += s.len();
-                    break 'partial;
-                }
-                Ok(ParceResult::Partial(gcode, offset)) => {
-                    gcode_pusher(gcode)
-                        .map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
-                    consumed_data_len += offset;
-                    s = &s[offset..];
-                    continue 'partial;
-                }
-                Err(ParceError::Empty) => {
-                    consumed_data_len += s.len();
-                    break 'partial;
+    match readline(serial, &mut *buf, |line| link.hpgl.splits(line)) {
+        Ok(line) => {
+            // Marlin: номер строки и контрольная сумма до всего остального
+            let s = match link.marlin.is_enabled() {
+                false => line,
+                true => match link.marlin.check(line) {
+                    MarlinLine::Unnumbered => line,
+                    MarlinLine::Reply(reply) => {
+                        let _ = serial.write(reply.as_bytes());
+                        return Ok(line.len());
+                    }
+                    MarlinLine::Command(command) => {
+                        // в буфере остается команда: не принятая очередью повторится без номера
+                        let end = line.chars().last().unwrap_or('\n');
+                        let mut text = heapless::String::<N>::new();
+                        let _ = text.push_str(&line[command]);
+                        let _ = text.push(end);
+                        *buf = text;
+                        &buf[..]
+                    }
+                },
+            };
+
+            // загрузка задания: строки сохраняются, а не выполняются
+            match upload_sink(s) {
+                UploadResult::NotUploading => {}
+                UploadResult::Reply(reply) => {
+                    let _ = serial.write(reply.as_bytes());
+                    return Ok(s.len());
                 }
-                Err(ParceError::Error(e)) => {
-                    consumed_data_len += s.len();
-                    let mut str = crate::config::HlString::new();
-                    let _ = write!(&mut str, "Error: {}\n\r", e);
-                    serial.write(e.as_bytes()).unwrap();
-                    break 'partial;
+                UploadResult::Skipped => return Ok(s.len()),
+                UploadResult::Full => return Err(SerialErrResult::Incomplead(0)),
+            }
+
+            if link.binary.enter(s) {
+                let _ = serial.write(b"ok\r\n");
+                return Ok(s.len());
+            }
+
+            // пиксели растра M160: в буфер пикселей, не в очередь G-кодов
+            match pixel_sink(PixelData::Line(s)) {
+                PixelResult::NotPixels => {}
+                PixelResult::Reply(reply) => {
+                    let _ = serial.write(reply.as_bytes());
+                    return Ok(s.len());
                 }
+                PixelResult::Stored => return Ok(s.len()),
+                PixelResult::Full => return Err(SerialErrResult::Incomplead(0)),
             }
-        },
+
+            // Marlin: M105, M114, M115 - ответ сразу
+            if let Some(req) = link.marlin.request(s) {
+                request_pusher(req).map_err(|_| SerialErrResult::Incomplead(0))?;
+                return Ok(s.len());
+            }
+
+            consumed_data_len = if link.hpgl.accepts(s) {
+                hpgl_line(serial, s, &mut link.hpgl, &mut gcode_pusher)?
+            } else {
+                dispatch_line::<B, GP, RP, N>(
+                    serial,
+                    s,
+                    link.marlin.is_enabled(),
+                    &mut gcode_pusher,
+                    &mut request_pusher,
+                )?
+            };
+        }
         Err(SerialErrResult::OutOfMemory) => return Err(SerialErrResult::OutOfMemory),
         Err(SerialErrResult::NoData) => {}
         Err(SerialErrResult::Incomplead(_)) => unreachable!(),
@@ -68,15 +182,109 @@ where
     Ok(consumed_data_len)
 }
 
+/// Строка в G-код или запрос. Очередь полна - `Incomplead` с числом уже принятых байт.
+/// marlin - ошибка разбора с `ok`
+pub fn dispatch_line<B, GP, RP, const N: usize>(
+    serial: &mut usbd_serial::SerialPort<'static, B>,
+    mut s: &str,
+    marlin: bool,
+    gcode_pusher: &mut GP,
+    request_pusher: &mut RP,
+) -> Result<usize, SerialErrResult>
+where
+    GP: FnMut(gcode::GCode) -> Result<(), gcode::GCode>,
+    RP: FnMut(gcode::Request) -> Result<(), gcode::Request>,
+    B: usb_device::bus::UsbBus,
+{
+    use gcode::{ParceError, ParceResult};
+
+    let mut consumed_data_len = 0;
+
+    'partial: loop {
+        match super::GCode::from_string::<N>(s) {
+            Ok(ParceResult::GCode(gcode)) => {
+                gcode_pusher(gcode).map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
+                consumed_data_len += s.len();
+                break 'partial;
+            }
+            Ok(ParceResult::Request(req)) => {
+                request_pusher(req).map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
+                consumed_data_len += s.len();
+                break 'partial;
+            }
+            Ok(ParceResult::Partial(gcode, offset)) => {
+                gcode_pusher(gcode).map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
+                consumed_data_len += offset;
+                s = &s[offset..];
+                continue 'partial;
+            }
+            Err(ParceError::Empty) => {
+                consumed_data_len += s.len();
+                break 'partial;
+            }
+            Err(ParceError::Error(e)) if marlin => {
+                consumed_data_len += s.len();
+                let _ = serial.write(marlin_error(&e).as_bytes());
+                break 'partial;
+            }
+            Err(ParceError::Error(e)) => {
+                consumed_data_len += s.len();
+                let mut str = crate::config::HlString::new();
+                let _ = write!(&mut str, "Error: {}\n\r", e);
+                serial.write(e.as_bytes()).unwrap();
+                break 'partial;
+            }
+        }
+    }
+
+    Ok(consumed_data_len)
+}
+
+/// Строка HPGL: G-код на каждую точку, состояние разбора - после принятых очередью
+fn hpgl_line<B, GP>(
+    serial: &mut usbd_serial::SerialPort<'static, B>,
+    s: &str,
+    hpgl: &mut Hpgl,
+    gcode_pusher: &mut GP,
+) -> Result<usize, SerialErrResult>
+where
+    GP: FnMut(gcode::GCode) -> Result<(), gcode::GCode>,
+    B: usb_device::bus::UsbBus,
+{
+    let mut consumed_data_len = 0;
+
+    loop {
+        match hpgl.next(&s[consumed_data_len..]) {
+            Ok((Some(gcode), offset, state)) => {
+                gcode_pusher(gcode).map_err(|_| SerialErrResult::Incomplead(consumed_data_len))?;
+                *hpgl = state;
+                consumed_data_len += offset;
+            }
+            Ok((None, _, state)) => {
+                *hpgl = state;
+                return Ok(s.len());
+            }
+            Err(e) => {
+                let mut str = crate::config::HlString::new();
+                let _ = write!(&mut str, "Error: {}\r\n", e);
+                let _ = serial.write(str.as_bytes());
+                return Ok(s.len());
+            }
+        }
+    }
+}
+
+/// splits - `;` тоже конец строки (HPGL)
 fn readline<'a, B: usb_device::bus::UsbBus, const N: usize>(
     serial: &mut usbd_serial::SerialPort<'static, B>,
     buf: &'a mut heapless::String<N>,
+    splits: impl Fn(&str) -> bool,
 ) -> Result<&'a str, SerialErrResult> {
     static ENDLINES: [char; 3] = ['\n', '\r', '?'];
 
     // если строка уже кончается на что-то из ENDLINES
     if let Some(ch) = buf.chars().last() {
-        if ENDLINES.contains(&ch) {
+        if ENDLINES.contains(&ch) || (ch == ';' && splits(buf)) {
             return Ok(&buf[..]);
         }
     }
@@ -87,13 +295,17 @@ fn readline<'a, B: usb_device::bus::UsbBus, const N: usize>(
             Ok(count) => {
                 if count == 1 {
                     let ch = ch[0] as char;
-                    if ch == '?' {
-                        return Ok("?");
+                    // realtime: статус, feed hold, cycle start
+                    match ch {
+                        '?' => return Ok("?"),
+                        '!' => return Ok("!"),
+                        '~' => return Ok("~"),
+                        _ => {}
                     }
                     if buf.push(ch).is_err() {
                         return Err(SerialErrResult::OutOfMemory);
                     }
-                    if ENDLINES.contains(&ch) {
+                    if ENDLINES.contains(&ch) || (ch == ';' && splits(buf)) {
                         // endline
                         return Ok(&buf[..]);
                     } else {
@@ -106,7 +318,30 @@ fn readline<'a, B: usb_device::bus::UsbBus, const N: usize>(
             Err(UsbError::WouldBlock) => {
                 return Err(SerialErrResult::NoData);
             }
-            Err(_) => panic!(),
+            Err(_) => {
+                // ошибка USB: недочитанная строка отбрасывается
+                buf.clear();
+                return Err(SerialErrResult::NoData);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modbus_escape() {
+        let mut escape = ModbusEscape::new();
+        // кадр Modbus и набор по одному символу
+        assert!(!escape.feed(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x02, 0xc4, 0x0b]));
+        for &b in b"$$34".iter() {
+            assert!(!escape.feed(&[b]));
         }
+        assert!(escape.feed(b"0=0\r"));
+        // без конца строки - не команда
+        assert!(!escape.feed(b"$340=0$340=1\n$340=00\n"));
+        assert!(escape.feed(b"\x03$340=0\n"));
     }
 }
@@ -0,0 +1,242 @@
+//! HPGL от программ для вывесок и плоттерных плагинов: инструкции `IN PU PD PA PR SP VS CI`
+//! переводятся в G-коды для `MotionMGR`, остальные пропускаются.
+//!
+//! Координаты - единицы плоттера (`HPGL_UNITS_PER_MM`), 0,0 - левый нижний угол поля.
+//! Инструкция с несколькими точками дает G-код на точку: разбор продолжается с той же
+//! инструкции, пока очередь не примет все.
+
+use crate::config;
+use crate::config::HlString as String;
+
+use super::gcode::Code;
+use super::settings::InputDialect;
+use super::GCode;
+
+#[derive(Clone, Copy)]
+pub struct Hpgl {
+    /// $310
+    dialect: InputDialect,
+    /// Auto: HPGL ли первая команда, None - команд еще не было
+    detected: Option<bool>,
+
+    pen_down: bool,
+    relative: bool,
+    /// перо, единицы плоттера
+    x: f32,
+    y: f32,
+    /// F от VS до следующего отрезка, мм/мин
+    speed: Option<f32>,
+    /// следующая точка или шаг CI текущей инструкции
+    step: usize,
+}
+
+impl Hpgl {
+    pub const fn new() -> Self {
+        Self {
+            dialect: InputDialect::GCode,
+            detected: None,
+            pen_down: false,
+            relative: false,
+            x: 0.0,
+            y: 0.0,
+            speed: None,
+            step: 0,
+        }
+    }
+
+    pub fn dialect(&self) -> InputDialect {
+        self.dialect
+    }
+
+    pub fn set_dialect(&mut self, dialect: InputDialect) {
+        self.dialect = dialect;
+    }
+
+    /// Разбирать строку как HPGL. Запросы `$`, `?`, `!`, `~` - всегда G-код
+    pub fn accepts(&mut self, line: &str) -> bool {
+        let first_char = line.trim_start().chars().next();
+        match first_char {
+            None => return false,
+            Some(c) if ['$', '?', '!', '~'].contains(&c) => return false,
+            _ => {}
+        }
+        match self.dialect {
+            InputDialect::GCode => false,
+            InputDialect::Hpgl => true,
+            InputDialect::Auto => *self.detected.get_or_insert_with(|| is_hpgl(line)),
+        }
+    }
+
+    /// `;` - конец строки: HPGL часто идет без переводов строк
+    pub fn splits(&self, line: &str) -> bool {
+        match self.dialect {
+            InputDialect::GCode => false,
+            InputDialect::Hpgl => true,
+            InputDialect::Auto => self.detected.unwrap_or_else(|| is_hpgl(line)),
+        }
+    }
+
+    /// Следующий G-код text: (G-код, разобрано байт, состояние после него).
+    /// G-кода нет - строка кончилась. Состояние применяется, когда G-код принят очередью
+    pub fn next(&self, text: &str) -> Result<(Option<GCode>, usize, Self), String> {
+        let mut state = *self;
+        let mut pos = 0;
+        loop {
+            let rest = &text[pos..];
+            let start = match rest.find(|c: char| !c.is_ascii_whitespace() && c != ';') {
+                Some(start) => start,
+                None => return Ok((None, text.len(), state)),
+            };
+
+            let statement = &rest[start..];
+            let mnemonic = match statement.as_bytes() {
+                [a, b, ..] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                    (a.to_ascii_uppercase(), b.to_ascii_uppercase())
+                }
+                _ => return Err("Invalid HPGL instruction".into()),
+            };
+            let params = &statement[2..];
+            let params = match params.find(|c: char| c == ';' || c.is_ascii_alphabetic()) {
+                Some(end) => &params[..end],
+                None => params,
+            };
+
+            match state.statement(mnemonic, params)? {
+                // остались точки инструкции
+                Some(gcode) if state.step > 0 => return Ok((Some(gcode), pos, state)),
+                Some(gcode) => return Ok((Some(gcode), pos + start + 2 + params.len(), state)),
+                None => pos += start + 2 + params.len(),
+            }
+        }
+    }
+
+    fn statement(&mut self, mnemonic: (u8, u8), params: &str) -> Result<Option<GCode>, String> {
+        match mnemonic {
+            (b'I', b'N') => {
+                *self = Self {
+                    dialect: self.dialect,
+                    detected: self.detected,
+                    ..Self::new()
+                };
+                Ok(Some(GCode::new(Code::G(90))))
+            }
+            (b'P', b'U') | (b'P', b'D') | (b'P', b'A') | (b'P', b'R') => {
+                match mnemonic.1 {
+                    b'U' => self.pen_down = false,
+                    b'D' => self.pen_down = true,
+                    b'A' => self.relative = false,
+                    _ => self.relative = true,
+                }
+                self.point(params)
+            }
+            (b'S', b'P') => {
+                // SP1 - перо T0, SP0 - перо убрано
+                let pen = param(params, 0)?.unwrap_or_default();
+                Ok(Some(if pen >= 1.0 {
+                    let mut gcode = GCode::new(Code::M(3));
+                    gcode.set_t(libm::floorf(pen) - 1.0);
+                    gcode
+                } else {
+                    GCode::new(Code::M(5))
+                }))
+            }
+            (b'V', b'S') => {
+                // см/с -> мм/мин
+                self.speed = param(params, 0)?.map(|v| v * 600.0);
+                Ok(None)
+            }
+            (b'C', b'I') => self.circle(params),
+            _ => Ok(None),
+        }
+    }
+
+    /// Очередная точка PU/PD/PA/PR
+    fn point(&mut self, params: &str) -> Result<Option<GCode>, String> {
+        let n = 2 * self.step;
+        let (x, y) = match (param(params, n)?, param(params, n + 1)?) {
+            (Some(x), Some(y)) => (x, y),
+            (None, _) => {
+                self.step = 0;
+                return Ok(None);
+            }
+            (Some(_), None) => return Err("HPGL odd coordinate count".into()),
+        };
+
+        if self.relative {
+            self.x += x;
+            self.y += y;
+        } else {
+            self.x = x;
+            self.y = y;
+        }
+        self.step += 1;
+        Ok(Some(self.go(self.pen_down as u32, self.x, self.y)))
+    }
+
+    /// CI: окружность вокруг пера против часовой, затем перо обратно в центр
+    fn circle(&mut self, params: &str) -> Result<Option<GCode>, String> {
+        let r = match param(params, 0)? {
+            Some(r) if r != 0.0 => libm::fabsf(r),
+            Some(_) => return Ok(None),
+            None => return Err("HPGL CI radius required".into()),
+        };
+
+        let (x, y) = (self.x, self.y);
+        let gcode = match self.step {
+            0 => self.go(0, x + r, y),
+            1 => {
+                let mut gcode = self.go(3, x + r, y);
+                gcode.set_ij(-r / config::HPGL_UNITS_PER_MM, 0.0);
+                gcode
+            }
+            _ => {
+                self.step = 0;
+                return Ok(Some(self.go(0, x, y)));
+            }
+        };
+        self.step += 1;
+        Ok(Some(gcode))
+    }
+
+    /// G0/G1/G3 в точку (x, y) в единицах плоттера, VS - на первый отрезок
+    fn go(&mut self, code: u32, x: f32, y: f32) -> GCode {
+        let mut gcode = GCode::new(Code::G(code));
+        gcode.set_xy(
+            to_mm(x, config::MOTION_X_RANGE),
+            to_mm(y, config::MOTION_Y_RANGE),
+        );
+        if code != 0 {
+            if let Some(speed) = self.speed.take() {
+                gcode.set_f(speed);
+            }
+        }
+        gcode
+    }
+}
+
+/// Первая команда - две буквы: у G-кода за буквой число
+fn is_hpgl(line: &str) -> bool {
+    matches!(
+        line.trim_start().as_bytes(),
+        [a, b, ..] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic()
+    )
+}
+
+/// n-й параметр инструкции, через запятую или пробел
+fn param(params: &str, n: usize) -> Result<Option<f32>, String> {
+    match params
+        .split(|c: char| c == ',' || c.is_ascii_whitespace())
+        .filter(|p| !p.is_empty())
+        .nth(n)
+    {
+        Some(p) => crate::support::parse_float(p)
+            .map(Some)
+            .ok_or_else(|| "Invalid HPGL number".into()),
+        None => Ok(None),
+    }
+}
+
+/// Единицы плоттера от левого нижнего угла -> мм от центра поля
+fn to_mm(units: f32, range: f32) -> f32 {
+    units / config::HPGL_UNITS_PER_MM - range / 2.0
+}
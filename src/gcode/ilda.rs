@@ -0,0 +1,297 @@
+//! M170: показ файлов ILDA (.ild) - юстировочные фигуры и демо-анимации на тех же зеркалах.
+//!
+//! Байты файла идут строками данных `;H:`/`;B:` (или кадрами `2` протокола `$B`) через
+//! буфер пикселей, либо читаются из строк данных сохраненного задания. Принятый кадр
+//! повторяется по кругу, новый подменяет его в конце прохода. Задание по концу текста
+//! начинается сначала - анимация идет по кругу до `M171`.
+//!
+//! Форматы 0 и 1 (3D/2D с палитрой), 4 и 5 (3D/2D true color), палитры (2) пропускаются.
+//! Цвет не используется: точка либо видна, либо погашена (бит blanking).
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::nv_storage::NvStorage;
+
+use super::gcode::MAX_LEN;
+use super::job_store::JobStore;
+use super::raster::{decode_data_line, PixelBuffer};
+
+const MAGIC: &[u8; 4] = b"ILDA";
+const HEADER_SIZE: usize = 32;
+const RECORD_MAX: usize = 10;
+
+/// бит status: точка погашена
+const STATUS_BLANKING: u8 = 1 << 6;
+
+#[derive(Clone, Copy, Default)]
+pub struct IldaPoint {
+    /// -32768..32767 на все поле
+    pub x: i16,
+    pub y: i16,
+    pub blank: bool,
+}
+
+/// Откуда байты файла
+#[derive(Clone, Copy, PartialEq)]
+pub enum IldaSource {
+    /// строки данных с USB
+    Stream,
+    /// строки данных задания в слоте
+    Job(u8),
+}
+
+enum Decoded {
+    Nothing,
+    /// точка, true - последняя в кадре
+    Point(IldaPoint, bool),
+}
+
+/// Разбор потока байт: заголовок секции, затем ее записи
+struct Decoder {
+    buf: [u8; HEADER_SIZE],
+    pos: usize,
+    format: u8,
+    records: u16,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            buf: [0; HEADER_SIZE],
+            pos: 0,
+            format: 0,
+            records: 0,
+        }
+    }
+
+    fn record_size(format: u8) -> Option<usize> {
+        match format {
+            0 => Some(8),
+            1 => Some(6),
+            2 => Some(3),
+            4 => Some(10),
+            5 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, b: u8) -> Result<Decoded, String> {
+        self.buf[self.pos] = b;
+        self.pos += 1;
+
+        if self.records == 0 {
+            // заголовок, до "ILDA" байты пропускаются
+            if self.pos <= MAGIC.len() && b != MAGIC[self.pos - 1] {
+                self.pos = (b == MAGIC[0]) as usize;
+                return Ok(Decoded::Nothing);
+            }
+            if self.pos == HEADER_SIZE {
+                self.pos = 0;
+                self.format = self.buf[7];
+                if Self::record_size(self.format).is_none() {
+                    return Err("Unsupported ILDA format".into());
+                }
+                // 0 записей - конец файла
+                self.records = u16::from_be_bytes([self.buf[24], self.buf[25]]);
+            }
+            return Ok(Decoded::Nothing);
+        }
+
+        if self.pos < Self::record_size(self.format).unwrap_or(RECORD_MAX) {
+            return Ok(Decoded::Nothing);
+        }
+        self.pos = 0;
+        self.records -= 1;
+
+        let status = match self.format {
+            0 | 4 => self.buf[6],
+            1 | 5 => self.buf[4],
+            _ => return Ok(Decoded::Nothing),
+        };
+        let point = IldaPoint {
+            x: i16::from_be_bytes([self.buf[0], self.buf[1]]),
+            y: i16::from_be_bytes([self.buf[2], self.buf[3]]),
+            blank: status & STATUS_BLANKING != 0,
+        };
+        Ok(Decoded::Point(point, self.records == 0))
+    }
+}
+
+pub struct IldaPlayer {
+    source: Option<IldaSource>,
+    decoder: Decoder,
+
+    /// показываемый кадр и следующий
+    frames: [[IldaPoint; config::ILDA_FRAME_POINTS]; 2],
+    len: [usize; 2],
+    active: usize,
+    /// следующий кадр принят, подменит показываемый в конце прохода
+    ready: bool,
+    point: usize,
+    next_nanos: u64,
+
+    /// задание: смещение следующей строки, декодированная строка данных
+    job_pos: usize,
+    line: [u8; MAX_LEN],
+    line_len: usize,
+    line_pos: usize,
+    /// байт данных за текущий проход задания
+    pass_bytes: usize,
+}
+
+impl IldaPlayer {
+    pub const fn new() -> Self {
+        Self {
+            source: None,
+            decoder: Decoder::new(),
+            frames: [[IldaPoint {
+                x: 0,
+                y: 0,
+                blank: true,
+            }; config::ILDA_FRAME_POINTS]; 2],
+            len: [0; 2],
+            active: 0,
+            ready: false,
+            point: 0,
+            next_nanos: 0,
+            job_pos: 0,
+            line: [0; MAX_LEN],
+            line_len: 0,
+            line_pos: 0,
+            pass_bytes: 0,
+        }
+    }
+
+    pub fn start(&mut self, source: IldaSource, now: u64) {
+        self.source = Some(source);
+        self.decoder = Decoder::new();
+        self.len = [0; 2];
+        self.ready = false;
+        self.point = 0;
+        self.next_nanos = now;
+        self.job_pos = 0;
+        self.line_len = 0;
+        self.line_pos = 0;
+        self.pass_bytes = 0;
+    }
+
+    pub fn stop(&mut self) {
+        self.source = None;
+    }
+
+    pub fn source(&self) -> Option<IldaSource> {
+        self.source
+    }
+
+    /// Байты с USB, пока не принят следующий кадр. true - байты взяты из буфера
+    pub fn load_stream(&mut self, buf: &mut PixelBuffer) -> Result<bool, String> {
+        let mut taken = false;
+        let mut b = [0u8; 1];
+        while !self.ready && buf.take(&mut b) == 1 {
+            taken = true;
+            self.feed(b[0])?;
+        }
+        Ok(taken)
+    }
+
+    /// Строки данных задания, по концу текста - сначала
+    pub fn load_job<NVS: NvStorage>(&mut self, store: &JobStore<NVS>) -> Result<(), String> {
+        let slot = match self.source {
+            Some(IldaSource::Job(slot)) => slot,
+            _ => return Ok(()),
+        };
+        if store.is_uploading() {
+            return Err("Job upload in progress".into());
+        }
+
+        let mut wrapped = false;
+        while !self.ready {
+            if self.line_pos < self.line_len {
+                let b = self.line[self.line_pos];
+                self.line_pos += 1;
+                self.pass_bytes += 1;
+                self.feed(b)?;
+                continue;
+            }
+
+            let mut text = [0u8; MAX_LEN + 1];
+            let n = store.read(slot, self.job_pos, &mut text);
+            if n == 0 {
+                if store.job(slot).is_none() {
+                    return Err("No job in slot".into());
+                }
+                if self.pass_bytes == 0 || wrapped {
+                    return Err("No ILDA frame in job".into());
+                }
+                wrapped = true;
+                self.job_pos = 0;
+                self.pass_bytes = 0;
+                continue;
+            }
+
+            // строки сохранены не длиннее MAX_LEN, всегда с \n
+            let len = text[..n].iter().position(|b| *b == b'\n').unwrap_or(n);
+            self.job_pos += len + 1;
+            self.line_pos = 0;
+            self.line_len = match core::str::from_utf8(&text[..len])
+                .ok()
+                .and_then(|line| decode_data_line(line, &mut self.line))
+            {
+                Some(Some(n)) => n,
+                Some(None) => return Err("Invalid ILDA data in job".into()),
+                None => 0,
+            };
+        }
+        Ok(())
+    }
+
+    fn feed(&mut self, b: u8) -> Result<(), String> {
+        if let Decoded::Point(point, last) = self.decoder.push(b)? {
+            // лишние точки кадра отбрасываются
+            let next = 1 - self.active;
+            if self.len[next] < config::ILDA_FRAME_POINTS {
+                self.frames[next][self.len[next]] = point;
+                self.len[next] += 1;
+            }
+            if last {
+                self.ready = true;
+                if self.len[self.active] == 0 {
+                    self.swap();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn swap(&mut self) {
+        self.active = 1 - self.active;
+        self.len[1 - self.active] = 0;
+        self.ready = false;
+        self.point = 0;
+    }
+
+    /// Точка, которую пора вывести при частоте rate точек/с
+    pub fn next_point(&mut self, now: u64, rate: f32) -> Option<IldaPoint> {
+        let len = self.len[self.active];
+        if len == 0 || now < self.next_nanos {
+            return None;
+        }
+        let period = (1_000_000_000.0 / rate) as u64;
+        // отставание больше точки (пауза, hold) не догоняется
+        self.next_nanos = if now - self.next_nanos > period {
+            now + period
+        } else {
+            self.next_nanos + period
+        };
+
+        let point = self.frames[self.active][self.point];
+        self.point += 1;
+        if self.point >= len {
+            self.point = 0;
+            if self.ready {
+                self.swap();
+            }
+        }
+        Some(point)
+    }
+}
@@ -0,0 +1,520 @@
+//! Выполнение сохраненного задания: строки читаются из flash и проходят тот же путь
+//! `GCode::from_string` -> `MotionMGR::process`, что и строки с USB.
+//! Step-and-repeat: задание выполняется для каждой копии сетки или списка смещений,
+//! копия переносится `MotionMGR::set_transform`
+
+use core::fmt::Write;
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::format_float_simple;
+use crate::support::nv_storage::NvStorage;
+
+use super::gcode::{Code, JobRequest, ParceError, ParceResult};
+use super::job_store::JobStore;
+use super::matrix_mark::MatrixLayout;
+use super::motion_mgr::LongString;
+use super::template;
+use super::text_mark::TextLayout;
+use super::transform::Transform;
+use super::{GCode, MAX_LEN};
+
+/// Расстановка копий
+enum JobArray {
+    Single,
+    Grid {
+        cols: u16,
+        rows: u16,
+        pitch_x: f32,
+        pitch_y: f32,
+    },
+    /// X, Y, угол
+    List(heapless::Vec<(f32, f32, f32), { config::JOB_ARRAY_MAX_OFFSETS }>),
+}
+
+impl JobArray {
+    fn copies(&self) -> usize {
+        match self {
+            JobArray::Single => 1,
+            JobArray::Grid { cols, rows, .. } => *cols as usize * *rows as usize,
+            JobArray::List(list) => list.len().max(1),
+        }
+    }
+
+    fn placement(&self, copy: usize) -> Transform {
+        match self {
+            JobArray::Single => Transform::IDENTITY,
+            JobArray::Grid {
+                cols,
+                pitch_x,
+                pitch_y,
+                ..
+            } => {
+                let (col, row) = (copy % *cols as usize, copy / *cols as usize);
+                Transform::new(col as f32 * pitch_x, row as f32 * pitch_y, 0.0)
+            }
+            JobArray::List(list) => match list.get(copy) {
+                Some(&(x, y, angle)) => Transform::new(x, y, angle),
+                None => Transform::IDENTITY,
+            },
+        }
+    }
+
+    /// `[ARRAY:...]`
+    fn print(&self, out: &mut LongString) {
+        let _ = match self {
+            JobArray::Single => write!(out, "[ARRAY:1]\r\n"),
+            JobArray::Grid {
+                cols,
+                rows,
+                pitch_x,
+                pitch_y,
+            } => write!(
+                out,
+                "[ARRAY:{}x{}|{},{}]\r\n",
+                cols,
+                rows,
+                format_float_simple(*pitch_x, 3),
+                format_float_simple(*pitch_y, 3)
+            ),
+            JobArray::List(list) => {
+                let _ = write!(out, "[ARRAY:{}", list.len());
+                for (x, y, angle) in list {
+                    let _ = write!(
+                        out,
+                        "|{},{},{}",
+                        format_float_simple(*x, 3),
+                        format_float_simple(*y, 3),
+                        format_float_simple(*angle, 3)
+                    );
+                }
+                write!(out, "]\r\n")
+            }
+        };
+    }
+}
+
+struct RunningJob {
+    slot: u8,
+    /// 0 - пока не остановят
+    repeat: u32,
+    done: u32,
+    copy: usize,
+    copies: usize,
+
+    /// смещение следующей строки в тексте
+    pos: usize,
+    line: heapless::String<MAX_LEN>,
+    /// разобранная часть строки (G90/G91 в начале)
+    line_offset: usize,
+    line_no: u32,
+
+    next: Option<GCode>,
+
+    waiting_trigger: bool,
+    trigger_last: bool,
+}
+
+pub struct JobRunner {
+    job: Option<RunningJob>,
+    array: JobArray,
+    /// перенос для MotionMGR, применяется после остановки движения
+    placement: Option<Transform>,
+}
+
+impl JobRunner {
+    pub const fn new() -> Self {
+        Self {
+            job: None,
+            array: JobArray::Single,
+            placement: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Слот выполняемого задания
+    pub fn slot(&self) -> Option<u8> {
+        self.job.as_ref().map(|job| job.slot)
+    }
+
+    /// trigger - состояние входа запуска, None - запуск без ожидания,
+    /// origin - положение и режим G90 перед запуском
+    pub fn start<NVS: NvStorage>(
+        &mut self,
+        store: &mut JobStore<NVS>,
+        slot: u8,
+        repeat: u32,
+        trigger: Option<bool>,
+        origin: (f32, f32, bool),
+    ) -> Result<(), String> {
+        if self.job.is_some() {
+            return Err("Job running\r\n".into());
+        }
+        if store.is_uploading() {
+            return Err("Job upload in progress\r\n".into());
+        }
+        if store.job(slot).is_none() {
+            return Err("No job in slot\r\n".into());
+        }
+        if !store.verify(slot) {
+            return Err("Job checksum error\r\n".into());
+        }
+        self.check_copies(store, slot, origin)?;
+
+        store.set_running(Some(slot));
+        self.placement = Some(self.array.placement(0));
+        self.job = Some(RunningJob {
+            slot,
+            repeat,
+            done: 0,
+            copy: 0,
+            copies: self.array.copies(),
+            pos: 0,
+            line: heapless::String::new(),
+            line_offset: 0,
+            line_no: 0,
+            next: None,
+            waiting_trigger: trigger.is_some(),
+            trigger_last: trigger.unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    /// `$FL`, `$FD`, `$FR`, `$FS`. blocked - движение или авария, запуск невозможен
+    pub fn process_request<NVS: NvStorage>(
+        &mut self,
+        store: &mut JobStore<NVS>,
+        req: JobRequest,
+        blocked: bool,
+        trigger: Option<bool>,
+        origin: (f32, f32, bool),
+    ) -> Result<Option<LongString>, String> {
+        let mut s = LongString::new();
+        match req {
+            JobRequest::List => {
+                store.print(&mut s);
+                self.array.print(&mut s);
+            }
+            JobRequest::Delete(slot) => store.delete(slot)?,
+            JobRequest::Run(slot, repeat) => {
+                if blocked {
+                    return Err("Motion busy or alarm\r\n".into());
+                }
+                self.start(store, slot, repeat, trigger, origin)?;
+            }
+            JobRequest::Stop => self.stop(store),
+            JobRequest::Grid(cols, rows, pitch_x, pitch_y) => {
+                self.array = JobArray::Grid {
+                    cols,
+                    rows,
+                    pitch_x,
+                    pitch_y,
+                }
+            }
+            JobRequest::Offset(x, y, angle) => {
+                if !matches!(self.array, JobArray::List(_)) {
+                    self.array = JobArray::List(heapless::Vec::new());
+                }
+                if let JobArray::List(list) = &mut self.array {
+                    list.push((x, y, angle))
+                        .map_err(|_| String::from("Too many offsets\r\n"))?;
+                }
+            }
+            JobRequest::ClearArray => self.array = JobArray::Single,
+        }
+        s.push_str("ok\r\n").unwrap();
+        Ok(Some(s))
+    }
+
+    /// Остановить после текущей команды
+    pub fn stop<NVS: NvStorage>(&mut self, store: &mut JobStore<NVS>) {
+        if self.job.take().is_some() {
+            store.set_running(None);
+            self.placement = Some(Transform::IDENTITY);
+        }
+    }
+
+    /// Перенос следующей копии, применять только при остановленном движении
+    pub fn take_placement(&mut self) -> Option<Transform> {
+        self.placement.take()
+    }
+
+    /// Каждая копия задания помещается в поле. origin - положение и режим G90 перед запуском:
+    /// относительные перемещения продолжаются от конца предыдущей копии
+    fn check_copies<NVS: NvStorage>(
+        &self,
+        store: &JobStore<NVS>,
+        slot: u8,
+        origin: (f32, f32, bool),
+    ) -> Result<(), String> {
+        let (half_x, half_y) = (config::MOTION_X_RANGE / 2.0, config::MOTION_Y_RANGE / 2.0);
+
+        let mut pass = origin;
+        // проход заканчивается там же, где начался - габарит у всех копий одинаковый
+        let mut repeated = None;
+        for copy in 0..self.array.copies() {
+            let ext = match repeated {
+                Some(ext) => ext,
+                None => {
+                    let from = pass;
+                    let ext = Self::extent(store, slot, &mut pass)?;
+                    if pass == from {
+                        repeated = Some(ext);
+                    }
+                    ext
+                }
+            };
+            let [min_x, min_y, max_x, max_y] = match ext {
+                Some(e) => e,
+                None => continue,
+            };
+
+            let t = self.array.placement(copy);
+            for (x, y) in [(min_x, min_y), (max_x, min_y), (min_x, max_y), (max_x, max_y)] {
+                let (x, y) = t.apply(x, y);
+                if x < -half_x || x > half_x || y < -half_y || y > half_y {
+                    let mut s = String::new();
+                    let _ = write!(&mut s, "Copy {} outside field\r\n", copy);
+                    return Err(s);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Габарит одного прохода задания [min X, min Y, max X, max Y], дуги - описанным квадратом.
+    /// pass - положение и режим G90 до прохода, после возврата - в конце прохода
+    fn extent<NVS: NvStorage>(
+        store: &JobStore<NVS>,
+        slot: u8,
+        pass: &mut (f32, f32, bool),
+    ) -> Result<Option<[f32; 4]>, String> {
+        let mut ext: Option<[f32; 4]> = None;
+        let mut include = |x: f32, y: f32| {
+            let e = ext.get_or_insert([x, y, x, y]);
+            *e = [e[0].min(x), e[1].min(y), e[2].max(x), e[3].max(y)];
+        };
+
+        let (x, y, absolute) = pass;
+        let mut pos = 0;
+        let mut line = heapless::String::<MAX_LEN>::new();
+        while Self::read_line(store, slot, &mut pos, &mut line) {
+            let mut text = line.as_str();
+            loop {
+                let (gcode, rest) = match GCode::from_string::<MAX_LEN>(text) {
+                    Ok(ParceResult::GCode(gcode)) => (gcode, ""),
+                    Ok(ParceResult::Partial(gcode, offset)) => (gcode, &text[offset..]),
+                    Ok(ParceResult::Request(_)) | Err(ParceError::Empty) => break,
+                    Err(ParceError::Error(e)) => return Err(e),
+                };
+
+                match gcode.code() {
+                    Code::G(90) => *absolute = true,
+                    Code::G(28) => {
+                        (*x, *y) = (0.0, 0.0);
+                        include(*x, *y);
+                    }
+                    // G91 X Y - относительное перемещение, как в MotionMGR
+                    Code::G(0 | 1 | 2 | 3 | 91) | Code::Empty => {
+                        if let Code::G(91) = gcode.code() {
+                            *absolute = false;
+                        }
+                        let (from_x, from_y) = (*x, *y);
+                        *x = target(gcode.get_x(), *x, *absolute);
+                        *y = target(gcode.get_y(), *y, *absolute);
+                        include(from_x, from_y);
+                        include(*x, *y);
+
+                        if let Code::G(2 | 3) = gcode.code() {
+                            let i = gcode.get_i().unwrap_or_default();
+                            let j = gcode.get_j().unwrap_or_default();
+                            let (cx, cy) = (from_x + i, from_y + j);
+                            let r = libm::sqrtf(i * i + j * j);
+                            include(cx - r, cy - r);
+                            include(cx + r, cy + r);
+                        }
+                    }
+                    Code::M(150) => {
+                        // рамка текста с самыми длинными значениями полей
+                        if let Some(text) = gcode.get_text() {
+                            let tx = target(gcode.get_x(), *x, *absolute);
+                            let ty = target(gcode.get_y(), *y, *absolute);
+                            let layout = TextLayout::from_gcode(&gcode, tx, ty)?;
+                            for (cx, cy) in layout.corners(template::max_len(text)) {
+                                include(cx, cy);
+                            }
+                        }
+                    }
+                    Code::M(160) => {
+                        // пиксели растра приходят только по USB
+                        return Err("Raster in stored job\r\n".into());
+                    }
+                    Code::M(152 | 153) => {
+                        // 2D код - квадрат P x P от левого нижнего угла
+                        if gcode.get_text().is_some() {
+                            let tx = target(gcode.get_x(), *x, *absolute);
+                            let ty = target(gcode.get_y(), *y, *absolute);
+                            let layout = MatrixLayout::from_gcode(&gcode, tx, ty)?;
+                            for (cx, cy) in layout.corners() {
+                                include(cx, cy);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+
+                if rest.is_empty() {
+                    break;
+                }
+                text = rest;
+            }
+        }
+        Ok(ext)
+    }
+
+    /// Подготовить следующую команду. idle - движение завершено,
+    /// trigger - состояние входа запуска. Возвращает сообщение хосту
+    pub fn poll<NVS: NvStorage>(
+        &mut self,
+        store: &mut JobStore<NVS>,
+        idle: bool,
+        trigger: Option<bool>,
+    ) -> Option<LongString> {
+        let job = self.job.as_mut()?;
+
+        if job.waiting_trigger {
+            let active = trigger.unwrap_or(true);
+            let rising = active && !job.trigger_last;
+            job.trigger_last = active;
+            if trigger.is_some() && !rising {
+                return None;
+            }
+            job.waiting_trigger = false;
+        }
+
+        while job.next.is_none() {
+            if job.line_offset >= job.line.len() {
+                job.line_offset = 0;
+                job.line_no += 1;
+                if !Self::read_line(store, job.slot, &mut job.pos, &mut job.line) {
+                    // конец текста: прогон засчитывается после завершения движения
+                    if !idle {
+                        return None;
+                    }
+                    return self.run_done(store, trigger);
+                }
+            }
+
+            match GCode::from_string::<MAX_LEN>(&job.line[job.line_offset..]) {
+                Ok(ParceResult::GCode(gcode)) => {
+                    job.next = Some(gcode);
+                    job.line_offset = job.line.len();
+                }
+                Ok(ParceResult::Partial(gcode, offset)) => {
+                    job.next = Some(gcode);
+                    job.line_offset += offset;
+                }
+                // $ и ? в задании не имеют смысла
+                Ok(ParceResult::Request(_)) | Err(ParceError::Empty) => {
+                    job.line_offset = job.line.len();
+                }
+                Err(ParceError::Error(e)) => return Some(self.abort(store, &e)),
+            }
+        }
+        None
+    }
+
+    /// Следующая команда задания, если она готова
+    pub fn peek(&self) -> Option<&GCode> {
+        self.job.as_ref()?.next.as_ref()
+    }
+
+    pub fn take(&mut self) -> Option<GCode> {
+        self.job.as_mut()?.next.take()
+    }
+
+    /// Ошибка выполнения команды задания: задание останавливается
+    pub fn abort<NVS: NvStorage>(&mut self, store: &mut JobStore<NVS>, err: &str) -> LongString {
+        let mut s = LongString::new();
+        if let Some(job) = self.job.as_ref() {
+            let _ = write!(
+                &mut s,
+                "[MSG:Job {} line {}: {}]\r\n",
+                job.slot,
+                job.line_no,
+                err.trim_end()
+            );
+        }
+        self.stop(store);
+        s
+    }
+
+    fn read_line<NVS: NvStorage>(
+        store: &JobStore<NVS>,
+        slot: u8,
+        pos: &mut usize,
+        line: &mut heapless::String<MAX_LEN>,
+    ) -> bool {
+        let mut buf = [0u8; MAX_LEN + 1];
+        let n = store.read(slot, *pos, &mut buf);
+        if n == 0 {
+            return false;
+        }
+
+        // строки сохранены не длиннее MAX_LEN, всегда с \n
+        let len = buf[..n].iter().position(|b| *b == b'\n').unwrap_or(n);
+        *pos += len + 1;
+        line.clear();
+        if let Ok(text) = core::str::from_utf8(&buf[..len]) {
+            let _ = line.push_str(text);
+        }
+        true
+    }
+
+    fn run_done<NVS: NvStorage>(
+        &mut self,
+        store: &mut JobStore<NVS>,
+        trigger: Option<bool>,
+    ) -> Option<LongString> {
+        let job = self.job.as_mut()?;
+        let slot = job.slot;
+
+        job.copy += 1;
+        if job.copy >= job.copies {
+            // все копии - один прогон
+            job.copy = 0;
+            job.done += 1;
+            if let Err(e) = store.count_run(slot) {
+                return Some(self.abort(store, &e));
+            }
+            if job.repeat != 0 && job.done >= job.repeat {
+                let mut s = LongString::new();
+                let _ = write!(&mut s, "[MSG:Job {} done, {} runs]\r\n", slot, job.done);
+                self.stop(store);
+                return Some(s);
+            }
+            job.waiting_trigger = trigger.is_some();
+            job.trigger_last = trigger.unwrap_or_default();
+        }
+
+        // следующая копия с начала текста
+        job.pos = 0;
+        job.line.clear();
+        job.line_offset = 0;
+        job.line_no = 0;
+        self.placement = Some(self.array.placement(job.copy));
+        None
+    }
+}
+
+/// Координата после перемещения из pos по G90/G91
+fn target(value: Option<f32>, pos: f32, absolute: bool) -> f32 {
+    match value {
+        Some(v) if absolute => v,
+        Some(v) => pos + v,
+        None => pos,
+    }
+}
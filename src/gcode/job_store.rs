@@ -0,0 +1,624 @@
+//! Сохраненные задания: текст G-кода во flash для запуска без хоста.
+//!
+//! Разметка области: первая стираемая страница - журнал счетчиков запусков,
+//! остальное делится на `config::JOB_SLOTS` слотов по целому числу страниц.
+//! Слот: заголовок, затем строки G-кода через `\n`. Заголовок пишется последним,
+//! недогруженное задание остается без заголовка и не запускается.
+
+use core::fmt::Write;
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::nv_storage::{checksum_update, NvStorage, CHECKSUM_INIT};
+
+use super::motion_mgr::LongString;
+
+/// Заголовок задания во flash
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u32,
+    len: u32,
+    checksum: u32,
+    reserved: u32,
+}
+
+const JOB_MAGIC: u32 = 0x4a4f_4231; // "JOB1"
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+/// Запись журнала счетчиков: [COUNTER_MAGIC | slot, runs]
+const COUNTER_MAGIC: u32 = 0x434e_5400; // "CNT"
+const COUNTER_SIZE: usize = 8;
+
+/// Команда начала загрузки: `$FU<n>`
+const UPLOAD_CMD: &str = "$FU";
+/// Конец загрузки
+const UPLOAD_END: &str = "%";
+
+/// Задание в слоте
+#[derive(Clone, Copy)]
+pub struct JobInfo {
+    pub len: usize,
+    pub checksum: u32,
+}
+
+pub enum UploadResult {
+    /// загрузки нет, строка обрабатывается как обычно
+    NotUploading,
+    /// строка принята, ответ хосту
+    Reply(String),
+    /// строка принята, без ответа
+    Skipped,
+    /// нет места, строка будет принята позже
+    Full,
+}
+
+/// Строка загружаемого задания
+pub type UploadLine = heapless::String<{ super::MAX_LEN }>;
+
+/// Строки загрузки от USB до записи во flash: стирание и запись страниц идут в idle,
+/// не в прерывании USB. Ответы на строки отправляет idle
+pub struct UploadQueue {
+    uploading: bool,
+    lines: heapless::Deque<UploadLine, { config::JOB_UPLOAD_QUEUE_LINES }>,
+}
+
+struct Upload {
+    slot: u8,
+    len: usize,
+    /// страницы слота стерты до этого смещения
+    erased_to: usize,
+}
+
+pub struct JobStore<NVS: NvStorage> {
+    storage: NVS,
+    slot_size: usize,
+
+    upload: Option<Upload>,
+    running: Option<u8>,
+
+    runs: [u32; config::JOB_SLOTS],
+    counters_used: usize,
+}
+
+impl UploadQueue {
+    pub const fn new() -> Self {
+        Self {
+            uploading: false,
+            lines: heapless::Deque::new(),
+        }
+    }
+
+    /// Строка от хоста: `$FU<n>` и строки до `%` встают в очередь.
+    /// Вызывается до разбора строки как G-кода
+    pub fn upload_line(&mut self, line: &str) -> UploadResult {
+        let text = line.trim_matches(|c: char| c.is_ascii_whitespace());
+
+        if !self.uploading && !starts_with_ignore_case(text, UPLOAD_CMD) {
+            return UploadResult::NotUploading;
+        }
+        match text {
+            // real-time команды работают и во время загрузки
+            "?" | "!" | "~" => return UploadResult::NotUploading,
+            // пустые строки (\r\n) не сохраняются
+            "" => return UploadResult::Skipped,
+            _ => {}
+        }
+
+        let mut queued = UploadLine::new();
+        let _ = queued.push_str(&text[..text.len().min(super::MAX_LEN)]);
+        if self.lines.push_back(queued).is_err() {
+            return UploadResult::Full;
+        }
+        self.uploading = text != UPLOAD_END;
+        UploadResult::Skipped
+    }
+
+    /// Следующая строка для JobStore::upload_line
+    pub fn take(&mut self) -> Option<UploadLine> {
+        self.lines.pop_front()
+    }
+
+    /// Строка обработана JobStore: загрузка оборвалась на ошибке - отменяется и здесь
+    pub fn processed(&mut self, line: &str, store_uploading: bool) {
+        if !store_uploading && line != UPLOAD_END {
+            self.cancel();
+        }
+    }
+
+    /// Загрузка прервана: остаток строк отбрасывается, дальше строки - G-коды
+    pub fn cancel(&mut self) {
+        self.uploading = false;
+        self.lines.clear();
+    }
+}
+
+impl<NVS: NvStorage> JobStore<NVS> {
+    pub fn new(storage: NVS) -> Self {
+        let page = storage.erase_size();
+        let slot_size = (storage.capacity() - page) / config::JOB_SLOTS / page * page;
+
+        let mut store = Self {
+            storage,
+            slot_size,
+            upload: None,
+            running: None,
+            runs: [0; config::JOB_SLOTS],
+            counters_used: 0,
+        };
+        store.load_counters();
+        store
+    }
+
+    fn slot_offset(&self, slot: u8) -> usize {
+        self.storage.erase_size() + slot as usize * self.slot_size
+    }
+
+    fn check_slot(slot: u8) -> Result<(), String> {
+        if (slot as usize) < config::JOB_SLOTS {
+            Ok(())
+        } else {
+            Err("No such job slot\r\n".into())
+        }
+    }
+
+    /// Задание в слоте, None - пусто или загрузка не завершена
+    pub fn job(&self, slot: u8) -> Option<JobInfo> {
+        if slot as usize >= config::JOB_SLOTS {
+            return None;
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        self.storage.read(self.slot_offset(slot), &mut header);
+        let header: Header = unsafe { core::mem::transmute(header) };
+
+        if header.magic != JOB_MAGIC || header.len as usize > self.slot_size - HEADER_SIZE {
+            None
+        } else {
+            Some(JobInfo {
+                len: header.len as usize,
+                checksum: header.checksum,
+            })
+        }
+    }
+
+    /// Текст задания с offset, возвращает число прочитанных байт
+    pub fn read(&self, slot: u8, offset: usize, buf: &mut [u8]) -> usize {
+        let len = match self.job(slot) {
+            Some(job) => buf.len().min(job.len.saturating_sub(offset)),
+            None => 0,
+        };
+        self.storage.read(
+            self.slot_offset(slot) + HEADER_SIZE + offset,
+            &mut buf[..len],
+        );
+        len
+    }
+
+    /// Контрольная сумма текста совпадает с заголовком
+    pub fn verify(&self, slot: u8) -> bool {
+        let job = match self.job(slot) {
+            Some(job) => job,
+            None => return false,
+        };
+
+        let mut hash = CHECKSUM_INIT;
+        let mut buf = [0u8; 64];
+        let mut offset = 0;
+        while offset < job.len {
+            let n = self.read(slot, offset, &mut buf);
+            hash = checksum_update(hash, &buf[..n]);
+            offset += n;
+        }
+        hash == job.checksum
+    }
+
+    pub fn delete(&mut self, slot: u8) -> Result<(), String> {
+        Self::check_slot(slot)?;
+        if self.running == Some(slot) {
+            return Err("Job running\r\n".into());
+        }
+        if self.upload.as_ref().map(|u| u.slot) == Some(slot) {
+            self.upload = None;
+        }
+
+        // без заголовка слот пуст
+        self.storage
+            .erase(self.slot_offset(slot), HEADER_SIZE)
+            .map_err(|_| String::from("Failed to delete job\r\n"))?;
+        self.runs[slot as usize] = 0;
+        self.save_counter(slot)
+    }
+
+    /// Задание выполняется: его нельзя перезаписать или удалить
+    pub fn set_running(&mut self, slot: Option<u8>) {
+        self.running = slot;
+    }
+
+    pub fn is_uploading(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// Связь с хостом потеряна: недогруженное задание остается пустым
+    pub fn abort_upload(&mut self) {
+        self.upload = None;
+    }
+
+    /// Строка из UploadQueue: `$FU<n>` начинает загрузку, дальше строки сохраняются до `%`
+    pub fn upload_line(&mut self, line: &str) -> UploadResult {
+        let text = line.trim_matches(|c: char| c.is_ascii_whitespace());
+
+        if self.upload.is_none() {
+            if !starts_with_ignore_case(text, UPLOAD_CMD) {
+                return UploadResult::NotUploading;
+            }
+            return UploadResult::Reply(match self.begin_upload(&text[UPLOAD_CMD.len()..]) {
+                Ok(()) => "ok\r\n".into(),
+                Err(e) => e,
+            });
+        }
+
+        match text {
+            // real-time команды работают и во время загрузки
+            "?" | "!" | "~" => UploadResult::NotUploading,
+            // пустые строки (\r\n) не сохраняются
+            "" => UploadResult::Skipped,
+            UPLOAD_END => UploadResult::Reply(match self.finish_upload() {
+                Ok(()) => "ok\r\n".into(),
+                Err(e) => e,
+            }),
+            _ => UploadResult::Reply(match self.append(text) {
+                Ok(()) => "ok\r\n".into(),
+                Err(e) => {
+                    self.upload = None;
+                    e
+                }
+            }),
+        }
+    }
+
+    fn begin_upload(&mut self, arg: &str) -> Result<(), String> {
+        let slot = arg
+            .parse::<u8>()
+            .map_err(|_| String::from("Job slot required\r\n"))?;
+        Self::check_slot(slot)?;
+        if self.running == Some(slot) {
+            return Err("Job running\r\n".into());
+        }
+
+        // старый заголовок стирается сразу, остальные страницы - по мере записи
+        let page = self.storage.erase_size();
+        self.storage
+            .erase(self.slot_offset(slot), page)
+            .map_err(|_| String::from("Failed to erase job\r\n"))?;
+        self.upload = Some(Upload {
+            slot,
+            len: 0,
+            erased_to: page,
+        });
+        Ok(())
+    }
+
+    fn append(&mut self, text: &str) -> Result<(), String> {
+        let page = self.storage.erase_size();
+        let base = self.slot_offset(self.upload.as_ref().unwrap().slot);
+        let upload = self.upload.as_mut().unwrap();
+
+        // запись только по 16 бит: нечетная строка дополняется пустой
+        let mut data = [b'\n'; super::MAX_LEN + 2];
+        let len = text.len().min(super::MAX_LEN);
+        data[..len].copy_from_slice(&text.as_bytes()[..len]);
+        let size = (len + 2) & !1;
+
+        let offset = HEADER_SIZE + upload.len;
+        if offset + size > self.slot_size {
+            return Err("Job too large\r\n".into());
+        }
+
+        while upload.erased_to < offset + size {
+            self.storage
+                .erase(base + upload.erased_to, page)
+                .map_err(|_| String::from("Failed to erase job\r\n"))?;
+            upload.erased_to += page;
+        }
+
+        self.storage
+            .write(base + offset, &data[..size])
+            .map_err(|_| String::from("Failed to write job\r\n"))?;
+        upload.len += size;
+        Ok(())
+    }
+
+    fn finish_upload(&mut self) -> Result<(), String> {
+        let upload = self.upload.take().unwrap();
+        let base = self.slot_offset(upload.slot);
+
+        let mut hash = CHECKSUM_INIT;
+        let mut buf = [0u8; 64];
+        let mut offset = 0;
+        while offset < upload.len {
+            let n = buf.len().min(upload.len - offset);
+            self.storage
+                .read(base + HEADER_SIZE + offset, &mut buf[..n]);
+            hash = checksum_update(hash, &buf[..n]);
+            offset += n;
+        }
+
+        let header = Header {
+            magic: JOB_MAGIC,
+            len: upload.len as u32,
+            checksum: hash,
+            reserved: u32::MAX,
+        };
+        let header: [u8; HEADER_SIZE] = unsafe { core::mem::transmute(header) };
+        self.storage
+            .write(base, &header)
+            .map_err(|_| String::from("Failed to write job\r\n"))?;
+
+        self.runs[upload.slot as usize] = 0;
+        self.save_counter(upload.slot)
+    }
+
+    pub fn runs(&self, slot: u8) -> u32 {
+        self.runs[slot as usize]
+    }
+
+    /// Задание выполнено еще раз
+    pub fn count_run(&mut self, slot: u8) -> Result<(), String> {
+        self.runs[slot as usize] = self.runs[slot as usize].wrapping_add(1);
+        self.save_counter(slot)
+    }
+
+    fn load_counters(&mut self) {
+        let page = self.storage.erase_size();
+        let mut offset = 0;
+        while offset + COUNTER_SIZE <= page {
+            let mut rec = [0u8; COUNTER_SIZE];
+            self.storage.read(offset, &mut rec);
+            let tag = u32::from_le_bytes([rec[0], rec[1], rec[2], rec[3]]);
+            if tag == u32::MAX {
+                break;
+            }
+            let slot = (tag & 0xff) as usize;
+            if tag & !0xff == COUNTER_MAGIC && slot < config::JOB_SLOTS {
+                self.runs[slot] = u32::from_le_bytes([rec[4], rec[5], rec[6], rec[7]]);
+            }
+            offset += COUNTER_SIZE;
+        }
+        self.counters_used = offset;
+    }
+
+    /// Дописывает счетчик в журнал, заполненный журнал стирается и пишется заново
+    fn save_counter(&mut self, slot: u8) -> Result<(), String> {
+        let page = self.storage.erase_size();
+        let res = if self.counters_used + COUNTER_SIZE > page {
+            self.storage.erase(0, page).and_then(|_| {
+                self.counters_used = 0;
+                (0..config::JOB_SLOTS as u8).try_for_each(|s| self.write_counter(s))
+            })
+        } else {
+            self.write_counter(slot)
+        };
+        res.map_err(|_| String::from("Failed to save job counter\r\n"))
+    }
+
+    fn write_counter(&mut self, slot: u8) -> Result<(), crate::support::nv_storage::NvError> {
+        let mut rec = [0u8; COUNTER_SIZE];
+        rec[..4].copy_from_slice(&(COUNTER_MAGIC | slot as u32).to_le_bytes());
+        rec[4..].copy_from_slice(&self.runs[slot as usize].to_le_bytes());
+        self.storage.write(self.counters_used, &rec)?;
+        self.counters_used += COUNTER_SIZE;
+        Ok(())
+    }
+
+    /// `[JOB:<n>|<байт>|<контрольная сумма>|<запусков>]` для каждого сохраненного задания
+    pub fn print(&self, out: &mut LongString) {
+        for slot in 0..config::JOB_SLOTS as u8 {
+            if let Some(job) = self.job(slot) {
+                let _ = write!(
+                    out,
+                    "[JOB:{}|{}|{:08x}|{}]\r\n",
+                    slot,
+                    job.len,
+                    job.checksum,
+                    self.runs(slot)
+                );
+            }
+        }
+    }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.len() >= prefix.len()
+        && text.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::support::nv_storage::ram::{self, PAGE};
+
+    type Ram = ram::Ram<{ PAGE * 9 }>;
+
+    fn store() -> JobStore<Ram> {
+        JobStore::new(Ram::new())
+    }
+
+    fn reply(res: UploadResult) -> String {
+        match res {
+            UploadResult::Reply(s) => s,
+            _ => panic!("no reply"),
+        }
+    }
+
+    fn upload(store: &mut JobStore<Ram>, slot: u8, lines: &[&str]) {
+        let mut cmd = String::new();
+        write!(cmd, "$FU{}\r\n", slot).unwrap();
+        assert_eq!(reply(store.upload_line(&cmd)), "ok\r\n");
+        for line in lines {
+            match store.upload_line(line) {
+                UploadResult::Reply(s) => assert_eq!(s, "ok\r\n"),
+                UploadResult::Skipped => assert!(line.trim().is_empty()),
+                UploadResult::NotUploading | UploadResult::Full => panic!("line not stored"),
+            }
+        }
+        assert_eq!(reply(store.upload_line("%\r\n")), "ok\r\n");
+    }
+
+    fn text(store: &JobStore<Ram>, slot: u8) -> heapless::Vec<u8, 256> {
+        let mut buf = [0u8; 256];
+        let n = store.read(slot, 0, &mut buf);
+        heapless::Vec::from_slice(&buf[..n]).unwrap()
+    }
+
+    #[test]
+    fn upload_and_read() {
+        let mut store = store();
+        assert!(store.job(1).is_none());
+
+        upload(&mut store, 1, &["G0 X1\r\n", "", "G1 Y22\r\n"]);
+        assert!(!store.is_uploading());
+        assert!(store.verify(1));
+        // пустая строка не сохраняется, четная дополнена до 16 бит
+        assert_eq!(&text(&store, 1)[..], b"G0 X1\nG1 Y22\n\n");
+        assert!(store.job(0).is_none());
+
+        // задание переживает перезапуск
+        let store = JobStore::new(store.storage);
+        assert_eq!(store.job(1).unwrap().len, 14);
+        assert!(store.verify(1));
+    }
+
+    #[test]
+    fn unfinished_upload_is_empty() {
+        let mut store = store();
+        upload(&mut store, 2, &["G0 X1\r\n"]);
+
+        assert_eq!(reply(store.upload_line("$fu2")), "ok\r\n");
+        assert!(store.job(2).is_none());
+        // real-time команды проходят мимо загрузки
+        assert!(matches!(store.upload_line("?"), UploadResult::NotUploading));
+        assert_eq!(reply(store.upload_line("G0 Y1")), "ok\r\n");
+        store.abort_upload();
+        assert!(store.job(2).is_none());
+        assert!(matches!(
+            store.upload_line("G0"),
+            UploadResult::NotUploading
+        ));
+    }
+
+    #[test]
+    fn bad_slot_and_overflow() {
+        let mut store = store();
+        assert_eq!(reply(store.upload_line("$FU9")), "No such job slot\r\n");
+        assert_eq!(reply(store.upload_line("$FU")), "Job slot required\r\n");
+
+        assert_eq!(reply(store.upload_line("$FU0")), "ok\r\n");
+        let line = [b'G'; 100];
+        let line = core::str::from_utf8(&line).unwrap();
+        let res = loop {
+            let res = reply(store.upload_line(line));
+            if res != "ok\r\n" {
+                break res;
+            }
+        };
+        assert_eq!(res, "Job too large\r\n");
+        assert!(!store.is_uploading());
+        assert!(store.job(0).is_none());
+    }
+
+    #[test]
+    fn upload_queue() {
+        let mut store = store();
+        let mut queue = UploadQueue::new();
+        assert!(matches!(
+            queue.upload_line("G0 X1"),
+            UploadResult::NotUploading
+        ));
+
+        assert!(matches!(
+            queue.upload_line("$FU1\r\n"),
+            UploadResult::Skipped
+        ));
+        assert!(matches!(queue.upload_line("?"), UploadResult::NotUploading));
+        assert!(matches!(queue.upload_line("\r\n"), UploadResult::Skipped));
+        assert!(matches!(
+            queue.upload_line("G1 Y2\r\n"),
+            UploadResult::Skipped
+        ));
+        // строка остается в буфере USB до записи предыдущих
+        assert!(matches!(queue.upload_line("%"), UploadResult::Full));
+
+        while let Some(line) = queue.take() {
+            assert_eq!(reply(store.upload_line(&line)), "ok\r\n");
+            queue.processed(&line, store.is_uploading());
+        }
+        assert!(matches!(queue.upload_line("%"), UploadResult::Skipped));
+        let line = queue.take().unwrap();
+        assert_eq!(reply(store.upload_line(&line)), "ok\r\n");
+        queue.processed(&line, store.is_uploading());
+        assert_eq!(&text(&store, 1)[..], b"G1 Y2\n");
+
+        // ошибка записи прерывает загрузку, следующие строки - G-коды
+        assert!(matches!(queue.upload_line("$FU7"), UploadResult::Skipped));
+        let line = queue.take().unwrap();
+        assert_eq!(reply(store.upload_line(&line)), "No such job slot\r\n");
+        queue.processed(&line, store.is_uploading());
+        assert!(matches!(
+            queue.upload_line("G0 X1"),
+            UploadResult::NotUploading
+        ));
+    }
+
+    #[test]
+    fn delete_and_reupload() {
+        let mut store = store();
+        upload(&mut store, 0, &["G0 X1"]);
+        upload(&mut store, 3, &["G0 X3"]);
+
+        store.set_running(Some(0));
+        assert_eq!(store.delete(0).unwrap_err(), "Job running\r\n");
+        assert_eq!(reply(store.upload_line("$FU0")), "Job running\r\n");
+        store.set_running(None);
+
+        store.delete(0).unwrap();
+        assert!(store.job(0).is_none());
+        assert!(store.verify(3));
+
+        // перезапись слота стирает старый текст
+        upload(&mut store, 3, &["M5"]);
+        assert_eq!(&text(&store, 3)[..], b"M5\n\n");
+        assert!(store.verify(3));
+    }
+
+    #[test]
+    fn run_counter() {
+        let mut store = store();
+        upload(&mut store, 1, &["G0 X1"]);
+        upload(&mut store, 2, &["G0 X2"]);
+
+        // журнал переполняется и переписывается
+        for _ in 0..300 {
+            store.count_run(1).unwrap();
+        }
+        store.count_run(2).unwrap();
+        assert_eq!(store.runs(1), 300);
+
+        let mut store = JobStore::new(store.storage);
+        assert_eq!(store.runs(1), 300);
+        assert_eq!(store.runs(2), 1);
+
+        let mut out = LongString::new();
+        store.print(&mut out);
+        assert!(out.contains("[JOB:1|6|"));
+        assert!(out.contains("|300]\r\n[JOB:2|6|"));
+        assert!(out.ends_with("|1]\r\n"));
+
+        // новая загрузка и удаление сбрасывают счетчик
+        upload(&mut store, 1, &["G0 X1"]);
+        assert_eq!(store.runs(1), 0);
+        store.delete(2).unwrap();
+        let store = JobStore::new(store.storage);
+        assert_eq!(store.runs(1), 0);
+        assert_eq!(store.runs(2), 0);
+    }
+}
@@ -0,0 +1,26 @@
+use core::fmt::Write;
+
+use super::motion_mgr::LongString;
+
+/// Номера битов шины входов станка: дверь, аварийный стоп, кнопка старт, ключ.
+/// Полярность ($261) и дребезг ($260) - `DigitalInputs`
+pub const DOOR: u8 = 1 << 0;
+pub const ESTOP: u8 = 1 << 1;
+pub const START: u8 = 1 << 2;
+pub const KEY: u8 = 1 << 3;
+
+/// Буквы поля `Pn:` в порядке битов
+const PIN_LETTERS: [(u8, char); 4] = [(DOOR, 'D'), (ESTOP, 'R'), (START, 'S'), (KEY, 'K')];
+
+/// `|Pn:<буквы>`, ничего, если активных входов нет
+pub fn print(active: u8, out: &mut LongString) {
+    if active == 0 {
+        return;
+    }
+    let _ = out.push_str("|Pn:");
+    for (mask, letter) in PIN_LETTERS {
+        if active & mask != 0 {
+            let _ = out.write_char(letter);
+        }
+    }
+}
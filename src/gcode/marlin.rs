@@ -0,0 +1,165 @@
+//! Marlin-совместимость (`$330=1`) для программ 3D-принтеров: OctoPrint, Pronterface.
+//!
+//! Строка `N<номер> <команда>*<сумма>` проверяется до разбора: сумма - XOR байт до `*`,
+//! номер - следующий за последним принятым. Ошибка - `Error:...`, `Resend: <номер>` и `ok`,
+//! хост передает строки заново начиная с этого номера. Строки без номера принимаются как есть.
+//! `M110 [N<n>]` задает номер последней строки и отвечается здесь, `M105`, `M114`, `M115` -
+//! запросы мимо очереди G-кодов.
+
+use core::fmt::Write;
+use core::ops::Range;
+
+use crate::config::HlString as String;
+
+use super::gcode::Request;
+
+/// Ответ на строку: с номерами не помещается в `HlString`
+pub type MarlinReply = heapless::String<128>;
+
+pub enum MarlinLine {
+    /// строка без номера, как есть
+    Unnumbered,
+    /// строка с номером принята: байты команды без номера и суммы
+    Command(Range<usize>),
+    /// строка обработана, ответ хосту
+    Reply(MarlinReply),
+}
+
+pub struct MarlinLink {
+    enabled: bool,
+    /// номер последней принятой строки
+    last_line: u32,
+}
+
+impl MarlinLink {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            last_line: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// $330, номера строк - с начала
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            *self = Self::new();
+            self.enabled = enabled;
+        }
+    }
+
+    /// Номер строки и контрольная сумма
+    pub fn check(&mut self, line: &str) -> MarlinLine {
+        let text = line.trim();
+        let offset = line.len() - line.trim_start().len();
+
+        let (number, command) = match text.strip_prefix(|c| c == 'N' || c == 'n') {
+            Some(rest) => {
+                let digits = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let star = match text.rfind('*') {
+                    Some(star) => star,
+                    None => return self.resend("No Checksum with line number"),
+                };
+                (Some(rest[..digits].parse::<u32>().ok()), 1 + digits..star)
+            }
+            None if text.contains('*') => return self.resend("No Line Number with checksum"),
+            None => (None, 0..text.len()),
+        };
+        let m110 = m_code(&text[command.clone()]) == Some(110);
+
+        if let Some(number) = number {
+            let number = match number {
+                Some(n) if m110 || n == self.last_line.wrapping_add(1) => n,
+                _ => return self.resend("Line Number is not Last Line Number+1"),
+            };
+            let star = command.end;
+            let sum = text.as_bytes()[..star].iter().fold(0, |sum, b| sum ^ b);
+            if text[star + 1..].trim().parse::<u8>() != Ok(sum) {
+                return self.resend("checksum mismatch");
+            }
+            self.last_line = number;
+        }
+
+        if m110 {
+            // M110 N<n> - номер последней строки, без N - номер этой строки
+            if let Some(n) = param_n(&text[command.clone()].trim_start()[1..]) {
+                self.last_line = n;
+            }
+            return MarlinLine::Reply("ok\n".into());
+        }
+
+        match number {
+            Some(_) => {
+                let cmd = &text[command.clone()];
+                let start = offset + command.start + cmd.len() - cmd.trim_start().len();
+                MarlinLine::Command(start..start + cmd.trim().len())
+            }
+            None => MarlinLine::Unnumbered,
+        }
+    }
+
+    /// M105, M114, M115 - ответ сразу, мимо очереди G-кодов
+    pub fn request(&self, command: &str) -> Option<Request> {
+        if !self.enabled {
+            return None;
+        }
+        match m_code(command)? {
+            code @ (105 | 114 | 115) => Some(Request::Marlin(code)),
+            _ => None,
+        }
+    }
+
+    fn resend(&self, err: &str) -> MarlinLine {
+        let mut reply = MarlinReply::new();
+        let _ = write!(
+            &mut reply,
+            "Error:{}, Last Line: {}\nResend: {}\nok\n",
+            err,
+            self.last_line,
+            self.last_line.wrapping_add(1)
+        );
+        MarlinLine::Reply(reply)
+    }
+}
+
+/// Ошибка команды: `echo:` и `ok`, хост продолжает
+pub fn marlin_error(msg: &str) -> String {
+    const OK: &str = "\nok\n";
+
+    let mut s = String::new();
+    let _ = s.push_str("echo:");
+    for c in msg.trim_end().chars() {
+        if s.len() + c.len_utf8() + OK.len() > s.capacity() {
+            break;
+        }
+        let _ = s.push(c);
+    }
+    let _ = s.push_str(OK);
+    s
+}
+
+/// Номер M-кода в начале команды
+fn m_code(command: &str) -> Option<u32> {
+    let rest = command
+        .trim_start()
+        .strip_prefix(|c| c == 'M' || c == 'm')?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..digits].parse().ok()
+}
+
+/// Значение N у M110
+fn param_n(params: &str) -> Option<u32> {
+    let rest = &params[params.find(|c| c == 'N' || c == 'n')? + 1..];
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..digits].parse().ok()
+}
@@ -0,0 +1,165 @@
+//! M152/M153: 2D код. Темные модули маркируются штриховкой (строки модулей
+//! проходятся змейкой, отрезок на каждую серию темных модулей) или точками в центрах.
+//! Свободная зона вокруг символа не маркируется
+
+use crate::config::HlString as String;
+use crate::support::matrix_code::Matrix;
+
+use super::figure::{Stroke, StrokeKind};
+use super::transform::Transform;
+use super::GCode;
+
+/// Левый нижний угол, сторона символа, поворот и заполнение модулей
+#[derive(Clone, Copy)]
+pub struct MatrixLayout {
+    place: Transform,
+    /// сторона символа без свободной зоны, мм
+    side: f32,
+    dots: bool,
+}
+
+impl MatrixLayout {
+    /// `P<сторона> [L0 - штриховка | L1 - точки] [R<угол>]`, (x, y) - левый нижний угол
+    pub fn from_gcode(gcode: &GCode, x: f32, y: f32) -> Result<Self, String> {
+        let side = match gcode.get_p() {
+            Some(s) if s > 0.0 => s,
+            Some(_) => return Err("Code size below limit".into()),
+            None => return Err("Code size (P) required".into()),
+        };
+        let dots = match gcode.get_l().map(|l| l as u32) {
+            None | Some(0) => false,
+            Some(1) => true,
+            Some(_) => return Err("Code fill (L) 0-1 required".into()),
+        };
+
+        Ok(Self {
+            place: Transform::new(x, y, gcode.get_r().unwrap_or_default()),
+            side,
+            dots,
+        })
+    }
+
+    /// Углы символа
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let s = self.side;
+        [
+            self.place.apply(0.0, 0.0),
+            self.place.apply(s, 0.0),
+            self.place.apply(s, s),
+            self.place.apply(0.0, s),
+        ]
+    }
+}
+
+/// Код, разложенный на перемещения
+#[derive(Clone)]
+pub struct MatrixMark {
+    matrix: Matrix,
+    layout: MatrixLayout,
+    /// линий штриховки на строку модулей, для точек - 1
+    lines: usize,
+
+    line: usize,
+    /// позиция в строке по ходу змейки
+    pos: usize,
+    /// выполнен подвод к серии (точке), следующее - отрезок (точка)
+    marking: bool,
+}
+
+impl MatrixMark {
+    /// hatch - шаг штриховки, мм
+    pub fn new(matrix: Matrix, layout: MatrixLayout, hatch: f32) -> Self {
+        let module = layout.side / matrix.size() as f32;
+        let lines = if layout.dots {
+            1
+        } else {
+            (libm::roundf(module / hatch) as usize).max(1)
+        };
+
+        Self {
+            matrix,
+            layout,
+            lines,
+            line: 0,
+            pos: 0,
+            marking: false,
+        }
+    }
+
+    /// Следующее перемещение без продвижения, None - код закончен
+    pub fn peek(&self) -> Option<Stroke> {
+        self.locate().map(|(_, _, _, stroke)| stroke)
+    }
+
+    /// Следующее перемещение: (линия, позиция, marking после него, перемещение)
+    fn locate(&self) -> Option<(usize, usize, bool, Stroke)> {
+        let size = self.matrix.size();
+        let (mut line, mut pos) = (self.line, self.pos);
+        loop {
+            if line >= size * self.lines {
+                return None;
+            }
+            let row = line / self.lines;
+            let forward = line % 2 == 0;
+            let dark = |pos: usize| {
+                let col = if forward { pos } else { size - 1 - pos };
+                self.matrix.get(row, col)
+            };
+            // координата края модуля по ходу змейки, в модулях
+            let edge = |pos: usize| if forward { pos } else { size - pos } as f32;
+
+            if self.marking {
+                let (x, next) = if self.layout.dots {
+                    let center = if forward { pos } else { size - 1 - pos };
+                    (center as f32 + 0.5, pos + 1)
+                } else {
+                    let end = (pos..size).find(|&p| !dark(p)).unwrap_or(size);
+                    (edge(end), end)
+                };
+                let kind = if self.layout.dots {
+                    StrokeKind::Dot
+                } else {
+                    StrokeKind::Mark
+                };
+                return Some((line, next, false, self.stroke(kind, x, line)));
+            }
+
+            match (pos..size).find(|&p| dark(p)) {
+                Some(start) => {
+                    let x = if self.layout.dots {
+                        let center = if forward { start } else { size - 1 - start };
+                        center as f32 + 0.5
+                    } else {
+                        edge(start)
+                    };
+                    return Some((line, start, true, self.stroke(StrokeKind::Jump, x, line)));
+                }
+                None => {
+                    line += 1;
+                    pos = 0;
+                }
+            }
+        }
+    }
+
+    /// x - в модулях от левого края, линия штриховки line считается сверху
+    fn stroke(&self, kind: StrokeKind, x: f32, line: usize) -> Stroke {
+        let size = self.matrix.size() as f32;
+        let module = self.layout.side / size;
+        let y = size - (line as f32 + 0.5) / self.lines as f32;
+        let (x, y) = self.layout.place.apply(x * module, y * module);
+        Stroke { kind, x, y }
+    }
+}
+
+impl Iterator for MatrixMark {
+    type Item = Stroke;
+
+    fn next(&mut self) -> Option<Stroke> {
+        let (line, pos, marking, stroke) = self.locate()?;
+        self.line = line;
+        self.pos = pos;
+        self.marking = marking;
+        Some(stroke)
+    }
+}
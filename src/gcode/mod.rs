@@ -1,10 +1,38 @@
+mod binary_link;
+mod emission_limiter;
+mod event_log;
+mod figure;
+mod font;
 mod gcode;
 mod gcode_server;
+mod hpgl;
+mod ilda;
+mod job_runner;
+mod job_store;
+mod machine_inputs;
+mod marlin;
+mod matrix_mark;
 mod motion_mgr;
+mod plc_registers;
+mod power_curve;
+mod raster;
+mod serial_counter;
+mod settings;
+mod template;
+mod text_mark;
+mod transform;
 
+pub use event_log::Event;
 pub use gcode::{GCode, Request, MAX_LEN};
+pub use ilda::IldaSource;
+pub use job_runner::JobRunner;
+pub use job_store::{JobStore, UploadQueue, UploadResult};
+pub use marlin::marlin_error;
+pub use plc_registers::{PlcCommand, PlcRegisters};
+pub use raster::{PixelBuffer, PixelData, PixelResult};
 pub use gcode_server::serial_process;
 
-pub use gcode_server::SerialErrResult;
+pub use gcode_server::{LinkState, SerialErrResult};
 
-pub use motion_mgr::{MotionMGR, MotionStatus};
+pub use motion_mgr::{Alarm, MotionMGR, MotionStatus};
+pub use settings::{InputDialect, ModbusLink, Personality};
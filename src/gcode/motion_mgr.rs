@@ -3,21 +3,144 @@ use core::str::FromStr;
 
 use crate::config;
 use crate::config::HlString as String;
+use crate::support::datetime::DateTime;
+use crate::support::matrix_code;
+use crate::support::nv_storage::NvStorage;
+use crate::support::parallel_input_bus::ParallelInputBus;
+use crate::support::parallel_io::{DigitalInputs, DigitalOutputs};
+use crate::support::parallel_output_bus::ParallelOutputBus;
+use crate::support::rtc::RealTimeClock;
 
-pub type LongString = heapless::String<512>;
+pub type LongString = heapless::String<1024>;
 
+use super::emission_limiter::EmissionLimiter;
+use super::event_log::{Event, EventLog};
+use super::figure::{Figure, StrokeKind};
+use super::ilda::{IldaPlayer, IldaSource};
+use super::job_store::JobStore;
+use super::machine_inputs;
+use super::matrix_mark::{MatrixLayout, MatrixMark};
+use super::plc_registers::PlcStatus;
+use super::raster::{PixelBuffer, RasterPhase, Scanline};
+use super::serial_counter::SerialCounter;
+use super::settings::{InputDialect, ModbusLink, Personality, Settings};
+use super::template;
+use super::text_mark::{TextLayout, TextMark};
+use super::transform::Transform;
 use super::GCode;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum MotionStatus {
     IDLE,
     INTERPOLATING,
+    /// skywriting run-out: laser is already off, next chained segment may be accepted
+    RUNOUT,
+    /// feed hold or door open: motion is frozen, laser is off
+    HOLD,
 }
 
-pub struct MotionMGR<LASER, GALVO>
+/// Latched alarm: laser is off, galvo parked, G-codes are rejected until `$X`
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Alarm {
+    /// nothing received from the host for $240 ms while the laser is on or moving
+    HostTimeout = 20,
+    /// USB suspended or disconnected
+    UsbLink = 21,
+    /// restarted by the independent watchdog
+    Watchdog = 22,
+    /// laser was emitting longer than $250 s in one command
+    EmissionTimeout = 23,
+    /// emission duty in the last $252 s is above $251 %
+    DutyCycle = 24,
+    /// crystal did not start, running on HSI
+    ClockFailure = 25,
+    /// E-stop input
+    EStop = 26,
+    /// M66 Q<s> expired
+    InputTimeout = 27,
+    /// abort command from the PLC over Modbus
+    PlcAbort = 28,
+    /// PLL did not start, running on HSI 8 MHz without USB
+    PllFailure = 29,
+}
+
+impl Alarm {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Alarm::HostTimeout => "Host timeout",
+            Alarm::UsbLink => "USB link lost",
+            Alarm::Watchdog => "Watchdog reset",
+            Alarm::EmissionTimeout => "Emission time limit",
+            Alarm::DutyCycle => "Emission duty limit",
+            Alarm::ClockFailure => "HSE failed, running on HSI",
+            Alarm::EStop => "E-stop",
+            Alarm::InputTimeout => "Input wait timeout",
+            Alarm::PlcAbort => "Aborted by PLC",
+            Alarm::PllFailure => "PLL failed, running on HSI 8 MHz",
+        }
+    }
+}
+
+/// Machine state as reported by `?` and the PLC status register
+#[derive(PartialEq, Clone, Copy)]
+pub enum MachineState {
+    Idle = 0,
+    Run = 1,
+    Hold = 2,
+    Door = 3,
+    Alarm = 4,
+}
+
+impl MachineState {
+    fn name(&self) -> &'static str {
+        match self {
+            MachineState::Idle => "Idle",
+            MachineState::Run => "Run",
+            MachineState::Hold => "Hold:0",
+            MachineState::Door => "Door:1",
+            MachineState::Alarm => "Alarm",
+        }
+    }
+}
+
+/// How laser emission is driven during the current move
+#[derive(PartialEq, Clone, Copy)]
+enum Emission {
+    /// as is, M3 enables, M5 disables
+    Continuous,
+    /// only inside the marked part of the move
+    Skywriting,
+    /// pulse bursts every dot pitch
+    Dots,
+    /// pump power of the pixel under the beam (M160)
+    Raster,
+}
+
+/// M66 L<mode>
+#[derive(Clone, Copy, PartialEq)]
+enum WaitMode {
+    Rise = 1,
+    Fall = 2,
+    High = 3,
+    Low = 4,
+}
+
+#[derive(Clone, Copy)]
+struct InputWait {
+    mask: u8,
+    mode: WaitMode,
+    last: bool,
+    deadline: Option<u64>,
+}
+
+pub struct MotionMGR<LASER, GALVO, NVS, OUT, IN, RTC>
 where
     GALVO: crate::control::xy2_100::XY2_100Interface,
     LASER: crate::control::laser::LaserInterface,
+    NVS: crate::support::nv_storage::NvStorage,
+    OUT: ParallelOutputBus<Output = u8>,
+    IN: ParallelInputBus<Input = u8>,
+    RTC: RealTimeClock,
 {
     _status: MotionStatus,
     is_move_first_interpolation: bool,
@@ -34,30 +157,136 @@ where
     current_distance_y: f32,
     current_to_x: f32,
     current_to_y: f32,
+    current_start_x: f32,
+    current_start_y: f32,
     current_cmd_x: f32,
     current_cmd_y: f32,
     current_f: f32, // mm/min
+    current_jump_f: f32, // mm/min, 0 - jump
     current_s: u8,
     current_a: f32,
     current_b: u32,
+    current_q: u32, // pulse width, ns, 0 - not selected
     current_duration: f32,
+    current_length: f32,
     current_absolute: bool,
     current_laserenabled: bool,
     current_red_laserenabled: bool,
     laser_changed: bool,
 
+    // G2/G3
+    current_center_x: f32,
+    current_center_y: f32,
+    current_radius: f32,
+    current_start_angle: f32,
+    current_sweep: f32,
+
+    current_emission: Emission,
+
+    // G4 / M120 / pen delays
+    current_dwell: bool,
+    dwell_nanos: u64,
+    dwell_emit: bool,
+    test_fire: bool,
+    /// M3 / M120: waiting for the laser power-up sequence
+    laser_arming: bool,
+
+    // pen
+    current_pen: u32,
+    current_passes: u32,
+    passes_left: u32,
+    current_jump_delay: u64, // ns
+    current_mark_delay: u64, // ns
+
+    // skywriting: emission window as fraction of the current move
+    current_emit_from: f32,
+    current_emit_to: f32,
+    current_emitting: bool,
+    current_run_out: bool,
+    // direction of the last marked segment
+    current_dir_x: f32,
+    current_dir_y: f32,
+    // chained mark starts where the run-out stopped
+    skywriting_chained: Option<(f32, f32)>,
+
+    // dot marking
+    dot_mode: bool,
+    dot_next: f32,        // mm along the current move
+    dot_since_last: f32,  // mm along the path since the last dot
+    dot_hold_start: Option<u64>,
+    dot_hold: u64, // ns
+
+    settings: Settings,
+
+    alarm: Option<Alarm>,
+    alarm_reported: bool,
+    last_host_nanos: u64,
+    emission_limiter: EmissionLimiter,
+
+    // feed hold / door
+    inputs: DigitalInputs<IN>,
+    feed_hold: bool,
+    held: bool,
+    resuming: bool,
+    time_offset: u64, // ns the motion was held
+    last_real_nanos: u64,
+
+    // M62-M66
+    outputs: DigitalOutputs<OUT>,
+    sync_outputs_on: u8,
+    sync_outputs_off: u8,
+    input_wait: Option<InputWait>,
+
+    // step-and-repeat copy placement, applied at the galvo output
+    transform: Transform,
+
+    // M150 text, M152/M153 codes: strokes are fed one by one as G0/G1
+    figure: Option<Figure>,
+    figure_code: u32, // motion mode before the figure
+    serial: SerialCounter,
+    // {PLC} template field, written by the PLC over Modbus
+    plc_text: heapless::String<{ config::PLC_TEXT_MAX_LEN }>,
+
+    // M160 raster line and its pixels, raster_mode keeps the beam closed between lines
+    raster: Option<Scanline>,
+    raster_pixels: [u8; config::RASTER_LINE_MAX],
+    raster_mode: bool,
+    raster_code: u32, // motion mode before the line
+
+    // M170 ILDA playback: drives the galvo and the beam point by point until M171
+    ilda: IldaPlayer,
+
+    // $T clock for templates and the event log
+    rtc: RTC,
+    events: EventLog,
+
     avlb: usize,
 
     laser: LASER,
     galvo: GALVO,
+    storage: NVS,
 }
 
-impl<LASER, GALVO> MotionMGR<LASER, GALVO>
+impl<LASER, GALVO, NVS, OUT, IN, RTC> MotionMGR<LASER, GALVO, NVS, OUT, IN, RTC>
 where
     GALVO: crate::control::xy2_100::XY2_100Interface,
     LASER: crate::control::laser::LaserInterface,
+    NVS: crate::support::nv_storage::NvStorage,
+    OUT: ParallelOutputBus<Output = u8>,
+    IN: ParallelInputBus<Input = u8>,
+    RTC: RealTimeClock,
 {
-    pub fn new(galvo: GALVO, laser: LASER, buf_sz: usize) -> Self {
+    pub fn new(
+        galvo: GALVO,
+        laser: LASER,
+        storage: NVS,
+        outputs: OUT,
+        inputs: IN,
+        rtc: RTC,
+        buf_sz: usize,
+    ) -> Self {
+        let limits = laser.limits();
+        let serial = SerialCounter::load(&storage, config::SERIAL_COUNTER_OFFSET);
         Self {
             _status: MotionStatus::IDLE,
             is_move_first_interpolation: true,
@@ -71,37 +300,153 @@ where
             current_distance_y: 0.0,
             current_to_x: 0.0,
             current_to_y: 0.0,
+            current_start_x: 0.0,
+            current_start_y: 0.0,
             current_cmd_x: 0.0,
             current_cmd_y: 0.0,
             current_f: 100.0,
+            current_jump_f: 0.0,
             current_s: 0,
             current_a: 100.0,
-            current_b: crate::config::LASER_SYNC_CLOCK_KHZ * 1000,
+            current_b: limits.freq_default,
+            current_q: limits.pulse_widths.first().copied().unwrap_or(0),
             current_duration: 0.0,
+            current_length: 0.0,
             current_absolute: true,
             current_laserenabled: false,
             current_red_laserenabled: false,
             laser_changed: false,
 
+            current_center_x: 0.0,
+            current_center_y: 0.0,
+            current_radius: 0.0,
+            current_start_angle: 0.0,
+            current_sweep: 0.0,
+
+            current_emission: Emission::Continuous,
+
+            current_dwell: false,
+            dwell_nanos: 0,
+            dwell_emit: false,
+            test_fire: false,
+            laser_arming: false,
+
+            current_pen: 0,
+            current_passes: 1,
+            passes_left: 1,
+            current_jump_delay: 0,
+            current_mark_delay: 0,
+
+            current_emit_from: 0.0,
+            current_emit_to: 1.0,
+            current_emitting: true,
+            current_run_out: false,
+            current_dir_x: 0.0,
+            current_dir_y: 0.0,
+            skywriting_chained: None,
+
+            dot_mode: false,
+            dot_next: 0.0,
+            dot_since_last: f32::INFINITY,
+            dot_hold_start: None,
+            dot_hold: 0,
+
+            settings: Settings::default(),
+
+            alarm: None,
+            alarm_reported: false,
+            last_host_nanos: 0,
+            emission_limiter: EmissionLimiter::new(),
+
+            inputs: DigitalInputs::new(inputs),
+            feed_hold: false,
+            held: false,
+            resuming: false,
+            time_offset: 0,
+            last_real_nanos: 0,
+
+            outputs: DigitalOutputs::new(outputs),
+            sync_outputs_on: 0,
+            sync_outputs_off: 0,
+            input_wait: None,
+
+            transform: Transform::IDENTITY,
+
+            figure: None,
+            figure_code: 0,
+            serial,
+            plc_text: heapless::String::new(),
+
+            raster: None,
+            raster_pixels: [0; config::RASTER_LINE_MAX],
+            raster_mode: false,
+            raster_code: 0,
+
+            ilda: IldaPlayer::new(),
+
+            rtc,
+            events: EventLog::new(),
+
             avlb: buf_sz,
 
             laser,
             galvo,
+            storage,
         }
     }
 
     pub fn begin(&mut self) {
+        if let Some(settings) = Settings::load(&self.storage) {
+            self.settings = settings;
+        }
+        let _ = self.select_pen(0);
+        self.apply_sequence_delays();
+
         self.set_galvo_position(0.0, 0.0);
+        self.log_event(Event::Boot);
     }
 
     pub fn is_busy(&self) -> bool {
         self._status != MotionStatus::IDLE
+            || self.figure.is_some()
+            || self.raster.is_some()
+            || self.ilda.source().is_some()
     }
 
     pub fn process(&mut self, gcode: &mut GCode, avlb: usize) -> Result<Option<String>, String> {
         self.avlb = avlb;
-        if self._status == MotionStatus::IDLE {
+        if let Some(alarm) = self.alarm {
+            let mut s = String::new();
+            write!(&mut s, "Alarm lock ({}), unlock with $X", alarm as u32).unwrap();
+            return Err(s);
+        }
+        self.emission_limiter.new_command();
+        if self._status == MotionStatus::RUNOUT && self.chains_with(gcode) {
+            // next mark continues the previous one - drop run-out and run-in
+            self.chain_move();
+        }
+        if self.ilda.source().is_some() {
+            use super::gcode::Code;
+
+            // the galvo and the beam belong to the playback
+            if !matches!(gcode.code(), Code::M(5 | 171)) {
+                return Err("ILDA playing, stop with M171".into());
+            }
+        }
+        if self._status == MotionStatus::IDLE && self.figure.is_none() {
             use super::gcode::Code;
+
+            if let Some(pen) = gcode.get_t() {
+                // T<n> or T<n> M6
+                self.select_pen(pen as u32)?;
+                if gcode.code() == Code::Empty
+                    && gcode.get_x().is_none()
+                    && gcode.get_y().is_none()
+                {
+                    return Ok(None);
+                }
+            }
+
             match gcode.code() {
                 Code::G(_) => {
                     self.process_gcodes(gcode)?;
@@ -118,20 +463,105 @@ where
         }
     }
 
+    /// Can `gcode` be started during run-out of the current skywriting segment:
+    /// it is a G1 mark whose direction differs from the previous one less than
+    /// the skywriting corner threshold
+    pub fn chains_with(&self, gcode: &GCode) -> bool {
+        use super::gcode::Code;
+
+        if self._status != MotionStatus::RUNOUT
+            || !self.current_laserenabled
+            || self.figure.is_some()
+        {
+            return false;
+        }
+
+        match gcode.code() {
+            Code::G(1) => {}
+            Code::Empty if self.current_code == 1 => {}
+            _ => return false,
+        }
+
+        let (to_x, to_y) = if self.current_absolute {
+            (
+                gcode.get_x().unwrap_or(self.current_to_x),
+                gcode.get_y().unwrap_or(self.current_to_y),
+            )
+        } else {
+            (
+                self.current_to_x + gcode.get_x().unwrap_or_default(),
+                self.current_to_y + gcode.get_y().unwrap_or_default(),
+            )
+        };
+        self.chains_to(to_x, to_y)
+    }
+
+    /// Mark to (to_x, to_y) turns from the previous one less than the corner threshold
+    fn chains_to(&self, to_x: f32, to_y: f32) -> bool {
+        let dx = to_x - self.current_to_x;
+        let dy = to_y - self.current_to_y;
+        let len = libm::sqrtf(dx * dx + dy * dy);
+        if len == 0.0 {
+            return false;
+        }
+
+        let cos_angle = (dx * self.current_dir_x + dy * self.current_dir_y) / len;
+        cos_angle >= crate::support::sin_cos(self.settings.skywriting_corner.to_radians()).1
+    }
+
     pub fn tic(&mut self, now_nanos: u64) -> MotionStatus {
-        self._now = now_nanos;
-        if self._status == MotionStatus::INTERPOLATING {
+        // motion time stands still while held
+        let dt = now_nanos.wrapping_sub(self.last_real_nanos);
+        self.last_real_nanos = now_nanos;
+        let held = self.hold();
+        if held {
+            self.time_offset = self.time_offset.wrapping_add(dt);
+        }
+        self._now = now_nanos.wrapping_sub(self.time_offset);
+        self.laser.tic(now_nanos);
+
+        // the host waits for "ok" while a command executes, silence counts from its end
+        if self._status != MotionStatus::IDLE || self.figure.is_some() || self.raster.is_some() {
+            self.last_host_nanos = self._now;
+        }
+        let timeout = (self.settings.host_timeout_ms * 1_000_000.0) as u64;
+        if timeout > 0
+            && (self.current_laserenabled || self.is_busy())
+            && self._now.wrapping_sub(self.last_host_nanos) > timeout
+        {
+            self.raise_alarm(Alarm::HostTimeout);
+        }
+        self.check_emission_limits(now_nanos);
+
+        if held && self.alarm.is_none() {
+            return MotionStatus::HOLD;
+        }
+
+        if self._status != MotionStatus::IDLE {
             if self.interpolate_move() {
                 self.set_galvo_position(self.current_cmd_x, self.current_cmd_y);
             }
         }
+        if self.figure.is_some() {
+            self.feed_figure();
+        }
+        if self.raster.is_some() {
+            self.feed_raster();
+        }
+        if self.ilda.source().is_some() {
+            self.feed_ilda();
+        }
 
         if self.laser_changed {
             if self.current_laserenabled {
-                self.laser.set_pump_power(self.current_s);
-                self.laser.set_frequency(self.current_b as u32);
-                self.laser.set_power_pwm(self.current_a);
-                self.laser.enable();
+                self.enable_laser();
+                self.current_emitting = true;
+                if self._status == MotionStatus::IDLE
+                    || (self.laser_arming && !self.current_dwell)
+                {
+                    // with skywriting or dots emission is opened by the next mark
+                    self.set_emission(self.idle_emission());
+                }
             } else {
                 self.laser.disable()
             }
@@ -155,6 +585,16 @@ where
             gcode.set_code(Code::G(if self.current_code == 0 { 0 } else { 1 }));
         }
 
+        if let Code::G(0 | 1 | 2 | 3 | 28) = gcode.code() {
+            // vector moves after a raster use the usual emission
+            self.raster_mode = false;
+        }
+        if let Code::G(1 | 2 | 3 | 4) = gcode.code() {
+            if self.current_laserenabled && !self.laser_changed && !self.laser_ready() {
+                return Err("Laser not ready".into());
+            }
+        }
+
         match gcode.code() {
             Code::G(0) => {
                 self.current_code = 0;
@@ -162,29 +602,33 @@ where
             }
             Code::G(1) => {
                 self.current_code = 1;
-
-                if let Some(new_s) = gcode.get_s() {
-                    if let Err(_) = Self::set_value(
-                        &mut self.current_s,
-                        new_s as u8,
-                        'S',
-                        config::MOTION_MAX_S as u8,
-                        0,
-                    ) {
-                        if new_s > config::MOTION_MAX_S {
-                            self.current_s = config::MOTION_MAX_S as u8;
-                        } else {
-                            self.current_s = 0;
-                        }
-                    }
-                }
-                if let Some(new_f) = gcode.get_f() {
-                    Self::set_value(&mut self.current_f, new_f, 'F', i32::MAX as f32, 0.01f32)?;
+                self.set_sf(&gcode)?;
+                self.set_xyab(&gcode)?;
+            }
+            Code::G(code @ (2 | 3)) => {
+                // arc, I J - center offset from the start point
+                if gcode.get_i().is_none() && gcode.get_j().is_none() {
+                    return Err("Arc center (I, J) required".into());
                 }
 
+                self.current_code = code;
+                self.set_sf(&gcode)?;
+                self.current_center_x = self.current_from_x + gcode.get_i().unwrap_or_default();
+                self.current_center_y = self.current_from_y + gcode.get_j().unwrap_or_default();
                 self.set_xyab(&gcode)?;
             }
 
+            Code::G(4) => {
+                // dwell, P or S - seconds, motion mode is not changed
+                let seconds = match gcode.get_p().or(gcode.get_s()) {
+                    Some(v) if v >= 0.0 => v,
+                    Some(_) => return Err("Dwell time below limit".into()),
+                    None => return Err("Dwell time (P, S) required".into()),
+                };
+                self.dwell_nanos = (seconds * 1_000_000_000.0) as u64;
+                self.dwell_emit = true;
+                self.current_dwell = true;
+            }
             Code::G(28) => {
                 self.current_code = 28;
                 self.current_to_x = 0.0;
@@ -216,8 +660,37 @@ where
             _ => return Ok(()),
         }
 
+        self.start_motion();
+        Ok(())
+    }
+
+    /// Move from current_from_* to current_to_* (or dwell) by current_code
+    fn start_motion(&mut self) {
+        if !self.current_dwell {
+            // M62/M63 take effect with the next motion
+            self.outputs
+                .update(self.sync_outputs_on, self.sync_outputs_off);
+            self.sync_outputs_on = 0;
+            self.sync_outputs_off = 0;
+        }
+
+        self.current_start_x = self.current_from_x;
+        self.current_start_y = self.current_from_y;
+        self.passes_left = self.current_passes;
         self._status = MotionStatus::INTERPOLATING;
+    }
 
+    fn set_sf(&mut self, gcode: &GCode) -> Result<(), String> {
+        if let Some(new_s) = gcode.get_s() {
+            self.current_s = if gcode.is_s_code() {
+                (new_s as u8).min(self.laser.limits().power_max)
+            } else {
+                self.s_to_code(new_s)?
+            };
+        }
+        if let Some(new_f) = gcode.get_f() {
+            Self::set_value(&mut self.current_f, new_f, 'F', i32::MAX as f32, 0.01f32)?;
+        }
         Ok(())
     }
 
@@ -277,13 +750,11 @@ where
         }
 
         if let Some(new_b) = gcode.get_b() {
-            if let Err(_) = Self::set_value(&mut self.current_b, new_b as u32, 'B', 80000, 20000) {
-                if new_b > 80000.0 {
-                    self.current_b = 80000; // перебор
-                } else {
-                    self.current_b = 20000; // недобор
-                }
-            }
+            self.current_b = self.clamp_frequency(new_b);
+        }
+
+        if let Some(new_q) = gcode.get_q() {
+            self.current_q = self.check_pulse_width(new_q)?;
         }
 
         Ok(())
@@ -338,23 +809,12 @@ where
             }
             3 | 4 => {
                 if let Some(new_s) = gcode.get_s() {
-                    if let Err(_) = Self::set_value(
-                        &mut self.current_s,
-                        new_s as u8,
-                        'S',
-                        config::MOTION_MAX_S as u8,
-                        0,
-                    ) {
-                        if new_s > config::MOTION_MAX_S {
-                            self.current_s = config::MOTION_MAX_S as u8;
-                        } else {
-                            self.current_s = 0;
-                        }
-                    }
+                    self.current_s = self.s_to_code(new_s)?;
                 }
 
                 if code == 3 {
                     self.current_laserenabled = true;
+                    self.wait_laser_ready();
                 } else {
                     self.current_red_laserenabled = true;
                 }
@@ -367,149 +827,1567 @@ where
                 self.laser_changed = true;
             }
 
-            _ => {}
-        }
-        Ok(None)
-    }
+            62..=65 => {
+                // digital output P<n>: M62/M63 - on/off with the next motion, M64/M65 - now
+                let mask = self.output_mask(gcode)?;
+                match code {
+                    62 => {
+                        self.sync_outputs_on |= mask;
+                        self.sync_outputs_off &= !mask;
+                    }
+                    63 => {
+                        self.sync_outputs_off |= mask;
+                        self.sync_outputs_on &= !mask;
+                    }
+                    64 => self.outputs.update(mask, 0),
+                    _ => self.outputs.update(0, mask),
+                }
+            }
 
-    fn process_other(&mut self, gcode: &mut GCode) -> Result<(), String> {
-        match self.current_code {
-            0 | 1 => self.process_gcodes(gcode),
-            c => {
-                let mut s = String::new();
-                write!(&mut s, "Cannot continue move for G{}", c).unwrap();
-                Err(s)
+            66 => {
+                // wait for input P<n>: L1 - rise, L2 - fall, L3 - high, L4 - low, Q - timeout, s
+                let n = match gcode.get_p() {
+                    Some(n) if n >= 0.0 && (n as u8) < self.inputs.count() => n as u8,
+                    Some(_) => return Err("No such input".into()),
+                    None => return Err("Input number (P) required".into()),
+                };
+                let mode = match gcode.get_l().map(|l| l as u32) {
+                    Some(1) => WaitMode::Rise,
+                    Some(2) => WaitMode::Fall,
+                    Some(3) => WaitMode::High,
+                    Some(4) => WaitMode::Low,
+                    _ => return Err("Wait mode (L) 1-4 required".into()),
+                };
+                let deadline = match gcode.get_q() {
+                    Some(q) if q > 0.0 => {
+                        Some(self._now.wrapping_add((q * 1_000_000_000.0) as u64))
+                    }
+                    _ => None,
+                };
+
+                let mask = 1 << n;
+                self.input_wait = Some(InputWait {
+                    mask,
+                    mode,
+                    last: self.inputs.is_active(mask),
+                    deadline,
+                });
+                self._status = MotionStatus::INTERPOLATING;
             }
-        }
-    }
 
-    pub fn process_status_req(
-        &mut self,
-        req: &super::Request,
-    ) -> Result<Option<LongString>, String> {
-        use super::Request;
-        use crate::support::format_float_simple;
-        let ok = Some(LongString::from_str("ok\r\n").unwrap());
+            120 => {
+                // test fire at the current position: S A B - laser parameters, P - time, ms
+                let ms = match gcode.get_p() {
+                    Some(v) => v,
+                    None => return Err("Fire time (P) required".into()),
+                };
+                Self::set_value(
+                    &mut self.dwell_nanos,
+                    (ms * 1_000_000.0) as u64,
+                    'P',
+                    (config::LASER_FIRE_MAX_MS * 1_000_000.0) as u64,
+                    0,
+                )?;
 
-        match req {
-            Request::Dollar('G') => {
-                let mut s = LongString::new();
-                write!(
-                    &mut s,
-                    "[GC:G{g1} G54 G17 G21 G9{g9} G94 M5 M9 T0 F{f} S{s}]\r\nok\r\n",
-                    g1 = self.current_code,
-                    g9 = (!self.current_absolute as u32),
-                    s = self.current_s,
-                    f = format_float_simple(self.current_f, 3),
-                )
-                .unwrap();
-                Ok(Some(s))
+                let s = match gcode.get_s() {
+                    Some(s) => self.s_to_code(s)?,
+                    None => self.current_s,
+                };
+                let a = gcode
+                    .get_a()
+                    .map(|a| a.max(0.0).min(100.0))
+                    .unwrap_or(self.current_a);
+                let b = gcode
+                    .get_b()
+                    .map(|b| self.clamp_frequency(b))
+                    .unwrap_or(self.current_b);
+                let q = match gcode.get_q() {
+                    Some(q) => self.check_pulse_width(q)?,
+                    None => self.current_q,
+                };
+
+                self.laser.disable();
+                self.laser.set_pump_power(s);
+                self.laser.set_frequency(b);
+                self.laser.set_pulse_width(q);
+                self.laser.set_power_pwm(a);
+                self.laser.enable();
+                self.current_emitting = true;
+                self.wait_laser_ready();
+
+                self.test_fire = true;
+                self.dwell_emit = true;
+                self.current_dwell = true;
+                self._status = MotionStatus::INTERPOLATING;
             }
-            Request::Dollar('#') => {
-                let mut s = LongString::new();
-                /*"[G54:0.000,0.000,0.000]\r
-                [G55:0.000,0.000,0.000]\r
-                [G56:0.000,0.000,0.000]\r
-                [G57:0.000,0.000,0.000]\r
-                [G58:0.000,0.000,0.000]\r
-                [G59:0.000,0.000,0.000]\r
-                [G28:0.000,0.000,0.000]\r
-                [G30:0.000,0.000,0.000]\r
-                [G92:0.000,0.000,0.000]\r
-                [TLO:0.000]\r
-                [PRB:0.000,0.000,0.000:0]\r
-                ok\r\n"*/
-                write!(
-                    &mut s,
-                    "[G54:0.000,0.000,0.000]\r
-[G55:0.000,0.000,0.000]\r
-[G56:0.000,0.000,0.000]\r
-[TLO:0.000]\r
-[PRB:0.000,0.000,0.000:0]\r
-ok\r\n"
-                )
-                .unwrap();
-                Ok(Some(s))
+
+            121 => {
+                // power calibration: S - pump code, P - measured power; no S - clear curve
+                match (gcode.get_s(), gcode.get_p()) {
+                    (None, _) => self.settings.power_curve.clear(),
+                    (Some(code), Some(power)) if power >= 0.0 => {
+                        let mut c = 0u8;
+                        Self::set_value(&mut c, code as u8, 'S', u8::MAX, 0)?;
+                        self.settings.power_curve.insert(c, power)?;
+                    }
+                    _ => return Err("Measured power (P) required".into()),
+                }
+                self.settings.save(&mut self.storage)?;
             }
-            Request::Dollar('X') => {
-                // unlock
-                Ok(ok)
+
+            110 => {
+                // dot marking on, S - dot pitch, um
+                if let Some(pitch) = gcode.get_s() {
+                    self.settings.set(210, pitch)?;
+                }
+                self.dot_mode = true;
+                self.dot_since_last = f32::INFINITY;
+                self.set_emission(self.idle_emission());
             }
-            Request::Status => {
-                let mut s = LongString::new();
-                write!(
-                    &mut s,
-                    "<{state}|MPos:{x:.3},{y:.3},0.000|Bf:{bf},150|FS:{f},{s}>\r\n",
-                    state = if self.is_busy() { "Run" } else { "Idle" },
-                    x = format_float_simple(self.current_cmd_x, 3),
-                    y = format_float_simple(self.current_cmd_y, 3),
-                    bf = self.avlb,
-                    s = self.current_s,
-                    f = format_float_simple(self.current_f, 3),
-                )
-                .unwrap();
-                Ok(Some(s))
+
+            111 => {
+                // dot marking off
+                self.dot_mode = false;
+                self.set_emission(self.idle_emission());
             }
-            Request::Dollar(dl) => {
-                let mut s = String::new();
-                write!(&mut s, "Unsupported command ${}\r\n", dl).unwrap();
-                Err(s)
+
+            150 => {
+                // text "<template>": X Y - baseline start, P - height, L - spacing, R - angle
+                self.start_text(gcode)?;
             }
-        }
-    }
 
-    fn interpolate_move(&mut self) -> bool {
-        if self.is_move_first_interpolation {
-            if [0, 28].contains(&self.current_code) {
-                // don't interpolate
-                self.current_from_x = self.current_to_x;
-                self.current_from_y = self.current_to_y;
-                self.current_cmd_x = self.current_to_x;
-                self.current_cmd_y = self.current_to_y;
-                self._status = MotionStatus::IDLE;
-                self.is_move_first_interpolation = true;
-                return true;
+            151 => {
+                // serial number for {SN}: P - set, without P - report
+                match gcode.get_p() {
+                    Some(n) if n >= 0.0 => self.serial.set(&mut self.storage, n as u32)?,
+                    Some(_) => return Err("Serial number below limit".into()),
+                    None => {
+                        let mut s = String::new();
+                        write!(&mut s, "[SN:{}]\r\nok\r\n", self.serial.value()).unwrap();
+                        return Ok(Some(s));
+                    }
+                }
             }
-            if self.current_code == 1 {
-                // G1
-                self.current_distance_x = self.current_to_x - self.current_from_x;
-                self.current_distance_y = self.current_to_y - self.current_from_y;
 
-                self.current_duration = calculate_move_length_nanos(
-                    self.current_distance_x,
-                    self.current_distance_y,
-                    self.current_f,
-                );
+            152 | 153 => {
+                // DataMatrix / QR "<template>": X Y - lower left corner, P - size,
+                // L0 - hatch, L1 - dots, R - angle
+                self.start_code(code, gcode)?;
+            }
 
-                self.current_startnanos = self._now;
-                self.current_endnanos = self
-                    ._now
-                    .wrapping_add(libm::roundf(self.current_duration) as u64);
-                self.is_move_first_interpolation = false;
+            160 => {
+                // raster line: X Y - start of pixel 0, P - pixel pitch, L - pixels, R - angle,
+                // S - power of pixel 255, F - speed
+                self.start_raster(gcode)?;
+            }
+
+            170 => {
+                // ILDA playback: P - stored job with the file, without P - USB data lines
+                self.start_ilda(gcode)?;
+            }
+
+            171 => self.stop_ilda(),
+
+            400 => {
+                // the queue hands out the next G-code only after the motion stopped,
+                // so the reply itself tells the host the marking is finished
             }
+
+            _ => {}
         }
+        Ok(None)
+    }
 
-        //Actual interpolation
-        if self._now >= self.current_endnanos {
-            //done interpolating
-            self.current_from_x = self.current_to_x;
-            self.current_from_y = self.current_to_y;
-            self.current_cmd_x = self.current_to_x;
-            self.current_cmd_y = self.current_to_y;
-            self._status = MotionStatus::IDLE;
-            self.is_move_first_interpolation = true;
-            return self._now == self.current_endnanos;
-        } else {
-            let fraction_of_move =
-                self._now.wrapping_sub(self.current_startnanos) as f32 / self.current_duration;
-            self.current_cmd_x = self.current_from_x + (self.current_distance_x * fraction_of_move);
-            self.current_cmd_y = self.current_from_y + (self.current_distance_y * fraction_of_move);
-            return true;
+    /// M150: template fields are expanded, the text box is checked against the field,
+    /// then the strokes are fed by `feed_figure()`
+    fn start_text(&mut self, gcode: &GCode) -> Result<(), String> {
+        let (x, y) = self.figure_origin(gcode);
+        let layout = TextLayout::from_gcode(gcode, x, y)?;
+        let (text, serial_used) = self.expand_template(gcode)?;
+        if text.is_empty() {
+            return Ok(());
         }
+
+        let corners = layout.corners(text.len());
+        let figure = Figure::Text(TextMark::new(text, layout));
+        self.start_figure(gcode, figure, &corners, serial_used, "Text outside field")
     }
 
-    fn set_galvo_position(&mut self, x: f32, y: f32) {
-        use crate::support::map;
+    /// M152/M153: the expanded template is encoded into a module matrix
+    fn start_code(&mut self, code: u32, gcode: &GCode) -> Result<(), String> {
+        let (x, y) = self.figure_origin(gcode);
+        let layout = MatrixLayout::from_gcode(gcode, x, y)?;
+        let (text, serial_used) = self.expand_template(gcode)?;
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let matrix = if code == 152 {
+            matrix_code::datamatrix::encode(text.as_bytes())
+        } else {
+            matrix_code::qr::encode(text.as_bytes())
+        };
+        let matrix = match matrix {
+            Some(m) => m,
+            None => return Err("Code data too long".into()),
+        };
+
+        let hatch = self.settings.code_hatch_um / 1000.0;
+        let corners = layout.corners();
+        let figure = Figure::Matrix(MatrixMark::new(matrix, layout, hatch));
+        self.start_figure(gcode, figure, &corners, serial_used, "Code outside field")
+    }
+
+    /// X Y of M150/M152/M153/M160, current position if omitted
+    fn figure_origin(&self, gcode: &GCode) -> (f32, f32) {
+        if self.current_absolute {
+            (
+                gcode.get_x().unwrap_or(self.current_from_x),
+                gcode.get_y().unwrap_or(self.current_from_y),
+            )
+        } else {
+            (
+                self.current_from_x + gcode.get_x().unwrap_or_default(),
+                self.current_from_y + gcode.get_y().unwrap_or_default(),
+            )
+        }
+    }
+
+    /// Quoted template with {SN}, date and shift fields -> (text, {SN} used)
+    fn expand_template(&self, gcode: &GCode) -> Result<(String, bool), String> {
+        let template = match gcode.get_text() {
+            Some(t) => t,
+            None => return Err(r#"Text ("...") required"#.into()),
+        };
+
+        let fields = template::Fields {
+            serial: self.serial.value(),
+            plc: &self.plc_text,
+            time: self.time(),
+            shifts: self.settings.shift_start_h,
+        };
+        let mut text = String::new();
+        let serial_used = template::expand(template, &fields, &mut text)?;
+        Ok((text, serial_used))
+    }
+
+    /// Figure box must be inside the field, then the serial number is used up
+    /// and the strokes are fed by `feed_figure()`
+    fn start_figure(
+        &mut self,
+        gcode: &GCode,
+        figure: Figure,
+        corners: &[(f32, f32)],
+        serial_used: bool,
+        outside: &str,
+    ) -> Result<(), String> {
+        let inside = corners.iter().all(|(x, y)| {
+            x.abs() <= config::MOTION_X_RANGE / 2.0 && y.abs() <= config::MOTION_Y_RANGE / 2.0
+        });
+        if !inside {
+            return Err(outside.into());
+        }
+        if self.current_laserenabled && !self.laser_changed && !self.laser_ready() {
+            return Err("Laser not ready".into());
+        }
+        self.set_sf(gcode)?;
+
+        // the number is used up even if the marking is interrupted
+        if serial_used {
+            self.serial.advance(&mut self.storage)?;
+        }
+
+        self.figure_code = self.current_code;
+        self.figure = Some(figure);
+        self.feed_figure();
+        Ok(())
+    }
+
+    /// M150/M152/M153: next stroke when the previous one is done, a mark may
+    /// continue the skywriting run-out like a chained G1
+    fn feed_figure(&mut self) {
+        let stroke = match self.figure.as_ref().map(|f| f.peek()) {
+            Some(Some(stroke)) => stroke,
+            Some(None) if self._status == MotionStatus::IDLE => {
+                self.figure = None;
+                self.current_code = self.figure_code;
+                return;
+            }
+            _ => return,
+        };
+
+        match self._status {
+            MotionStatus::IDLE => {}
+            MotionStatus::RUNOUT
+                if stroke.kind == StrokeKind::Mark && self.chains_to(stroke.x, stroke.y) =>
+            {
+                self.chain_move();
+            }
+            _ => return,
+        }
+
+        if let Some(figure) = self.figure.as_mut() {
+            figure.advance();
+        }
+        match stroke.kind {
+            StrokeKind::Jump | StrokeKind::Mark => {
+                self.current_code = (stroke.kind == StrokeKind::Mark) as u32;
+                self.current_to_x = stroke.x;
+                self.current_to_y = stroke.y;
+            }
+            StrokeKind::Dot => {
+                // same hold as a dot of M110 marking
+                self.dwell_nanos = self.dot_hold_nanos();
+                self.dwell_emit = true;
+                self.current_dwell = true;
+            }
+        }
+        self.start_motion();
+    }
+
+    /// M160: the line waits for its pixels in `load_raster()`, the beam is closed
+    fn start_raster(&mut self, gcode: &GCode) -> Result<(), String> {
+        let (x, y) = self.figure_origin(gcode);
+        let line = Scanline::from_gcode(gcode, x, y, self.settings.raster_overscan)?;
+        let inside = [line.start(), line.end()].iter().all(|(x, y)| {
+            x.abs() <= config::MOTION_X_RANGE / 2.0 && y.abs() <= config::MOTION_Y_RANGE / 2.0
+        });
+        if !inside {
+            return Err("Raster outside field".into());
+        }
+        if self.current_laserenabled && !self.laser_changed && !self.laser_ready() {
+            return Err("Laser not ready".into());
+        }
+
+        // the beam follows the pixels once per loop pass
+        let feed = gcode.get_f().unwrap_or(self.current_f);
+        let pixel_nanos = line.pitch() * 60.0 / feed * 1_000_000_000.0;
+        if pixel_nanos < 1_000_000_000.0 / config::SYSTICK_RATE_HZ as f32 {
+            return Err("Pixel time below tick, lower F".into());
+        }
+        self.set_sf(gcode)?;
+
+        self.raster_code = self.current_code;
+        self.raster_mode = true;
+        self.raster = Some(line);
+        self.set_emission(false);
+        self._status = MotionStatus::INTERPOLATING;
+        Ok(())
+    }
+
+    /// M160 line is waiting for pixels
+    pub fn raster_loading(&self) -> bool {
+        matches!(&self.raster, Some(line) if line.phase == RasterPhase::Loading)
+    }
+
+    /// Pixels of the waiting line from the USB buffer, all loaded - jump to the run-in.
+    /// true - some pixels were taken
+    pub fn load_raster(&mut self, buf: &mut PixelBuffer) -> bool {
+        let line = match self.raster.as_mut() {
+            Some(line) if line.phase == RasterPhase::Loading => line,
+            _ => return false,
+        };
+        let n = buf.take(&mut self.raster_pixels[line.loaded..line.count]);
+        line.loaded += n;
+
+        if line.loaded == line.count {
+            line.phase = RasterPhase::Jump;
+            (self.current_to_x, self.current_to_y) = line.start();
+            self.current_code = 0;
+            self.start_motion();
+        }
+        n > 0
+    }
+
+    /// M170: frames are played from the next tic, the beam is closed until the first point
+    fn start_ilda(&mut self, gcode: &GCode) -> Result<(), String> {
+        let source = match gcode.get_p() {
+            Some(p) if p >= 0.0 && (p as usize) < config::JOB_SLOTS => IldaSource::Job(p as u8),
+            Some(_) => return Err("No such job slot".into()),
+            None => IldaSource::Stream,
+        };
+        if self.current_laserenabled && !self.laser_ready() {
+            return Err("Laser not ready".into());
+        }
+
+        self.set_emission(false);
+        self.ilda.start(source, self._now);
+        Ok(())
+    }
+
+    /// M171 or bad ILDA data: the beam and the pointer return to their M3/M4 state
+    fn stop_ilda(&mut self) {
+        self.ilda.stop();
+        self.set_emission(self.idle_emission());
+        let red = if self.current_red_laserenabled {
+            self.current_a
+        } else {
+            0.0
+        };
+        self.laser.set_red_laser_power(red);
+    }
+
+    /// Source of the M170 file bytes for the idle loop
+    pub fn ilda_source(&self) -> Option<IldaSource> {
+        self.ilda.source()
+    }
+
+    /// M170: file bytes from the USB data buffer, true - some bytes were taken.
+    /// Err - playback is stopped
+    pub fn load_ilda(&mut self, buf: &mut PixelBuffer) -> Result<bool, String> {
+        self.ilda
+            .load_stream(buf)
+            .map_err(|e| self.ilda_failed(&e))
+    }
+
+    /// M170 P<n>: file bytes from the data lines of the stored job
+    pub fn load_ilda_job<S: NvStorage>(&mut self, store: &JobStore<S>) -> Result<(), String> {
+        self.ilda
+            .load_job(store)
+            .map_err(|e| self.ilda_failed(&e))
+    }
+
+    fn ilda_failed(&mut self, err: &str) -> String {
+        self.stop_ilda();
+        let mut s = String::new();
+        let _ = write!(&mut s, "[MSG:ILDA stopped: {}]\r\n", err);
+        s
+    }
+
+    /// M170: next point at $320 points/s, blanking closes EM and the red pointer
+    fn feed_ilda(&mut self) {
+        let rate = self.settings.ilda_point_rate;
+        let point = match self.ilda.next_point(self._now, rate) {
+            Some(point) => point,
+            None => return,
+        };
+
+        self.set_galvo_position(
+            point.x as f32 / 32768.0 * config::MOTION_X_RANGE / 2.0,
+            point.y as f32 / 32768.0 * config::MOTION_Y_RANGE / 2.0,
+        );
+        if self.current_laserenabled && self.laser_ready() {
+            self.set_emission(!point.blank);
+        }
+        if self.current_red_laserenabled {
+            let red = if point.blank { 0.0 } else { self.current_a };
+            self.laser.set_red_laser_power(red);
+        }
+    }
+
+    /// M160: the scan follows the jump, then the motion mode is restored
+    fn feed_raster(&mut self) {
+        if self._status != MotionStatus::IDLE {
+            return;
+        }
+        let line = match self.raster.as_mut() {
+            Some(line) => line,
+            None => return,
+        };
+
+        match line.phase {
+            RasterPhase::Loading => {}
+            RasterPhase::Jump => {
+                line.phase = RasterPhase::Scan;
+                (self.current_to_x, self.current_to_y) = line.end();
+                self.current_code = 1;
+                self.start_motion();
+            }
+            RasterPhase::Scan => {
+                self.raster = None;
+                self.current_code = self.raster_code;
+                // pixels changed the pump power
+                self.apply_laser_params();
+            }
+        }
+    }
+
+    /// $T clock, None - not set
+    fn time(&self) -> Option<DateTime> {
+        self.rtc.time().map(DateTime::from_unix)
+    }
+
+    /// Event log entry stamped with the clock, seconds since start if not set
+    pub fn log_event(&mut self, event: Event) {
+        let uptime = (self.last_real_nanos / 1_000_000_000) as u32;
+        self.events.push(self.rtc.time(), uptime, event);
+    }
+
+    fn process_other(&mut self, gcode: &mut GCode) -> Result<(), String> {
+        match self.current_code {
+            0 | 1 => self.process_gcodes(gcode),
+            c => {
+                let mut s = String::new();
+                write!(&mut s, "Cannot continue move for G{}", c).unwrap();
+                Err(s)
+            }
+        }
+    }
+
+    pub fn process_status_req(
+        &mut self,
+        req: &super::Request,
+    ) -> Result<Option<LongString>, String> {
+        use super::Request;
+        use crate::support::format_float_simple;
+        let ok = Some(LongString::from_str("ok\r\n").unwrap());
+
+        match req {
+            Request::Dollar('G') => {
+                let mut s = LongString::new();
+                write!(
+                    &mut s,
+                    "[GC:G{g1} G54 G17 G21 G9{g9} G94 M5 M9 T{t} F{f} S{s}]\r\nok\r\n",
+                    g1 = self.current_code,
+                    g9 = (!self.current_absolute as u32),
+                    t = self.current_pen,
+                    s = self.current_s,
+                    f = format_float_simple(self.current_f, 3),
+                )
+                .unwrap();
+                Ok(Some(s))
+            }
+            Request::Dollar('#') => {
+                let mut s = LongString::new();
+                /*"[G54:0.000,0.000,0.000]\r
+                [G55:0.000,0.000,0.000]\r
+                [G56:0.000,0.000,0.000]\r
+                [G57:0.000,0.000,0.000]\r
+                [G58:0.000,0.000,0.000]\r
+                [G59:0.000,0.000,0.000]\r
+                [G28:0.000,0.000,0.000]\r
+                [G30:0.000,0.000,0.000]\r
+                [G92:0.000,0.000,0.000]\r
+                [TLO:0.000]\r
+                [PRB:0.000,0.000,0.000:0]\r
+                ok\r\n"*/
+                write!(
+                    &mut s,
+                    "[G54:0.000,0.000,0.000]\r
+[G55:0.000,0.000,0.000]\r
+[G56:0.000,0.000,0.000]\r
+[TLO:0.000]\r
+[PRB:0.000,0.000,0.000:0]\r
+ok\r\n"
+                )
+                .unwrap();
+                Ok(Some(s))
+            }
+            Request::Dollar('X') => {
+                // unlock
+                if self.inputs.is_active(machine_inputs::ESTOP) {
+                    return Err("E-stop active".into());
+                }
+                if self.alarm.take().is_some() {
+                    self.log_event(Event::Unlock);
+                    Ok(Some(
+                        LongString::from_str("[MSG:Caution: Unlocked]\r\nok\r\n").unwrap(),
+                    ))
+                } else {
+                    Ok(ok)
+                }
+            }
+            Request::Dollar('$') => {
+                let mut s = LongString::new();
+                for id in Settings::IDS {
+                    self.settings.print(id, &mut s);
+                }
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Setting(id, None) => {
+                let mut s = LongString::new();
+                if self.settings.get(*id).is_none() {
+                    let mut e = String::new();
+                    write!(&mut e, "Unsupported setting ${}\r\n", id).unwrap();
+                    return Err(e);
+                }
+                self.settings.print(*id, &mut s);
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Setting(id, Some(value)) => {
+                if self.is_busy() {
+                    return Err("Motion busy!".into());
+                }
+                self.settings.set(*id, *value)?;
+                self.settings.save(&mut self.storage)?;
+                self.set_emission(self.idle_emission());
+                self.apply_sequence_delays();
+                Ok(ok)
+            }
+            Request::Dollar('L') => {
+                // [LASER:name|B min,max,default|S max|Q w1,w2,..]
+                let limits = self.laser.limits();
+                let mut s = LongString::new();
+                let _ = write!(
+                    &mut s,
+                    "[LASER:{}|B{},{},{}|S{}|Q",
+                    limits.name,
+                    limits.freq_min,
+                    limits.freq_max,
+                    limits.freq_default,
+                    limits.power_max
+                );
+                for (i, w) in limits.pulse_widths.iter().enumerate() {
+                    let _ = write!(&mut s, "{}{}", if i > 0 { "," } else { "" }, w);
+                }
+                s.push_str("]\r\nok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Dollar('I') => {
+                // build info and the crash before the last reset
+                let mut s = LongString::new();
+                let _ = write!(
+                    &mut s,
+                    "[VER:{}:OPAL-rust]\r\n[OPT:{}]\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    self.laser.limits().name
+                );
+                let _ = write!(&mut s, "[RTC:{}|", self.rtc.source());
+                let _ = match self.time() {
+                    Some(time) => write!(&mut s, "{}]\r\n", time),
+                    None if self.rtc.is_running() => write!(&mut s, "not set]\r\n"),
+                    None => write!(&mut s, "not running]\r\n"),
+                };
+                if !self.rtc.keeps_time_off_power() {
+                    // laser-ipg/laser-mopa: PC14/PC15 are taken by the laser alarm bus, no LSE
+                    s.push_str("[MSG:RTC resets on power-off]\r\n").unwrap();
+                }
+                crate::support::crash::print(&mut s);
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Dollar('E') => {
+                let mut s = LongString::new();
+                self.events.print(&mut s);
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Dollar('C') => {
+                let mut s = LongString::new();
+                self.settings.power_curve.print(&mut s);
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Pen(n) => {
+                let n = n.map_or(self.current_pen as usize, |n| n as usize);
+                if n >= config::PENS_COUNT {
+                    let mut e = String::new();
+                    write!(&mut e, "Pen T{} not exists", n).unwrap();
+                    return Err(e);
+                }
+                let mut s = LongString::new();
+                self.settings.print_pen(n, &mut s);
+                s.push_str("ok\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Status => {
+                let mut s = LongString::new();
+                write!(
+                    &mut s,
+                    "<{state}|MPos:{x:.3},{y:.3},0.000|Bf:{bf},150|FS:{f},{s}|Em:{em},{duty}",
+                    state = self.machine_state().name(),
+                    x = format_float_simple(self.current_cmd_x, 3),
+                    y = format_float_simple(self.current_cmd_y, 3),
+                    bf = self.avlb,
+                    s = self.current_s,
+                    f = format_float_simple(self.current_f, 3),
+                    em = format_float_simple(
+                        self.emission_limiter.command_on_nanos() as f32 / 1_000_000_000.0,
+                        1
+                    ),
+                    duty = format_float_simple(
+                        self.emission_limiter
+                            .duty((self.settings.duty_window_s * 1_000_000_000.0) as u64)
+                            * 100.0,
+                        1
+                    ),
+                )
+                .unwrap();
+                machine_inputs::print(self.inputs.state(), &mut s);
+                s.push_str(">\r\n").unwrap();
+                Ok(Some(s))
+            }
+            Request::Time(None) => match self.time() {
+                Some(time) => {
+                    let mut s = LongString::new();
+                    write!(&mut s, "[TIME:{}]\r\nok\r\n", time).unwrap();
+                    Ok(Some(s))
+                }
+                None => Err("Clock not set".into()),
+            },
+            Request::Time(Some(secs)) => {
+                self.rtc
+                    .set_time(*secs)
+                    .map_err(|_| String::from("RTC not running"))?;
+                self.log_event(Event::ClockSet);
+                Ok(ok)
+            }
+            Request::FeedHold => {
+                if self.alarm.is_none() {
+                    self.feed_hold = true;
+                }
+                Ok(None)
+            }
+            Request::CycleStart => {
+                self.feed_hold = false;
+                Ok(None)
+            }
+            Request::Marlin(code) => Ok(Some(self.marlin_report(*code))),
+            Request::Dollar(dl) => {
+                let mut s = String::new();
+                write!(&mut s, "Unsupported command ${}\r\n", dl).unwrap();
+                Err(s)
+            }
+            // handled by JobRunner
+            Request::Job(_) => Err("Stored jobs not available\r\n".into()),
+        }
+    }
+
+    /// Marlin M105 / M114 / M115 replies, the laser takes the place of the hotend
+    fn marlin_report(&self, code: u32) -> LongString {
+        use crate::control::laser::LaserState;
+        use crate::support::format_float_simple;
+
+        let mut s = LongString::new();
+        match code {
+            105 => {
+                // T: power when emitting / power set by M3, no bed: zeros as Marlin without one
+                let power = self.current_s as f32 * 100.0 / u8::MAX as f32;
+                let actual = if self.laser.state() == LaserState::Emitting {
+                    power
+                } else {
+                    0.0
+                };
+                let target = if self.current_laserenabled {
+                    power
+                } else {
+                    0.0
+                };
+                let _ = write!(
+                    &mut s,
+                    "ok T:{} /{} B:0.00 /0.00 @:0 B@:0\n",
+                    format_float_simple(actual, 2),
+                    format_float_simple(target, 2),
+                );
+            }
+            114 => {
+                let _ = write!(
+                    &mut s,
+                    "X:{} Y:{} Z:0.00 E:0.00\nok\n",
+                    format_float_simple(self.current_cmd_x, 2),
+                    format_float_simple(self.current_cmd_y, 2),
+                );
+            }
+            _ => {
+                let _ = write!(
+                    &mut s,
+                    "FIRMWARE_NAME:OPAL-rust {} ({}) PROTOCOL_VERSION:1.0 \
+                     MACHINE_TYPE:Galvo laser EXTRUDER_COUNT:0\n",
+                    env!("CARGO_PKG_VERSION"),
+                    self.laser.limits().name
+                );
+                // only what is implemented: hosts treat a missing Cap as not supported
+                for cap in ["ARCS", "GCODE_QUOTED_STRINGS"] {
+                    let _ = write!(&mut s, "Cap:{}:1\n", cap);
+                }
+                s.push_str("ok\n").unwrap();
+            }
+        }
+        s
+    }
+
+    fn interpolate_move(&mut self) -> bool {
+        if let Some(wait) = self.input_wait.as_mut() {
+            let active = self.inputs.is_active(wait.mask);
+            let done = match wait.mode {
+                WaitMode::Rise => active && !wait.last,
+                WaitMode::Fall => !active && wait.last,
+                WaitMode::High => active,
+                WaitMode::Low => !active,
+            };
+            wait.last = active;
+
+            if done {
+                self.input_wait = None;
+                self._status = MotionStatus::IDLE;
+            } else if let Some(deadline) = wait.deadline {
+                if self._now >= deadline {
+                    self.raise_alarm(Alarm::InputTimeout);
+                }
+            }
+            return false;
+        }
+
+        if self.raster_loading() {
+            return false;
+        }
+
+        if self.laser_arming {
+            if !self.laser_ready() {
+                return false;
+            }
+            self.laser_arming = false;
+            if !self.current_dwell {
+                self._status = MotionStatus::IDLE;
+                return false;
+            }
+        }
+
+        if self.current_dwell {
+            self.dwell();
+            return false;
+        }
+
+        if self.is_move_first_interpolation {
+            self.apply_laser_params();
+
+            if !self.idle_emission() && [0, 28].contains(&self.current_code) {
+                // jumps are always dark
+                self.set_emission(false);
+            }
+
+            if self.current_code == 28 || (self.current_code == 0 && self.current_jump_f <= 0.0)
+            {
+                // don't interpolate
+                self.dot_since_last = f32::INFINITY;
+                self.finish_move();
+                self.start_move_delay();
+                return true;
+            }
+            if [0, 1, 2, 3].contains(&self.current_code) {
+                // G0 with pen jump speed, G1, G2, G3
+                let chained = self.skywriting_chained.take();
+                self.current_distance_x = self.current_to_x - self.current_from_x;
+                self.current_distance_y = self.current_to_y - self.current_from_y;
+
+                if self.current_code < 2 {
+                    self.current_length = libm::sqrtf(
+                        self.current_distance_x * self.current_distance_x
+                            + self.current_distance_y * self.current_distance_y,
+                    );
+                } else {
+                    self.prepare_arc();
+                }
+
+                self.current_emission = Emission::Continuous;
+                self.current_emit_from = 0.0;
+                self.current_emit_to = 1.0;
+                self.current_run_out = false;
+
+                if self.current_code == 0 {
+                    self.dot_since_last = f32::INFINITY;
+                } else if self.current_laserenabled {
+                    if self.raster.is_some() {
+                        self.prepare_raster();
+                    } else if self.dot_mode {
+                        self.prepare_dots();
+                    } else if self.settings.skywriting && self.current_code == 1 {
+                        self.prepare_skywriting(chained);
+                    } else {
+                        self.set_emission(true);
+                    }
+                }
+
+                let feed = if self.current_code == 0 {
+                    self.current_jump_f
+                } else {
+                    self.current_f
+                };
+                self.current_duration =
+                    calculate_move_length_nanos(self.current_length, 0.0, feed);
+
+                self.current_startnanos = self._now;
+                self.current_endnanos = self
+                    ._now
+                    .wrapping_add(libm::roundf(self.current_duration) as u64);
+                self.is_move_first_interpolation = false;
+            }
+        }
+
+        if self.current_emission == Emission::Dots && self.interpolate_dots() {
+            return self.dot_hold_start.is_some();
+        }
+
+        //Actual interpolation
+        if self._now >= self.current_endnanos {
+            //done interpolating
+            if self.current_code != 0 && self.passes_left > 1 {
+                // next pass from the same start point, return is dark
+                self.passes_left -= 1;
+                self.set_emission(false);
+                self.current_from_x = self.current_start_x;
+                self.current_from_y = self.current_start_y;
+                self.dot_since_last = f32::INFINITY;
+                self._status = MotionStatus::INTERPOLATING;
+                self.is_move_first_interpolation = true;
+                return false;
+            }
+
+            self.set_emission(self.idle_emission());
+            let exact = self._now == self.current_endnanos;
+            self.finish_move();
+            self.start_move_delay();
+            return exact;
+        } else {
+            let fraction_of_move =
+                self._now.wrapping_sub(self.current_startnanos) as f32 / self.current_duration;
+            let (x, y) = self.path_point(fraction_of_move);
+            self.current_cmd_x = x;
+            self.current_cmd_y = y;
+
+            if self.current_emission == Emission::Raster {
+                self.raster_beam(fraction_of_move);
+            }
+            if self.current_emission == Emission::Skywriting {
+                self.set_emission(
+                    fraction_of_move >= self.current_emit_from
+                        && fraction_of_move < self.current_emit_to,
+                );
+                if self.current_run_out
+                    && self.passes_left <= 1
+                    && fraction_of_move >= self.current_emit_to
+                {
+                    self._status = MotionStatus::RUNOUT;
+                }
+            }
+            return true;
+        }
+    }
+
+    /// Pen delays: jump delay after G0, mark delay after marks, the beam is closed
+    fn start_move_delay(&mut self) {
+        let delay = if self.current_code == 0 {
+            self.current_jump_delay
+        } else if self.current_laserenabled && self.current_code != 28 {
+            self.current_mark_delay
+        } else {
+            0
+        };
+
+        if delay > 0 {
+            self.dwell_nanos = delay;
+            self.dwell_emit = false;
+            self.current_dwell = true;
+            self._status = MotionStatus::INTERPOLATING;
+        }
+    }
+
+    /// G4 and M120: hold position for dwell_nanos, beam is on if the laser is enabled
+    fn dwell(&mut self) {
+        if self.is_move_first_interpolation {
+            self.current_startnanos = self._now;
+            self.current_endnanos = self._now.wrapping_add(self.dwell_nanos);
+            self.is_move_first_interpolation = false;
+            if !self.dwell_emit {
+                self.set_emission(false);
+            } else if self.current_laserenabled {
+                self.set_emission(true);
+            }
+        }
+
+        if self._now >= self.current_endnanos {
+            if self.test_fire {
+                self.laser.disable();
+                self.test_fire = false;
+                // restore M3 state
+                self.laser_changed = self.current_laserenabled;
+            }
+            self.set_emission(self.idle_emission());
+
+            self.current_dwell = false;
+            self._status = MotionStatus::IDLE;
+            self.is_move_first_interpolation = true;
+            if self.laser_changed {
+                self.wait_laser_ready();
+            }
+        }
+    }
+
+    /// Point of the current move path, fraction 0.0 - start, 1.0 - end
+    fn path_point(&self, fraction: f32) -> (f32, f32) {
+        if self.current_code == 2 || self.current_code == 3 {
+            let angle = self.current_start_angle + self.current_sweep * fraction;
+            let (sin, cos) = crate::support::sin_cos(angle);
+            (
+                self.current_center_x + self.current_radius * cos,
+                self.current_center_y + self.current_radius * sin,
+            )
+        } else {
+            (
+                self.current_from_x + (self.current_distance_x * fraction),
+                self.current_from_y + (self.current_distance_y * fraction),
+            )
+        }
+    }
+
+    /// G2 - CW, G3 - CCW, start == end - full circle
+    fn prepare_arc(&mut self) {
+        use core::f32::consts::PI;
+
+        let (sx, sy) = (
+            self.current_from_x - self.current_center_x,
+            self.current_from_y - self.current_center_y,
+        );
+        let (ex, ey) = (
+            self.current_to_x - self.current_center_x,
+            self.current_to_y - self.current_center_y,
+        );
+
+        self.current_radius = libm::sqrtf(sx * sx + sy * sy);
+        self.current_start_angle = libm::atan2f(sy, sx);
+
+        let mut sweep = libm::atan2f(ey, ex) - self.current_start_angle;
+        if self.current_code == 2 {
+            if sweep >= 0.0 {
+                sweep -= 2.0 * PI;
+            }
+        } else if sweep <= 0.0 {
+            sweep += 2.0 * PI;
+        }
+        self.current_sweep = sweep;
+        self.current_length = self.current_radius * libm::fabsf(sweep);
+    }
+
+    /// First dot of the move continues the pitch of the previous one
+    fn prepare_dots(&mut self) {
+        let pitch = self.settings.dot_pitch_um / 1000.0;
+
+        self.dot_next = (pitch - self.dot_since_last).max(0.0);
+        self.dot_hold = self.dot_hold_nanos();
+        self.dot_hold_start = None;
+        self.current_emission = Emission::Dots;
+
+        self.set_emission(false);
+    }
+
+    /// Dot time: $212 pulses at the current frequency, not less than $211
+    fn dot_hold_nanos(&self) -> u64 {
+        let burst = self.settings.dot_pulses as u64 * 1_000_000_000 / self.current_b as u64;
+        let dwell = (self.settings.dot_dwell_us * 1000.0) as u64;
+        dwell.max(burst)
+    }
+
+    /// Stops at each dot for the burst/dwell time.
+    /// true - position is driven by dots, interpolation is not needed
+    fn interpolate_dots(&mut self) -> bool {
+        let pitch = self.settings.dot_pitch_um / 1000.0;
+
+        if let Some(hold_start) = self.dot_hold_start {
+            let held = self._now.wrapping_sub(hold_start);
+            if held < self.dot_hold {
+                return true;
+            }
+
+            // shift the move in time by the hold
+            self.current_startnanos = self.current_startnanos.wrapping_add(held);
+            self.current_endnanos = self.current_endnanos.wrapping_add(held);
+            self.dot_hold_start = None;
+            self.dot_next += pitch;
+        }
+
+        let fraction_of_move = if self._now >= self.current_endnanos {
+            1.0
+        } else {
+            self._now.wrapping_sub(self.current_startnanos) as f32 / self.current_duration
+        };
+
+        if self.dot_next <= fraction_of_move * self.current_length {
+            let fraction = if self.current_length > 0.0 {
+                self.dot_next / self.current_length
+            } else {
+                0.0
+            };
+            let (x, y) = self.path_point(fraction);
+            self.current_cmd_x = x;
+            self.current_cmd_y = y;
+
+            self.laser.fire_pulses(self.settings.dot_pulses);
+            self.dot_hold_start = Some(self._now);
+            return true;
+        }
+
+        if fraction_of_move >= 1.0 {
+            self.dot_since_last = self.current_length - (self.dot_next - pitch);
+        }
+
+        false
+    }
+
+    /// Extends current G1 by run-in before and run-out after the marked part,
+    /// emission is enabled only inside the marked part.
+    fn prepare_skywriting(&mut self, chained: Option<(f32, f32)>) {
+        if let Some((x, y)) = chained {
+            // the galvo goes on from the run-out point, not from the corner
+            self.current_from_x = x;
+            self.current_from_y = y;
+            self.current_distance_x = self.current_to_x - x;
+            self.current_distance_y = self.current_to_y - y;
+            self.current_length = libm::sqrtf(
+                self.current_distance_x * self.current_distance_x
+                    + self.current_distance_y * self.current_distance_y,
+            );
+        }
+        let length = self.current_length;
+        if length == 0.0 {
+            return;
+        }
+
+        self.current_dir_x = self.current_distance_x / length;
+        self.current_dir_y = self.current_distance_y / length;
+
+        let run_in = if chained.is_some() {
+            0.0
+        } else {
+            self.settings.skywriting_run_in
+        };
+        let run_out = self.settings.skywriting_run_out;
+
+        let total = run_in + length + run_out;
+        self.current_emit_from = run_in / total;
+        self.current_emit_to = (run_in + length) / total;
+        self.current_run_out = run_out > 0.0;
+
+        // current_from_* is restored to current_to_* when the move is done
+        self.current_from_x -= self.current_dir_x * run_in;
+        self.current_from_y -= self.current_dir_y * run_in;
+        self.current_distance_x = self.current_dir_x * total;
+        self.current_distance_y = self.current_dir_y * total;
+        self.current_length = total;
+        self.current_emission = Emission::Skywriting;
+
+        self.set_emission(false);
+    }
+
+    /// Beam is driven by the pixels, closed until the first one
+    fn prepare_raster(&mut self) {
+        if let Some(line) = self.raster.as_mut() {
+            line.beam = None;
+        }
+        self.current_emission = Emission::Raster;
+        self.set_emission(false);
+    }
+
+    /// Pixel under the beam scales S, pixel 0 closes the beam
+    fn raster_beam(&mut self, fraction: f32) {
+        let pixel = match self.raster.as_ref() {
+            Some(line) => {
+                let d = fraction * self.current_length - line.overscan;
+                let offset = self.settings.raster_offset_um[line.reverse as usize] / 1000.0;
+                line.pixel(d, offset)
+            }
+            None => return,
+        };
+        let value = pixel.map_or(0, |i| self.raster_pixels[i]);
+
+        let line = self.raster.as_mut().unwrap();
+        if line.beam == Some(value) {
+            return;
+        }
+        line.beam = Some(value);
+
+        let code = self.pixel_code(value);
+        if code > 0 {
+            self.laser.set_pump_power(code);
+        }
+        self.set_emission(code > 0);
+    }
+
+    /// Pixel 0-255 scales the power of S (not the pump code): through the $C power curve if set
+    fn pixel_code(&self, value: u8) -> u8 {
+        let curve = &self.settings.power_curve;
+        if curve.is_empty() {
+            ((value as u32 * self.current_s as u32 + 127) / 255) as u8
+        } else {
+            let power = curve.power_for_code(self.current_s) * value as f32 / 255.0;
+            curve.code_for_power(power)
+        }
+    }
+
+    /// Drop the run-out of the current mark, the next one starts at the galvo position
+    fn chain_move(&mut self) {
+        let (x, y) = (self.current_cmd_x, self.current_cmd_y);
+        self.finish_move();
+        self.current_cmd_x = x;
+        self.current_cmd_y = y;
+        self.skywriting_chained = Some((x, y));
+    }
+
+    fn finish_move(&mut self) {
+        self.current_from_x = self.current_to_x;
+        self.current_from_y = self.current_to_y;
+        self.current_cmd_x = self.current_to_x;
+        self.current_cmd_y = self.current_to_y;
+        self._status = MotionStatus::IDLE;
+        self.is_move_first_interpolation = true;
+    }
+
+    fn select_pen(&mut self, n: u32) -> Result<(), String> {
+        let pen = match self.settings.pens.get(n as usize) {
+            Some(pen) => *pen,
+            None => {
+                let mut s = String::new();
+                write!(&mut s, "Pen T{} not exists", n).unwrap();
+                return Err(s);
+            }
+        };
+
+        let s = self.s_to_code(pen.s)?;
+        let q = if pen.q > 0.0 {
+            self.check_pulse_width(pen.q)?
+        } else {
+            self.current_q
+        };
+
+        self.current_pen = n;
+        self.current_f = pen.mark_speed;
+        self.current_jump_f = pen.jump_speed;
+        self.current_s = s;
+        self.current_a = pen.a;
+        self.current_b = self.clamp_frequency(pen.b);
+        self.current_q = q;
+        self.current_passes = pen.passes;
+        self.current_jump_delay = (pen.jump_delay_us * 1000.0) as u64;
+        self.current_mark_delay = (pen.mark_delay_us * 1000.0) as u64;
+        Ok(())
+    }
+
+    /// S in units of $220 to pump code D[0..7]
+    fn s_to_code(&self, s: f32) -> Result<u8, String> {
+        use super::settings::SUnits;
+
+        let curve = &self.settings.power_curve;
+        let power_max = self.laser.limits().power_max as f32;
+        let s = s.max(0.0);
+        match self.settings.s_units {
+            SUnits::Code => Ok(s.min(power_max) as u8),
+            SUnits::Percent if curve.is_empty() => Ok((s.min(100.0) * power_max / 100.0) as u8),
+            SUnits::Percent => Ok(curve.code_for_power(s.min(100.0) * curve.max_power() / 100.0)),
+            SUnits::Watts if curve.is_empty() => Err("Power calibration is empty".into()),
+            SUnits::Watts => Ok(curve.code_for_power(s)),
+        }
+    }
+
+    /// S, A, B are modal per move: pushed to the running laser at the move start,
+    /// the driver skips values that are not changed
+    fn apply_laser_params(&mut self) {
+        if self.current_laserenabled && !self.laser_changed {
+            self.laser.set_pump_power(self.current_s);
+            self.laser.set_frequency(self.current_b);
+            self.laser.set_pulse_width(self.current_q);
+            self.laser.set_power_pwm(self.current_a);
+        }
+    }
+
+    /// B вне диапазона лазера ограничивается, как и A
+    fn clamp_frequency(&self, b: f32) -> u32 {
+        let limits = self.laser.limits();
+        (b.max(limits.freq_min as f32) as u32).min(limits.freq_max)
+    }
+
+    /// Q - только из таблицы длительностей лазера
+    fn check_pulse_width(&self, q: f32) -> Result<u32, String> {
+        let q = q as u32;
+        if self.laser.limits().pulse_widths.contains(&q) {
+            Ok(q)
+        } else {
+            let mut s = String::new();
+            write!(&mut s, "Pulse width Q{} not supported", q).unwrap();
+            Err(s)
+        }
+    }
+
+    /// Max emission time per command and rolling duty-cycle cap
+    fn check_emission_limits(&mut self, now_nanos: u64) {
+        use crate::control::laser::LaserState;
+
+        let window = (self.settings.duty_window_s * 1_000_000_000.0) as u64;
+        self.emission_limiter.update(
+            now_nanos,
+            self.laser.state() == LaserState::Emitting,
+            window,
+        );
+
+        let max_on = (self.settings.emission_max_s * 1_000_000_000.0) as u64;
+        if max_on > 0 && self.emission_limiter.command_on_nanos() > max_on {
+            self.raise_alarm(Alarm::EmissionTimeout);
+        } else if self.settings.duty_max < 100.0
+            && self.emission_limiter.duty(window) * 100.0 > self.settings.duty_max
+        {
+            self.raise_alarm(Alarm::DutyCycle);
+        }
+    }
+
+    pub fn alarm(&self) -> Option<Alarm> {
+        self.alarm
+    }
+
+    /// Language of host lines ($310)
+    pub fn input_dialect(&self) -> InputDialect {
+        self.settings.input_dialect
+    }
+
+    /// Host protocol ($330)
+    pub fn personality(&self) -> Personality {
+        self.settings.personality
+    }
+
+    /// Modbus RTU port ($340)
+    pub fn modbus_link(&self) -> ModbusLink {
+        self.settings.modbus_link
+    }
+
+    /// Modbus slave address ($341) and USART3 baud rate ($342)
+    pub fn modbus_config(&self) -> (u8, u32) {
+        (
+            self.settings.modbus_address as u8,
+            self.settings.modbus_baud,
+        )
+    }
+
+    fn machine_state(&self) -> MachineState {
+        if self.alarm.is_some() {
+            MachineState::Alarm
+        } else if self.inputs.is_active(machine_inputs::DOOR | machine_inputs::KEY) {
+            MachineState::Door
+        } else if self.feed_hold {
+            MachineState::Hold
+        } else if self.is_busy() {
+            MachineState::Run
+        } else {
+            MachineState::Idle
+        }
+    }
+
+    /// Snapshot for the PLC input registers
+    pub fn plc_status(&self) -> PlcStatus {
+        PlcStatus {
+            state: self.machine_state(),
+            alarm: self.alarm,
+            laser_state: self.laser.state(),
+            laser_status: self.laser.get_status(),
+            inputs: self.inputs.state(),
+            serial: self.serial.value(),
+            x: self.current_cmd_x,
+            y: self.current_cmd_y,
+        }
+    }
+
+    /// {PLC} template field text
+    pub fn set_plc_text(&mut self, text: &str) {
+        self.plc_text.clear();
+        let _ = self.plc_text.push_str(text);
+    }
+
+    /// Serial number for the next {SN}, same as M151 P<n>
+    pub fn set_serial(&mut self, value: u32) -> Result<(), String> {
+        self.serial.set(&mut self.storage, value)
+    }
+
+    /// Stored job trigger input ($270) state, None - no trigger
+    pub fn job_trigger(&self) -> Option<bool> {
+        if self.settings.job_trigger < 0.0 {
+            None
+        } else {
+            Some(self.inputs.is_active(1 << self.settings.job_trigger as u8))
+        }
+    }
+
+    /// Current position and G90 mode: stored job copies continue relative moves from here
+    pub fn job_origin(&self) -> (f32, f32, bool) {
+        (self.current_to_x, self.current_to_y, self.current_absolute)
+    }
+
+    /// Door, E-stop, start, key: read the machine input bus
+    pub fn update_inputs(&mut self, now_nanos: u64) {
+        let rising = self.inputs.poll(
+            self.settings.input_invert as u8,
+            now_nanos,
+            (self.settings.input_debounce_ms * 1_000_000.0) as u64,
+        );
+
+        if rising & machine_inputs::ESTOP != 0 {
+            self.raise_alarm(Alarm::EStop);
+        }
+        if rising & machine_inputs::START != 0 {
+            // cycle start
+            self.feed_hold = false;
+        }
+    }
+
+    /// Feed hold and door/key: laser is off and motion time is frozen,
+    /// after release the laser passes its power-up sequence again. true - held
+    fn hold(&mut self) -> bool {
+        if self.feed_hold || self.inputs.is_active(machine_inputs::DOOR | machine_inputs::KEY) {
+            if !self.held {
+                self.held = true;
+                self.laser.disable();
+            }
+            self.resuming = false;
+            return true;
+        }
+
+        if self.held {
+            self.held = false;
+            if self.current_laserenabled {
+                self.enable_laser();
+                // opened at Ready only if it was open before the hold
+                self.laser.set_emission(self.current_emitting);
+                self.resuming = true;
+            }
+        }
+
+        if self.resuming {
+            if !self.laser_ready() {
+                return true;
+            }
+            self.resuming = false;
+        }
+        false
+    }
+
+    fn enable_laser(&mut self) {
+        self.laser.set_pump_power(self.current_s);
+        self.laser.set_frequency(self.current_b as u32);
+        self.laser.set_pulse_width(self.current_q);
+        self.laser.set_power_pwm(self.current_a);
+        self.laser.enable();
+    }
+
+    /// Something is received from the host, restarts the silence timeout
+    pub fn host_activity(&mut self) {
+        self.last_host_nanos = self._now;
+    }
+
+    /// Laser off, motion aborted, galvo parked; latched until `$X`
+    pub fn raise_alarm(&mut self, alarm: Alarm) {
+        self.feed_hold = false;
+        self.resuming = false;
+        self.laser.disable();
+        self.laser.set_red_laser_power(0.0);
+        self.current_laserenabled = false;
+        self.current_red_laserenabled = false;
+        self.laser_changed = false;
+        self.current_emitting = false;
+
+        self.current_dwell = false;
+        self.test_fire = false;
+        self.laser_arming = false;
+        self.input_wait = None;
+        self.sync_outputs_on = 0;
+        self.sync_outputs_off = 0;
+        if self.figure.take().is_some() {
+            self.current_code = self.figure_code;
+        }
+        if self.raster.take().is_some() {
+            self.current_code = self.raster_code;
+        }
+        self.raster_mode = false;
+        self.ilda.stop();
+        self.is_move_first_interpolation = true;
+        self._status = MotionStatus::IDLE;
+
+        self.current_from_x = config::GALVO_PARK_X;
+        self.current_from_y = config::GALVO_PARK_Y;
+        self.current_to_x = config::GALVO_PARK_X;
+        self.current_to_y = config::GALVO_PARK_Y;
+        self.current_cmd_x = config::GALVO_PARK_X;
+        self.current_cmd_y = config::GALVO_PARK_Y;
+        self.transform = Transform::IDENTITY;
+        self.set_galvo_position(config::GALVO_PARK_X, config::GALVO_PARK_Y);
+
+        if self.alarm.is_none() {
+            self.alarm = Some(alarm);
+            self.alarm_reported = false;
+            self.log_event(Event::Alarm(alarm));
+        }
+    }
+
+    /// `ALARM:<n>` once per latched alarm
+    pub fn poll_alarm(&mut self) -> Option<String> {
+        match self.alarm {
+            Some(alarm) if !self.alarm_reported => {
+                self.alarm_reported = true;
+                let mut s = String::new();
+                match self.settings.personality {
+                    // Marlin hosts stop the print on Error:
+                    Personality::Marlin => {
+                        write!(&mut s, "Error:ALARM:{} {}\n", alarm as u32, alarm.message())
+                    }
+                    Personality::Grbl => write!(
+                        &mut s,
+                        "ALARM:{}\r\n[MSG:{}]\r\n",
+                        alarm as u32,
+                        alarm.message()
+                    ),
+                }
+                .unwrap();
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    /// Host connected: report the latched alarm again
+    pub fn repeat_alarm(&mut self) {
+        self.alarm_reported = false;
+    }
+
+    /// M62-M65 P<n>
+    fn output_mask(&self, gcode: &GCode) -> Result<u8, String> {
+        match gcode.get_p() {
+            Some(n) if n >= 0.0 && (n as u8) < self.outputs.count() => Ok(1 << n as u8),
+            Some(_) => Err("No such output".into()),
+            None => Err("Output number (P) required".into()),
+        }
+    }
+
+    /// Laser power-up: next G-code is accepted only after the laser reports Ready
+    fn wait_laser_ready(&mut self) {
+        self.laser_arming = true;
+        self._status = MotionStatus::INTERPOLATING;
+    }
+
+    fn laser_ready(&self) -> bool {
+        use crate::control::laser::LaserState;
+
+        matches!(self.laser.state(), LaserState::Ready | LaserState::Emitting)
+    }
+
+    fn apply_sequence_delays(&mut self) {
+        self.laser.set_sequence_delays(
+            (self.settings.laser_arm_delay_ms * 1_000_000.0) as u64,
+            (self.settings.laser_ready_delay_ms * 1_000_000.0) as u64,
+        );
+    }
+
+    /// Emission state between moves: skywriting, dots and raster open it only for marks,
+    /// ILDA - per point
+    fn idle_emission(&self) -> bool {
+        !(self.settings.skywriting
+            || self.dot_mode
+            || self.raster_mode
+            || self.ilda.source().is_some())
+    }
+
+    fn set_emission(&mut self, emit: bool) {
+        if self.current_emitting != emit {
+            self.laser.set_emission(emit);
+            self.current_emitting = emit;
+        }
+    }
+
+    /// Placement of the following moves (step-and-repeat), checked against
+    /// the field by the caller; set only while idle
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    fn set_galvo_position(&mut self, x: f32, y: f32) {
+        use crate::support::map;
+
+        let (x, y) = self.transform.apply(x, y);
 
         let cmd_x = if config::AXIS_INVERSE_X {
             map(
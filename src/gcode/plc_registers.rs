@@ -0,0 +1,286 @@
+//! Карта регистров Modbus RTU для ПЛК: состояние станка, лазера и сохраненных заданий,
+//! выбор и запуск задания, серийный номер `{SN}` и текст `{PLC}`.
+//!
+//! Input registers обновляются в каждом проходе idle. Запись holding registers только
+//! запоминается, команда исполняется после ответа тем же путем, что `$`-запросы хоста,
+//! ее результат - в input register `IR_COMMAND_RESULT`.
+
+use crate::config;
+use crate::control::laser::{LaserState, LaserStatus};
+use crate::support::modbus_rtu::{ModbusException, ModbusRegisters};
+use crate::support::nv_storage::NvStorage;
+
+use super::gcode::{JobRequest, Request};
+use super::job_store::JobStore;
+use super::motion_mgr::{Alarm, MachineState};
+use super::serial_counter::SERIAL_MAX;
+
+// Input registers (04)
+/// 0 - Idle, 1 - Run, 2 - Hold, 3 - Door, 4 - Alarm
+const IR_STATE: u16 = 0;
+/// код аварии `ALARM:<n>`, 0 - нет
+const IR_ALARM: u16 = 1;
+/// 0 - Off, 1 - Armed, 2 - Ready, 3 - Emitting
+const IR_LASER_STATE: u16 = 2;
+/// `LaserStatus`: 1 - Normal, 0, 3, 4 - аварии лазера
+const IR_LASER_STATUS: u16 = 3;
+/// слот выполняемого задания + 1, 0 - нет
+const IR_JOB_RUNNING: u16 = 4;
+/// последняя исполненная команда и ее результат: 0 - выполнена, 1 - отклонена
+const IR_COMMAND: u16 = 5;
+const IR_COMMAND_RESULT: u16 = 6;
+/// входы станка: 1 - дверь, 2 - E-stop, 4 - старт, 8 - ключ
+const IR_INPUTS: u16 = 7;
+/// бит n - в слоте n есть задание
+const IR_JOBS: u16 = 8;
+/// серийный номер для следующей маркировки, старшее и младшее слово
+const IR_SERIAL: u16 = 9;
+/// текущая точка, 0.01 мм со знаком
+const IR_X: u16 = 11;
+const IR_Y: u16 = 12;
+/// запусков задания слота n: `IR_RUNS + 2 * n` старшее слово, `+ 1` младшее
+const IR_RUNS: u16 = 16;
+
+// Holding registers (03, 06, 16)
+/// запись исполняет `PlcCommand`, читается 0
+const HR_COMMAND: u16 = 0;
+/// слот и число прогонов для команды 1 (0 - пока не остановят)
+const HR_SLOT: u16 = 1;
+const HR_REPEAT: u16 = 2;
+/// серийный номер, старшее и младшее слово. Сохраняется при записи младшего
+const HR_SERIAL: u16 = 3;
+/// текст `{PLC}`: по 2 ASCII символа на регистр, старший байт первый, 0 - конец
+const HR_TEXT: u16 = 16;
+const HR_TEXT_COUNT: u16 = (config::PLC_TEXT_MAX_LEN / 2) as u16;
+
+/// Команды регистра `HR_COMMAND`
+const CMD_START: u16 = 1;
+const CMD_STOP: u16 = 2;
+const CMD_ABORT: u16 = 3;
+const CMD_UNLOCK: u16 = 4;
+const CMD_FEED_HOLD: u16 = 5;
+const CMD_CYCLE_START: u16 = 6;
+const CMD_TEXT_MODE: u16 = 7;
+
+pub type PlcText = heapless::String<{ config::PLC_TEXT_MAX_LEN }>;
+
+/// Команда ПЛК для idle
+#[derive(Clone, Copy)]
+pub enum PlcCommand {
+    /// как запрос хоста
+    Request(Request),
+    /// авария `ALARM:28`: лазер выключается сразу, задание прерывается
+    Abort,
+}
+
+/// Состояние `MotionMGR` для input registers
+#[derive(Clone, Copy)]
+pub struct PlcStatus {
+    pub state: MachineState,
+    pub alarm: Option<Alarm>,
+    pub laser_state: LaserState,
+    pub laser_status: LaserStatus,
+    pub inputs: u8,
+    pub serial: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+pub struct PlcRegisters {
+    status: PlcStatus,
+    running: Option<u8>,
+    jobs: u16,
+    runs: [u32; config::JOB_SLOTS],
+
+    command: Option<u16>,
+    last_command: u16,
+    rejected: bool,
+
+    slot: u16,
+    repeat: u16,
+    serial: [u16; 2],
+    serial_changed: bool,
+    text: [u16; HR_TEXT_COUNT as usize],
+    text_changed: bool,
+}
+
+impl PlcRegisters {
+    pub const fn new() -> Self {
+        Self {
+            status: PlcStatus {
+                state: MachineState::Idle,
+                alarm: None,
+                laser_state: LaserState::Off,
+                laser_status: LaserStatus::Normal,
+                inputs: 0,
+                serial: 0,
+                x: 0.0,
+                y: 0.0,
+            },
+            running: None,
+            jobs: 0,
+            runs: [0; config::JOB_SLOTS],
+            command: None,
+            last_command: 0,
+            rejected: false,
+            slot: 0,
+            repeat: 1,
+            serial: [0; 2],
+            serial_changed: false,
+            text: [0; HR_TEXT_COUNT as usize],
+            text_changed: false,
+        }
+    }
+
+    /// Input registers: состояние на этот проход idle
+    pub fn refresh<NVS: NvStorage>(
+        &mut self,
+        status: PlcStatus,
+        running: Option<u8>,
+        store: &JobStore<NVS>,
+    ) {
+        self.status = status;
+        self.running = running;
+        self.jobs = 0;
+        for slot in 0..config::JOB_SLOTS as u8 {
+            if store.job(slot).is_some() {
+                self.jobs |= 1 << slot;
+            }
+            self.runs[slot as usize] = store.runs(slot);
+        }
+    }
+
+    /// Записанная команда, результат - `command_done`
+    pub fn take_command(&mut self) -> Option<PlcCommand> {
+        let code = self.command.take()?;
+        self.last_command = code;
+        let slot = self.slot as u8;
+        let request = match code {
+            CMD_START => Request::Job(JobRequest::Run(slot, self.repeat as u32)),
+            CMD_STOP => Request::Job(JobRequest::Stop),
+            CMD_ABORT => return Some(PlcCommand::Abort),
+            CMD_UNLOCK => Request::Dollar('X'),
+            CMD_FEED_HOLD => Request::FeedHold,
+            CMD_CYCLE_START => Request::CycleStart,
+            CMD_TEXT_MODE => Request::Setting(340, Some(0.0)),
+            _ => return None,
+        };
+        Some(PlcCommand::Request(request))
+    }
+
+    pub fn command_done(&mut self, ok: bool) {
+        self.rejected = !ok;
+    }
+
+    /// Записанный серийный номер
+    pub fn take_serial(&mut self) -> Option<u32> {
+        if !self.serial_changed {
+            return None;
+        }
+        self.serial_changed = false;
+        Some((self.serial[0] as u32) << 16 | self.serial[1] as u32)
+    }
+
+    /// Записанный текст `{PLC}`
+    pub fn take_text(&mut self) -> Option<PlcText> {
+        if !self.text_changed {
+            return None;
+        }
+        self.text_changed = false;
+
+        let mut text = PlcText::new();
+        for c in self.text.iter().flat_map(|r| r.to_be_bytes()) {
+            if c == 0 {
+                break;
+            }
+            let _ = text.push(c as char);
+        }
+        Some(text)
+    }
+}
+
+impl ModbusRegisters for PlcRegisters {
+    fn read_input(&self, addr: u16) -> Result<u16, ModbusException> {
+        let status = &self.status;
+        let value = match addr {
+            IR_STATE => status.state as u16,
+            IR_ALARM => status.alarm.map_or(0, |a| a as u16),
+            IR_LASER_STATE => match status.laser_state {
+                LaserState::Off => 0,
+                LaserState::Armed => 1,
+                LaserState::Ready => 2,
+                LaserState::Emitting => 3,
+            },
+            IR_LASER_STATUS => status.laser_status as u16,
+            IR_JOB_RUNNING => self.running.map_or(0, |slot| slot as u16 + 1),
+            IR_COMMAND => self.last_command,
+            IR_COMMAND_RESULT => self.rejected as u16,
+            IR_INPUTS => status.inputs as u16,
+            IR_JOBS => self.jobs,
+            IR_SERIAL => (status.serial >> 16) as u16,
+            a if a == IR_SERIAL + 1 => status.serial as u16,
+            IR_X => to_register(status.x),
+            IR_Y => to_register(status.y),
+            a if a >= IR_RUNS && a < IR_RUNS + 2 * config::JOB_SLOTS as u16 => {
+                let runs = self.runs[(a - IR_RUNS) as usize / 2];
+                if (a - IR_RUNS) % 2 == 0 {
+                    (runs >> 16) as u16
+                } else {
+                    runs as u16
+                }
+            }
+            _ => return Err(ModbusException::IllegalDataAddress),
+        };
+        Ok(value)
+    }
+
+    fn read_holding(&self, addr: u16) -> Result<u16, ModbusException> {
+        match addr {
+            HR_COMMAND => Ok(0),
+            HR_SLOT => Ok(self.slot),
+            HR_REPEAT => Ok(self.repeat),
+            a if a == HR_SERIAL || a == HR_SERIAL + 1 => Ok(self.serial[(a - HR_SERIAL) as usize]),
+            a if a >= HR_TEXT && a < HR_TEXT + HR_TEXT_COUNT => {
+                Ok(self.text[(a - HR_TEXT) as usize])
+            }
+            _ => Err(ModbusException::IllegalDataAddress),
+        }
+    }
+
+    fn write_holding(&mut self, addr: u16, value: u16) -> Result<(), ModbusException> {
+        match addr {
+            HR_COMMAND => match value {
+                CMD_START..=CMD_TEXT_MODE => self.command = Some(value),
+                _ => return Err(ModbusException::IllegalDataValue),
+            },
+            HR_SLOT if (value as usize) < config::JOB_SLOTS => self.slot = value,
+            HR_REPEAT => self.repeat = value,
+            HR_SERIAL => self.serial[0] = value,
+            a if a == HR_SERIAL + 1 => {
+                let serial = (self.serial[0] as u32) << 16 | value as u32;
+                if serial > SERIAL_MAX {
+                    return Err(ModbusException::IllegalDataValue);
+                }
+                self.serial[1] = value;
+                self.serial_changed = true;
+            }
+            a if a >= HR_TEXT && a < HR_TEXT + HR_TEXT_COUNT => {
+                // печатный ASCII, 0 - конец текста
+                let printable = |c: u8| c == 0 || (0x20..0x7f).contains(&c);
+                if !value.to_be_bytes().iter().all(|c| printable(*c)) {
+                    return Err(ModbusException::IllegalDataValue);
+                }
+                self.text[(a - HR_TEXT) as usize] = value;
+                self.text_changed = true;
+            }
+            HR_SLOT => return Err(ModbusException::IllegalDataValue),
+            _ => return Err(ModbusException::IllegalDataAddress),
+        }
+        Ok(())
+    }
+}
+
+/// мм -> 0.01 мм со знаком в регистре
+fn to_register(mm: f32) -> u16 {
+    libm::roundf(mm * 100.0) as i16 as u16
+}
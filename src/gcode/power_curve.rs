@@ -0,0 +1,137 @@
+use core::fmt::Write;
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::format_float_simple;
+
+use super::motion_mgr::LongString;
+
+/// Измеренная мощность лазера при заданном коде D[0..7]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CalPoint {
+    pub code: u32,
+    pub power: f32,
+}
+
+/// Кусочно-линейная калибровка мощность -> код накачки.
+/// Точки отсортированы по коду, ниже первой точки - линейно к (0, 0).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PowerCurve {
+    count: u32,
+    points: [CalPoint; config::POWER_CURVE_POINTS],
+}
+
+impl PowerCurve {
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn points(&self) -> &[CalPoint] {
+        &self.points[..self.count as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    /// Добавить точку, точка с тем же кодом заменяется
+    pub fn insert(&mut self, code: u8, power: f32) -> Result<(), String> {
+        let code = code as u32;
+        let count = self.count as usize;
+
+        let pos = self.points[..count]
+            .iter()
+            .position(|p| p.code >= code)
+            .unwrap_or(count);
+
+        if pos < count && self.points[pos].code == code {
+            self.points[pos].power = power;
+            return Ok(());
+        }
+
+        if count == self.points.len() {
+            let mut s = String::new();
+            write!(&mut s, "Power curve full ({})", self.points.len()).unwrap();
+            return Err(s);
+        }
+
+        self.points.copy_within(pos..count, pos + 1);
+        self.points[pos] = CalPoint { code, power };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Максимальная измеренная мощность
+    pub fn max_power(&self) -> f32 {
+        self.points()
+            .iter()
+            .fold(0.0f32, |max, p| if p.power > max { p.power } else { max })
+    }
+
+    /// Код накачки для мощности, мощность выше измеренной - код последней точки
+    pub fn code_for_power(&self, power: f32) -> u8 {
+        if power <= 0.0 {
+            return 0;
+        }
+
+        let mut prev = CalPoint::default();
+        for p in self.points() {
+            if p.power >= power {
+                let span = p.power - prev.power;
+                let k = if span > 0.0 {
+                    (power - prev.power) / span
+                } else {
+                    1.0
+                };
+                let code = prev.code as f32 + (p.code as f32 - prev.code as f32) * k;
+                return libm::roundf(code) as u8;
+            }
+            prev = *p;
+        }
+        prev.code as u8
+    }
+
+    /// Мощность при коде накачки, обратно `code_for_power`.
+    /// Код выше последней точки - ее мощность
+    pub fn power_for_code(&self, code: u8) -> f32 {
+        let code = code as u32;
+
+        let mut prev = CalPoint::default();
+        for p in self.points() {
+            if p.code >= code {
+                let span = (p.code - prev.code) as f32;
+                let k = if span > 0.0 {
+                    (code - prev.code) as f32 / span
+                } else {
+                    1.0
+                };
+                return prev.power + (p.power - prev.power) * k;
+            }
+            prev = *p;
+        }
+        prev.power
+    }
+
+    /// `[CAL:<code>,<power>]` по строке на точку
+    pub fn print(&self, out: &mut LongString) {
+        for p in self.points() {
+            let _ = write!(
+                out,
+                "[CAL:{},{}]\r\n",
+                p.code,
+                format_float_simple(p.power, 3)
+            );
+        }
+    }
+}
+
+impl Default for PowerCurve {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            points: [CalPoint::default(); config::POWER_CURVE_POINTS],
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! M160: растровая гравировка построчно. Строка растра - отрезок от X Y под углом R
+//! из L пикселей с шагом P, мощность каждого пикселя - байт из потока данных.
+//!
+//! Пиксели идут отдельными строками `;H:<hex>` или `;B:<base64>` мимо очереди G-кодов
+//! в буфер пикселей (как загрузка задания), строка растра начинается, когда все ее
+//! пиксели приняты. Переполненный буфер останавливает прием по USB.
+//! В бинарном протоколе (`$B`) пиксели приходят кадром как есть.
+//! Тот же буфер несет байты файла ILDA (`M170`).
+
+use crate::config;
+use crate::config::HlString as String;
+
+use super::gcode::MAX_LEN;
+use super::GCode;
+
+/// Пиксели в hex
+const HEX_PREFIX: &str = ";H:";
+/// Пиксели в base64
+const BASE64_PREFIX: &str = ";B:";
+
+/// Строка текстового протокола или байты кадра бинарного
+pub enum PixelData<'a> {
+    Line(&'a str),
+    Frame(&'a [u8]),
+}
+
+pub enum PixelResult {
+    /// не строка пикселей, обрабатывается как обычно
+    NotPixels,
+    /// строка принята или ошибочна, ответ хосту
+    Reply(String),
+    /// кадр принят, ответ - ACK протокола
+    Stored,
+    /// нет места, строка будет принята позже
+    Full,
+}
+
+/// Принятые, но еще не взятые строкой растра пиксели
+pub struct PixelBuffer {
+    pixels: heapless::Deque<u8, { config::RASTER_BUFFER_SIZE }>,
+}
+
+impl PixelBuffer {
+    pub const fn new() -> Self {
+        Self {
+            pixels: heapless::Deque::new(),
+        }
+    }
+
+    pub fn pixel_data(&mut self, data: PixelData) -> PixelResult {
+        match data {
+            PixelData::Line(line) => self.pixel_line(line),
+            PixelData::Frame(pixels) if self.push(pixels) => PixelResult::Stored,
+            PixelData::Frame(_) => PixelResult::Full,
+        }
+    }
+
+    fn pixel_line(&mut self, line: &str) -> PixelResult {
+        let mut buf = [0u8; MAX_LEN];
+        let n = match decode_data_line(line, &mut buf) {
+            None => return PixelResult::NotPixels,
+            Some(None) => return PixelResult::Reply("Invalid pixel data\r\n".into()),
+            Some(Some(n)) => n,
+        };
+        if !self.push(&buf[..n]) {
+            return PixelResult::Full;
+        }
+        PixelResult::Reply("ok\r\n".into())
+    }
+
+    /// Все или ничего: false - нет места
+    fn push(&mut self, pixels: &[u8]) -> bool {
+        if self.pixels.capacity() - self.pixels.len() < pixels.len() {
+            return false;
+        }
+        for &p in pixels {
+            let _ = self.pixels.push_back(p);
+        }
+        true
+    }
+
+    /// Забрать пиксели в out, возвращает сколько взято
+    pub fn take(&mut self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for p in out.iter_mut() {
+            match self.pixels.pop_front() {
+                Some(v) => *p = v,
+                None => break,
+            }
+            n += 1;
+        }
+        n
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.clear();
+    }
+}
+
+/// Этап строки растра
+#[derive(Clone, Copy, PartialEq)]
+pub enum RasterPhase {
+    /// ждем пиксели
+    Loading,
+    /// переход к началу разгона
+    Jump,
+    /// проход с модуляцией мощности
+    Scan,
+}
+
+#[derive(Clone, Copy)]
+pub struct Scanline {
+    /// начало пикселя 0
+    x: f32,
+    y: f32,
+    /// направление прохода
+    dir_x: f32,
+    dir_y: f32,
+    /// шаг пикселей, мм
+    pitch: f32,
+    pub count: usize,
+    /// обратный проход (R в (90, 270]), свое смещение пикселей
+    pub reverse: bool,
+
+    /// разгон и торможение, мм
+    pub overscan: f32,
+
+    pub phase: RasterPhase,
+    pub loaded: usize,
+    /// мощность под лучом, None - еще не выставлена
+    pub beam: Option<u8>,
+}
+
+impl Scanline {
+    /// `P<шаг> L<пикселей> [R<угол>]`, (x, y) - начало пикселя 0
+    pub fn from_gcode(gcode: &GCode, x: f32, y: f32, overscan: f32) -> Result<Self, String> {
+        let pitch = match gcode.get_p() {
+            Some(p) if p > 0.0 => p,
+            Some(_) => return Err("Pixel pitch below limit".into()),
+            None => return Err("Pixel pitch (P) required".into()),
+        };
+        let count = match gcode.get_l() {
+            Some(l) if l >= 1.0 && l as usize <= config::RASTER_LINE_MAX => l as usize,
+            Some(_) => return Err("Pixel count (L) out of range".into()),
+            None => return Err("Pixel count (L) required".into()),
+        };
+
+        let mut angle = libm::fmodf(gcode.get_r().unwrap_or_default(), 360.0);
+        if angle < 0.0 {
+            angle += 360.0;
+        }
+        let (dir_y, dir_x) = crate::support::sin_cos(angle.to_radians());
+
+        Ok(Self {
+            x,
+            y,
+            dir_x,
+            dir_y,
+            pitch,
+            count,
+            reverse: angle > 90.0 && angle <= 270.0,
+            overscan,
+            phase: RasterPhase::Loading,
+            loaded: 0,
+            beam: None,
+        })
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    pub fn length(&self) -> f32 {
+        self.pitch * self.count as f32
+    }
+
+    /// Начало разгона
+    pub fn start(&self) -> (f32, f32) {
+        self.point(-self.overscan)
+    }
+
+    /// Конец торможения
+    pub fn end(&self) -> (f32, f32) {
+        self.point(self.length() + self.overscan)
+    }
+
+    /// Точка на расстоянии d от начала пикселя 0 по ходу строки, мм
+    pub fn point(&self, d: f32) -> (f32, f32) {
+        (self.x + self.dir_x * d, self.y + self.dir_y * d)
+    }
+
+    /// Пиксель под лучом в d, offset - сдвиг пикселей по ходу строки, мм
+    pub fn pixel(&self, d: f32, offset: f32) -> Option<usize> {
+        let i = libm::floorf((d - offset) / self.pitch);
+        if i >= 0.0 && (i as usize) < self.count {
+            Some(i as usize)
+        } else {
+            None
+        }
+    }
+}
+
+/// Строка данных `;H:`/`;B:` в out: None - не строка данных, Some(None) - ошибка в данных
+pub(super) fn decode_data_line(line: &str, out: &mut [u8]) -> Option<Option<usize>> {
+    let text = line.trim_matches(|c: char| c.is_ascii_whitespace());
+    if let Some(hex) = text.strip_prefix(HEX_PREFIX) {
+        Some(decode_hex(hex, out))
+    } else {
+        text.strip_prefix(BASE64_PREFIX)
+            .map(|base64| decode_base64(base64, out))
+    }
+}
+
+fn decode_hex(text: &str, out: &mut [u8]) -> Option<usize> {
+    let digits = text.as_bytes();
+    if digits.len() % 2 != 0 || digits.len() / 2 > out.len() {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16);
+    for (o, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *o = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+    }
+    Some(digits.len() / 2)
+}
+
+/// Стандартный алфавит, `=` в конце не обязательны
+fn decode_base64(text: &str, out: &mut [u8]) -> Option<usize> {
+    let (mut acc, mut bits, mut n) = (0u32, 0, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6 | v as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            *out.get_mut(n)? = (acc >> bits) as u8;
+            n += 1;
+        }
+    }
+    Some(n)
+}
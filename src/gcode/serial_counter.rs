@@ -0,0 +1,189 @@
+//! Серийный номер `{SN}` шаблонов M150, M152, M153: журнал в двух страницах после настроек.
+//! Каждое значение дописывается записью с порядковым номером. Заполненная страница не стирается,
+//! пока новое значение не записано в другую: при пропадании питания остается последнее записанное
+
+use crate::config::HlString as String;
+use crate::support::nv_storage::NvStorage;
+
+/// Запись журнала: [значение, порядковый номер]
+const RECORD_SIZE: usize = 8;
+/// Чистый flash
+const EMPTY: u32 = u32::MAX;
+
+/// Номер не больше
+pub const SERIAL_MAX: u32 = 999_999_999;
+
+pub struct SerialCounter {
+    offset: usize,
+    value: u32,
+    /// порядковый номер последней записи
+    seq: u32,
+    /// страница последней записи, 0 или 1
+    page: usize,
+    /// занято журналом от начала страницы
+    used: usize,
+}
+
+impl SerialCounter {
+    /// offset - начало журнала в storage, две целые страницы
+    pub fn load<NVS: NvStorage>(storage: &NVS, offset: usize) -> Self {
+        let page_size = storage.erase_size();
+        let mut counter = Self {
+            offset,
+            value: 0,
+            seq: 0,
+            // журнал пуст: первая запись - в страницу 0
+            page: 1,
+            used: page_size,
+        };
+        let mut found = false;
+
+        for page in 0..2 {
+            let mut used = 0;
+            while used + RECORD_SIZE <= page_size {
+                let mut rec = [0u8; RECORD_SIZE];
+                storage.read(counter.page_offset(page, page_size) + used, &mut rec);
+                if rec.iter().all(|b| *b == 0xff) {
+                    break;
+                }
+                used += RECORD_SIZE;
+
+                // запись, оборванная до порядкового номера, занимает место, но не действует
+                let value = u32::from_le_bytes([rec[0], rec[1], rec[2], rec[3]]);
+                let seq = u32::from_le_bytes([rec[4], rec[5], rec[6], rec[7]]);
+                if seq != EMPTY && value <= SERIAL_MAX && (!found || seq > counter.seq) {
+                    found = true;
+                    counter.value = value;
+                    counter.seq = seq;
+                    counter.page = page;
+                }
+            }
+            if found && counter.page == page {
+                counter.used = used;
+            }
+        }
+        counter
+    }
+
+    fn page_offset(&self, page: usize, page_size: usize) -> usize {
+        self.offset + page * page_size
+    }
+
+    /// Номер для следующей маркировки
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub fn set<NVS: NvStorage>(&mut self, storage: &mut NVS, value: u32) -> Result<(), String> {
+        if value > SERIAL_MAX {
+            return Err("Serial number above limit".into());
+        }
+
+        // страница заполнена: запись в другую, эта остается до следующего переключения
+        let page_size = storage.erase_size();
+        let (page, used) = if self.used + RECORD_SIZE > page_size {
+            let page = self.page ^ 1;
+            storage
+                .erase(self.page_offset(page, page_size), page_size)
+                .map_err(|_| String::from("Failed to save serial number"))?;
+            (page, 0)
+        } else {
+            (self.page, self.used)
+        };
+
+        let seq = self.seq.wrapping_add(1);
+        let mut rec = [0u8; RECORD_SIZE];
+        rec[..4].copy_from_slice(&value.to_le_bytes());
+        rec[4..].copy_from_slice(&seq.to_le_bytes());
+        storage
+            .write(self.page_offset(page, page_size) + used, &rec)
+            .map_err(|_| String::from("Failed to save serial number"))?;
+
+        self.page = page;
+        self.used = used + RECORD_SIZE;
+        self.seq = seq;
+        self.value = value;
+        Ok(())
+    }
+
+    /// Номер использован, следующий - на 1 больше (после SERIAL_MAX - 0)
+    pub fn advance<NVS: NvStorage>(&mut self, storage: &mut NVS) -> Result<(), String> {
+        let next = if self.value >= SERIAL_MAX {
+            0
+        } else {
+            self.value + 1
+        };
+        self.set(storage, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::support::nv_storage::ram::{Ram, PAGE};
+
+    const OFFSET: usize = PAGE;
+
+    fn storage() -> Ram<{ PAGE * 3 }> {
+        Ram::new()
+    }
+
+    #[test]
+    fn survives_restart() {
+        let mut storage = storage();
+        let mut counter = SerialCounter::load(&storage, OFFSET);
+        assert_eq!(counter.value(), 0);
+
+        counter.set(&mut storage, 41).unwrap();
+        counter.advance(&mut storage).unwrap();
+        assert_eq!(SerialCounter::load(&storage, OFFSET).value(), 42);
+
+        assert_eq!(
+            counter.set(&mut storage, SERIAL_MAX + 1).unwrap_err(),
+            "Serial number above limit"
+        );
+        counter.set(&mut storage, SERIAL_MAX).unwrap();
+        counter.advance(&mut storage).unwrap();
+        assert_eq!(SerialCounter::load(&storage, OFFSET).value(), 0);
+    }
+
+    #[test]
+    fn pages_alternate() {
+        let mut storage = storage();
+        let mut counter = SerialCounter::load(&storage, OFFSET);
+        // три страницы журнала: запись идет в обе по очереди
+        for n in 1..=3 * PAGE / RECORD_SIZE {
+            counter.set(&mut storage, n as u32).unwrap();
+            let mut loaded = SerialCounter::load(&storage, OFFSET);
+            assert_eq!(loaded.value(), n as u32);
+            // продолжение после перезапуска - с того же места
+            loaded.advance(&mut storage).unwrap();
+            counter = SerialCounter::load(&storage, OFFSET);
+            assert_eq!(counter.value(), n as u32 + 1);
+        }
+        // настройки перед журналом не затронуты
+        assert!(storage.0[..OFFSET].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn power_loss_while_switching_pages() {
+        let mut storage = storage();
+        let mut counter = SerialCounter::load(&storage, OFFSET);
+        for n in 0..PAGE / RECORD_SIZE {
+            counter.set(&mut storage, n as u32).unwrap();
+        }
+        let last = counter.value();
+
+        // другая страница стерта, новая запись не дошла
+        let mut torn = Ram::<{ PAGE * 3 }>(storage.0);
+        torn.0[OFFSET + PAGE..].fill(0xff);
+        assert_eq!(SerialCounter::load(&torn, OFFSET).value(), last);
+
+        // запись оборвана до порядкового номера
+        torn.0[OFFSET + PAGE..OFFSET + PAGE + 4].copy_from_slice(&7u32.to_le_bytes());
+        let mut counter = SerialCounter::load(&torn, OFFSET);
+        assert_eq!(counter.value(), last);
+        counter.advance(&mut torn).unwrap();
+        assert_eq!(SerialCounter::load(&torn, OFFSET).value(), last + 1);
+    }
+}
@@ -0,0 +1,573 @@
+use core::convert::TryInto;
+use core::fmt::Write;
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::format_float_simple;
+use crate::support::nv_storage::{checksum, NvStorage};
+
+use super::motion_mgr::LongString;
+use super::power_curve::PowerCurve;
+
+/// Единицы S ($220)
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum SUnits {
+    /// код D[0..7] как есть
+    Code = 0,
+    /// % от максимальной мощности по калибровке, без калибровки - % от кода 255
+    Percent = 1,
+    /// Вт по калибровке
+    Watts = 2,
+}
+
+/// Язык строк с USB ($310)
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputDialect {
+    GCode = 0,
+    Hpgl = 1,
+    /// по первой команде после подключения
+    Auto = 2,
+}
+
+/// Протокол ответов хосту ($330)
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Personality {
+    Grbl = 0,
+    /// OctoPrint, Pronterface: номера строк, контрольные суммы, M105/M114/M115/M400
+    Marlin = 1,
+}
+
+/// Порт Modbus RTU ($340)
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModbusLink {
+    Off = 0,
+    /// PB10 (TX) / PB11 (RX), скорость $342
+    Usart = 1,
+    /// вместо текстового протокола USB
+    Usb = 2,
+}
+
+/// Набор параметров лазера, выбирается `T<n>` или `T<n> M6`.
+/// Поле k пера n - настройка `$<1000 + n * 10 + k>`
+#[derive(Clone, Copy)]
+pub struct Pen {
+    /// F for G1/G2/G3, mm/min
+    pub mark_speed: f32,
+    /// F for G0, mm/min, 0 - jump
+    pub jump_speed: f32,
+    pub s: f32,
+    pub a: f32,
+    pub b: f32,
+    /// each mark is repeated this number of times
+    pub passes: u32,
+    /// hold after G0, us
+    pub jump_delay_us: f32,
+    /// hold after mark with the beam closed, us
+    pub mark_delay_us: f32,
+    /// pulse width, ns, 0 - not changed (MOPA only)
+    pub q: f32,
+}
+
+impl Pen {
+    const FIELDS: u16 = 9;
+
+    fn get(&self, field: u16) -> Option<f32> {
+        match field {
+            0 => Some(self.mark_speed),
+            1 => Some(self.jump_speed),
+            2 => Some(self.s),
+            3 => Some(self.a),
+            4 => Some(self.b),
+            5 => Some(self.passes as f32),
+            6 => Some(self.jump_delay_us),
+            7 => Some(self.mark_delay_us),
+            8 => Some(self.q),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, id: u16, field: u16, value: f32) -> Result<(), String> {
+        let check = |min, max| Settings::check_range(id, value, min, max);
+        match field {
+            0 => self.mark_speed = check(0.01, 1_000_000.0)?,
+            1 => self.jump_speed = check(0.0, 10_000_000.0)?,
+            2 => self.s = check(0.0, config::MOTION_MAX_S)?,
+            3 => self.a = check(0.0, 100.0)?,
+            // ограничивается диапазоном лазера при выборе пера
+            4 => self.b = check(0.0, 10_000_000.0)?,
+            5 => self.passes = check(1.0, 1000.0)? as u32,
+            6 => self.jump_delay_us = check(0.0, 1_000_000.0)?,
+            7 => self.mark_delay_us = check(0.0, 1_000_000.0)?,
+            8 => self.q = check(0.0, 1_000_000.0)?,
+            _ => return Err(Settings::unsupported(id)),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Pen {
+    fn default() -> Self {
+        Self {
+            mark_speed: 100.0,
+            jump_speed: 0.0,
+            s: 0.0,
+            a: 100.0,
+            b: (config::LASER_SYNC_CLOCK_KHZ * 1000) as f32,
+            passes: 1,
+            jump_delay_us: 0.0,
+            mark_delay_us: 0.0,
+            q: 0.0,
+        }
+    }
+}
+
+const SETTINGS_MAGIC: u32 = 0x4f50_414c; // "OPAL"
+
+/// Формат записи настроек во flash, меняется только при изменении разделов записи.
+/// Новые настройки добавляются без смены версии: отсутствующие в записи - по умолчанию
+const SETTINGS_VERSION: u16 = 1;
+
+/// magic: u32, версия: u16, длина данных: u16, контрольная сумма: u32
+const HEADER_SIZE: usize = 12;
+
+/// Заголовок и данные: настройки (номер: u16, значение: f32), перья (перьев: u8, полей: u8,
+/// значения f32), калибровка (точек: u8, код: u8, мощность: f32). Все little-endian
+const RECORD_MAX: usize = HEADER_SIZE
+    + 1
+    + Settings::IDS.len() * 6
+    + 2
+    + config::PENS_COUNT * Pen::FIELDS as usize * 4
+    + 1
+    + config::POWER_CURVE_POINTS * 5;
+
+// запись настроек стирается целиком, за ней - журнал серийного номера
+const _: () = assert!(RECORD_MAX <= config::SERIAL_COUNTER_OFFSET);
+
+/// Настройки, изменяемые командой `$<n>=<value>`
+#[derive(Clone, Copy)]
+pub struct Settings {
+    /// $200 - skywriting on/off
+    pub skywriting: bool,
+    /// $201 - skywriting run-in, mm
+    pub skywriting_run_in: f32,
+    /// $202 - skywriting run-out, mm
+    pub skywriting_run_out: f32,
+    /// $203 - skywriting corner threshold, deg
+    pub skywriting_corner: f32,
+
+    /// $210 - dot marking pitch, um
+    pub dot_pitch_um: f32,
+    /// $211 - dot marking dwell, us
+    pub dot_dwell_us: f32,
+    /// $212 - dot marking pulses per dot
+    pub dot_pulses: u32,
+
+    /// $220 - S units
+    pub s_units: SUnits,
+    /// M121 / $C
+    pub power_curve: PowerCurve,
+
+    /// $230 - laser power-up: Sync -> EE, ms
+    pub laser_arm_delay_ms: f32,
+    /// $231 - laser power-up: EE -> EM, ms
+    pub laser_ready_delay_ms: f32,
+
+    /// $240 - host silence timeout while the laser is on or moving, ms, 0 - off
+    pub host_timeout_ms: f32,
+
+    /// $250 - max emission time in one command, s, 0 - off
+    pub emission_max_s: f32,
+    /// $251 - max emission duty in the window, %, 100 - off
+    pub duty_max: f32,
+    /// $252 - duty-cycle window, s
+    pub duty_window_s: f32,
+
+    /// $260 - machine inputs debounce, ms
+    pub input_debounce_ms: f32,
+    /// $261 - machine inputs invert mask: 1 - door, 2 - E-stop, 4 - start, 8 - key
+    pub input_invert: u32,
+
+    /// $270 - stored job trigger input: -1 - none, 0-3 - machine input
+    pub job_trigger: f32,
+
+    /// $280-$282 - start of shifts A, B, C for `{SHIFT}`, h, -1 - no shift
+    pub shift_start_h: [f32; 3],
+
+    /// $290 - 2D code hatch pitch, um
+    pub code_hatch_um: f32,
+
+    /// $300, $301 - raster pixel shift along forward / reverse lines, um
+    pub raster_offset_um: [f32; 2],
+    /// $302 - raster overscan before and after the line, mm
+    pub raster_overscan: f32,
+
+    /// $310 - input dialect: 0 - G-code, 1 - HPGL, 2 - autodetect
+    pub input_dialect: InputDialect,
+
+    /// $320 - M170 ILDA playback rate, points/s
+    pub ilda_point_rate: f32,
+
+    /// $330 - host protocol: 0 - GRBL, 1 - Marlin
+    pub personality: Personality,
+
+    /// $340 - Modbus RTU port: 0 - off, 1 - USART3, 2 - USB CDC
+    pub modbus_link: ModbusLink,
+    /// $341 - Modbus slave address, applied after restart
+    pub modbus_address: u32,
+    /// $342 - USART3 baud rate, applied after restart
+    pub modbus_baud: u32,
+
+    /// $1000.. - pens
+    pub pens: [Pen; config::PENS_COUNT],
+}
+
+impl Settings {
+    /// Номера всех настроек в порядке вывода по `$$`
+    pub const IDS: [u16; 30] = [
+        200, 201, 202, 203, 210, 211, 212, 220, 230, 231, 240, 250, 251, 252, 260, 261, 270, 280,
+        281, 282, 290, 300, 301, 302, 310, 320, 330, 340, 341, 342,
+    ];
+
+    pub fn get(&self, id: u16) -> Option<f32> {
+        match id {
+            200 => Some(self.skywriting as u32 as f32),
+            201 => Some(self.skywriting_run_in),
+            202 => Some(self.skywriting_run_out),
+            203 => Some(self.skywriting_corner),
+            210 => Some(self.dot_pitch_um),
+            211 => Some(self.dot_dwell_us),
+            212 => Some(self.dot_pulses as f32),
+            220 => Some(self.s_units as u32 as f32),
+            230 => Some(self.laser_arm_delay_ms),
+            231 => Some(self.laser_ready_delay_ms),
+            240 => Some(self.host_timeout_ms),
+            250 => Some(self.emission_max_s),
+            251 => Some(self.duty_max),
+            252 => Some(self.duty_window_s),
+            260 => Some(self.input_debounce_ms),
+            261 => Some(self.input_invert as f32),
+            270 => Some(self.job_trigger),
+            280..=282 => Some(self.shift_start_h[(id - 280) as usize]),
+            290 => Some(self.code_hatch_um),
+            300 | 301 => Some(self.raster_offset_um[(id - 300) as usize]),
+            302 => Some(self.raster_overscan),
+            310 => Some(self.input_dialect as u32 as f32),
+            320 => Some(self.ilda_point_rate),
+            330 => Some(self.personality as u32 as f32),
+            340 => Some(self.modbus_link as u32 as f32),
+            341 => Some(self.modbus_address as f32),
+            342 => Some(self.modbus_baud as f32),
+            _ => {
+                let (pen, field) = Self::pen_field(id)?;
+                self.pens[pen].get(field)
+            }
+        }
+    }
+
+    pub fn set(&mut self, id: u16, value: f32) -> Result<(), String> {
+        match id {
+            200 => self.skywriting = value != 0.0,
+            201 => self.skywriting_run_in = Self::check_range(id, value, 0.0, 10.0)?,
+            202 => self.skywriting_run_out = Self::check_range(id, value, 0.0, 10.0)?,
+            203 => self.skywriting_corner = Self::check_range(id, value, 0.0, 180.0)?,
+            210 => self.dot_pitch_um = Self::check_range(id, value, 1.0, 100_000.0)?,
+            211 => self.dot_dwell_us = Self::check_range(id, value, 0.0, 1_000_000.0)?,
+            212 => self.dot_pulses = Self::check_range(id, value, 1.0, 10_000.0)? as u32,
+            220 => {
+                self.s_units = match Self::check_range(id, value, 0.0, 2.0)? as u32 {
+                    0 => SUnits::Code,
+                    1 => SUnits::Percent,
+                    _ => SUnits::Watts,
+                }
+            }
+            230 => self.laser_arm_delay_ms = Self::check_range(id, value, 0.0, 1000.0)?,
+            231 => self.laser_ready_delay_ms = Self::check_range(id, value, 0.0, 1000.0)?,
+            240 => self.host_timeout_ms = Self::check_range(id, value, 0.0, 600_000.0)?,
+            250 => self.emission_max_s = Self::check_range(id, value, 0.0, 3600.0)?,
+            251 => self.duty_max = Self::check_range(id, value, 1.0, 100.0)?,
+            252 => self.duty_window_s = Self::check_range(id, value, 1.0, 3600.0)?,
+            260 => self.input_debounce_ms = Self::check_range(id, value, 0.0, 1000.0)?,
+            261 => self.input_invert = Self::check_range(id, value, 0.0, 15.0)? as u32,
+            270 => self.job_trigger = libm::floorf(Self::check_range(id, value, -1.0, 3.0)?),
+            280..=282 => {
+                self.shift_start_h[(id - 280) as usize] = match value {
+                    v if v < 0.0 => -1.0,
+                    v => Self::check_range(id, v, 0.0, 23.99)?,
+                }
+            }
+            290 => self.code_hatch_um = Self::check_range(id, value, 1.0, 10_000.0)?,
+            300 | 301 => {
+                self.raster_offset_um[(id - 300) as usize] =
+                    Self::check_range(id, value, -10_000.0, 10_000.0)?
+            }
+            302 => self.raster_overscan = Self::check_range(id, value, 0.0, 10.0)?,
+            310 => {
+                self.input_dialect = match Self::check_range(id, value, 0.0, 2.0)? as u32 {
+                    0 => InputDialect::GCode,
+                    1 => InputDialect::Hpgl,
+                    _ => InputDialect::Auto,
+                }
+            }
+            320 => {
+                self.ilda_point_rate =
+                    Self::check_range(id, value, 10.0, config::SYSTICK_RATE_HZ as f32)?
+            }
+            330 => {
+                self.personality = match Self::check_range(id, value, 0.0, 1.0)? as u32 {
+                    0 => Personality::Grbl,
+                    _ => Personality::Marlin,
+                }
+            }
+            340 => {
+                self.modbus_link = match Self::check_range(id, value, 0.0, 2.0)? as u32 {
+                    0 => ModbusLink::Off,
+                    1 => ModbusLink::Usart,
+                    _ => ModbusLink::Usb,
+                }
+            }
+            341 => self.modbus_address = Self::check_range(id, value, 1.0, 247.0)? as u32,
+            342 => self.modbus_baud = Self::check_range(id, value, 1200.0, 115_200.0)? as u32,
+            _ => match Self::pen_field(id) {
+                Some((pen, field)) => self.pens[pen].set(id, field, value)?,
+                None => return Err(Self::unsupported(id)),
+            },
+        }
+        Ok(())
+    }
+
+    /// `[PEN:n|F..|J..|S..|A..|B..|Q..|N..|D..,..]`
+    pub fn print_pen(&self, n: usize, out: &mut LongString) {
+        let pen = &self.pens[n];
+        let _ = write!(
+            out,
+            "[PEN:{}|F{}|J{}|S{}|A{}|B{}|Q{}|N{}|D{},{}]\r\n",
+            n,
+            format_float_simple(pen.mark_speed, 3),
+            format_float_simple(pen.jump_speed, 3),
+            pen.s as u32,
+            format_float_simple(pen.a, 3),
+            pen.b as u32,
+            pen.q as u32,
+            pen.passes,
+            pen.jump_delay_us as u32,
+            pen.mark_delay_us as u32,
+        );
+    }
+
+    /// Прочитать сохраненные настройки, None - нет записи или она повреждена.
+    /// Значения проверяются как при `$<n>=<value>`, недопустимые остаются по умолчанию
+    pub fn load<S: NvStorage>(storage: &S) -> Option<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        storage.read(0, &mut header);
+        let mut r = Reader(&header);
+        let (magic, version, len, crc) = (r.u32()?, r.u16()?, r.u16()? as usize, r.u32()?);
+        if magic != SETTINGS_MAGIC || version != SETTINGS_VERSION || len > RECORD_MAX - HEADER_SIZE
+        {
+            return None;
+        }
+
+        let mut data = [0u8; RECORD_MAX - HEADER_SIZE];
+        let data = &mut data[..len];
+        storage.read(HEADER_SIZE, data);
+        if checksum(data) != crc {
+            return None;
+        }
+
+        let mut settings = Self::default();
+        let mut r = Reader(data);
+        for _ in 0..r.u8()? {
+            // настройка, неизвестная этой версии прошивки, пропускается
+            let (id, value) = (r.u16()?, r.f32()?);
+            let _ = settings.set(id, value);
+        }
+        let (pens, fields) = (r.u8()? as usize, r.u8()? as u16);
+        for n in 0..pens {
+            for field in 0..fields {
+                let value = r.f32()?;
+                if let Some(pen) = settings.pens.get_mut(n) {
+                    let _ = pen.set(1000 + n as u16 * 10 + field, field, value);
+                }
+            }
+        }
+        for _ in 0..r.u8()? {
+            let (code, power) = (r.u8()?, r.f32()?);
+            if power >= 0.0 {
+                let _ = settings.power_curve.insert(code, power);
+            }
+        }
+        Some(settings)
+    }
+
+    pub fn save<S: NvStorage>(&self, storage: &mut S) -> Result<(), String> {
+        let mut record = [0u8; RECORD_MAX];
+        let mut w = Writer {
+            buf: &mut record,
+            len: HEADER_SIZE,
+        };
+        w.put(&[Self::IDS.len() as u8]);
+        for id in Self::IDS {
+            w.put(&id.to_le_bytes());
+            w.put(&self.get(id).unwrap_or_default().to_le_bytes());
+        }
+        w.put(&[config::PENS_COUNT as u8, Pen::FIELDS as u8]);
+        for pen in &self.pens {
+            for field in 0..Pen::FIELDS {
+                w.put(&pen.get(field).unwrap_or_default().to_le_bytes());
+            }
+        }
+        let points = self.power_curve.points();
+        w.put(&[points.len() as u8]);
+        for p in points {
+            w.put(&[p.code as u8]);
+            w.put(&p.power.to_le_bytes());
+        }
+
+        let len = w.len;
+        let crc = checksum(&record[HEADER_SIZE..len]);
+        let mut w = Writer {
+            buf: &mut record,
+            len: 0,
+        };
+        w.put(&SETTINGS_MAGIC.to_le_bytes());
+        w.put(&SETTINGS_VERSION.to_le_bytes());
+        w.put(&((len - HEADER_SIZE) as u16).to_le_bytes());
+        w.put(&crc.to_le_bytes());
+
+        storage
+            .erase(0, len)
+            .and_then(|_| storage.write(0, &record[..len]))
+            .map_err(|_| "Failed to save settings\r\n".into())
+    }
+
+    fn pen_field(id: u16) -> Option<(usize, u16)> {
+        let n = id.checked_sub(1000)?;
+        let (pen, field) = ((n / 10) as usize, n % 10);
+        if pen < config::PENS_COUNT && field < Pen::FIELDS {
+            Some((pen, field))
+        } else {
+            None
+        }
+    }
+
+    fn unsupported(id: u16) -> String {
+        let mut s = String::new();
+        write!(&mut s, "Unsupported setting ${}\r\n", id).unwrap();
+        s
+    }
+
+    /// `$<n>=<value>`, по строке на настройку
+    pub fn print(&self, id: u16, out: &mut LongString) {
+        if let Some(v) = self.get(id) {
+            let _ = write!(out, "${}={}\r\n", id, format_float_simple(v, 3));
+        }
+    }
+
+    fn check_range(id: u16, value: f32, min: f32, max: f32) -> Result<f32, String> {
+        // NaN тоже вне диапазона
+        if !(value >= min && value <= max) {
+            let mut s = String::new();
+            write!(&mut s, "${} out of range\r\n", id).unwrap();
+            Err(s)
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            skywriting: config::SKYWRITING_ENABLED,
+            skywriting_run_in: config::SKYWRITING_RUN_IN_MM,
+            skywriting_run_out: config::SKYWRITING_RUN_OUT_MM,
+            skywriting_corner: config::SKYWRITING_CORNER_DEG,
+
+            dot_pitch_um: config::DOT_PITCH_UM,
+            dot_dwell_us: config::DOT_DWELL_US,
+            dot_pulses: config::DOT_PULSES,
+
+            s_units: SUnits::Code,
+            power_curve: PowerCurve::default(),
+
+            laser_arm_delay_ms: config::LASER_ARM_DELAY_MS,
+            laser_ready_delay_ms: config::LASER_READY_DELAY_MS,
+
+            host_timeout_ms: config::HOST_TIMEOUT_MS,
+
+            emission_max_s: config::EMISSION_MAX_S,
+            duty_max: config::EMISSION_DUTY_MAX,
+            duty_window_s: config::EMISSION_DUTY_WINDOW_S,
+
+            input_debounce_ms: config::INPUT_DEBOUNCE_MS,
+            input_invert: config::INPUT_INVERT,
+
+            job_trigger: config::JOB_TRIGGER_INPUT,
+
+            shift_start_h: config::SHIFT_START_H,
+
+            code_hatch_um: config::CODE_HATCH_PITCH_UM,
+
+            raster_offset_um: config::RASTER_OFFSET_UM,
+            raster_overscan: config::RASTER_OVERSCAN_MM,
+
+            input_dialect: InputDialect::GCode,
+
+            ilda_point_rate: config::ILDA_POINT_RATE,
+
+            personality: Personality::Grbl,
+
+            modbus_link: ModbusLink::Off,
+            modbus_address: config::MODBUS_ADDRESS,
+            modbus_baud: config::MODBUS_BAUD,
+
+            pens: [Pen::default(); config::PENS_COUNT],
+        }
+    }
+}
+
+/// Запись настроек по полям
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+}
+
+/// Чтение записи настроек, None - запись кончилась
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.0.len() < N {
+            return None;
+        }
+        let (value, rest) = self.0.split_at(N);
+        self.0 = rest;
+        value.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.take().map(f32::from_le_bytes)
+    }
+}
@@ -0,0 +1,138 @@
+//! Поля шаблона текста M150: `{SN}`, `{SN:<цифр>}` - серийный номер,
+//! `{YYYY}` `{YY}` `{MM}` `{DD}` `{JJJ}` `{HH}` `{MI}` - дата и время, `{SHIFT}` - смена,
+//! `{PLC}` - текст от ПЛК по Modbus
+
+use core::fmt::Write;
+
+use crate::config;
+use crate::config::HlString as String;
+use crate::support::datetime::DateTime;
+
+/// Серийный номер не длиннее
+const SERIAL_MAX_DIGITS: usize = 10;
+
+/// Значения полей на момент маркировки
+pub struct Fields<'a> {
+    pub serial: u32,
+    pub plc: &'a str,
+    pub time: Option<DateTime>,
+    /// начала смен, часы, < 0 - смены нет
+    pub shifts: [f32; 3],
+}
+
+/// Подставляет поля, true - использован серийный номер
+pub fn expand(template: &str, fields: &Fields, out: &mut String) -> Result<bool, String> {
+    let mut serial_used = false;
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]).map_err(|_| too_long())?;
+        let (field, tail) = rest[start + 1..]
+            .split_once('}')
+            .ok_or_else(|| String::from("Unterminated template field"))?;
+        rest = tail;
+
+        let (name, width) = match field.split_once(':') {
+            Some((name, width)) => (
+                name,
+                width
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|w| *w <= SERIAL_MAX_DIGITS)
+                    .ok_or_else(|| String::from("Invalid serial width"))?,
+            ),
+            None => (field, 0),
+        };
+
+        let res = match field_name(name).as_str() {
+            "SN" => {
+                serial_used = true;
+                write!(out, "{:0w$}", fields.serial, w = width)
+            }
+            "PLC" => out.write_str(fields.plc),
+            "SHIFT" => {
+                let c = shift(&fields.shifts, clock(fields)?)
+                    .ok_or_else(|| String::from("No shifts set"))?;
+                write!(out, "{}", c)
+            }
+            "YYYY" => write!(out, "{:04}", clock(fields)?.year),
+            "YY" => write!(out, "{:02}", clock(fields)?.year % 100),
+            "MM" => write!(out, "{:02}", clock(fields)?.month),
+            "DD" => write!(out, "{:02}", clock(fields)?.day),
+            "JJJ" => write!(out, "{:03}", clock(fields)?.yday),
+            "HH" => write!(out, "{:02}", clock(fields)?.hour),
+            "MI" => write!(out, "{:02}", clock(fields)?.minute),
+            _ => {
+                let mut s = String::new();
+                let _ = write!(&mut s, "Unknown template field {{{}}}", name);
+                return Err(s);
+            }
+        };
+        res.map_err(|_| too_long())?;
+    }
+    out.push_str(rest).map_err(|_| too_long())?;
+    Ok(serial_used)
+}
+
+/// Имя поля без учета регистра: `{sn}` - то же, что `{SN}`
+fn field_name(name: &str) -> heapless::String<8> {
+    name.chars()
+        .take(8)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Длина текста после подстановки не больше (для габарита без значений полей)
+pub fn max_len(template: &str) -> usize {
+    let mut len = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        len += start;
+        match rest[start + 1..].split_once('}') {
+            Some((field, tail)) => {
+                len += match field_name(field).as_str() {
+                    "SHIFT" => 1,
+                    "PLC" => config::PLC_TEXT_MAX_LEN,
+                    f if f.starts_with("SN") => SERIAL_MAX_DIGITS,
+                    _ => field.len(),
+                };
+                rest = tail;
+            }
+            None => return len + rest.len() - start,
+        }
+    }
+    len + rest.len()
+}
+
+/// Буква смены: A, B, C по `shifts`, смена идет с начала до начала следующей
+/// (последняя - через полночь)
+pub fn shift(shifts: &[f32; 3], time: &DateTime) -> Option<char> {
+    let now = time.hour as f32 + time.minute as f32 / 60.0;
+
+    // последняя начавшаяся сегодня, иначе - начавшаяся вчера
+    let mut today: Option<(f32, char)> = None;
+    let mut latest: Option<(f32, char)> = None;
+    for (&start, c) in shifts.iter().zip(['A', 'B', 'C']) {
+        if start < 0.0 {
+            continue;
+        }
+        if latest.map_or(true, |(s, _)| start > s) {
+            latest = Some((start, c));
+        }
+        if start <= now && today.map_or(true, |(s, _)| start > s) {
+            today = Some((start, c));
+        }
+    }
+    today.or(latest).map(|(_, c)| c)
+}
+
+fn clock<'a>(fields: &'a Fields) -> Result<&'a DateTime, String> {
+    fields
+        .time
+        .as_ref()
+        .ok_or_else(|| String::from("Clock not set, use $T=YYYY-MM-DD HH:MM:SS"))
+}
+
+fn too_long() -> String {
+    "Text too long".into()
+}
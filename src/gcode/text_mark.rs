@@ -0,0 +1,129 @@
+//! M150: текст однолинейным шрифтом. Отрезки подаются в MotionMGR по одному
+//! как G0 (перо поднято) и G1, проходят ту же интерполяцию, перья, skywriting и точки
+
+use crate::config::HlString as String;
+
+use super::figure::{Stroke, StrokeKind};
+use super::font;
+use super::transform::Transform;
+use super::GCode;
+
+/// Начало базовой линии, высота прописных, интервал и поворот текста
+#[derive(Clone, Copy)]
+pub struct TextLayout {
+    place: Transform,
+    /// мм на единицу сетки шрифта
+    scale: f32,
+    /// шаг знакомест, мм
+    pitch: f32,
+}
+
+impl TextLayout {
+    /// `P<высота> [L<интервал>] [R<угол>]`, (x, y) - начало базовой линии
+    pub fn from_gcode(gcode: &GCode, x: f32, y: f32) -> Result<Self, String> {
+        let height = match gcode.get_p() {
+            Some(h) if h > 0.0 => h,
+            Some(_) => return Err("Text height below limit".into()),
+            None => return Err("Text height (P) required".into()),
+        };
+        let scale = height / font::HEIGHT;
+        let gap = match gcode.get_l() {
+            Some(l) if l >= 0.0 => l,
+            Some(_) => return Err("Letter spacing below limit".into()),
+            None => font::GAP * scale,
+        };
+
+        Ok(Self {
+            place: Transform::new(x, y, gcode.get_r().unwrap_or_default()),
+            scale,
+            pitch: font::WIDTH * scale + gap,
+        })
+    }
+
+    /// Углы рамки `chars` знаков
+    pub fn corners(&self, chars: usize) -> [(f32, f32); 4] {
+        let width = self.pitch * chars.saturating_sub(1) as f32 + font::WIDTH * self.scale;
+        let (bottom, top) = (font::DESCENT * self.scale, font::ASCENT * self.scale);
+        [
+            self.place.apply(0.0, bottom),
+            self.place.apply(width, bottom),
+            self.place.apply(width, top),
+            self.place.apply(0.0, top),
+        ]
+    }
+
+    /// Точка сетки шрифта знака index -> координаты программы
+    fn place(&self, index: usize, (x, y): (f32, f32)) -> (f32, f32) {
+        self.place
+            .apply(index as f32 * self.pitch + x * self.scale, y * self.scale)
+    }
+}
+
+/// Текст, разложенный на перемещения
+#[derive(Clone)]
+pub struct TextMark {
+    text: String,
+    layout: TextLayout,
+
+    index: usize,
+    point: usize,
+    pen_up: bool,
+}
+
+impl TextMark {
+    pub fn new(text: String, layout: TextLayout) -> Self {
+        Self {
+            text,
+            layout,
+            index: 0,
+            point: 0,
+            pen_up: true,
+        }
+    }
+
+    /// Следующее перемещение без продвижения, None - текст закончен
+    pub fn peek(&self) -> Option<Stroke> {
+        self.locate().map(|(_, _, stroke)| stroke)
+    }
+
+    /// Следующая точка после текущей: (знак, точка в знаке, перемещение)
+    fn locate(&self) -> Option<(usize, usize, Stroke)> {
+        let (mut index, mut point, mut pen_up) = (self.index, self.point, self.pen_up);
+        loop {
+            let glyph = font::glyph(*self.text.as_bytes().get(index)?);
+            match glyph.get(point).map(|p| font::point(*p)) {
+                None => {
+                    index += 1;
+                    point = 0;
+                    pen_up = true;
+                }
+                Some(None) => {
+                    point += 1;
+                    pen_up = true;
+                }
+                Some(Some(p)) => {
+                    let (x, y) = self.layout.place(index, p);
+                    let kind = if pen_up {
+                        StrokeKind::Jump
+                    } else {
+                        StrokeKind::Mark
+                    };
+                    let stroke = Stroke { kind, x, y };
+                    return Some((index, point + 1, stroke));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TextMark {
+    type Item = Stroke;
+
+    fn next(&mut self) -> Option<Stroke> {
+        let (index, point, stroke) = self.locate()?;
+        self.index = index;
+        self.point = point;
+        self.pen_up = false;
+        Some(stroke)
+    }
+}
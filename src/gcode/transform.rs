@@ -0,0 +1,32 @@
+//! Step-and-repeat: поворот вокруг начала координат программы, затем смещение
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    dx: f32,
+    dy: f32,
+    cos: f32,
+    sin: f32,
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self {
+        dx: 0.0,
+        dy: 0.0,
+        cos: 1.0,
+        sin: 0.0,
+    };
+
+    /// angle - градусы против часовой стрелки
+    pub fn new(dx: f32, dy: f32, angle: f32) -> Self {
+        let (sin, cos) = crate::support::sin_cos(angle.to_radians());
+        Self { dx, dy, cos, sin }
+    }
+
+    /// координаты программы -> координаты поля
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            x * self.cos - y * self.sin + self.dx,
+            x * self.sin + y * self.cos + self.dy,
+        )
+    }
+}
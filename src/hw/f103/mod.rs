@@ -3,3 +3,86 @@ mod clock_config_72;
 pub(crate) use clock_config_72::{
     ADC_DEVIDER, AHB_DEVIDER, APB1_DEVIDER, APB2_DEVIDER, PLL_MUL, PLL_P_DIV, USB_DEVIDER,
 };
+
+//-----------------------------------------------------------------------------
+
+use stm32f1xx_hal::{
+    device::rcc::cfgr::PLLXTPRE_A,
+    rcc::{AdcPre, HPre, PPre, UsbPre},
+};
+
+const fn ahb_div(div: HPre) -> u32 {
+    match div {
+        HPre::DIV1 => 1,
+        HPre::DIV2 => 2,
+        HPre::DIV4 => 4,
+        HPre::DIV8 => 8,
+        HPre::DIV16 => 16,
+        HPre::DIV64 => 64,
+        HPre::DIV128 => 128,
+        HPre::DIV256 => 256,
+        HPre::DIV512 => 512,
+    }
+}
+
+const fn apb_div(div: PPre) -> u32 {
+    match div {
+        PPre::DIV1 => 1,
+        PPre::DIV2 => 2,
+        PPre::DIV4 => 4,
+        PPre::DIV8 => 8,
+        PPre::DIV16 => 16,
+    }
+}
+
+const fn adc_div(div: AdcPre) -> u32 {
+    match div {
+        AdcPre::DIV2 => 2,
+        AdcPre::DIV4 => 4,
+        AdcPre::DIV6 => 6,
+        AdcPre::DIV8 => 8,
+    }
+}
+
+// Проверка тактирования при сборке: другой кварц (config::XTAL_FREQ) или
+// другие делители не должны давать частоты вне RM0008
+const _: () = {
+    let xtal = crate::config::XTAL_FREQ;
+    assert!(
+        xtal >= 4_000_000 && xtal <= 16_000_000,
+        "XTAL_FREQ: HSE must be 4-16 MHz"
+    );
+    assert!(PLL_MUL >= 2 && PLL_MUL <= 16, "PLL_MUL must be 2-16");
+
+    let pll_in = match PLL_P_DIV {
+        PLLXTPRE_A::DIV1 => xtal,
+        PLLXTPRE_A::DIV2 => xtal / 2,
+    };
+    let sysclk = pll_in * PLL_MUL;
+    assert!(sysclk <= 72_000_000, "SYSCLK above 72 MHz, check XTAL_FREQ / PLL_MUL");
+
+    let hclk = sysclk / ahb_div(AHB_DEVIDER);
+    assert!(hclk <= 72_000_000, "HCLK above 72 MHz");
+    assert!(hclk / apb_div(APB1_DEVIDER) <= 36_000_000, "PCLK1 above 36 MHz");
+    assert!(hclk / apb_div(APB2_DEVIDER) <= 72_000_000, "PCLK2 above 72 MHz");
+    assert!(
+        hclk / apb_div(APB2_DEVIDER) / adc_div(ADC_DEVIDER) <= 14_000_000,
+        "ADC clock above 14 MHz"
+    );
+
+    let usbclk = match USB_DEVIDER {
+        UsbPre::DIV1 => sysclk,
+        UsbPre::DIV1_5 => sysclk * 2 / 3,
+    };
+    assert!(usbclk == 48_000_000, "USB clock must be 48 MHz, check XTAL_FREQ / PLL_MUL");
+
+    // TIM2 (гальво) от APB1, при делителе > 1 частота таймера x2
+    let pclk1_tim = match APB1_DEVIDER {
+        PPre::DIV1 => hclk,
+        _ => hclk / apb_div(APB1_DEVIDER) * 2,
+    };
+    assert!(
+        pclk1_tim % crate::config::GALVO_CLOCK_RATE == 0,
+        "GALVO_CLOCK_RATE does not divide the TIM2 clock"
+    );
+};
@@ -1,6 +1,8 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 #![feature(macro_metavar_expr)]
+// тесты собираются для хоста без приложения RTIC, большая часть кода не используется
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 mod config;
 mod control;
@@ -8,31 +10,37 @@ mod gcode;
 mod hw;
 mod support;
 
-use panic_abort as _;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
 use rtic::app;
 
 use stm32f1xx_hal::afio::AfioExt;
 use stm32f1xx_hal::dma::DmaExt;
 use stm32f1xx_hal::flash::FlashExt;
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
+use stm32f1xx_hal::gpio::ErasedPin;
 use stm32f1xx_hal::gpio::{
-    Floating, GpioExt, Input, Output, PushPull, PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7, PA9, PB3,
-    PB4, PB5, PB6, PC13, PC14, PC15,
+    Floating, GpioExt, Input, Output, PullDown, PullUp, PushPull, PA0, PA1, PA2, PA3, PA4, PA5,
+    PA6, PA7, PB0, PB1, PB12, PB13, PB14, PB15, PB2, PB3, PB4, PB5, PB6, PC13, PC14, PC15,
 };
 use stm32f1xx_hal::rcc::{HPre, PPre};
 use stm32f1xx_hal::time::Hertz;
 use stm32f1xx_hal::timer::{PwmChannel, Timer};
 use stm32f1xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
+use stm32f1xx_hal::watchdog::IndependentWatchdog;
 
 use stm32f1xx_hal::dma::dma1;
-use stm32f1xx_hal::pac::{Interrupt, TIM1, TIM2, TIM4};
+use stm32f1xx_hal::pac::{Interrupt, TIM1, TIM2, TIM4, USART3};
 
-use usb_device::prelude::{UsbDevice, UsbDeviceBuilder};
+use usb_device::prelude::{UsbDevice, UsbDeviceBuilder, UsbDeviceState};
 
 use usbd_serial::SerialPort;
 
 use systick_monotonic::Systick;
 
 use support::clocking::{ClockConfigProvider, MyConfig};
+use support::nv_storage::stm32f1_flash::InternalFlash;
+use support::rtc::stm32f1_rtc::{RtcSource, Stm32f1Rtc};
 
 use control::xy2_100::XY2_100Interface;
 
@@ -40,6 +48,77 @@ use hw::{ADC_DEVIDER, AHB_DEVIDER, APB1_DEVIDER, APB2_DEVIDER, PLL_MUL, PLL_P_DI
 
 //-----------------------------------------------------------------------------
 
+// LSE занимает PC14/PC15 - биты аварии IPG/MOPA
+#[cfg(all(
+    feature = "rtc-lse",
+    any(feature = "laser-ipg", feature = "laser-mopa")
+))]
+compile_error!("rtc-lse uses PC14/PC15, the laser alarm bus of laser-ipg/laser-mopa");
+
+/// Часовой кварц с батареей VBAT или HSE/128 (часы сбрасываются при выключении)
+#[cfg(feature = "rtc-lse")]
+const RTC_SOURCE: RtcSource = RtcSource::Lse;
+#[cfg(not(feature = "rtc-lse"))]
+const RTC_SOURCE: RtcSource = RtcSource::Hse(config::XTAL_FREQ);
+
+//-----------------------------------------------------------------------------
+
+/// от хоста пришла строка, сбрасывает таймаут молчания
+static HOST_ACTIVITY: AtomicBool = AtomicBool::new(false);
+/// USB был сконфигурирован
+static USB_CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// USB ушел в suspend или отключен после конфигурации
+static USB_LINK_LOST: AtomicBool = AtomicBool::new(false);
+/// USB сконфигурирован хостом
+static USB_CONNECTED: AtomicBool = AtomicBool::new(false);
+/// USB в бинарном протоколе `$B`: G-коды без ответа "ok", подтверждает кадр
+static BINARY_MODE: AtomicBool = AtomicBool::new(false);
+/// язык строк с USB ($310) для разбора в прерывании USB
+static INPUT_DIALECT: AtomicU8 = AtomicU8::new(gcode::InputDialect::GCode as u8);
+/// протокол ответов ($330): номера строк Marlin проверяются в прерывании USB
+static MARLIN_MODE: AtomicBool = AtomicBool::new(false);
+/// порт Modbus RTU ($340): байты USART3 или USB идут в очередь Modbus
+static MODBUS_LINK: AtomicU8 = AtomicU8::new(gcode::ModbusLink::Off as u8);
+
+//-----------------------------------------------------------------------------
+
+/// Лазер выключается первым, затем отчет для следующей загрузки и перезапуск
+#[cfg(not(test))]
+#[inline(never)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    unsafe {
+        control::laser::emergency_off();
+        support::crash::save_panic(info);
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[cfg(not(test))]
+#[cortex_m_rt::exception]
+unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    control::laser::emergency_off();
+    support::crash::save_fault(ef);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// CSS: кварц отказал, SYSCLK уже переключен на HSI и все частоты таймеров неверны.
+/// После перезапуска кварц не запустится и freeze перейдет на HSI
+#[cfg(not(test))]
+#[cortex_m_rt::exception]
+unsafe fn NonMaskableInt() {
+    let rcc = &*stm32f1xx_hal::device::RCC::ptr();
+    if rcc.cir.read().cssf().bit_is_set() {
+        control::laser::emergency_off();
+        rcc.cir.write(|w| w.cssc().set_bit());
+        support::crash::save_message("HSE failure (CSS)");
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+//-----------------------------------------------------------------------------
+
 struct HighPerformanceClockConfigProvider;
 
 impl HighPerformanceClockConfigProvider {
@@ -78,10 +157,81 @@ impl HighPerformanceClockConfigProvider {
         }
     }
 
-    fn freeze(_acr: &mut stm32f1xx_hal::flash::ACR) -> stm32f1xx_hal::rcc::Clocks {
+    /// Ждать готовности генератора не дольше CLOCK_STARTUP_TIMEOUT_MS, ядро еще на HSI 8 МГц
+    fn wait_ready<F: Fn() -> bool>(ready: F) -> bool {
+        const POLLS_PER_MS: u32 = 8; // по 1000 тактов
+
+        for _ in 0..crate::config::CLOCK_STARTUP_TIMEOUT_MS * POLLS_PER_MS {
+            if ready() {
+                return true;
+            }
+            cortex_m::asm::delay(1000);
+        }
+        ready()
+    }
+
+    /// Без кварца: HSI / 2 * 12 = 48 МГц, USB работает без делителя
+    fn hsi_config() -> MyConfig {
+        MyConfig {
+            hse_p_div: PLL_P_DIV,
+            hse: None,
+            pllmul: Some(Self::pll_mul_bits(12)),
+            hpre: HPre::DIV1,
+            ppre1: PPre::DIV2,
+            ppre2: PPre::DIV1,
+            usbpre: stm32f1xx_hal::rcc::UsbPre::DIV1,
+            adcpre: stm32f1xx_hal::rcc::AdcPre::DIV4,
+        }
+    }
+
+    /// Без кварца и PLL: HSI 8 МГц, USB не работает
+    fn hsi_direct_config() -> MyConfig {
+        MyConfig {
+            hse_p_div: PLL_P_DIV,
+            hse: None,
+            pllmul: None,
+            hpre: HPre::DIV1,
+            ppre1: PPre::DIV1,
+            ppre2: PPre::DIV1,
+            usbpre: stm32f1xx_hal::rcc::UsbPre::DIV1,
+            adcpre: stm32f1xx_hal::rcc::AdcPre::DIV2,
+        }
+    }
+
+    /// Возвращает частоты и аварию, если кварц или PLL не запустились
+    fn freeze(
+        _acr: &mut stm32f1xx_hal::flash::ACR,
+    ) -> (stm32f1xx_hal::rcc::Clocks, Option<gcode::Alarm>) {
         use stm32f1xx_hal::time::MHz;
 
-        let cfg = Self::to_config();
+        let rcc = unsafe { &*stm32f1xx_hal::device::RCC::ptr() };
+
+        // enable HSE and wait for it to be ready, no crystal - HSI
+        rcc.cr.modify(|_, w| w.hseon().set_bit());
+        let mut hse_ok = Self::wait_ready(|| rcc.cr.read().hserdy().bit_is_set());
+
+        let mut alarm = None;
+        let mut cfg = Self::to_config();
+        if hse_ok {
+            // PLL от кварца
+            Self::start_pll(&cfg);
+            if !Self::wait_ready(|| rcc.cr.read().pllrdy().bit_is_set()) {
+                rcc.cr.modify(|_, w| w.pllon().clear_bit());
+                hse_ok = false;
+            }
+        }
+        if !hse_ok {
+            rcc.cr.modify(|_, w| w.hseon().clear_bit());
+            cfg = Self::hsi_config();
+            alarm = Some(gcode::Alarm::ClockFailure);
+            Self::start_pll(&cfg);
+            if !Self::wait_ready(|| rcc.cr.read().pllrdy().bit_is_set()) {
+                // перезапуск не поможет: ядро остается на HSI, лазер заблокирован аварией
+                rcc.cr.modify(|_, w| w.pllon().clear_bit());
+                cfg = Self::hsi_direct_config();
+                alarm = Some(gcode::Alarm::PllFailure);
+            }
+        }
 
         let clocks = cfg.get_clocks();
         // adjust flash wait states
@@ -98,33 +248,6 @@ impl HighPerformanceClockConfigProvider {
             })
         }
 
-        let rcc = unsafe { &*stm32f1xx_hal::device::RCC::ptr() };
-
-        if cfg.hse.is_some() {
-            // enable HSE and wait for it to be ready
-
-            rcc.cr.modify(|_, w| w.hseon().set_bit());
-
-            while rcc.cr.read().hserdy().bit_is_clear() {}
-        }
-
-        if let Some(pllmul_bits) = cfg.pllmul {
-            // enable PLL and wait for it to be ready
-
-            #[allow(unused_unsafe)]
-            rcc.cfgr
-                .modify(|_, w| unsafe { w.pllxtpre().variant(PLL_P_DIV) });
-
-            #[allow(unused_unsafe)]
-            rcc.cfgr.modify(|_, w| unsafe {
-                w.pllmul().bits(pllmul_bits).pllsrc().bit(cfg.hse.is_some())
-            });
-
-            rcc.cr.modify(|_, w| w.pllon().set_bit());
-
-            while rcc.cr.read().pllrdy().bit_is_clear() {}
-        }
-
         rcc.cfgr.modify(|_, w| unsafe {
             w.adcpre().variant(cfg.adcpre);
             w.ppre2()
@@ -148,7 +271,30 @@ impl HighPerformanceClockConfigProvider {
                 })
         });
 
-        clocks
+        if hse_ok {
+            // отказ кварца -> NMI
+            rcc.cr.modify(|_, w| w.csson().set_bit());
+        }
+
+        (clocks, alarm)
+    }
+
+    /// enable PLL, готовность ждет вызывающий
+    fn start_pll(cfg: &MyConfig) {
+        let rcc = unsafe { &*stm32f1xx_hal::device::RCC::ptr() };
+
+        if let Some(pllmul_bits) = cfg.pllmul {
+            #[allow(unused_unsafe)]
+            rcc.cfgr
+                .modify(|_, w| unsafe { w.pllxtpre().variant(cfg.hse_p_div) });
+
+            #[allow(unused_unsafe)]
+            rcc.cfgr.modify(|_, w| unsafe {
+                w.pllmul().bits(pllmul_bits).pllsrc().bit(cfg.hse.is_some())
+            });
+
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+        }
     }
 }
 
@@ -221,6 +367,26 @@ crate::simple_parallel_input_bus! { LaserAlarmBus: u8 =>
     )
 }
 
+// Выходы M62-M65
+crate::simple_parallel_output_bus! { AuxOutputBus: u8 =>
+    (
+        pin PB0<Output<PushPull>>,
+        pin PB1<Output<PushPull>>,
+        pin PB2<Output<PushPull>>
+    )
+}
+
+// Входы станка: дверь, E-stop, старт, ключ.
+// E-stop - нормально замкнутый контакт на землю: нажатие или обрыв провода дают 1
+crate::simple_parallel_input_bus! { MachineInputBus: u8 =>
+    (
+        pin PB12<Input<PullDown>>,
+        pin PB13<Input<PullUp>>,
+        pin PB14<Input<PullDown>>,
+        pin PB15<Input<PullDown>>
+    )
+}
+
 //-----------------------------------------------------------------------------
 
 type Galvo = control::xy2_100::XY2_100<
@@ -234,6 +400,22 @@ type Galvo = control::xy2_100::XY2_100<
     ),
 >;
 
+#[cfg(any(feature = "laser-ipg", feature = "laser-mopa"))]
+type Laser = control::laser::Laser<
+    LaserDataBus,
+    LaserAlarmBus,
+    ErasedPin<Output<PushPull>>,
+    PwmChannel<TIM4, 2>,
+    PwmChannel<TIM4, 3>,
+    PwmChannel<TIM4, 1>,
+    PwmChannel<TIM1, 2>,
+>;
+
+#[cfg(any(feature = "laser-co2", feature = "laser-diode"))]
+type Laser =
+    control::laser::PwmLaser<PwmChannel<TIM4, 2>, PwmChannel<TIM4, 3>, PwmChannel<TIM1, 2>>;
+
+#[cfg(not(test))]
 #[app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [RTCALARM])]
 mod app {
     use super::*;
@@ -244,22 +426,28 @@ mod app {
         serial: SerialPort<'static, UsbBus<Peripheral>>,
         gcode_queue: heapless::Deque<gcode::GCode, { config::GCODE_QUEUE_SIZE }>,
         request_queue: heapless::Deque<gcode::Request, { config::GCODE_QUEUE_SIZE }>,
+        jobs: gcode::JobStore<InternalFlash>,
+        uploads: gcode::UploadQueue,
+        pixels: gcode::PixelBuffer,
+        modbus_rx: heapless::Deque<u8, { config::MODBUS_RX_QUEUE_SIZE }>,
     }
 
     #[local]
     struct Local {
         motion_mgr: gcode::MotionMGR<
-            control::laser::Laser<
-                LaserDataBus,
-                LaserAlarmBus,
-                PA9<Output<PushPull>>,
-                PwmChannel<TIM4, 2>,
-                PwmChannel<TIM4, 3>,
-                PwmChannel<TIM4, 1>,
-                PwmChannel<TIM1, 2>,
-            >,
+            Laser,
             Galvo,
+            InternalFlash,
+            AuxOutputBus,
+            MachineInputBus,
+            Stm32f1Rtc,
         >,
+        watchdog: IndependentWatchdog,
+        job_runner: gcode::JobRunner,
+        modbus: support::modbus_rtu::ModbusSlave,
+        modbus_uart_rx: stm32f1xx_hal::serial::Rx<USART3>,
+        modbus_uart_tx: stm32f1xx_hal::serial::Tx<USART3>,
+        plc: gcode::PlcRegisters,
     }
 
     #[monotonic(binds = SysTick, default = true)]
@@ -269,6 +457,17 @@ mod app {
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         static mut USB_BUS: Option<usb_device::bus::UsbBusAllocator<UsbBusType>> = None;
 
+        // перезапуск по IWDG
+        let watchdog_reset = {
+            let rcc = unsafe { &*stm32f1xx_hal::device::RCC::ptr() };
+            let reset = rcc.csr.read().iwdgrstf().bit_is_set();
+            rcc.csr.modify(|_, w| w.rmvf().set_bit());
+            reset
+        };
+
+        // отчет о падении до перезагрузки выводится по $I
+        support::crash::check_previous();
+
         let mut flash = ctx.device.FLASH.constrain();
 
         let dma_channels = ctx.device.DMA1.split();
@@ -306,10 +505,31 @@ mod app {
             gpioc.pc15.into_floating_input(&mut gpioc.crh),
         );
 
-        let clocks = HighPerformanceClockConfigProvider::freeze(&mut flash.acr);
+        let aux_outputs = AuxOutputBus(
+            gpiob.pb0.into_push_pull_output(&mut gpiob.crl),
+            gpiob.pb1.into_push_pull_output(&mut gpiob.crl),
+            gpiob.pb2.into_push_pull_output(&mut gpiob.crl),
+        );
+
+        let machine_inputs = MachineInputBus(
+            gpiob.pb12.into_pull_down_input(&mut gpiob.crh),
+            gpiob.pb13.into_pull_up_input(&mut gpiob.crh),
+            gpiob.pb14.into_pull_down_input(&mut gpiob.crh),
+            gpiob.pb15.into_pull_down_input(&mut gpiob.crh),
+        );
+
+        let (clocks, clock_alarm) = HighPerformanceClockConfigProvider::freeze(&mut flash.acr);
 
         let mono = Systick::new(ctx.core.SYST, clocks.sysclk().to_Hz());
 
+        let rtc = Stm32f1Rtc::new(
+            ctx.device.RTC,
+            ctx.device.BKP,
+            &ctx.device.PWR,
+            RTC_SOURCE,
+            clocks.sysclk().to_Hz(),
+        );
+
         let laser_pwm_tim_clocks = clocks.pclk1_tim();
         let (l_sync, l_em, l_ee) = Timer::new(ctx.device.TIM4, &clocks)
             .pwm_hz(
@@ -373,9 +593,26 @@ mod app {
 
         galvo_ctrl.begin(clocks.pclk1_tim());
 
+        #[cfg(feature = "laser-ipg")]
+        let laser = control::laser::Laser::new(
+            &control::laser::IPG_TYPE_D,
+            laser_power_bus,
+            Some(gpioa.pa9.into_push_pull_output(&mut gpioa.crh).erase()),
+            None,
+            laser_alarm_bus,
+            l_em,
+            l_ee,
+            l_sync,
+            laser_red_beam_pwm,
+            laser_pwm_tim_clocks,
+        );
+
+        #[cfg(feature = "laser-mopa")]
         let laser = control::laser::Laser::new(
+            &control::laser::JPT_MOPA,
             laser_power_bus,
-            Some(gpioa.pa9.into_push_pull_output(&mut gpioa.crh)),
+            Some(gpioa.pa9.into_push_pull_output(&mut gpioa.crh).erase()),
+            Some(gpioa.pa8.into_push_pull_output(&mut gpioa.crh).erase()),
             laser_alarm_bus,
             l_em,
             l_ee,
@@ -384,9 +621,83 @@ mod app {
             laser_pwm_tim_clocks,
         );
 
-        let mut motion_mgr = gcode::MotionMGR::new(galvo_ctrl, laser, config::GCODE_QUEUE_SIZE);
+        #[cfg(any(feature = "laser-co2", feature = "laser-diode"))]
+        let laser = {
+            // шина D[0..7], сигналы состояния и SYNC не используются
+            let _ = (laser_power_bus, laser_alarm_bus, l_sync);
+
+            #[cfg(feature = "laser-co2")]
+            let limits = &control::laser::CO2_PWM;
+            #[cfg(feature = "laser-diode")]
+            let limits = &control::laser::DIODE_TTL;
+
+            control::laser::PwmLaser::new(
+                limits,
+                l_em,
+                l_ee,
+                laser_red_beam_pwm,
+                laser_pwm_tim_clocks,
+            )
+        };
+
+        let settings_storage =
+            InternalFlash::new(config::SETTINGS_FLASH_ADDR, config::SETTINGS_FLASH_SIZE);
+
+        let mut motion_mgr = gcode::MotionMGR::new(
+            galvo_ctrl,
+            laser,
+            settings_storage,
+            aux_outputs,
+            machine_inputs,
+            rtc,
+            config::GCODE_QUEUE_SIZE,
+        );
+
+        let jobs = gcode::JobStore::new(InternalFlash::new(
+            config::JOB_FLASH_ADDR,
+            config::JOB_FLASH_SIZE,
+        ));
 
         motion_mgr.begin();
+        if watchdog_reset {
+            motion_mgr.raise_alarm(gcode::Alarm::Watchdog);
+        }
+        if let Some(alarm) = clock_alarm {
+            motion_mgr.raise_alarm(alarm);
+        }
+
+        //---------------------------------------------------------------------
+
+        // Modbus RTU: USART3 на PB10/PB11. USART1 занят: PA9/PA10 - LATCH и RED_LASER,
+        // с ремапом PB6/PB7 - GALVO и LSYNC,
+        // скорость $342 - только при запуске
+        let (modbus_address, modbus_baud) = motion_mgr.modbus_config();
+        let mut modbus = support::modbus_rtu::ModbusSlave::new();
+        modbus.configure(modbus_address, modbus_baud);
+
+        let (modbus_uart_tx, mut modbus_uart_rx) = stm32f1xx_hal::serial::Serial::usart3(
+            ctx.device.USART3,
+            (
+                gpiob.pb10.into_alternate_push_pull(&mut gpiob.crh),
+                gpiob.pb11,
+            ),
+            &mut afio.mapr,
+            stm32f1xx_hal::serial::Config::default()
+                .baudrate(stm32f1xx_hal::time::Bps(modbus_baud)),
+            clocks,
+        )
+        .split();
+        modbus_uart_rx.listen();
+
+        //---------------------------------------------------------------------
+
+        // IWDG не считает, пока ядро остановлено отладчиком
+        ctx.device.DBGMCU.cr.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+
+        let mut watchdog = IndependentWatchdog::new(ctx.device.IWDG);
+        watchdog.start(systick_monotonic::fugit::MillisDurationU32::millis(
+            config::WATCHDOG_TIMEOUT_MS,
+        ));
 
         //---------------------------------------------------------------------
 
@@ -396,50 +707,119 @@ mod app {
                 serial,
                 gcode_queue: heapless::Deque::new(),
                 request_queue: heapless::Deque::new(),
+                jobs,
+                uploads: gcode::UploadQueue::new(),
+                pixels: gcode::PixelBuffer::new(),
+                modbus_rx: heapless::Deque::new(),
+            },
+            Local {
+                motion_mgr,
+                watchdog,
+                job_runner: gcode::JobRunner::new(),
+                modbus,
+                modbus_uart_rx,
+                modbus_uart_tx,
+                plc: gcode::PlcRegisters::new(),
             },
-            Local { motion_mgr },
             init::Monotonics(mono),
         )
     }
 
     //-------------------------------------------------------------------------
 
-    #[task(binds = USB_HP_CAN_TX, shared = [usb_device, serial, gcode_queue, request_queue], priority = 1)]
+    #[task(binds = USB_HP_CAN_TX, shared = [usb_device, serial, gcode_queue, request_queue, uploads, pixels, modbus_rx], priority = 1)]
     fn usb_tx(ctx: usb_tx::Context) {
         let mut usb_device = ctx.shared.usb_device;
         let mut serial = ctx.shared.serial;
         let mut gcode_queue = ctx.shared.gcode_queue;
         let mut request_queue = ctx.shared.request_queue;
+        let mut uploads = ctx.shared.uploads;
+        let mut pixels = ctx.shared.pixels;
+        let mut modbus_rx = ctx.shared.modbus_rx;
 
         let gcode_pusher = move |gcode| gcode_queue.lock(|q| q.push_back(gcode));
         let request_pusher = move |request| request_queue.lock(|q| q.push_back(request));
+        let upload_sink = move |line: &str| uploads.lock(|u| u.upload_line(line));
+        let pixel_sink = move |data: gcode::PixelData| pixels.lock(|p| p.pixel_data(data));
+        let modbus_sink = move |data: &[u8]| {
+            modbus_rx.lock(|q| {
+                for &b in data {
+                    let _ = q.push_back(b);
+                }
+            })
+        };
 
         if !(&mut usb_device, &mut serial).lock(move |usb_device, serial| {
-            super::usb_poll(usb_device, serial, gcode_pusher, request_pusher)
+            super::usb_poll(
+                usb_device,
+                serial,
+                gcode_pusher,
+                request_pusher,
+                upload_sink,
+                pixel_sink,
+                modbus_sink,
+            )
         }) {
             cortex_m::peripheral::NVIC::mask(Interrupt::USB_HP_CAN_TX);
             cortex_m::peripheral::NVIC::mask(Interrupt::USB_LP_CAN_RX0);
         }
     }
 
-    #[task(binds = USB_LP_CAN_RX0, shared = [usb_device, serial, gcode_queue, request_queue], priority = 1)]
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_device, serial, gcode_queue, request_queue, uploads, pixels, modbus_rx], priority = 1)]
     fn usb_rx0(ctx: usb_rx0::Context) {
         let mut usb_device = ctx.shared.usb_device;
         let mut serial = ctx.shared.serial;
         let mut gcode_queue = ctx.shared.gcode_queue;
         let mut request_queue = ctx.shared.request_queue;
+        let mut uploads = ctx.shared.uploads;
+        let mut pixels = ctx.shared.pixels;
+        let mut modbus_rx = ctx.shared.modbus_rx;
 
         let gcode_pusher = move |gcode| gcode_queue.lock(|q| q.push_back(gcode));
         let request_pusher = move |request| request_queue.lock(|q| q.push_back(request));
+        let upload_sink = move |line: &str| uploads.lock(|u| u.upload_line(line));
+        let pixel_sink = move |data: gcode::PixelData| pixels.lock(|p| p.pixel_data(data));
+        let modbus_sink = move |data: &[u8]| {
+            modbus_rx.lock(|q| {
+                for &b in data {
+                    let _ = q.push_back(b);
+                }
+            })
+        };
 
         if !(&mut usb_device, &mut serial).lock(move |usb_device, serial| {
-            super::usb_poll(usb_device, serial, gcode_pusher, request_pusher)
+            super::usb_poll(
+                usb_device,
+                serial,
+                gcode_pusher,
+                request_pusher,
+                upload_sink,
+                pixel_sink,
+                modbus_sink,
+            )
         }) {
             cortex_m::peripheral::NVIC::mask(Interrupt::USB_HP_CAN_TX);
             cortex_m::peripheral::NVIC::mask(Interrupt::USB_LP_CAN_RX0);
         }
     }
 
+    /// Modbus RTU: байты USART3 в очередь, кадры разбирает idle
+    #[task(binds = USART3, shared = [modbus_rx], local = [modbus_uart_rx], priority = 2)]
+    fn usart3(ctx: usart3::Context) {
+        use embedded_hal::serial::Read;
+
+        let rx = ctx.local.modbus_uart_rx;
+        let mut modbus_rx = ctx.shared.modbus_rx;
+
+        let active = MODBUS_LINK.load(Ordering::SeqCst) == gcode::ModbusLink::Usart as u8;
+        while let Ok(b) = rx.read() {
+            if active {
+                // очередь полна - кадр испорчен, мастер повторит запрос
+                let _ = modbus_rx.lock(|q| q.push_back(b));
+            }
+        }
+    }
+
     #[task(binds = DMA1_CHANNEL2, priority = 2)]
     fn dma1_ch2(_ctx: dma1_ch2::Context) {
         unsafe {
@@ -447,9 +827,16 @@ mod app {
         }
     }
 
+    #[task(binds = TIM4, priority = 3)]
+    fn tim4(_ctx: tim4::Context) {
+        unsafe {
+            Laser::pulse_event();
+        }
+    }
+
     //-------------------------------------------------------------------------
 
-    #[idle(shared=[gcode_queue, request_queue, serial], local = [motion_mgr])]
+    #[idle(shared=[gcode_queue, request_queue, serial, jobs, uploads, pixels, modbus_rx], local = [motion_mgr, watchdog, job_runner, modbus, modbus_uart_tx, plc])]
     fn idle(ctx: idle::Context) -> ! {
         use core::str::FromStr;
 
@@ -458,14 +845,29 @@ mod app {
         let mut gcode_queue = ctx.shared.gcode_queue;
         let mut request_queue = ctx.shared.request_queue;
         let mut serial = ctx.shared.serial;
+        let mut jobs = ctx.shared.jobs;
+        let mut uploads = ctx.shared.uploads;
+        let mut pixels = ctx.shared.pixels;
+        let mut modbus_rx = ctx.shared.modbus_rx;
 
         let mm = ctx.local.motion_mgr;
         //let mut mm = ctx.shared.motion_mgr;
+        let watchdog = ctx.local.watchdog;
+        let runner = ctx.local.job_runner;
+        let modbus = ctx.local.modbus;
+        let modbus_uart_tx = ctx.local.modbus_uart_tx;
+        let plc = ctx.local.plc;
+        // слот задания на прошлом проходе, для журнала событий
+        let mut last_slot = None;
 
         fn send<const N: usize>(
             serial: &mut shared_resources::serial_that_needs_to_be_locked,
             msg: Option<heapless::String<N>>,
         ) {
+            // USB занят Modbus RTU, текст сбил бы кадры
+            if MODBUS_LINK.load(Ordering::SeqCst) == gcode::ModbusLink::Usb as u8 {
+                return;
+            }
             if let Some(msg) = msg {
                 let _ = serial.lock(|s| s.write(msg.as_bytes()));
                 //rtic::pend(stm32f1xx_hal::device::Interrupt::USB_HP_CAN_TX);
@@ -473,11 +875,134 @@ mod app {
         }
 
         loop {
-            let res = if mm.tic(monotonics::MonoTimer::now().ticks() * NANOSEC_PER_SYSTICK)
-                == gcode::MotionStatus::IDLE
+            watchdog.feed();
+            INPUT_DIALECT.store(mm.input_dialect() as u8, Ordering::SeqCst);
+            let marlin = mm.personality() == gcode::Personality::Marlin;
+            MARLIN_MODE.store(marlin, Ordering::SeqCst);
+            let modbus_link = mm.modbus_link();
+            MODBUS_LINK.store(modbus_link as u8, Ordering::SeqCst);
+
+            if HOST_ACTIVITY.swap(false, Ordering::SeqCst) {
+                mm.host_activity();
+            }
+            if USB_LINK_LOST.swap(false, Ordering::SeqCst) {
+                mm.raise_alarm(gcode::Alarm::UsbLink);
+                uploads.lock(|u| u.cancel());
+                jobs.lock(|j| j.abort_upload());
+            }
+
+            // загрузка задания: запись во flash здесь, прерывание USB только ставит строки в очередь
+            if let Some(line) = uploads.lock(|u| u.take()) {
+                let (reply, uploading) = jobs.lock(|j| (j.upload_line(&line), j.is_uploading()));
+                uploads.lock(|u| u.processed(&line, uploading));
+                if let gcode::UploadResult::Reply(msg) = reply {
+                    send(&mut serial, Some(msg));
+                }
+                unsafe {
+                    cortex_m::peripheral::NVIC::unmask(Interrupt::USB_HP_CAN_TX);
+                    cortex_m::peripheral::NVIC::unmask(Interrupt::USB_LP_CAN_RX0);
+                }
+            }
+            if runner.is_running() {
+                // сохраненное задание не требует хоста
+                mm.host_activity();
+            }
+            if USB_CONNECTED.swap(false, Ordering::SeqCst) {
+                // авария до подключения хоста (IWDG, кварц) сообщается еще раз
+                mm.repeat_alarm();
+            }
+
+            let now = monotonics::MonoTimer::now().ticks() * NANOSEC_PER_SYSTICK;
+            mm.update_inputs(now);
+            let status = mm.tic(now);
+
+            // M160: пиксели строки растра из буфера USB, M170: байты файла ILDA. При аварии
+            // данные прерванного растра сбрасываются, чтобы прием не встал на полном буфере
+            let pixels_taken = if mm.alarm().is_some() {
+                pixels.lock(|p| p.clear());
+                true
+            } else {
+                match mm.ilda_source() {
+                    Some(gcode::IldaSource::Stream) => match pixels.lock(|p| mm.load_ilda(p)) {
+                        Ok(taken) => taken,
+                        Err(msg) => {
+                            send(&mut serial, Some(msg));
+                            true
+                        }
+                    },
+                    Some(gcode::IldaSource::Job(_)) => {
+                        if let Err(msg) = jobs.lock(|j| mm.load_ilda_job(j)) {
+                            send(&mut serial, Some(msg));
+                        }
+                        false
+                    }
+                    None => mm.raster_loading() && pixels.lock(|p| mm.load_raster(p)),
+                }
+            };
+            if pixels_taken {
+                unsafe {
+                    cortex_m::peripheral::NVIC::unmask(Interrupt::USB_HP_CAN_TX);
+                    cortex_m::peripheral::NVIC::unmask(Interrupt::USB_LP_CAN_RX0);
+                }
+            }
+
+            if let Some(msg) = mm.poll_alarm() {
+                // остаток программы не выполняется
+                gcode_queue.lock(|gcq| gcq.clear());
+                send(&mut serial, Some(msg));
+                if runner.is_running() {
+                    let msg = jobs.lock(|j| runner.abort(j, "alarm"));
+                    send(&mut serial, Some(msg));
+                }
+            }
+
+            // сохраненное задание: G-коды хоста ждут его окончания
+            let idle = status == gcode::MotionStatus::IDLE;
+            let job_active = runner.is_running() && mm.alarm().is_none();
+            if job_active {
+                let trigger = mm.job_trigger();
+                let msg = jobs.lock(|j| runner.poll(j, idle, trigger));
+                send(&mut serial, msg);
+            }
+
+            // step-and-repeat: следующая копия переносится после остановки зеркал
+            if idle {
+                if let Some(placement) = runner.take_placement() {
+                    mm.set_transform(placement);
+                }
+            }
+
+            if job_active {
+                let ready = match runner.peek() {
+                    Some(next) => {
+                        idle || (status == gcode::MotionStatus::RUNOUT && mm.chains_with(next))
+                    }
+                    None => false,
+                };
+                if ready {
+                    let mut gcode = runner.take().unwrap();
+                    if let Err(e) = mm.process(&mut gcode, config::GCODE_QUEUE_SIZE) {
+                        let msg = jobs.lock(|j| runner.abort(j, &e));
+                        send(&mut serial, Some(msg));
+                    }
+                }
+            }
+
+            let res = if !runner.is_running()
+                && (status == gcode::MotionStatus::IDLE || status == gcode::MotionStatus::RUNOUT)
             {
-                let (msg, avlb) =
-                    gcode_queue.lock(|gcq| (gcq.pop_front(), gcq.capacity() - gcq.len()));
+                let (msg, avlb) = gcode_queue.lock(|gcq| {
+                    // during skywriting run-out only a chained mark may be started
+                    let msg = match gcq.front() {
+                        Some(next)
+                            if status == gcode::MotionStatus::IDLE || mm.chains_with(next) =>
+                        {
+                            gcq.pop_front()
+                        }
+                        _ => None,
+                    };
+                    (msg, gcq.capacity() - gcq.len())
+                });
                 if let Some(mut gcode) = msg {
                     unsafe {
                         cortex_m::peripheral::NVIC::unmask(Interrupt::USB_HP_CAN_TX);
@@ -485,9 +1010,15 @@ mod app {
                     }
                     match mm.process(&mut gcode, avlb - 1) {
                         Ok(Some(s)) => Some(s),
+                        Ok(None) if BINARY_MODE.load(Ordering::SeqCst) => None,
+                        Ok(None) if marlin => {
+                            Some(unsafe { config::HlString::from_str("ok\n").unwrap_unchecked() })
+                        }
                         Ok(None) => {
                             Some(unsafe { config::HlString::from_str("ok\n\r").unwrap_unchecked() })
                         }
+                        // Marlin: ошибка не останавливает хост, ждущий "ok"
+                        Err(s) if marlin => Some(gcode::marlin_error(&s)),
                         Err(s) => Some(s),
                     }
                 } else {
@@ -499,46 +1030,149 @@ mod app {
 
             send(&mut serial, res);
 
-            let res = if let Some(req) = request_queue.lock(|rq| rq.pop_front()) {
-                Some(mm.process_status_req(&req))
+            // Modbus RTU: регистры ПЛК, команда исполняется как запрос хоста
+            let plc_command = if modbus_link != gcode::ModbusLink::Off {
+                let status = mm.plc_status();
+                jobs.lock(|j| plc.refresh(status, runner.slot(), j));
+
+                let mut port = super::ModbusIo {
+                    rx: &mut modbus_rx,
+                    usart: &mut *modbus_uart_tx,
+                    usb: if modbus_link == gcode::ModbusLink::Usb {
+                        Some(&mut serial)
+                    } else {
+                        None
+                    },
+                };
+                if modbus.poll(&mut port, &mut *plc, now) {
+                    mm.host_activity();
+                }
+
+                if let Some(text) = plc.take_text() {
+                    mm.set_plc_text(&text);
+                }
+                if let Some(sn) = plc.take_serial() {
+                    if let Err(msg) = mm.set_serial(sn) {
+                        send(&mut serial, Some(msg));
+                    }
+                }
+                plc.take_command()
             } else {
                 None
             };
+            let plc_request = match plc_command {
+                Some(gcode::PlcCommand::Abort) => {
+                    mm.raise_alarm(gcode::Alarm::PlcAbort);
+                    plc.command_done(true);
+                    None
+                }
+                Some(gcode::PlcCommand::Request(req)) => Some(req),
+                None => None,
+            };
+
+            let from_plc = plc_request.is_some();
+            let res = match plc_request.or_else(|| request_queue.lock(|rq| rq.pop_front())) {
+                Some(gcode::Request::Job(req)) => {
+                    let blocked = mm.is_busy() || mm.alarm().is_some();
+                    let trigger = mm.job_trigger();
+                    let origin = mm.job_origin();
+                    Some(jobs.lock(|j| runner.process_request(j, req, blocked, trigger, origin)))
+                }
+                Some(req) => Some(mm.process_status_req(&req)),
+                None => None,
+            };
+
+            // ответ на команду ПЛК - в регистр результата
+            let res = match res {
+                Some(r) if from_plc => {
+                    plc.command_done(r.is_ok());
+                    None
+                }
+                res => res,
+            };
 
             match res {
                 Some(Ok(msg)) => {
                     send(&mut serial, msg);
                 }
+                Some(Err(msg)) if marlin => {
+                    send(&mut serial, Some(gcode::marlin_error(&msg)));
+                }
                 Some(Err(msg)) => {
                     send(&mut serial, Some(msg));
                 }
                 _ => {}
             }
 
-            if !mm.is_busy() {
+            let slot = runner.slot();
+            if slot != last_slot {
+                if let Some(s) = last_slot {
+                    mm.log_event(gcode::Event::JobEnd(s));
+                }
+                if let Some(s) = slot {
+                    mm.log_event(gcode::Event::JobStart(s));
+                }
+                last_slot = slot;
+            }
+
+            if !mm.is_busy() && !runner.is_running() {
                 cortex_m::asm::wfi();
             }
         }
     }
 }
 
-fn usb_poll<B: usb_device::bus::UsbBus, GP, RP>(
+fn usb_poll<B: usb_device::bus::UsbBus, GP, RP, US, PS, MS>(
     usb_dev: &mut usb_device::prelude::UsbDevice<'static, B>,
     serial: &mut usbd_serial::SerialPort<'static, B>,
     gcode_pusher: GP,
-    request_pusher: RP,
+    mut request_pusher: RP,
+    upload_sink: US,
+    pixel_sink: PS,
+    mut modbus_sink: MS,
 ) -> bool
 where
     GP: FnMut(gcode::GCode) -> Result<(), gcode::GCode>,
     RP: FnMut(gcode::Request) -> Result<(), gcode::Request>,
+    US: FnMut(&str) -> gcode::UploadResult,
+    PS: FnMut(gcode::PixelData) -> gcode::PixelResult,
+    MS: FnMut(&[u8]),
     B: usb_device::bus::UsbBus,
 {
     use gcode::SerialErrResult;
     use heapless::String;
 
     static mut BUF: String<{ gcode::MAX_LEN }> = String::new();
+    static mut LINK: gcode::LinkState = gcode::LinkState::new();
+
+    let polled = usb_dev.poll(&mut [serial]);
+
+    let configured = usb_dev.state() == UsbDeviceState::Configured;
+    let was_configured = USB_CONFIGURED.swap(configured, Ordering::SeqCst);
+    if was_configured && !configured {
+        USB_LINK_LOST.store(true, Ordering::SeqCst);
+        unsafe { LINK.reset() };
+    } else if !was_configured && configured {
+        USB_CONNECTED.store(true, Ordering::SeqCst);
+    }
+
+    if !polled {
+        return true;
+    }
 
-    if !usb_dev.poll(&mut [serial]) {
+    // Modbus RTU по USB ($340=2): байты в очередь Modbus, кадры разбирает idle.
+    // Строка `$340=0` из терминала возвращает консоль G-кодов
+    if MODBUS_LINK.load(Ordering::SeqCst) == gcode::ModbusLink::Usb as u8 {
+        let mut data = [0u8; 64];
+        while let Ok(n) = serial.read(&mut data) {
+            if n == 0 {
+                break;
+            }
+            modbus_sink(&data[..n]);
+            if unsafe { LINK.modbus_escape.feed(&data[..n]) } {
+                let _ = request_pusher(gcode::Request::Setting(340, Some(0.0)));
+            }
+        }
         return true;
     }
 
@@ -553,19 +1187,47 @@ where
         }
     };
 
-    match gcode::serial_process(
+    // в бинарном протоколе BUF не используется
+    let binary = unsafe { LINK.binary.is_active() };
+    let dialect = match INPUT_DIALECT.load(Ordering::SeqCst) {
+        0 => gcode::InputDialect::GCode,
+        1 => gcode::InputDialect::Hpgl,
+        _ => gcode::InputDialect::Auto,
+    };
+    unsafe { LINK.hpgl.set_dialect(dialect) };
+    unsafe { LINK.marlin.set_enabled(MARLIN_MODE.load(Ordering::SeqCst)) };
+
+    let res = gcode::serial_process(
         serial,
         unsafe { &mut *core::ptr::addr_of_mut!(BUF) },
+        unsafe { &mut *core::ptr::addr_of_mut!(LINK) },
         gcode_pusher,
         request_pusher,
-    ) {
-        Ok(trimm_size) => trimm_buff(trimm_size),
+        upload_sink,
+        pixel_sink,
+    );
+    BINARY_MODE.store(unsafe { LINK.binary.is_active() }, Ordering::SeqCst);
+
+    match res {
+        Ok(trimm_size) => {
+            if trimm_size > 0 {
+                HOST_ACTIVITY.store(true, Ordering::SeqCst);
+            }
+            if !binary {
+                trimm_buff(trimm_size)
+            }
+        }
         Err(SerialErrResult::OutOfMemory) => {
             unsafe { BUF.clear() };
             serial.write(b"Command too long! (150)").unwrap();
         }
-        Err(SerialErrResult::Incomplead(_trimm_size)) => {
+        Err(SerialErrResult::Incomplead(trimm_size)) => {
             //serial.write(b"Command buffer full").unwrap();
+            HOST_ACTIVITY.store(true, Ordering::SeqCst);
+            // принятые очередью команды строки не повторяются
+            if !binary {
+                trimm_buff(trimm_size)
+            }
             return false;
         }
         _ => {}
@@ -573,3 +1235,33 @@ where
 
     true
 }
+
+/// Порт Modbus RTU для idle: байты из очереди приема, ответ - в USART3 или в USB
+struct ModbusIo<'a, RX, USB> {
+    rx: RX,
+    usart: &'a mut stm32f1xx_hal::serial::Tx<USART3>,
+    /// Some - Modbus по USB ($340=2)
+    usb: Option<USB>,
+}
+
+impl<RX, USB> support::modbus_rtu::ModbusPort for ModbusIo<'_, RX, USB>
+where
+    RX: rtic::Mutex<T = heapless::Deque<u8, { config::MODBUS_RX_QUEUE_SIZE }>>,
+    USB: rtic::Mutex<T = SerialPort<'static, UsbBus<Peripheral>>>,
+{
+    fn read(&mut self) -> Option<u8> {
+        self.rx.lock(|q| q.pop_front())
+    }
+
+    fn write(&mut self, data: &[u8]) -> usize {
+        use embedded_hal::serial::Write;
+
+        match self.usb.as_mut() {
+            Some(usb) => usb.lock(|s| s.write(data).unwrap_or(0)),
+            None => {
+                let usart = &mut *self.usart;
+                data.iter().take_while(|b| usart.write(**b).is_ok()).count()
+            }
+        }
+    }
+}
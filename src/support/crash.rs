@@ -0,0 +1,149 @@
+//! Отчет о падении (panic, HardFault), переживающий перезагрузку: лежит в RAM
+//! в секции `.noinit`, которую не обнуляет startup
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Записан обработчиком падения, еще не прочитан после перезагрузки
+const CRASH_NEW: u32 = 0xdead_c0de;
+/// Прочитан при загрузке, больше не сообщается
+const CRASH_SEEN: u32 = 0x5ee5_c0de;
+
+const TEXT_LEN: usize = 96;
+
+#[repr(u32)]
+#[derive(Clone, Copy)]
+enum Kind {
+    Panic = 1,
+    HardFault = 2,
+    Message = 3,
+}
+
+#[repr(C)]
+struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    text_len: u32,
+    text: [u8; TEXT_LEN],
+
+    // HardFault
+    pc: u32,
+    lr: u32,
+    xpsr: u32,
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+}
+
+#[link_section = ".noinit.CRASH"]
+static mut CRASH: core::mem::MaybeUninit<CrashRecord> = core::mem::MaybeUninit::uninit();
+
+/// Падение было до этой загрузки
+static PREVIOUS_CRASH: AtomicBool = AtomicBool::new(false);
+
+/// Пишет сколько влезет, остальное отбрасывает
+struct TextWriter<'a> {
+    buf: &'a mut [u8; TEXT_LEN],
+    len: usize,
+}
+
+impl<'a> Write for TextWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            if self.len == TEXT_LEN {
+                break;
+            }
+            // отчет уходит одной строкой
+            self.buf[self.len] = if b == b'\r' || b == b'\n' { b' ' } else { b };
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn record() -> &'static mut CrashRecord {
+    &mut *CRASH.as_mut_ptr()
+}
+
+/// Вызывается из panic handler, прерывания уже запрещены.
+/// Только место паники: форматирование сообщения тянет во flash Debug всех типов из unwrap/expect
+pub unsafe fn save_panic(info: &core::panic::PanicInfo) {
+    let r = record();
+    r.kind = Kind::Panic as u32;
+
+    let mut w = TextWriter {
+        buf: &mut r.text,
+        len: 0,
+    };
+    let _ = match info.location() {
+        Some(l) => write!(&mut w, "panicked at {}:{}", l.file(), l.line()),
+        None => w.write_str("panicked"),
+    };
+    r.text_len = w.len as u32;
+
+    r.magic = CRASH_NEW;
+}
+
+/// Вызывается из HardFault
+pub unsafe fn save_fault(ef: &cortex_m_rt::ExceptionFrame) {
+    let r = record();
+    r.kind = Kind::HardFault as u32;
+    r.text_len = 0;
+
+    r.pc = ef.pc();
+    r.lr = ef.lr();
+    r.xpsr = ef.xpsr();
+    r.cfsr = core::ptr::read_volatile(0xE000_ED28 as *const u32);
+    r.hfsr = core::ptr::read_volatile(0xE000_ED2C as *const u32);
+    r.mmfar = core::ptr::read_volatile(0xE000_ED34 as *const u32);
+    r.bfar = core::ptr::read_volatile(0xE000_ED38 as *const u32);
+
+    r.magic = CRASH_NEW;
+}
+
+/// Вызывается из NMI и т.п.: причина известна
+pub unsafe fn save_message(msg: &str) {
+    let r = record();
+    r.kind = Kind::Message as u32;
+
+    let mut w = TextWriter {
+        buf: &mut r.text,
+        len: 0,
+    };
+    let _ = w.write_str(msg);
+    r.text_len = w.len as u32;
+
+    r.magic = CRASH_NEW;
+}
+
+/// При загрузке: забрать отчет о падении, если он есть
+pub fn check_previous() -> bool {
+    let r = unsafe { record() };
+    let crashed = unsafe { core::ptr::read_volatile(&r.magic) } == CRASH_NEW;
+    if crashed {
+        unsafe { core::ptr::write_volatile(&mut r.magic, CRASH_SEEN) };
+    }
+    PREVIOUS_CRASH.store(crashed, Ordering::SeqCst);
+    crashed
+}
+
+/// `[MSG:Crash: ...]` о падении до этой загрузки
+pub fn print<W: Write>(out: &mut W) {
+    if !PREVIOUS_CRASH.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let r = unsafe { record() };
+    let _ = if r.kind == Kind::HardFault as u32 {
+        write!(
+            out,
+            "[MSG:Crash: HardFault PC={:08x} LR={:08x} xPSR={:08x} CFSR={:08x} HFSR={:08x} MMFAR={:08x} BFAR={:08x}]\r\n",
+            r.pc, r.lr, r.xpsr, r.cfsr, r.hfsr, r.mmfar, r.bfar
+        )
+    } else {
+        let len = (r.text_len as usize).min(TEXT_LEN);
+        let text = core::str::from_utf8(&r.text[..len]).unwrap_or("?");
+        write!(out, "[MSG:Crash: {}]\r\n", text)
+    };
+}
@@ -0,0 +1,14 @@
+/// CRC-16/MODBUS: полином 0x8005 (отраженный 0xA001), начальное 0xFFFF
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, b| {
+        let mut crc = crc ^ *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+        crc
+    })
+}
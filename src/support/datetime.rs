@@ -0,0 +1,126 @@
+//! Календарная дата из секунд с 1970-01-01 00:00:00 и обратно, без часовых поясов
+
+use core::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// день года, 1-366
+    pub yday: u16,
+}
+
+const SECS_PER_DAY: u32 = 86_400;
+
+impl DateTime {
+    pub fn from_unix(secs: u32) -> Self {
+        let days = secs / SECS_PER_DAY;
+        let rem = secs % SECS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+            yday: (days - days_from_civil(year, 1, 1) + 1) as u16,
+        }
+    }
+
+    pub fn to_unix(&self) -> u32 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as u32 * 3600
+            + self.minute as u32 * 60
+            + self.second as u32
+    }
+
+    /// `YYYY-MM-DD HH:MM:SS` (или `T` вместо пробела), 1970-2105
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (date, time) = text.split_once([' ', 'T', 't'])?;
+
+        let mut d = date.split('-').map(|v| v.parse::<u16>().ok());
+        let (year, month, day) = (d.next()??, d.next()??, d.next()??);
+        let mut t = time.trim().split(':').map(|v| v.parse::<u8>().ok());
+        let (hour, minute, second) = (t.next()??, t.next()??, t.next().unwrap_or(Some(0))?);
+        if d.next().is_some() || t.next().is_some() {
+            return None;
+        }
+
+        if !(1970..=2105).contains(&year)
+            || !(1..=12).contains(&month)
+            || day < 1
+            || day > days_in_month(year, month as u8)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+
+        let mut dt = Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour,
+            minute,
+            second,
+            yday: 0,
+        };
+        dt.yday = Self::from_unix(dt.to_unix()).yday;
+        Some(dt)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn is_leap(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u16 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Дни с 1970-01-01 (H. Hinnant, "chrono-compatible low-level date algorithms")
+fn days_from_civil(year: u16, month: u8, day: u8) -> u32 {
+    let y = year as u32 - if month <= 2 { 1 } else { 0 };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month as u32 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as u32 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: u32) -> (u16, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+    (year, month, day)
+}
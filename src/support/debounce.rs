@@ -0,0 +1,27 @@
+/// Подавление дребезга набора входов: новое состояние принимается,
+/// если оно не менялось debounce_nanos
+pub struct Debouncer {
+    stable: u8,
+    last_raw: u8,
+    since: u64,
+}
+
+impl Debouncer {
+    pub const fn new() -> Self {
+        Self {
+            stable: 0,
+            last_raw: 0,
+            since: 0,
+        }
+    }
+
+    pub fn update(&mut self, raw: u8, now_nanos: u64, debounce_nanos: u64) -> u8 {
+        if raw != self.last_raw {
+            self.last_raw = raw;
+            self.since = now_nanos;
+        } else if now_nanos.wrapping_sub(self.since) >= debounce_nanos {
+            self.stable = raw;
+        }
+        self.stable
+    }
+}
@@ -3,13 +3,18 @@ use core::fmt::Write;
 use num::traits::float::FloatCore;
 
 pub fn format_float_simple(v: f32, percision: i32) -> crate::config::HlString {
-    let a = v.floor();
+    let scale = 10u64.pow(percision.max(0) as u32);
+    // rounded once, so the fraction can't carry to 1000 or lose leading zeros
+    let scaled = (v.abs() * scale as f32).round() as u64;
+    let sign = if v < 0.0 && scaled > 0 { "-" } else { "" };
     let mut res = crate::config::HlString::new();
     write!(
         &mut res,
-        "{}.{}",
-        a as i32,
-        ((v - a) * 10.0.powi(percision)).round() as i32
+        "{}{}.{:0width$}",
+        sign,
+        scaled / scale,
+        scaled % scale,
+        width = percision.max(0) as usize
     )
     .unwrap();
     res
@@ -0,0 +1,72 @@
+//! 2D коды для маркировки: DataMatrix ECC200 и QR, матрица модулей без кучи
+
+pub mod datamatrix;
+pub mod qr;
+mod reed_solomon;
+
+/// Сторона самого большого символа: QR версии 6
+pub const MAX_SIZE: usize = 41;
+
+/// Квадратная матрица модулей, строка 0 - верхняя
+#[derive(Clone)]
+pub struct Matrix {
+    size: u8,
+    bits: [u8; (MAX_SIZE * MAX_SIZE).div_ceil(8)],
+}
+
+impl Matrix {
+    pub fn new(size: usize) -> Self {
+        debug_assert!(size <= MAX_SIZE);
+        Self {
+            size: size as u8,
+            bits: [0; (MAX_SIZE * MAX_SIZE).div_ceil(8)],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// true - темный модуль (маркируется)
+    pub fn get(&self, row: usize, col: usize) -> bool {
+        let i = row * self.size() + col;
+        self.bits[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, dark: bool) {
+        let i = row * self.size() + col;
+        if dark {
+            self.bits[i / 8] |= 1 << (i % 8);
+        } else {
+            self.bits[i / 8] &= !(1 << (i % 8));
+        }
+    }
+}
+
+#[cfg(test)]
+impl Matrix {
+    /// Сравнение с эталонным символом: `#` - темный модуль, `.` - светлый
+    pub(crate) fn assert_rows(&self, rows: &[&str]) {
+        assert_eq!(self.size(), rows.len());
+        for (row, line) in rows.iter().enumerate() {
+            let actual: heapless::String<MAX_SIZE> = (0..self.size())
+                .map(|col| if self.get(row, col) { '#' } else { '.' })
+                .collect();
+            assert_eq!(actual.as_str(), *line, "row {}", row);
+        }
+    }
+
+    /// Растр для декодера: `scale` пикселей на модуль, светлое поле `quiet` модулей вокруг
+    pub(crate) fn pixels(&self, scale: usize, quiet: usize) -> (Vec<bool>, usize) {
+        let width = (self.size() + 2 * quiet) * scale;
+        let pixels = (0..width * width)
+            .map(|i| {
+                let (row, col) = (i / width / scale, i % width / scale);
+                (quiet..quiet + self.size()).contains(&row)
+                    && (quiet..quiet + self.size()).contains(&col)
+                    && self.get(row - quiet, col - quiet)
+            })
+            .collect();
+        (pixels, width)
+    }
+}
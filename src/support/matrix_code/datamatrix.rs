@@ -0,0 +1,343 @@
+//! DataMatrix ECC200 (ISO/IEC 16022): кодирование ASCII, квадратные символы 10x10 - 36x36
+
+use super::reed_solomon::Field;
+use super::Matrix;
+
+const FIELD: Field = Field::new(0x12d);
+
+/// Квадратный символ: сторона, областей данных по стороне, слов данных и проверочных
+struct Symbol {
+    size: usize,
+    regions: usize,
+    data: usize,
+    ecc: usize,
+}
+
+#[rustfmt::skip]
+const SYMBOLS: [Symbol; 11] = [
+    Symbol { size: 10, regions: 1, data: 3, ecc: 5 },
+    Symbol { size: 12, regions: 1, data: 5, ecc: 7 },
+    Symbol { size: 14, regions: 1, data: 8, ecc: 10 },
+    Symbol { size: 16, regions: 1, data: 12, ecc: 12 },
+    Symbol { size: 18, regions: 1, data: 18, ecc: 14 },
+    Symbol { size: 20, regions: 1, data: 22, ecc: 18 },
+    Symbol { size: 22, regions: 1, data: 30, ecc: 20 },
+    Symbol { size: 24, regions: 1, data: 36, ecc: 24 },
+    Symbol { size: 26, regions: 1, data: 44, ecc: 28 },
+    Symbol { size: 32, regions: 2, data: 62, ecc: 36 },
+    Symbol { size: 36, regions: 2, data: 86, ecc: 42 },
+];
+
+const MAX_CODEWORDS: usize = 86 + 42;
+
+/// Символ наименьшего размера, None - данные не помещаются в 36x36
+pub fn encode(text: &[u8]) -> Option<Matrix> {
+    let mut codewords = [0u8; MAX_CODEWORDS];
+    let len = encode_ascii(text, &mut codewords)?;
+    let symbol = SYMBOLS.iter().find(|s| s.data >= len)?;
+
+    // заполнение: 129, затем псевдослучайные 129 + ...
+    for (pos, c) in codewords.iter_mut().enumerate().take(symbol.data).skip(len) {
+        *c = if pos == len {
+            129
+        } else {
+            let r = (149 * (pos + 1)) % 253 + 1;
+            let pad = 129 + r;
+            (if pad > 254 { pad - 254 } else { pad }) as u8
+        };
+    }
+
+    let (data, ecc) = codewords[..symbol.data + symbol.ecc].split_at_mut(symbol.data);
+    FIELD.encode(data, 1, ecc);
+
+    Some(place_symbol(symbol, &codewords[..symbol.data + symbol.ecc]))
+}
+
+/// ASCII: пары цифр - одно слово, байты > 127 - через Upper Shift
+fn encode_ascii(text: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        let next = text.get(i + 1).copied().unwrap_or(0);
+        if c.is_ascii_digit() && next.is_ascii_digit() {
+            *out.get_mut(len)? = 130 + (c - b'0') * 10 + (next - b'0');
+            i += 2;
+        } else if c > 127 {
+            *out.get_mut(len)? = 235;
+            len += 1;
+            *out.get_mut(len)? = c - 127;
+            i += 1;
+        } else {
+            *out.get_mut(len)? = c + 1;
+            i += 1;
+        }
+        len += 1;
+    }
+    Some(len)
+}
+
+/// Размещение слов в области данных (ISO/IEC 16022, приложение F), затем
+/// разбиение на области с L-образным искателем и синхродорожками
+fn place_symbol(symbol: &Symbol, codewords: &[u8]) -> Matrix {
+    let region = symbol.size / symbol.regions - 2;
+    let n = region * symbol.regions;
+
+    let mut placement = Placement {
+        rows: n,
+        cols: n,
+        codewords,
+        data: Matrix::new(n),
+        used: Matrix::new(n),
+    };
+    placement.place();
+
+    let mut m = Matrix::new(symbol.size);
+    for ry in 0..symbol.regions {
+        for rx in 0..symbol.regions {
+            let (top, left) = (ry * (region + 2), rx * (region + 2));
+            let last = region + 1;
+            for i in 0..region + 2 {
+                // слева и снизу сплошные, сверху и справа - чередование
+                m.set(top + i, left, true);
+                m.set(top + last, left + i, true);
+                m.set(top, left + i, i % 2 == 0);
+                m.set(top + i, left + last, i % 2 == 1);
+            }
+        }
+    }
+
+    for row in 0..n {
+        for col in 0..n {
+            let r = row + 2 * (row / region) + 1;
+            let c = col + 2 * (col / region) + 1;
+            m.set(r, c, placement.data.get(row, col));
+        }
+    }
+    m
+}
+
+struct Placement<'a> {
+    rows: usize,
+    cols: usize,
+    codewords: &'a [u8],
+    data: Matrix,
+    used: Matrix,
+}
+
+impl<'a> Placement<'a> {
+    fn place(&mut self) {
+        let (rows, cols) = (self.rows as isize, self.cols as isize);
+        let mut pos = 0;
+        let (mut row, mut col): (isize, isize) = (4, 0);
+
+        loop {
+            if row == rows && col == 0 {
+                self.corner(pos, &CORNER1);
+                pos += 1;
+            }
+            if row == rows - 2 && col == 0 && cols % 4 != 0 {
+                self.corner(pos, &CORNER2);
+                pos += 1;
+            }
+            if row == rows - 2 && col == 0 && cols % 8 == 4 {
+                self.corner(pos, &CORNER3);
+                pos += 1;
+            }
+            if row == rows + 4 && col == 2 && cols % 8 == 0 {
+                self.corner(pos, &CORNER4);
+                pos += 1;
+            }
+
+            // вверх-вправо
+            loop {
+                if row < rows && col >= 0 && !self.used.get(row as usize, col as usize) {
+                    self.utah(row, col, pos);
+                    pos += 1;
+                }
+                row -= 2;
+                col += 2;
+                if row < 0 || col >= cols {
+                    break;
+                }
+            }
+            row += 1;
+            col += 3;
+
+            // вниз-влево
+            loop {
+                if row >= 0 && col < cols && !self.used.get(row as usize, col as usize) {
+                    self.utah(row, col, pos);
+                    pos += 1;
+                }
+                row += 2;
+                col -= 2;
+                if row >= rows || col < 0 {
+                    break;
+                }
+            }
+            row += 3;
+            col += 1;
+
+            if row >= rows && col >= cols {
+                break;
+            }
+        }
+
+        // неиспользованный правый нижний угол
+        if !self.used.get(self.rows - 1, self.cols - 1) {
+            self.data.set(self.rows - 1, self.cols - 1, true);
+            self.data.set(self.rows - 2, self.cols - 2, true);
+        }
+    }
+
+    /// Бит bit (0 - старший) слова pos в модуль (row, col), с переносом через край
+    fn module(&mut self, mut row: isize, mut col: isize, pos: usize, bit: u8) {
+        let (rows, cols) = (self.rows as isize, self.cols as isize);
+        if row < 0 {
+            row += rows;
+            col += 4 - ((rows + 4) % 8);
+        }
+        if col < 0 {
+            col += cols;
+            row += 4 - ((cols + 4) % 8);
+        }
+        let (row, col) = (row as usize, col as usize);
+
+        let word = self.codewords.get(pos).copied().unwrap_or(0);
+        self.data.set(row, col, word & (0x80 >> bit) != 0);
+        self.used.set(row, col, true);
+    }
+
+    /// Обычное размещение слова: "буква U" вокруг (row, col)
+    fn utah(&mut self, row: isize, col: isize, pos: usize) {
+        for (bit, (dr, dc)) in UTAH.iter().enumerate() {
+            self.module(row + dr, col + dc, pos, bit as u8);
+        }
+    }
+
+    /// Особые случаи у углов: (строка, столбец) от начала или от конца (отрицательные)
+    fn corner(&mut self, pos: usize, cells: &[(isize, isize); 8]) {
+        let (rows, cols) = (self.rows as isize, self.cols as isize);
+        for (bit, &(r, c)) in cells.iter().enumerate() {
+            let r = if r < 0 { rows + r } else { r };
+            let c = if c < 0 { cols + c } else { c };
+            self.module(r, c, pos, bit as u8);
+        }
+    }
+}
+
+const UTAH: [(isize, isize); 8] = [
+    (-2, -2),
+    (-2, -1),
+    (-1, -2),
+    (-1, -1),
+    (-1, 0),
+    (0, -2),
+    (0, -1),
+    (0, 0),
+];
+
+#[rustfmt::skip]
+const CORNER1: [(isize, isize); 8] = [(-1, 0), (-1, 1), (-1, 2), (0, -2), (0, -1), (1, -1), (2, -1), (3, -1)];
+#[rustfmt::skip]
+const CORNER2: [(isize, isize); 8] = [(-3, 0), (-2, 0), (-1, 0), (0, -4), (0, -3), (0, -2), (0, -1), (1, -1)];
+#[rustfmt::skip]
+const CORNER3: [(isize, isize); 8] = [(-3, 0), (-2, 0), (-1, 0), (0, -2), (0, -1), (1, -1), (2, -1), (3, -1)];
+#[rustfmt::skip]
+const CORNER4: [(isize, isize); 8] = [(-1, 0), (-1, -1), (0, -3), (0, -2), (0, -1), (1, -3), (1, -2), (1, -1)];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // эталоны - символы независимого кодера ECC200 в режиме ASCII, читаются декодером
+
+    #[test]
+    fn digits_10x10() {
+        // пример ISO/IEC 16022: слова 142 164 186 114 25 5 88 102
+        const SYMBOL: [&str; 10] = [
+            "#.#.#.#.#.",
+            "##..#.##.#",
+            "##.....#..",
+            "##...###.#",
+            "##....#...",
+            "#.....####",
+            "###.##....",
+            "####.##..#",
+            "#..###.#..",
+            "##########",
+        ];
+        encode(b"123456").unwrap().assert_rows(&SYMBOL);
+    }
+
+    #[test]
+    fn mixed_case_18x18() {
+        const SYMBOL: [&str; 18] = [
+            "#.#.#.#.#.#.#.#.#.",
+            "#.##.##.#....#.#.#",
+            "##...##.##..#####.",
+            "###.##..#.#.#.#.##",
+            "##.....#..#.#.#...",
+            "###.#.##.#.......#",
+            "#.#.##.#..#.###...",
+            "#...##.#.##....###",
+            "#.###..##..#####..",
+            "#.#.###.#..##.####",
+            "###...###...###...",
+            "#...#.#.#.#.#.#.##",
+            "######.###..##..#.",
+            "#...###.#.##.#...#",
+            "#.##.....#####.#..",
+            "###.##....##.#..##",
+            "#..#.#.#.#.....#..",
+            "##################",
+        ];
+        encode(b"Hello, World!").unwrap().assert_rows(&SYMBOL);
+    }
+
+    #[test]
+    fn padding_14x14() {
+        const SYMBOL: [&str; 14] = [
+            "#.#.#.#.#.#.#.",
+            "#.##.##......#",
+            "###.#...#..#..",
+            "#...#.####...#",
+            "##..#...#.#.#.",
+            "####.#..#....#",
+            "##......#.#...",
+            "#..######.#.##",
+            "##.#...##.#...",
+            "#..#...###.#.#",
+            "#.##..#.......",
+            "#####...##.###",
+            "##...####.....",
+            "##############",
+        ];
+        encode(b"Lot 42/a").unwrap().assert_rows(&SYMBOL);
+    }
+
+    #[test]
+    fn decodes() {
+        let texts: [&[u8]; 5] = [
+            b"SN 000123",
+            b"2026-10-19 A",
+            b"https://example.com/p?id=42",
+            &[b'7'; 171],
+            &[b'z'; 86],
+        ];
+        for text in texts.iter() {
+            let (pixels, width) = encode(text).unwrap().pixels(1, 0);
+            let decoded = ::datamatrix::DataMatrix::decode(&pixels, width).unwrap();
+            assert_eq!(decoded.as_slice(), *text);
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        assert_eq!(encode(&[b'A'; 86]).unwrap().size(), 36);
+        assert!(encode(&[b'A'; 87]).is_none());
+        // пары цифр - одно слово
+        assert_eq!(encode(&[b'1'; 172]).unwrap().size(), 36);
+    }
+}
@@ -0,0 +1,416 @@
+//! QR Code (ISO/IEC 18004): байтовый режим, уровень коррекции M, версии 1-6
+
+use super::reed_solomon::Field;
+use super::Matrix;
+
+const FIELD: Field = Field::new(0x11d);
+
+const MAX_VERSION: usize = 6;
+
+/// Уровень M: проверочных слов в блоке и блоков, по версиям 1-6
+const ECC_PER_BLOCK: [usize; MAX_VERSION] = [10, 16, 26, 18, 24, 16];
+const BLOCKS: [usize; MAX_VERSION] = [1, 1, 1, 2, 2, 4];
+/// Биты формата уровня M
+const ECL_BITS: u32 = 0b00;
+
+const MAX_CODEWORDS: usize = 172;
+
+/// Символ наименьшей версии, None - данные не помещаются в версию 6
+pub fn encode(text: &[u8]) -> Option<Matrix> {
+    let version = (1..=MAX_VERSION).find(|&v| 4 + 8 + text.len() * 8 <= data_codewords(v) * 8)?;
+    let data_len = data_codewords(version);
+
+    let mut data = [0u8; MAX_CODEWORDS];
+    let mut bits = BitWriter {
+        buf: &mut data[..data_len],
+        len: 0,
+    };
+    bits.push(0b0100, 4);
+    bits.push(text.len() as u32, 8);
+    for &b in text {
+        bits.push(b as u32, 8);
+    }
+    // терминатор, до целого байта, затем 0xec 0x11
+    let terminator = (data_len * 8 - bits.len).min(4);
+    bits.push(0, terminator);
+    bits.push(0, (8 - bits.len % 8) % 8);
+    let used = bits.len / 8;
+    for (i, b) in data[used..data_len].iter_mut().enumerate() {
+        *b = if i % 2 == 0 { 0xec } else { 0x11 };
+    }
+
+    let mut codewords = [0u8; MAX_CODEWORDS];
+    let total = interleave(version, &data[..data_len], &mut codewords);
+
+    let size = version * 4 + 17;
+    let mut m = Matrix::new(size);
+    let mut function = Matrix::new(size);
+    draw_function_patterns(version, &mut m, &mut function);
+    draw_codewords(&codewords[..total], &mut m, &function);
+
+    // маска с наименьшим штрафом
+    let (mut best, mut mask) = (u32::MAX, 0);
+    for candidate in 0..8 {
+        apply_mask(candidate, &mut m, &function);
+        draw_format_bits(candidate, &mut m);
+        let penalty = penalty(&m);
+        if penalty < best {
+            (best, mask) = (penalty, candidate);
+        }
+        apply_mask(candidate, &mut m, &function);
+    }
+    apply_mask(mask, &mut m, &function);
+    draw_format_bits(mask, &mut m);
+    Some(m)
+}
+
+/// Слов всего в символе версии v без функциональных модулей
+fn raw_codewords(v: usize) -> usize {
+    let mut bits = (16 * v + 128) * v + 64;
+    if v >= 2 {
+        // до версии 6 - одно выравнивающее поле
+        bits -= 25;
+    }
+    bits / 8
+}
+
+fn data_codewords(v: usize) -> usize {
+    raw_codewords(v) - ECC_PER_BLOCK[v - 1] * BLOCKS[v - 1]
+}
+
+/// Деление данных на блоки, проверочные слова, чередование блоков. Возвращает число слов
+fn interleave(version: usize, data: &[u8], out: &mut [u8]) -> usize {
+    let blocks = BLOCKS[version - 1];
+    let ecc_len = ECC_PER_BLOCK[version - 1];
+    let raw = raw_codewords(version);
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks - ecc_len;
+
+    let mut ecc = [[0u8; 32]; 4];
+    let mut starts = [0usize; 5];
+    for i in 0..blocks {
+        let len = short_len + if i < short_blocks { 0 } else { 1 };
+        starts[i + 1] = starts[i] + len;
+        FIELD.encode(&data[starts[i]..starts[i + 1]], 0, &mut ecc[i][..ecc_len]);
+    }
+
+    let mut n = 0;
+    for i in 0..=short_len {
+        for b in 0..blocks {
+            // в коротких блоках последнего слова нет
+            if starts[b] + i < starts[b + 1] {
+                out[n] = data[starts[b] + i];
+                n += 1;
+            }
+        }
+    }
+    for i in 0..ecc_len {
+        for e in ecc.iter().take(blocks) {
+            out[n] = e[i];
+            n += 1;
+        }
+    }
+    n
+}
+
+fn draw_function_patterns(version: usize, m: &mut Matrix, function: &mut Matrix) {
+    let size = m.size();
+    let mut set = |row: usize, col: usize, dark: bool| {
+        m.set(row, col, dark);
+        function.set(row, col, true);
+    };
+
+    // синхродорожки
+    for i in 0..size {
+        set(6, i, i % 2 == 0);
+        set(i, 6, i % 2 == 0);
+    }
+
+    // искатели с разделителями
+    for (cr, cc) in [(3, 3), (3, size - 4), (size - 4, 3)] {
+        for dr in -4isize..=4 {
+            for dc in -4isize..=4 {
+                let (r, c) = (cr as isize + dr, cc as isize + dc);
+                if r < 0 || c < 0 || r >= size as isize || c >= size as isize {
+                    continue;
+                }
+                let dist = dr.abs().max(dc.abs());
+                set(r as usize, c as usize, dist != 2 && dist != 4);
+            }
+        }
+    }
+
+    // выравнивающее поле
+    if version >= 2 {
+        let center = size as isize - 7;
+        for dr in -2isize..=2 {
+            for dc in -2isize..=2 {
+                let (r, c) = ((center + dr) as usize, (center + dc) as usize);
+                set(r, c, dr.abs().max(dc.abs()) != 1);
+            }
+        }
+    }
+
+    // место под формат и темный модуль, синхродорожки не трогаем
+    for i in (0..9).filter(|&i| i != 6) {
+        set(8, i, false);
+        set(i, 8, false);
+    }
+    for i in 0..8 {
+        set(8, size - 1 - i, false);
+        set(size - 1 - i, 8, false);
+    }
+    set(size - 8, 8, true);
+}
+
+/// Зигзаг парами столбцов справа налево, в обход функциональных модулей
+fn draw_codewords(codewords: &[u8], m: &mut Matrix, function: &Matrix) {
+    let size = m.size();
+    let mut i = 0;
+    let mut right = size - 1;
+    loop {
+        if right == 6 {
+            right = 5;
+        }
+        for vert in 0..size {
+            for j in 0..2 {
+                let col = right - j;
+                let upward = (right + 1) & 2 == 0;
+                let row = if upward { size - 1 - vert } else { vert };
+                if !function.get(row, col) && i < codewords.len() * 8 {
+                    m.set(row, col, codewords[i / 8] & (0x80 >> (i % 8)) != 0);
+                    i += 1;
+                }
+            }
+        }
+        if right < 2 {
+            break;
+        }
+        right -= 2;
+    }
+}
+
+/// Маска инвертирует модули данных, повторное применение снимает ее
+fn apply_mask(mask: u8, m: &mut Matrix, function: &Matrix) {
+    let size = m.size();
+    for row in 0..size {
+        for col in 0..size {
+            let (x, y) = (col, row);
+            let invert = match mask {
+                0 => (x + y) % 2 == 0,
+                1 => y % 2 == 0,
+                2 => x % 3 == 0,
+                3 => (x + y) % 3 == 0,
+                4 => (x / 3 + y / 2) % 2 == 0,
+                5 => x * y % 2 + x * y % 3 == 0,
+                6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+            };
+            if invert && !function.get(row, col) {
+                m.set(row, col, !m.get(row, col));
+            }
+        }
+    }
+}
+
+/// Уровень и маска, BCH(15, 5), две копии
+fn draw_format_bits(mask: u8, m: &mut Matrix) {
+    let size = m.size();
+    let data = ECL_BITS << 3 | mask as u32;
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    let bits = (data << 10 | rem) ^ 0x5412;
+    let bit = |i: usize| (bits >> i) & 1 != 0;
+
+    for i in 0..6 {
+        m.set(i, 8, bit(i));
+    }
+    m.set(7, 8, bit(6));
+    m.set(8, 8, bit(7));
+    m.set(8, 7, bit(8));
+    for i in 9..15 {
+        m.set(8, 14 - i, bit(i));
+    }
+
+    for i in 0..8 {
+        m.set(8, size - 1 - i, bit(i));
+    }
+    for i in 8..15 {
+        m.set(size - 15 + i, 8, bit(i));
+    }
+    m.set(size - 8, 8, true);
+}
+
+/// Штраф маски: серии, блоки 2x2, похожие на искатель участки, баланс
+fn penalty(m: &Matrix) -> u32 {
+    let size = m.size();
+    let mut penalty = 0;
+
+    for horizontal in [true, false] {
+        let get = |a: usize, b: usize| if horizontal { m.get(a, b) } else { m.get(b, a) };
+        for a in 0..size {
+            // серии одного цвета длиной 5 и больше
+            let mut run = 0;
+            for b in 0..size {
+                if b > 0 && get(a, b) == get(a, b - 1) {
+                    run += 1;
+                } else {
+                    run = 1;
+                }
+                if run == 5 {
+                    penalty += 3;
+                } else if run > 5 {
+                    penalty += 1;
+                }
+            }
+
+            // 1:1:3:1:1 в светлой рамке, за каждую сторону с четырьмя светлыми,
+            // за краем - светлые
+            let dark = |b: isize| b >= 0 && (b as usize) < size && get(a, b as usize);
+            for b in 0..size as isize {
+                let finder = [false, true, false, true, true, true, false, true, false]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &d)| dark(b - 1 + i as isize) == d);
+                if finder {
+                    for mut light in [(b - 4..b), (b + 7..b + 11)] {
+                        if light.all(|i| !dark(i)) {
+                            penalty += 40;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for row in 0..size - 1 {
+        for col in 0..size - 1 {
+            let c = m.get(row, col);
+            if c == m.get(row, col + 1) && c == m.get(row + 1, col) && c == m.get(row + 1, col + 1)
+            {
+                penalty += 3;
+            }
+        }
+    }
+
+    let dark = (0..size)
+        .flat_map(|r| (0..size).map(move |c| (r, c)))
+        .filter(|&(r, c)| m.get(r, c))
+        .count();
+    let total = size * size;
+    // отклонение доли темных от 50% шагами по 5%
+    let k = (dark * 20).abs_diff(total * 10).div_ceil(total) - 1;
+    penalty + k as u32 * 10
+}
+
+struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    fn push(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if value & (1 << i) != 0 {
+                self.buf[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // эталоны - символы независимого кодера QR (байтовый режим, уровень M, маска по штрафу)
+
+    #[test]
+    fn mixed_case_version_1() {
+        const SYMBOL: [&str; 21] = [
+            "#######.#..#..#######",
+            "#.....#.##....#.....#",
+            "#.###.#..#.#..#.###.#",
+            "#.###.#.##.#..#.###.#",
+            "#.###.#..###..#.###.#",
+            "#.....#..#.##.#.....#",
+            "#######.#.#.#.#######",
+            "........##.##........",
+            "#.##.###.#.##.#..#.##",
+            "..#.##.###..##...##.#",
+            "##..#.#..###.#.#...##",
+            "#.#.....####....##.#.",
+            "#.##.##...#.#.##....#",
+            "........#.####..#.#.#",
+            "#######.###...#.#....",
+            "#.....#.###.##.#.###.",
+            "#.###.#..##..#.#####.",
+            "#.###.#.#...##...###.",
+            "#.###.#.#..##.##..#..",
+            "#.....#...##.####...#",
+            "#######.#.######..#..",
+        ];
+        encode(b"Hello, World!").unwrap().assert_rows(&SYMBOL);
+    }
+
+    #[test]
+    fn url_version_2() {
+        const SYMBOL: [&str; 25] = [
+            "#######...#.#.#...#######",
+            "#.....#.....#..#..#.....#",
+            "#.###.#.##..#..#..#.###.#",
+            "#.###.#.#..###.##.#.###.#",
+            "#.###.#.#.###.#...#.###.#",
+            "#.....#.##...###..#.....#",
+            "#######.#.#.#.#.#.#######",
+            "........#.#.#.##.........",
+            "#.#####..#.....##.#####..",
+            "###..#.#.#..##...#.#...#.",
+            ".#.#..#..#..#####..#.#.##",
+            "##..##.#.###.#..#.##....#",
+            "....#######.#.#...#...###",
+            "#..#.#.###.........#.#.#.",
+            "#..####..#.##..###.###.##",
+            "#.###......#..###..##...#",
+            "#.##.##.#.##....#####.#..",
+            "........##..#####...##...",
+            "#######...#..##.#.#.#.###",
+            "#.....#.#...##..#...##.#.",
+            "#.###.#.#.#.#.#######.#.#",
+            "#.###.#.###.....###.#####",
+            "#.###.#.#..##...#....##.#",
+            "#.....#....#..###.####..#",
+            "#######.#.##.....#.######",
+        ];
+        encode(b"https://Ex.com/0007").unwrap().assert_rows(&SYMBOL);
+    }
+
+    #[test]
+    fn decodes() {
+        let texts: [&[u8]; 5] = [
+            b"SN 000123",
+            b"2026-10-19 A",
+            b"https://example.com/p?id=42",
+            &[b'7'; 60],
+            &[b'z'; 106],
+        ];
+        for text in texts.iter() {
+            let (pixels, width) = encode(text).unwrap().pixels(4, 4);
+            let mut image = rqrr::PreparedImage::prepare_from_bitmap(width, width, |x, y| {
+                pixels[y * width + x]
+            });
+            let grids = image.detect_grids();
+            assert_eq!(grids.len(), 1);
+            let (_, decoded) = grids[0].decode().unwrap();
+            assert_eq!(decoded.as_bytes(), *text);
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        assert_eq!(encode(&[b'Q'; 106]).unwrap().size(), 41);
+        assert!(encode(&[b'Q'; 107]).is_none());
+    }
+}
@@ -0,0 +1,67 @@
+//! Коды Рида-Соломона над GF(256): DataMatrix - полином 0x12d, корни с α^1,
+//! QR - полином 0x11d, корни с α^0
+
+/// Проверочных слов в блоке не больше
+const MAX_ECC: usize = 64;
+
+pub struct Field {
+    exp: [u8; 255],
+    log: [u8; 256],
+}
+
+impl Field {
+    pub const fn new(poly: u16) -> Self {
+        let mut exp = [0u8; 255];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= poly;
+            }
+            i += 1;
+        }
+        Self { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[(self.log[a as usize] as usize + self.log[b as usize] as usize) % 255]
+        }
+    }
+
+    /// Проверочные слова data, корни генератора α^first .. α^(first + ecc.len() - 1)
+    pub fn encode(&self, data: &[u8], first: usize, ecc: &mut [u8]) {
+        let n = ecc.len();
+        debug_assert!(n <= MAX_ECC);
+
+        // коэффициенты генератора от старшего, без старшей 1
+        let mut gen = [0u8; MAX_ECC];
+        gen[n - 1] = 1;
+        for i in 0..n {
+            let root = self.exp[(first + i) % 255];
+            for j in 0..n {
+                gen[j] = self.mul(gen[j], root);
+                if j + 1 < n {
+                    gen[j] ^= gen[j + 1];
+                }
+            }
+        }
+
+        // остаток от деления data(x) * x^n на генератор
+        ecc.fill(0);
+        for &b in data {
+            let factor = b ^ ecc[0];
+            ecc.copy_within(1.., 0);
+            ecc[n - 1] = 0;
+            for j in 0..n {
+                ecc[j] ^= self.mul(gen[j], factor);
+            }
+        }
+    }
+}
@@ -1,10 +1,30 @@
 pub mod clocking;
 
+pub mod crash;
+pub mod datetime;
+pub mod debounce;
+pub mod matrix_code;
+pub mod modbus_rtu;
+
+pub mod nv_storage;
+
 pub mod parallel_input_bus;
+pub mod parallel_io;
 pub mod parallel_output_bus;
 
+pub mod rtc;
+
+mod crc16;
+pub use crc16::crc16;
+
 mod map;
 pub use map::map;
 
 mod format_float_simple;
 pub use format_float_simple::format_float_simple;
+
+mod parse_float;
+pub use parse_float::parse_float;
+
+mod sin_cos;
+pub use sin_cos::sin_cos;
@@ -0,0 +1,460 @@
+//! Modbus RTU slave: прием кадров, функции 03, 04, 06, 16 и ответ.
+//!
+//! Кадр: `адрес | функция | данные | CRC16` (CRC-16/MODBUS, младшим байтом вперед).
+//! Конец кадра - по длине из заголовка функции, иначе по паузе t3.5. Кадр с ошибкой CRC
+//! или чужим адресом молча отбрасывается, адрес 0 (broadcast) - запись без ответа.
+//! Порт и регистры - трейты, без HAL: разбор проверяется на хосте с поддельным портом.
+
+use super::crc16;
+
+/// Кадр RTU не длиннее
+pub const FRAME_MAX: usize = 256;
+
+const FN_READ_HOLDING: u8 = 0x03;
+const FN_READ_INPUT: u8 = 0x04;
+const FN_WRITE_SINGLE: u8 = 0x06;
+const FN_WRITE_MULTIPLE: u8 = 0x10;
+
+/// Регистров в запросе не больше (ответ помещается в кадр)
+const READ_MAX: u16 = 125;
+const WRITE_MAX: u16 = 123;
+
+/// Адрес, функция, CRC
+const FRAME_MIN: usize = 4;
+const BROADCAST: u8 = 0;
+
+/// Коды исключений в ответе `функция | 0x80`
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ModbusException {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+}
+
+/// Последовательный порт: UART или USB CDC
+pub trait ModbusPort {
+    /// Принятый байт, None - пока нет
+    fn read(&mut self) -> Option<u8>;
+    /// Передать сколько примет порт, возвращает число переданных байт
+    fn write(&mut self, data: &[u8]) -> usize;
+}
+
+/// Карта регистров устройства
+pub trait ModbusRegisters {
+    /// Input register (04)
+    fn read_input(&self, addr: u16) -> Result<u16, ModbusException>;
+    /// Holding register (03)
+    fn read_holding(&self, addr: u16) -> Result<u16, ModbusException>;
+    /// Holding register (06, 16)
+    fn write_holding(&mut self, addr: u16, value: u16) -> Result<(), ModbusException>;
+}
+
+pub struct ModbusSlave {
+    address: u8,
+    /// t3.5: пауза, после которой кадр считается законченным, нс
+    silence_nanos: u64,
+
+    frame: [u8; FRAME_MAX],
+    len: usize,
+    last_rx_nanos: u64,
+
+    /// ответ, еще не весь переданный
+    reply: [u8; FRAME_MAX],
+    reply_len: usize,
+    sent: usize,
+}
+
+impl ModbusSlave {
+    pub const fn new() -> Self {
+        Self {
+            address: 1,
+            silence_nanos: 1_750_000,
+            frame: [0; FRAME_MAX],
+            len: 0,
+            last_rx_nanos: 0,
+            reply: [0; FRAME_MAX],
+            reply_len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Адрес 1..247 и скорость порта, бод
+    pub fn configure(&mut self, address: u8, baud: u32) {
+        self.address = address;
+        // 3.5 символа по 11 бит, выше 19200 бод - фиксированные 1.75 мс
+        self.silence_nanos = if baud > 19200 {
+            1_750_000
+        } else {
+            38_500_000_000 / baud.max(1) as u64
+        };
+    }
+
+    /// Прием, исполнение запроса и передача ответа. true - исполнен кадр для этого адреса
+    pub fn poll<P: ModbusPort, R: ModbusRegisters>(
+        &mut self,
+        port: &mut P,
+        regs: &mut R,
+        now_nanos: u64,
+    ) -> bool {
+        self.flush(port);
+
+        let mut executed = false;
+        while let Some(b) = port.read() {
+            // пауза внутри кадра - начало нового
+            if self.len > 0 && now_nanos.saturating_sub(self.last_rx_nanos) > self.silence_nanos {
+                executed |= self.end_of_frame(regs);
+            }
+            if self.len == FRAME_MAX {
+                self.len = 0;
+            }
+            self.frame[self.len] = b;
+            self.len += 1;
+            self.last_rx_nanos = now_nanos;
+
+            if Some(self.len) == self.expected_len() {
+                executed |= self.execute(regs);
+                self.len = 0;
+            }
+        }
+
+        if self.len > 0 && now_nanos.saturating_sub(self.last_rx_nanos) > self.silence_nanos {
+            executed |= self.end_of_frame(regs);
+        }
+
+        self.flush(port);
+        executed
+    }
+
+    /// Длина кадра по заголовку, None - еще неизвестна или функция без известной длины
+    fn expected_len(&self) -> Option<usize> {
+        match self.frame[..self.len] {
+            [_, FN_READ_HOLDING | FN_READ_INPUT | FN_WRITE_SINGLE, ..] => Some(8),
+            [_, FN_WRITE_MULTIPLE, _, _, _, _, count, ..] => Some(9 + count as usize),
+            _ => None,
+        }
+    }
+
+    /// Пауза t3.5: кадр неизвестной длины исполняется, обрывок отбрасывается
+    fn end_of_frame<R: ModbusRegisters>(&mut self, regs: &mut R) -> bool {
+        let executed = self.expected_len().is_none() && self.execute(regs);
+        self.len = 0;
+        executed
+    }
+
+    /// Проверка CRC и адреса, исполнение и ответ
+    fn execute<R: ModbusRegisters>(&mut self, regs: &mut R) -> bool {
+        if self.len < FRAME_MIN {
+            return false;
+        }
+        let end = self.len - 2;
+        let crc = u16::from_le_bytes([self.frame[end], self.frame[end + 1]]);
+        if crc16(&self.frame[..end]) != crc {
+            return false;
+        }
+        let address = self.frame[0];
+        if address != self.address && address != BROADCAST {
+            return false;
+        }
+
+        let function = self.frame[1];
+        self.reply[0] = self.address;
+        self.reply[1] = function;
+        let len = match self.function(regs, function, end) {
+            Ok(len) => len,
+            Err(e) => {
+                self.reply[1] = function | 0x80;
+                self.reply[2] = e as u8;
+                3
+            }
+        };
+
+        // на broadcast ответа нет
+        if address != BROADCAST {
+            let crc = crc16(&self.reply[..len]).to_le_bytes();
+            self.reply[len..len + 2].copy_from_slice(&crc);
+            self.reply_len = len + 2;
+            self.sent = 0;
+        }
+        true
+    }
+
+    /// Данные ответа в reply[2..], возвращает длину ответа без CRC
+    fn function<R: ModbusRegisters>(
+        &mut self,
+        regs: &mut R,
+        function: u8,
+        end: usize,
+    ) -> Result<usize, ModbusException> {
+        let frame = &self.frame;
+        let word = |at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+
+        match function {
+            FN_READ_HOLDING | FN_READ_INPUT => {
+                let (start, count) = (word(2), word(4));
+                if count == 0 || count > READ_MAX {
+                    return Err(ModbusException::IllegalDataValue);
+                }
+                for i in 0..count {
+                    let addr = start
+                        .checked_add(i)
+                        .ok_or(ModbusException::IllegalDataAddress)?;
+                    let value = if function == FN_READ_HOLDING {
+                        regs.read_holding(addr)?
+                    } else {
+                        regs.read_input(addr)?
+                    };
+                    let at = 3 + 2 * i as usize;
+                    self.reply[at..at + 2].copy_from_slice(&value.to_be_bytes());
+                }
+                self.reply[2] = (2 * count) as u8;
+                Ok(3 + 2 * count as usize)
+            }
+            FN_WRITE_SINGLE => {
+                regs.write_holding(word(2), word(4))?;
+                // ответ - эхо запроса
+                self.reply[2..6].copy_from_slice(&self.frame[2..6]);
+                Ok(6)
+            }
+            FN_WRITE_MULTIPLE => {
+                let (start, count) = (word(2), word(4));
+                let bytes = 2 * count as usize;
+                if count == 0 || count > WRITE_MAX || frame[6] as usize != bytes || end != 7 + bytes
+                {
+                    return Err(ModbusException::IllegalDataValue);
+                }
+                // регистры пишутся по порядку, до первой ошибки
+                for i in 0..count {
+                    let addr = start
+                        .checked_add(i)
+                        .ok_or(ModbusException::IllegalDataAddress)?;
+                    regs.write_holding(addr, word(7 + 2 * i as usize))?;
+                }
+                self.reply[2..6].copy_from_slice(&self.frame[2..6]);
+                Ok(6)
+            }
+            _ => Err(ModbusException::IllegalFunction),
+        }
+    }
+
+    /// Передать остаток ответа
+    fn flush<P: ModbusPort>(&mut self, port: &mut P) {
+        if self.sent < self.reply_len {
+            self.sent += port.write(&self.reply[self.sent..self.reply_len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Порт: принятые байты из очереди, передача - не больше room байт
+    struct Port {
+        rx: heapless::Deque<u8, FRAME_MAX>,
+        tx: heapless::Vec<u8, FRAME_MAX>,
+        room: usize,
+    }
+
+    impl Port {
+        fn new() -> Self {
+            Self {
+                rx: heapless::Deque::new(),
+                tx: heapless::Vec::new(),
+                room: FRAME_MAX,
+            }
+        }
+
+        fn receive(&mut self, data: &[u8]) {
+            for b in data {
+                self.rx.push_back(*b).unwrap();
+            }
+        }
+    }
+
+    impl ModbusPort for Port {
+        fn read(&mut self) -> Option<u8> {
+            self.rx.pop_front()
+        }
+
+        fn write(&mut self, data: &[u8]) -> usize {
+            let n = data.len().min(self.room);
+            self.tx.extend_from_slice(&data[..n]).unwrap();
+            self.room -= n;
+            n
+        }
+    }
+
+    /// Holding 0-7, запись 0xffff - недопустимое значение; input 0-15 - 0x100 + адрес
+    struct Registers {
+        holding: [u16; 8],
+    }
+
+    impl ModbusRegisters for Registers {
+        fn read_input(&self, addr: u16) -> Result<u16, ModbusException> {
+            if addr < 16 {
+                Ok(0x100 + addr)
+            } else {
+                Err(ModbusException::IllegalDataAddress)
+            }
+        }
+
+        fn read_holding(&self, addr: u16) -> Result<u16, ModbusException> {
+            self.holding
+                .get(addr as usize)
+                .copied()
+                .ok_or(ModbusException::IllegalDataAddress)
+        }
+
+        fn write_holding(&mut self, addr: u16, value: u16) -> Result<(), ModbusException> {
+            match self.holding.get_mut(addr as usize) {
+                Some(_) if value == 0xffff => Err(ModbusException::IllegalDataValue),
+                Some(r) => {
+                    *r = value;
+                    Ok(())
+                }
+                None => Err(ModbusException::IllegalDataAddress),
+            }
+        }
+    }
+
+    /// Кадр с CRC
+    fn frame(body: &[u8]) -> heapless::Vec<u8, FRAME_MAX> {
+        let mut f = heapless::Vec::from_slice(body).unwrap();
+        f.extend_from_slice(&crc16(body).to_le_bytes()).unwrap();
+        f
+    }
+
+    struct Bus {
+        slave: ModbusSlave,
+        regs: Registers,
+        port: Port,
+        now: u64,
+    }
+
+    impl Bus {
+        fn new() -> Self {
+            let mut slave = ModbusSlave::new();
+            slave.configure(7, 19200);
+            Self {
+                slave,
+                regs: Registers { holding: [0; 8] },
+                port: Port::new(),
+                now: 1_000_000_000,
+            }
+        }
+
+        fn poll(&mut self) -> bool {
+            self.slave.poll(&mut self.port, &mut self.regs, self.now)
+        }
+
+        /// Запрос целиком, ответ после паузы между кадрами
+        fn request(&mut self, body: &[u8]) -> heapless::Vec<u8, FRAME_MAX> {
+            self.port.receive(&frame(body));
+            self.poll();
+            self.now += 10_000_000;
+            self.poll();
+            core::mem::take(&mut self.port.tx)
+        }
+    }
+
+    #[test]
+    fn crc() {
+        // CRC-16/MODBUS "123456789" = 0x4b37
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+        // чтение holding 0, 1 регистр у адреса 1: CRC 84 0A
+        assert_eq!(frame(&[1, 3, 0, 0, 0, 1])[6..], [0x84, 0x0a]);
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut bus = Bus::new();
+        bus.regs.holding[2] = 0x1234;
+
+        assert_eq!(
+            bus.request(&[7, 3, 0, 1, 0, 2]),
+            frame(&[7, 3, 4, 0, 0, 0x12, 0x34])
+        );
+        assert_eq!(
+            bus.request(&[7, 4, 0, 14, 0, 2]),
+            frame(&[7, 4, 4, 0x01, 0x0e, 0x01, 0x0f])
+        );
+    }
+
+    #[test]
+    fn write_registers() {
+        let mut bus = Bus::new();
+
+        // 06: ответ - эхо запроса
+        assert_eq!(
+            bus.request(&[7, 6, 0, 3, 0xab, 0xcd]),
+            frame(&[7, 6, 0, 3, 0xab, 0xcd])
+        );
+        assert_eq!(bus.regs.holding[3], 0xabcd);
+
+        // 16: адрес и число регистров
+        assert_eq!(
+            bus.request(&[7, 0x10, 0, 4, 0, 2, 4, 0, 1, 0, 2]),
+            frame(&[7, 0x10, 0, 4, 0, 2])
+        );
+        assert_eq!(bus.regs.holding[4..6], [1, 2]);
+
+        // broadcast исполняется без ответа
+        bus.port.receive(&frame(&[0, 6, 0, 0, 0, 9]));
+        assert!(bus.poll());
+        assert!(bus.port.tx.is_empty());
+        assert_eq!(bus.regs.holding[0], 9);
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut bus = Bus::new();
+
+        assert_eq!(bus.request(&[7, 3, 0, 7, 0, 2]), frame(&[7, 0x83, 2]));
+        assert_eq!(bus.request(&[7, 4, 0, 0, 0, 0]), frame(&[7, 0x84, 3]));
+        assert_eq!(bus.request(&[7, 4, 0, 0, 0, 126]), frame(&[7, 0x84, 3]));
+        assert_eq!(bus.request(&[7, 6, 0, 1, 0xff, 0xff]), frame(&[7, 0x86, 3]));
+        // счетчик байт не совпадает с числом регистров
+        assert_eq!(
+            bus.request(&[7, 0x10, 0, 0, 0, 2, 2, 0, 1]),
+            frame(&[7, 0x90, 3])
+        );
+        // неизвестная функция - по паузе t3.5
+        assert_eq!(bus.request(&[7, 0x2b, 1, 2]), frame(&[7, 0xab, 1]));
+
+        // чужой адрес и ошибка CRC - без ответа
+        assert!(bus.request(&[8, 3, 0, 0, 0, 1]).is_empty());
+        let mut bad = frame(&[7, 3, 0, 0, 0, 1]);
+        bad[7] ^= 1;
+        bus.port.receive(&bad);
+        assert!(!bus.poll());
+        assert!(bus.port.tx.is_empty());
+    }
+
+    #[test]
+    fn inter_frame_timeout() {
+        let mut bus = Bus::new();
+        let request = frame(&[7, 3, 0, 0, 0, 1]);
+
+        // кадр по частям в пределах t3.5 (2 мс при 19200 бод), ответ через медленный порт
+        bus.port.room = 3;
+        bus.port.receive(&request[..3]);
+        assert!(!bus.poll());
+        bus.now += 1_000_000;
+        bus.port.receive(&request[3..]);
+        assert!(bus.poll());
+        assert_eq!(bus.port.tx.len(), 3);
+        bus.port.room = FRAME_MAX;
+        bus.poll();
+        assert_eq!(bus.port.tx, frame(&[7, 3, 2, 0, 0]));
+        bus.port.tx.clear();
+
+        // пауза больше t3.5 отбрасывает обрывок, следующий кадр принимается
+        bus.port.receive(&request[..3]);
+        bus.poll();
+        bus.now += 3_000_000;
+        bus.port.receive(&request);
+        assert!(bus.poll());
+        assert_eq!(bus.port.tx, frame(&[7, 3, 2, 0, 0]));
+    }
+}
@@ -0,0 +1,40 @@
+/// Энергонезависимая память: внутренний flash или внешняя микросхема
+pub trait NvStorage {
+    /// размер области, байт
+    fn capacity(&self) -> usize;
+
+    /// размер стираемого блока, байт
+    fn erase_size(&self) -> usize;
+
+    fn read(&self, offset: usize, buf: &mut [u8]);
+
+    /// стирает блоки, покрывающие [offset, offset + len)
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError>;
+
+    /// запись в предварительно стертую область
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NvError {
+    OutOfRange,
+    Program,
+    WriteProtected,
+}
+
+pub const CHECKSUM_INIT: u32 = 0x811c_9dc5;
+
+/// FNV-1a, для проверки целостности записей
+pub fn checksum(data: &[u8]) -> u32 {
+    checksum_update(CHECKSUM_INIT, data)
+}
+
+/// FNV-1a по частям: начиная с CHECKSUM_INIT
+pub fn checksum_update(hash: u32, data: &[u8]) -> u32 {
+    data.iter()
+        .fold(hash, |h, b| (h ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+#[cfg(test)]
+pub mod ram;
+pub mod stm32f1_flash;
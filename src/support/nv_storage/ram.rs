@@ -0,0 +1,49 @@
+//! flash в RAM для тестов на хосте
+
+use super::{NvError, NvStorage};
+
+pub const PAGE: usize = 1024;
+
+/// Страницы по PAGE байт, запись только в стертые байты
+pub struct Ram<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Ram<N> {
+    pub fn new() -> Self {
+        Self([0xff; N])
+    }
+}
+
+impl<const N: usize> NvStorage for Ram<N> {
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn erase_size(&self) -> usize {
+        PAGE
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError> {
+        let end = (offset + len + PAGE - 1) / PAGE * PAGE;
+        if end > N {
+            return Err(NvError::OutOfRange);
+        }
+        self.0[offset / PAGE * PAGE..end].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+        let dst = self
+            .0
+            .get_mut(offset..offset + data.len())
+            .ok_or(NvError::OutOfRange)?;
+        if dst.iter().any(|b| *b != 0xff) {
+            return Err(NvError::Program);
+        }
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use stm32f1xx_hal::pac::FLASH;
+
+use super::{NvError, NvStorage};
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// 3.2 FLASH main features: page size = 1K
+const PAGE_SIZE: usize = 1024;
+
+/// Область внутреннего flash, не занятая прошивкой (см. memory.x)
+pub struct InternalFlash {
+    base: u32,
+    size: usize,
+}
+
+impl InternalFlash {
+    pub const fn new(base: u32, size: usize) -> Self {
+        Self { base, size }
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), NvError> {
+        if offset + len > self.size {
+            Err(NvError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unlock(flash: &stm32f1xx_hal::pac::flash::RegisterBlock) {
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+    }
+
+    fn lock(flash: &stm32f1xx_hal::pac::flash::RegisterBlock) {
+        flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    fn wait_result(flash: &stm32f1xx_hal::pac::flash::RegisterBlock) -> Result<(), NvError> {
+        while flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = flash.sr.read();
+        let res = if sr.pgerr().bit_is_set() {
+            Err(NvError::Program)
+        } else if sr.wrprterr().bit_is_set() {
+            Err(NvError::WriteProtected)
+        } else {
+            Ok(())
+        };
+
+        // сброс флагов записью 1
+        flash
+            .sr
+            .write(|w| w.eop().set_bit().pgerr().set_bit().wrprterr().set_bit());
+        res
+    }
+}
+
+impl NvStorage for InternalFlash {
+    fn capacity(&self) -> usize {
+        self.size
+    }
+
+    fn erase_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        let len = buf.len().min(self.size.saturating_sub(offset));
+        let src = (self.base as usize + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), len) };
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> Result<(), NvError> {
+        self.check_range(offset, len)?;
+
+        let flash = unsafe { &*FLASH::ptr() };
+        Self::unlock(flash);
+
+        let mut res = Ok(());
+        let first = offset / PAGE_SIZE;
+        let last = (offset + len + PAGE_SIZE - 1) / PAGE_SIZE;
+        for page in first..last {
+            flash.cr.modify(|_, w| w.per().set_bit());
+            flash
+                .ar
+                .write(|w| unsafe { w.far().bits(self.base + (page * PAGE_SIZE) as u32) });
+            flash.cr.modify(|_, w| w.strt().set_bit());
+            res = Self::wait_result(flash);
+            flash.cr.modify(|_, w| w.per().clear_bit());
+            if res.is_err() {
+                break;
+            }
+        }
+
+        Self::lock(flash);
+        res
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+        if offset & 1 != 0 {
+            return Err(NvError::OutOfRange);
+        }
+        self.check_range(offset, data.len())?;
+
+        let flash = unsafe { &*FLASH::ptr() };
+        Self::unlock(flash);
+
+        // программирование только по 16 бит
+        let mut res = Ok(());
+        flash.cr.modify(|_, w| w.pg().set_bit());
+        for (i, chunk) in data.chunks(2).enumerate() {
+            let hw = chunk[0] as u16 | (*chunk.get(1).unwrap_or(&0xff) as u16) << 8;
+            let dest = (self.base as usize + offset + i * 2) as *mut u16;
+            unsafe { core::ptr::write_volatile(dest, hw) };
+            compiler_fence(Ordering::SeqCst);
+            res = Self::wait_result(flash);
+            if res.is_err() {
+                break;
+            }
+        }
+        flash.cr.modify(|_, w| w.pg().clear_bit());
+
+        Self::lock(flash);
+        res
+    }
+}
@@ -1,5 +1,7 @@
 pub trait ParallelInputBus {
     type Input;
+    /// число линий шины
+    const WIDTH: u8;
     fn get(&self) -> Self::Input;
 }
 
@@ -10,6 +12,7 @@ macro_rules! simple_parallel_input_bus {
 
         impl crate::support::parallel_input_bus::ParallelInputBus for $name {
             type Input = $valtype;
+            const WIDTH: u8 = ${count($pint)};
 
             fn get(&self) -> $valtype {
                 let mut res = 0;
@@ -0,0 +1,70 @@
+//! Отдельные линии поверх параллельных шин: выходы M62-M65 включаются по одному,
+//! остальные линии шины сохраняют состояние, входы читаются с полярностью и подавлением дребезга
+
+use super::debounce::Debouncer;
+use super::parallel_input_bus::ParallelInputBus;
+use super::parallel_output_bus::ParallelOutputBus;
+
+pub struct DigitalOutputs<B> {
+    bus: B,
+    state: u8,
+}
+
+impl<B: ParallelOutputBus<Output = u8>> DigitalOutputs<B> {
+    /// Все выходы выключены
+    pub fn new(mut bus: B) -> Self {
+        bus.set(0);
+        Self { bus, state: 0 }
+    }
+
+    /// Число выходов, M62-M65 P<0..count-1>
+    pub fn count(&self) -> u8 {
+        B::WIDTH
+    }
+
+    /// Включить/выключить несколько выходов сразу
+    pub fn update(&mut self, on_mask: u8, off_mask: u8) {
+        self.state = (self.state | on_mask) & !off_mask;
+        self.bus.set(self.state);
+    }
+}
+
+pub struct DigitalInputs<B> {
+    bus: B,
+    debouncer: Debouncer,
+    active: u8,
+}
+
+impl<B: ParallelInputBus<Input = u8>> DigitalInputs<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            debouncer: Debouncer::new(),
+            active: 0,
+        }
+    }
+
+    /// Число входов, M66 P<0..count-1>
+    pub fn count(&self) -> u8 {
+        B::WIDTH
+    }
+
+    /// Прочитать шину, invert - маска инверсии. Возвращает входы, ставшие активными
+    pub fn poll(&mut self, invert: u8, now_nanos: u64, debounce_nanos: u64) -> u8 {
+        let active = self
+            .debouncer
+            .update(self.bus.get() ^ invert, now_nanos, debounce_nanos);
+        let rising = active & !self.active;
+        self.active = active;
+        rising
+    }
+
+    pub fn is_active(&self, mask: u8) -> bool {
+        self.active & mask != 0
+    }
+
+    /// Активные входы, биты как у шины
+    pub fn state(&self) -> u8 {
+        self.active
+    }
+}
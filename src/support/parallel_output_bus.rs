@@ -1,5 +1,7 @@
 pub trait ParallelOutputBus {
     type Output;
+    /// число линий шины
+    const WIDTH: u8;
     fn set(&mut self, value: Self::Output);
 }
 
@@ -12,6 +14,7 @@ macro_rules! simple_parallel_output_bus {
 
         impl crate::support::parallel_output_bus::ParallelOutputBus for $name {
             type Output = $valtype;
+            const WIDTH: u8 = ${count($pint)};
 
             fn set(&mut self, value: Self::Output) {
                 $(
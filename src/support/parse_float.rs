@@ -0,0 +1,60 @@
+/// `[+-]<цифры>[.<цифры>]` без `core::num::dec2flt`: его таблицы степеней занимают 13 КБ flash.
+/// До 7 значащих цифр результат тот же, что у `str::parse`, знаки дробной части сверх u32 отбрасываются
+pub fn parse_float(text: &str) -> Option<f32> {
+    let (negative, digits) = match text.as_bytes() {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        rest => (false, rest),
+    };
+
+    let mut mantissa = 0u32;
+    let mut divider = 1.0f32;
+    let mut point = false;
+    let mut any_digit = false;
+    for &c in digits {
+        match c {
+            b'.' if !point => point = true,
+            b'0'..=b'9' => {
+                any_digit = true;
+                match mantissa
+                    .checked_mul(10)
+                    .and_then(|m| m.checked_add((c - b'0') as u32))
+                {
+                    Some(m) => {
+                        mantissa = m;
+                        if point {
+                            divider *= 10.0;
+                        }
+                    }
+                    None if point => {}
+                    None => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+    if !any_digit {
+        return None;
+    }
+
+    let value = mantissa as f32 / divider;
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_float;
+
+    #[test]
+    fn same_as_core() {
+        for text in [
+            "0", "-0.5", "+12", "1.", ".25", "0.1", "-123.456", "9999.999", "0.0001", "16777217",
+        ] {
+            assert_eq!(parse_float(text), text.parse::<f32>().ok(), "{}", text);
+        }
+        for text in ["", "-", ".", "1.2.3", "1e3", "inf", "12a", "99999999999"] {
+            assert_eq!(parse_float(text), None, "{}", text);
+        }
+        assert!((parse_float("0.1234567891234").unwrap() - 0.123456789).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,25 @@
+//! Часы реального времени: секунды с 1970-01-01 в счетчике, который идет
+//! без ядра и переживает перезагрузку
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtcError {
+    NotRunning,
+}
+
+pub trait RealTimeClock {
+    /// Секунды с 1970-01-01, None - часы не установлены или не идут
+    fn time(&self) -> Option<u32>;
+
+    fn set_time(&mut self, secs: u32) -> Result<(), RtcError>;
+
+    /// Счетчик тактируется и считает
+    fn is_running(&self) -> bool;
+
+    /// Источник тактирования, для `$I`
+    fn source(&self) -> &'static str;
+
+    /// Часы идут при выключенном питании (батарея VBAT)
+    fn keeps_time_off_power(&self) -> bool;
+}
+
+pub mod stm32f1_rtc;
@@ -0,0 +1,197 @@
+use stm32f1xx_hal::pac::{BKP, PWR, RCC, RTC};
+
+use super::{RealTimeClock, RtcError};
+
+/// BKP_DR1: домен резервного питания настроен этой прошивкой с этим источником
+const MAGIC_LSE: u16 = 0x4c53;
+const MAGIC_HSE: u16 = 0x4853;
+/// BKP_DR2: время установлено хостом
+const TIME_SET: u16 = 0x5453;
+
+/// 32.768 кГц кварц на PC14/PC15
+const LSE_FREQ: u32 = 32_768;
+
+/// Ожидание синхронизации и записи регистров RTC, мс
+const SYNC_TIMEOUT_MS: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RtcSource {
+    /// часовой кварц: считает от батареи VBAT и при выключенном питании
+    Lse,
+    /// HSE / 128, Гц кварца: считает только пока есть питание, переживает сброс
+    Hse(u32),
+}
+
+impl RtcSource {
+    fn magic(&self) -> u16 {
+        match self {
+            RtcSource::Lse => MAGIC_LSE,
+            RtcSource::Hse(_) => MAGIC_HSE,
+        }
+    }
+
+    fn freq(&self) -> u32 {
+        match self {
+            RtcSource::Lse => LSE_FREQ,
+            RtcSource::Hse(hse) => hse / 128,
+        }
+    }
+}
+
+/// 18 Real-time clock (RTC): 32-битный счетчик секунд, делитель до 2^20
+pub struct Stm32f1Rtc {
+    rtc: RTC,
+    bkp: BKP,
+    source: RtcSource,
+    running: bool,
+    /// тактов ядра на 1 мс, для таймаутов
+    cycles_per_ms: u32,
+}
+
+impl Stm32f1Rtc {
+    /// Если домен уже настроен с тем же источником - счет продолжается,
+    /// иначе домен сбрасывается и часы считаются не установленными
+    pub fn new(rtc: RTC, bkp: BKP, pwr: &PWR, source: RtcSource, sysclk_hz: u32) -> Self {
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr
+            .modify(|_, w| w.pwren().set_bit().bkpen().set_bit());
+        // запись в домен резервного питания
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        let mut res = Self {
+            rtc,
+            bkp,
+            source,
+            running: false,
+            cycles_per_ms: sysclk_hz / 1000,
+        };
+        res.running = if res.bkp.dr[0].read().d().bits() == source.magic() {
+            res.sync()
+        } else {
+            res.configure()
+        };
+        res
+    }
+
+    fn configure(&mut self) -> bool {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        // источник выбирается один раз после сброса домена
+        rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+        rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+
+        match self.source {
+            RtcSource::Lse => {
+                rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+                let lse_ok = self.wait(crate::config::RTC_LSE_STARTUP_TIMEOUT_MS, || {
+                    rcc.bdcr.read().lserdy().bit_is_set()
+                });
+                if !lse_ok {
+                    rcc.bdcr.modify(|_, w| w.lseon().clear_bit());
+                    return false;
+                }
+                rcc.bdcr.modify(|_, w| w.rtcsel().lse().rtcen().set_bit());
+            }
+            RtcSource::Hse(_) => {
+                // работа от HSI - кварца нет
+                if rcc.cr.read().hserdy().bit_is_clear() {
+                    return false;
+                }
+                rcc.bdcr.modify(|_, w| w.rtcsel().hse().rtcen().set_bit());
+            }
+        }
+
+        if !self.sync() {
+            return false;
+        }
+        let prl = self.source.freq() - 1;
+        let written = self.write(|rtc| {
+            rtc.prlh.write(|w| unsafe { w.bits(prl >> 16) });
+            rtc.prll.write(|w| unsafe { w.bits(prl & 0xffff) });
+            rtc.cnth.write(|w| unsafe { w.bits(0) });
+            rtc.cntl.write(|w| unsafe { w.bits(0) });
+        });
+        if written {
+            self.bkp.dr[0].write(|w| w.d().bits(self.source.magic()));
+        }
+        written
+    }
+
+    /// После сброса ядра регистры RTC читаются только после синхронизации (RSF)
+    fn sync(&self) -> bool {
+        self.rtc.crl.modify(|_, w| w.rsf().clear_bit());
+        self.wait(SYNC_TIMEOUT_MS, || self.rtc.crl.read().rsf().bit_is_set())
+    }
+
+    /// 18.3.4 Configuring RTC registers: CNF, запись, ожидание RTOFF
+    fn write<F: FnOnce(&RTC)>(&self, f: F) -> bool {
+        if !self.wait(SYNC_TIMEOUT_MS, || self.rtc.crl.read().rtoff().bit_is_set()) {
+            return false;
+        }
+        self.rtc.crl.modify(|_, w| w.cnf().set_bit());
+        f(&self.rtc);
+        self.rtc.crl.modify(|_, w| w.cnf().clear_bit());
+        self.wait(SYNC_TIMEOUT_MS, || self.rtc.crl.read().rtoff().bit_is_set())
+    }
+
+    fn wait<F: Fn() -> bool>(&self, timeout_ms: u32, ready: F) -> bool {
+        for _ in 0..timeout_ms {
+            if ready() {
+                return true;
+            }
+            cortex_m::asm::delay(self.cycles_per_ms);
+        }
+        ready()
+    }
+
+    fn counter(&self) -> u32 {
+        // половины счетчика читаются отдельно, перенос между ними - повторить
+        loop {
+            let high = self.rtc.cnth.read().bits();
+            let low = self.rtc.cntl.read().bits();
+            if high == self.rtc.cnth.read().bits() {
+                return high << 16 | low;
+            }
+        }
+    }
+}
+
+impl RealTimeClock for Stm32f1Rtc {
+    fn time(&self) -> Option<u32> {
+        if self.running && self.bkp.dr[1].read().d().bits() == TIME_SET {
+            Some(self.counter())
+        } else {
+            None
+        }
+    }
+
+    fn set_time(&mut self, secs: u32) -> Result<(), RtcError> {
+        if !self.running {
+            return Err(RtcError::NotRunning);
+        }
+        let written = self.write(|rtc| {
+            rtc.cnth.write(|w| unsafe { w.bits(secs >> 16) });
+            rtc.cntl.write(|w| unsafe { w.bits(secs & 0xffff) });
+        });
+        if !written {
+            return Err(RtcError::NotRunning);
+        }
+        self.bkp.dr[1].write(|w| w.d().bits(TIME_SET));
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn source(&self) -> &'static str {
+        match self.source {
+            RtcSource::Lse => "LSE",
+            RtcSource::Hse(_) => "HSE/128",
+        }
+    }
+
+    fn keeps_time_off_power(&self) -> bool {
+        matches!(self.source, RtcSource::Lse)
+    }
+}
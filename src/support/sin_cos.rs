@@ -0,0 +1,38 @@
+/// sin и cos угла в радианах целиком в f32: `libm::sinf`/`cosf` считают через f64
+/// и тянут программный double, около 5 КБ flash. Приведение к [-pi/4, pi/4] точно
+/// для углов, что встречаются в разметке (десятки оборотов)
+pub fn sin_cos(angle: f32) -> (f32, f32) {
+    // pi/2 = PIO2_HI + PIO2_LO, у PIO2_HI 8 значащих бит: q * PIO2_HI без округления
+    const PIO2_HI: f32 = 1.5703125;
+    const PIO2_LO: f32 = 4.838_268e-4;
+
+    let q = libm::roundf(angle * core::f32::consts::FRAC_2_PI);
+    let x = (angle - q * PIO2_HI) - q * PIO2_LO;
+    let z = x * x;
+
+    // полиномы Cephes sinf/cosf
+    let sin = ((-1.951_529_6e-4 * z + 8.332_161e-3) * z - 1.666_665_5e-1) * z * x + x;
+    let cos = ((2.443_315_7e-5 * z - 1.388_731_6e-3) * z + 4.166_664_6e-2) * z * z - 0.5 * z + 1.0;
+
+    match q as i32 & 3 {
+        0 => (sin, cos),
+        1 => (cos, -sin),
+        2 => (-sin, -cos),
+        _ => (-cos, sin),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sin_cos;
+
+    #[test]
+    fn matches_std() {
+        for i in -2000..=2000 {
+            let a = i as f32 * 0.0317;
+            let (sin, cos) = sin_cos(a);
+            assert!((sin - a.sin()).abs() < 2e-6, "sin {}", a);
+            assert!((cos - a.cos()).abs() < 2e-6, "cos {}", a);
+        }
+    }
+}